@@ -0,0 +1,93 @@
+/// Where the stripper is in an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State{
+    Text,
+    /// Just after an `ESC`
+    Escape,
+    /// In a control sequence (`ESC [`), like the SGR sequences that set colors, which end with a byte from `@` to `~`
+    Csi,
+    /// In an operating system command (`ESC ]`), which ends with a BEL or an ST (`ESC \`)
+    Osc,
+    /// An `ESC` in an operating system command, which is usually the start of the ST ending it
+    OscEscape,
+    /// Just after `ESC (` or similar, which picks a character set with the next byte
+    Charset
+}
+
+/// Removes ANSI escape sequences (colors, cursor movement, window titles and so on) from output, leaving just the text
+/// 
+/// Programs think they're talking to a terminal, so they send these even to clients that can't do anything with them.
+/// Sequences can be split across calls to `strip`, since output arrives in arbitrary chunks
+#[derive(Debug)]
+pub struct AnsiStripper{
+    state: State
+}
+impl AnsiStripper{
+    pub fn new() -> Self{
+        Self{state: State::Text}
+    }
+
+    /// Returns `output` with any escape sequences in it taken out
+    pub fn strip(&mut self, output: &[u8]) -> Vec<u8>{
+        let mut res = Vec::with_capacity(output.len());
+        for &byte in output{
+            self.state = match (self.state, byte){
+                (State::Text, 0x1b) => State::Escape,
+                (State::Text, _) => {
+                    res.push(byte);
+                    State::Text
+                },
+                (State::Escape, b'[') => State::Csi,
+                (State::Escape, b']') => State::Osc,
+                (State::Escape, b'(' | b')' | b'*' | b'+') => State::Charset,
+                (State::Escape, 0x1b) => State::Escape,
+                // everything else after an ESC is a sequence of its own (like `ESC 7`, which saves the cursor)
+                (State::Escape, _) => State::Text,
+                (State::Csi, 0x40..=0x7e) => State::Text,
+                (State::Csi, 0x1b) => State::Escape,
+                (State::Csi, _) => State::Csi,
+                (State::Osc, 0x07) => State::Text,
+                (State::Osc, 0x1b) => State::OscEscape,
+                (State::Osc, _) => State::Osc,
+                (State::OscEscape, b'\\') => State::Text,
+                (State::OscEscape, _) => State::Osc,
+                (State::Charset, _) => State::Text
+            };
+        }
+        res
+    }
+}
+
+impl Default for AnsiStripper{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const COLORED: &[u8] = b"\x1b]0;pi@raspberrypi: ~\x07\x1b[01;32mpi@raspberrypi\x1b[00m:\x1b(B\x1b7~\x1b]2;title\x1b\\ $ ";
+
+    #[test]
+    fn escapes_are_removed(){
+        assert_eq!(AnsiStripper::new().strip(COLORED), b"pi@raspberrypi:~ $ ");
+        assert_eq!(AnsiStripper::new().strip(b"no escapes here\r\n"), b"no escapes here\r\n");
+    }
+
+    #[test]
+    fn escapes_split_across_chunks_are_removed(){
+        // every place the output could be split should give the same text
+        for split in 0..=COLORED.len(){
+            let mut stripper = AnsiStripper::new();
+            let mut res = stripper.strip(&COLORED[..split]);
+            res.extend(stripper.strip(&COLORED[split..]));
+            assert_eq!(res, b"pi@raspberrypi:~ $ ", "split at {}", split);
+        }
+        // and so should feeding it one byte at a time
+        let mut stripper = AnsiStripper::new();
+        let res: Vec<u8> = COLORED.iter().flat_map(|byte| stripper.strip(&[*byte])).collect();
+        assert_eq!(res, b"pi@raspberrypi:~ $ ");
+    }
+}
@@ -0,0 +1,73 @@
+use std::{env, fs::{File, OpenOptions}, io::{self, Write}, sync::{Mutex, OnceLock}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::logger;
+
+/// File given by the "RSPI_AUDIT_LOG" enviorment variable, shared by every client thread
+fn audit_file() -> &'static Mutex<Option<File>>{
+    static AUDIT_FILE: OnceLock<Mutex<Option<File>>> = OnceLock::new();
+    AUDIT_FILE.get_or_init(|| {
+        let file = env::var_os("RSPI_AUDIT_LOG").filter(|p| !p.is_empty()).and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(&path){
+                Ok(f) => Some(f),
+                Err(e) => {
+                    logger::error!("Could not open audit log {}: {}", path.to_string_lossy(), e);
+                    None
+                }
+            }
+        });
+        Mutex::new(file)
+    })
+}
+
+/// Appends a line to the audit log with the current unix timestamp, the client's IP, the kind of event, and its details
+/// 
+/// Does nothing if "RSPI_AUDIT_LOG" isn't set
+pub fn record(ip: &str, kind: &str, details: &str){
+    let mut file = match audit_file().lock(){
+        Ok(file) => file,
+        Err(e) => e.into_inner()
+    };
+    if let Some(file) = file.as_mut(){
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if let Err(e) = write_line(file, secs, ip, kind, details){
+            logger::error!("Could not write to audit log: {}", e);
+        }
+    }
+}
+
+/// Makes the audit log go to `file` from now on, whatever "RSPI_AUDIT_LOG" says, so tests can read back what was recorded
+#[cfg(test)]
+pub fn log_to(file: File){
+    let mut current = match audit_file().lock(){
+        Ok(current) => current,
+        Err(e) => e.into_inner()
+    };
+    *current = Some(file);
+}
+
+/// Writes one tab separated line of the audit log to `to`
+fn write_line(to: &mut impl Write, secs: u64, ip: &str, kind: &str, details: &str) -> io::Result<()>{
+    // newlines would let a client forge extra audit entries
+    let details = details.replace(['\n', '\r'], " ");
+    to.write_all(format!("{}\t{}\t{}\t{}\n", secs, ip, kind, details).as_bytes())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn lines_are_tab_separated(){
+        let mut log = Vec::new();
+        write_line(&mut log, 1700000000, "127.0.0.1", "auth_failed", "alice").unwrap();
+        write_line(&mut log, 1700000001, "127.0.0.1", "cmd", "ls -la").unwrap();
+        assert_eq!(String::from_utf8(log).unwrap(), "1700000000\t127.0.0.1\tauth_failed\talice\n1700000001\t127.0.0.1\tcmd\tls -la\n");
+    }
+
+    #[test]
+    fn details_cant_forge_extra_lines(){
+        let mut log = Vec::new();
+        write_line(&mut log, 1, "10.0.0.2", "cmd", "echo hi\r\n2\t10.0.0.3\tcmd\tforged").unwrap();
+        assert_eq!(String::from_utf8(log).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,236 @@
+//! Deciding which clients may log in, which embedders can replace with their own `Authenticator`
+
+use std::{env, io::{self, ErrorKind}, net::SocketAddr, path::PathBuf, str, sync::Arc};
+#[cfg(feature = "users")]
+use std::{fs, num::NonZeroU32, path::Path};
+
+#[cfg(feature = "users")]
+use ring::{pbkdf2, rand::{SecureRandom, SystemRandom}};
+
+/// What a user is allowed to do once they've logged in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role{
+    /// Can do anything, including managing processes that belong to the server or other clients
+    #[default]
+    Admin,
+    /// Can only run commands in their own session, and can't use `rspi` commands that manage the server's processes
+    Restricted
+}
+
+/// Who a client logged in as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User{
+    pub name: String,
+    /// Directory the user's sessions start in, and that `~` refers to
+    pub home: Option<PathBuf>,
+    pub role: Role
+}
+
+/// What an `Authenticator` decided about a client trying to log in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult{
+    /// The client may log in. If the authenticator knows who it is, its name shows up in the logs and audit log, and
+    /// it gets that user's home directory and role. Otherwise it's an admin
+    Accepted(Option<User>),
+    /// The client may not log in, for the `reason` given. The reason is logged, but never sent to the client, and `user`
+    /// is who the client said it was, if the authenticator could tell, which goes in the audit log too.\
+    /// Neither should ever contain what the client submitted as its password
+    Rejected{reason: String, user: Option<String>}
+}
+
+/// Decides whether a client may log in, from the first message it sends after connecting
+/// 
+/// Servers use `from_env` to pick one unless they're given something else, like a check against PAM, with
+/// `Server::set_authenticator`. Clients that log in with a TLS client certificate skip this entirely
+pub trait Authenticator: Send + Sync{
+    /// Checks the login message a client `submitted`. `peer` is where it connected from, or None for a Unix socket
+    fn authenticate(&self, submitted: &[u8], peer: Option<SocketAddr>) -> AuthResult;
+}
+
+/// Accepts clients that send the password in the "RSPI_SERVER_PASS" enviorment variable, or "Password" if it isn't set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvPassword;
+impl Authenticator for EnvPassword{
+    fn authenticate(&self, submitted: &[u8], _peer: Option<SocketAddr>) -> AuthResult{
+        let pass = env::var("RSPI_SERVER_PASS").unwrap_or(String::from("Password"));
+        let received = str::from_utf8(submitted).unwrap_or_default().trim_end_matches('\0');
+        if pass == received{
+            AuthResult::Accepted(None)
+        }else{
+            AuthResult::Rejected{reason: String::from("incorrect password"), user: None}
+        }
+    }
+}
+
+/// How many rounds of PBKDF2 new password hashes use
+#[cfg(feature = "users")]
+const HASH_ITERATIONS: u32 = 100_000;
+
+/// Hashes `password` with a random salt, in the form stored in a users file:
+/// `pbkdf2-sha256$<iterations>$<salt in hex>$<hash in hex>`
+#[cfg(feature = "users")]
+pub fn hash_password(password: &str) -> io::Result<String>{
+    let mut salt = [0u8; 16];
+    SystemRandom::new().fill(&mut salt).map_err(|_| io::Error::other("Could not generate a salt"))?;
+    let mut hash = [0u8; 32];
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).unwrap_or(NonZeroU32::MIN);
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    Ok(format!("pbkdf2-sha256${}${}${}", HASH_ITERATIONS, to_hex(&salt), to_hex(&hash)))
+}
+
+/// Checks `password` against a hash made by `hash_password`. Hashes that can't be parsed never match
+#[cfg(feature = "users")]
+pub fn verify_password(password: &str, hash: &str) -> bool{
+    let parts: Vec<&str> = hash.split('$').collect();
+    let ["pbkdf2-sha256", iterations, salt, hash] = parts.as_slice() else { return false };
+    let (Some(iterations), Some(salt), Some(hash)) = (iterations.parse().ok().and_then(NonZeroU32::new), from_hex(salt), from_hex(hash)) else { return false };
+    pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+#[cfg(feature = "users")]
+fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(feature = "users")]
+fn from_hex(hex: &str) -> Option<Vec<u8>>{
+    if !hex.len().is_multiple_of(2){
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// One line of a users file
+#[cfg(feature = "users")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UserEntry{
+    user: User,
+    hash: String
+}
+
+/// Accepts clients that log in with `<username>:<password>` as one of the users in a file
+/// 
+/// The file is given by the "RSPI_USERS_FILE" enviorment variable, with one `username:passwordhash:homedir:role`
+/// line per user, where the hash comes from `hash_password` (or `rs-pi-server --hash-password`), the home directory
+/// can be left empty and the role is `admin` or `restricted`. Blank lines and lines starting with `#` are skipped.\
+/// The file is read again for every login, so users can be added and removed without restarting the server
+#[cfg(feature = "users")]
+#[derive(Debug, Clone)]
+pub struct UserFile{
+    path: PathBuf
+}
+#[cfg(feature = "users")]
+impl UserFile{
+    /// Uses the users in the file at `path`, returning `io::ErrorKind::InvalidData` if it can't be parsed
+    pub fn new(path: &Path) -> io::Result<Self>{
+        Self::load(path)?;
+        Ok(Self{path: path.to_owned()})
+    }
+
+    fn load(path: &Path) -> io::Result<Vec<UserEntry>>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(src: &str) -> io::Result<Vec<UserEntry>>{
+        src.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| {
+            let invalid = |why: &str| io::Error::new(ErrorKind::InvalidData, format!("{} in users file line {:?}", why, line));
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, hash, home, role] = fields.as_slice() else { return Err(invalid("Expected username:passwordhash:homedir:role")) };
+            let role = match *role{
+                "admin" => Role::Admin,
+                "restricted" => Role::Restricted,
+                _ => return Err(invalid("Expected a role of admin or restricted"))
+            };
+            let home = Some(PathBuf::from(home)).filter(|home| !home.as_os_str().is_empty());
+            Ok(UserEntry{user: User{name: name.to_string(), home, role}, hash: hash.to_string()})
+        }).collect()
+    }
+}
+#[cfg(feature = "users")]
+impl Authenticator for UserFile{
+    fn authenticate(&self, submitted: &[u8], _peer: Option<SocketAddr>) -> AuthResult{
+        let received = str::from_utf8(submitted).unwrap_or_default().trim_end_matches('\0');
+        let Some((name, password)) = received.split_once(':') else {
+            return AuthResult::Rejected{reason: String::from("expected username:password"), user: None}
+        };
+        let users = match Self::load(&self.path){
+            Ok(users) => users,
+            Err(e) => return AuthResult::Rejected{reason: format!("could not read {}: {}", self.path.display(), e), user: Some(name.to_owned())}
+        };
+        match users.into_iter().find(|entry| entry.user.name == name){
+            Some(entry) if verify_password(password, &entry.hash) => AuthResult::Accepted(Some(entry.user)),
+            Some(_) => AuthResult::Rejected{reason: String::from("incorrect password"), user: Some(name.to_owned())},
+            None => AuthResult::Rejected{reason: String::from("no such user"), user: Some(name.to_owned())}
+        }
+    }
+}
+
+/// The authenticator a server uses unless it's given another: `UserFile` if "RSPI_USERS_FILE" is set, otherwise `EnvPassword`
+pub fn from_env() -> io::Result<Arc<dyn Authenticator>>{
+    match env::var_os("RSPI_USERS_FILE").filter(|p| !p.is_empty()){
+        #[cfg(feature = "users")]
+        Some(path) => Ok(Arc::new(UserFile::new(Path::new(&path))?)),
+        // falling back to the shared password would let in clients the users file was meant to keep out
+        #[cfg(not(feature = "users"))]
+        Some(_) => Err(users_unsupported()),
+        None => Ok(Arc::new(EnvPassword))
+    }
+}
+
+/// Stands in for `hash_password` when the server is built without the users feature, returning `io::ErrorKind::Unsupported`
+#[cfg(not(feature = "users"))]
+pub fn hash_password(_password: &str) -> io::Result<String>{
+    Err(users_unsupported())
+}
+
+#[cfg(not(feature = "users"))]
+fn users_unsupported() -> io::Error{
+    io::Error::new(ErrorKind::Unsupported, "RSPI_USERS_FILE needs the server to be built with the users feature")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rejected_passwords_arent_repeated(){
+        let AuthResult::Rejected{reason, user} = EnvPassword.authenticate(b"hunter2\0\0", None) else { panic!("accepted a wrong password") };
+        assert!(!reason.contains("hunter2"), "{}", reason);
+        assert_eq!(user, None);
+    }
+
+    #[test]
+    #[cfg(feature = "users")]
+    fn users_file_logins(){
+        let path = env::temp_dir().join(format!("rspi-auth-users-{}", std::process::id()));
+        fs::write(&path, format!("# comment\n\nalice:{}::admin\nbob:{}:/home/bob:restricted\n",
+            hash_password("wonderland").unwrap(), hash_password("builder").unwrap())).unwrap();
+        let users = UserFile::new(&path).unwrap();
+
+        assert_eq!(users.authenticate(b"alice:wonderland", None), AuthResult::Accepted(Some(User{name: String::from("alice"), home: None, role: Role::Admin})));
+        assert_eq!(users.authenticate(b"bob:builder", None),
+            AuthResult::Accepted(Some(User{name: String::from("bob"), home: Some(PathBuf::from("/home/bob")), role: Role::Restricted})));
+        for (submitted, who) in [(&b"alice:builder"[..], Some("alice")), (b"carol:wonderland", Some("carol")), (b"wonderland", None)]{
+            let AuthResult::Rejected{reason, user} = users.authenticate(submitted, None) else { panic!("accepted {:?}", submitted) };
+            assert!(!reason.contains("wonderland") && !reason.contains("builder"), "{}", reason);
+            assert_eq!(user.as_deref(), who);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "users")]
+    fn users_files_that_cant_be_parsed_are_errors(){
+        assert_eq!(UserFile::parse("alice:hash:").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(UserFile::parse("alice:hash::superuser").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "users")]
+    fn hashes_that_cant_be_parsed_never_match(){
+        assert!(!verify_password("", ""));
+        assert!(!verify_password("pw", "pbkdf2-sha256$0$00$00"));
+        assert!(!verify_password("pw", "md5$1$00$00"));
+        assert!(verify_password("pw", &hash_password("pw").unwrap()));
+    }
+}
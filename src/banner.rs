@@ -0,0 +1,92 @@
+use std::fmt;
+
+/// Version of the protocol this server speaks, bumped whenever a change would break existing clients
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional parts of the protocol this server supports, which clients can choose to use
+/// 
+/// `framing` is length-prefixed messages, turned on by sending `RSPI_FRAMED`, `lines` is newline-delimited
+/// messages, turned on by sending `RSPI_LINES`, and `sequenced` is length-prefixed messages that can't be replayed,
+/// turned on by sending `RSPI_SEQUENCED`
+pub const FEATURES: [&str; 3] = ["framing", "lines", "sequenced"];
+
+/// Describes a version of the protocol and the features that go with it, written like `RSPI/1 features=framing`
+/// 
+/// The server sends its own as a `BANNER` control message as soon as a client connects, and clients can send one
+/// back (prefixed by `RSPI_REQUIRE `) to be disconnected if the server can't give them what they need
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Banner{
+    pub version: u32,
+    pub features: Vec<String>
+}
+impl Banner{
+    /// The banner describing this server
+    pub fn server() -> Self{
+        Self{version: PROTOCOL_VERSION, features: FEATURES.iter().map(|f| f.to_string()).collect()}
+    }
+
+    /// Parses a banner, ie. "RSPI/1 features=framing,other". The features can be left out
+    pub fn parse(src: &str) -> Option<Self>{
+        let mut parts = src.split_whitespace();
+        let version = parts.next()?.strip_prefix("RSPI/")?.parse().ok()?;
+        let features = match parts.next(){
+            Some(features) => features.strip_prefix("features=")?.split(',').filter(|f| !f.is_empty()).map(str::to_owned).collect(),
+            None => Vec::new()
+        };
+        if parts.next().is_some() { return None }
+        Some(Self{version, features})
+    }
+
+    /// Lists what `required` asks for that this banner doesn't have, which is empty if they're compatible
+    /// 
+    /// Newer protocol versions aren't compatible with older ones, but older ones are with newer
+    pub fn missing(&self, required: &Banner) -> Vec<String>{
+        let mut missing = Vec::new();
+        if required.version > self.version{
+            missing.push(format!("RSPI/{}", required.version));
+        }
+        missing.extend(required.features.iter().filter(|f| !self.features.contains(f)).cloned());
+        missing
+    }
+}
+impl fmt::Display for Banner{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "RSPI/{} features={}", self.version, self.features.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn banner(version: u32, features: &[&str]) -> Banner{
+        Banner{version, features: features.iter().map(|f| f.to_string()).collect()}
+    }
+
+    #[test]
+    fn banners_parse(){
+        assert_eq!(Banner::parse("RSPI/1 features=framing,lines"), Some(banner(1, &["framing", "lines"])));
+        assert_eq!(Banner::parse("  RSPI/2   features=sequenced "), Some(banner(2, &["sequenced"])));
+        assert_eq!(Banner::parse("RSPI/3"), Some(banner(3, &[])));
+        assert_eq!(Banner::parse("RSPI/1 features="), Some(banner(1, &[])));
+        assert_eq!(Banner::parse("RSPI/1 features=,framing,"), Some(banner(1, &["framing"])));
+        for bad in ["", "RSPI", "RSPI/", "RSPI/x", "rspi/1", "SSH/2", "RSPI/1 framing", "RSPI/1 features=a extra", "RSPI/-1"]{
+            assert_eq!(Banner::parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn the_server_banner_round_trips(){
+        assert_eq!(Banner::parse(&Banner::server().to_string()), Some(Banner::server()));
+        assert!(Banner::server().missing(&Banner::server()).is_empty());
+    }
+
+    #[test]
+    fn missing_lists_newer_versions_and_unknown_features(){
+        let server = banner(2, &["framing", "lines"]);
+        assert!(server.missing(&banner(1, &[])).is_empty());
+        assert!(server.missing(&banner(2, &["lines", "framing"])).is_empty());
+        assert_eq!(server.missing(&banner(3, &[])), ["RSPI/3"]);
+        assert_eq!(server.missing(&banner(3, &["framing", "compression", "resume"])), ["RSPI/3", "compression", "resume"]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+/// How much of a command's output a session keeps when it's captured in head-tail mode, by default
+pub const DEFAULT_CAPTURE_BYTES: usize = 64 * 1024;
+
+/// Collects the first and last `limit` bytes of a command's output, counting how many bytes in the middle were dropped
+/// 
+/// Used in place of streaming output for clients that only want a bounded copy of it once the command finishes
+pub struct HeadTailCapture{
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    dropped: u64,
+    total: u64
+}
+impl HeadTailCapture{
+    pub fn new(limit: usize) -> Self{
+        Self{limit, head: Vec::new(), tail: VecDeque::new(), dropped: 0, total: 0}
+    }
+
+    /// Adds output to the capture, which goes into the head until it is full and into the tail after that
+    pub fn write(&mut self, mut buf: &[u8]){
+        self.total += buf.len() as u64;
+        let to_head = buf.len().min(self.limit - self.head.len());
+        self.head.extend_from_slice(&buf[..to_head]);
+        buf = &buf[to_head..];
+        // only the last `limit` bytes of what's left can end up in the tail
+        if buf.len() > self.limit{
+            self.dropped += (buf.len() - self.limit) as u64;
+            buf = &buf[buf.len() - self.limit..];
+        }
+        let overflow = (self.tail.len() + buf.len()).saturating_sub(self.limit);
+        self.tail.drain(..overflow);
+        self.dropped += overflow as u64;
+        self.tail.extend(buf);
+    }
+
+    /// Total number of bytes written to the capture, including the ones that were dropped
+    pub fn total(&self) -> u64{
+        self.total
+    }
+
+    /// The head, followed by a `...truncated M bytes...` line and the tail if anything was dropped in between
+    pub fn finish(self) -> Vec<u8>{
+        let mut res = self.head;
+        if self.dropped > 0{
+            if res.last() != Some(&b'\n') { res.push(b'\n'); }
+            res.extend_from_slice(format!("...truncated {} bytes...\n", self.dropped).as_bytes());
+        }
+        res.extend(self.tail);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Captures `output`, written in pieces of `chunk` bytes, keeping `limit` bytes at each end
+    fn capture(output: &[u8], limit: usize, chunk: usize) -> (Vec<u8>, u64){
+        let mut capture = HeadTailCapture::new(limit);
+        for piece in output.chunks(chunk){
+            capture.write(piece);
+        }
+        let total = capture.total();
+        (capture.finish(), total)
+    }
+
+    #[test]
+    fn short_output_is_kept_whole(){
+        assert_eq!(capture(b"hello\n", 4, 1), (b"hello\n".to_vec(), 6));
+        assert_eq!(capture(b"12345678", 4, 3), (b"12345678".to_vec(), 8));
+        assert_eq!(capture(b"", 4, 1), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn the_middle_is_dropped_and_counted(){
+        let output: Vec<u8> = (b'a'..=b'z').collect();
+        for chunk in [1, 3, 7, 26]{
+            let (captured, total) = capture(&output, 4, chunk);
+            assert_eq!(String::from_utf8(captured).unwrap(), "abcd\n...truncated 18 bytes...\nwxyz", "chunks of {}", chunk);
+            assert_eq!(total, 26);
+        }
+        // the truncation line doesn't get a blank line before it if the head already ends one
+        let (captured, _) = capture(b"ab\nmiddle\nyz", 3, 5);
+        assert_eq!(captured, b"ab\n...truncated 6 bytes...\n\nyz");
+    }
+}
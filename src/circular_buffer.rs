@@ -19,19 +19,82 @@ impl<const N: usize> CircularBuffer<N>{
 
     /// Writes the entire contents of this circular buffer to a writer
     pub fn write_to<T: Write>(&mut self, to: &mut T) -> io::Result<()>{
-        to.write_all(&self.data[self.head..N.min(self.head + self.len)])?;
-        if self.head + self.len > N {
-            to.write_all(&self.data[..self.len])?;
+        self.write_prefix_to(to, self.len)
+    }
+
+    /// Writes the first `len` bytes of this circular buffer to a writer, leaving the rest
+    pub fn write_prefix_to<T: Write>(&mut self, to: &mut T, len: usize) -> io::Result<()>{
+        let len = len.min(self.len);
+        to.write_all(&self.data[self.head..N.min(self.head + len)])?;
+        if self.head + len > N {
+            to.write_all(&self.data[..self.head + len - N])?;
         }
-        self.head = (self.head + self.len) % N;
-        self.len = 0;
+        self.head = (self.head + len) % N;
+        self.len -= len;
         Ok(())
     }
 
+    /// Copies everything in this buffer, without removing any of it
+    pub fn to_vec(&self) -> Vec<u8>{
+        let mut res = Vec::with_capacity(self.len);
+        res.extend_from_slice(&self.data[self.head..N.min(self.head + self.len)]);
+        if self.head + self.len > N{
+            res.extend_from_slice(&self.data[..self.head + self.len - N]);
+        }
+        res
+    }
+
+    /// Writes as much of `buf` as fits without overwriting anything, returning how many bytes that was
+    /// 
+    /// Unlike `write`, this never loses data. Once the buffer is full it writes nothing and returns 0, leaving
+    /// the rest of `buf` for the caller to try again once some has been read
+    pub fn write_lossless(&mut self, buf: &[u8]) -> usize{
+        let size = buf.len().min(self.free_space());
+        if size > 0{
+            let _ = self.write(&buf[..size]);
+        }
+        size
+    }
+
+    /// Number of bytes at the end of this buffer that are the start of a UTF-8 character whose
+    /// remaining bytes haven't been written yet
+    pub fn incomplete_utf8_len(&self) -> usize{
+        // a character is at most 4 bytes, so its first byte is within the last 3 of an incomplete one
+        for back in 1..=self.len.min(3){
+            let byte = self.data[(self.head + self.len - back) % N];
+            // skip over continuation bytes, which look like 0b10xxxxxx
+            if byte & 0xC0 == 0x80 { continue }
+            let char_len = match byte{
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1
+            };
+            return if back < char_len { back } else { 0 }
+        }
+        0
+    }
+
+    /// Throws away everything written to this buffer
+    pub fn clear(&mut self){
+        self.head = 0;
+        self.len = 0;
+    }
+
     pub fn is_empty(&self) -> bool{
         self.len == 0
     }
 
+    /// Number of bytes that can be written to this buffer before it starts overwriting what's already in it
+    pub fn free_space(&self) -> usize{
+        self.allocated_size() - self.len
+    }
+
+    /// Whether any more writes to this buffer will overwrite what's already in it
+    pub fn is_full(&self) -> bool{
+        self.len == N
+    }
+
     pub const fn allocated_size(&self) -> usize{
         N
     }
@@ -62,7 +125,7 @@ impl <const N: usize> Write for CircularBuffer<N>{
     /// size of this buffer. 
     /// 
     /// Writes after the buffer reaches length `N` will cause previously written data
-    /// to get overwritten. 
+    /// to get overwritten, oldest first (see `free_space`)
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let tail = (self.head + self.len) % N;
         let size = N.min(buf.len());
@@ -71,6 +134,9 @@ impl <const N: usize> Write for CircularBuffer<N>{
         if first_half < size{
             self.data[..size-first_half].copy_from_slice(&buf[first_half..size]);
         }
+        // whatever was overwritten was the oldest data, so the buffer now starts just after it
+        let overwritten = size.saturating_sub(self.free_space());
+        self.head = (self.head + overwritten) % N;
         self.len = N.min(self.len + size);
         Ok(size)
     }
@@ -84,4 +150,107 @@ impl <const N: usize> Default for CircularBuffer<N>{
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn cleared_buffers_are_empty_and_can_be_written_again(){
+        let mut buf = CircularBuffer::<4>::new();
+        buf.write_all(b"ab").unwrap();
+        buf.read_exact(&mut [0u8; 1]).unwrap();
+        // full, and wrapped around the end of the array
+        assert_eq!(buf.write_lossless(b"cdef"), 3);
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(buf.free_space(), 4);
+        assert_eq!(buf.to_vec(), b"");
+
+        assert_eq!(buf.write_lossless(b"ghij"), 4);
+        assert_eq!(buf.to_vec(), b"ghij");
+        let mut out = [0u8; 4];
+        buf.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"ghij");
+    }
+
+    #[test]
+    fn incomplete_characters_are_found_across_the_wrap(){
+        let mut buf = CircularBuffer::<8>::new();
+        buf.write_all("ab€".as_bytes()).unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 0);
+        // move the start along, so the next character wraps around the end of the array
+        buf.read_exact(&mut [0u8; 5]).unwrap();
+        buf.write_all(&"c€".as_bytes()[..3]).unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 2);
+        buf.write_all(&"€".as_bytes()[2..]).unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 0);
+        buf.write_all(&"😀".as_bytes()[..3]).unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 3);
+
+        // only the complete part is taken out
+        let mut out = Vec::new();
+        buf.write_prefix_to(&mut out, buf.len() - buf.incomplete_utf8_len()).unwrap();
+        assert_eq!(out, "c€".as_bytes());
+        assert_eq!(buf.to_vec(), &"😀".as_bytes()[..3]);
+    }
+
+    #[test]
+    fn ascii_and_stray_continuation_bytes_arent_incomplete(){
+        let mut buf = CircularBuffer::<8>::new();
+        assert_eq!(buf.incomplete_utf8_len(), 0);
+        buf.write_all(b"abc").unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 0);
+        buf.write_all(&[0x80, 0x80, 0x80]).unwrap();
+        assert_eq!(buf.incomplete_utf8_len(), 0);
+    }
+
+    #[test]
+    fn free_space_after_writes_and_reads(){
+        let mut buf = CircularBuffer::<8>::new();
+        assert_eq!(buf.free_space(), 8);
+        assert!(!buf.is_full());
+        buf.write_all(b"abcde").unwrap();
+        assert_eq!(buf.free_space(), 3);
+        let mut out = [0u8; 4];
+        assert_eq!(buf.read(&mut out).unwrap(), 4);
+        assert_eq!(&out, b"abcd");
+        assert_eq!(buf.free_space(), 7);
+
+        // runs past the end of the array and wraps around to the start
+        buf.write_all(b"fghijkl").unwrap();
+        assert_eq!(buf.free_space(), 0);
+        assert!(buf.is_full());
+        assert_eq!(buf.to_vec(), b"efghijkl");
+        assert_eq!(buf.read(&mut out).unwrap(), 4);
+        assert_eq!(&out, b"efgh");
+        assert_eq!(buf.free_space(), 4);
+        assert_eq!(buf.to_vec(), b"ijkl");
+    }
+
+    #[test]
+    fn lossy_writes_overwrite_the_oldest_data_at_capacity(){
+        let mut buf = CircularBuffer::<4>::new();
+        assert_eq!(buf.write(b"abc").unwrap(), 3);
+        assert_eq!(buf.write(b"def").unwrap(), 3);
+        assert_eq!(buf.len(), 4);
+        assert_eq!(buf.to_vec(), b"cdef");
+        assert_eq!(buf.write(b"ghijkl").unwrap(), 4);
+        assert_eq!(buf.to_vec(), b"ghij");
+    }
+
+    #[test]
+    fn lossless_writes_stop_at_capacity(){
+        let mut buf = CircularBuffer::<4>::new();
+        assert_eq!(buf.write_lossless(b"abc"), 3);
+        assert_eq!(buf.write_lossless(b"def"), 1);
+        assert_eq!(buf.write_lossless(b"ef"), 0);
+        assert_eq!(buf.to_vec(), b"abcd");
+
+        // reading makes room again, which the next write wraps around into
+        let mut out = [0u8; 2];
+        buf.read_exact(&mut out).unwrap();
+        assert_eq!(buf.write_lossless(b"efg"), 2);
+        assert_eq!(buf.to_vec(), b"cdef");
+    }
+}
@@ -1,8 +1,113 @@
-use std::{env, fs::File, io::{self, ErrorKind, Read, Write}, net::TcpStream, str, sync::{Arc, Mutex}, time::{self, Duration, UNIX_EPOCH}};
+use std::{env, fs::{File, OpenOptions}, io::{self, ErrorKind, Read, Write}, os::unix::process::ExitStatusExt, process::ExitStatus, str, sync::{mpsc::{Receiver, RecvTimeoutError, TryRecvError}, Arc, Mutex}, thread, time::{self, Duration, Instant, UNIX_EPOCH}};
 
 use super::command_runner::ClientSession;
 use super::secure_stream::SecureStream;
+use super::transport::Transport;
+use super::input_reader::{Framing, InputReader, INPUT_TIMEOUT};
 use super::file_transfer;
+use super::fileops;
+#[cfg(feature = "download")]
+use super::download;
+use super::sysinfo::SysInfo;
+use super::process_tree;
+use super::completion;
+use super::prompt::{self, PromptInfo};
+use super::process_state::{self, ProcessRecord};
+use super::shutdown::StopFlag;
+use super::logger;
+use super::audit;
+use super::banner::Banner;
+use super::motd;
+use super::json;
+use super::poison;
+use super::capture;
+use super::rate_limit;
+use super::start_dir;
+use super::ansi::AnsiStripper;
+use super::auth::{AuthResult, Authenticator, Role, User};
+
+/// Marks the start of a control message, so the client can tell it apart from regular process output
+/// 
+/// Control messages are always a single line of the form `\x1bRSPI:<KIND> <args...>\n`
+pub const CONTROL_PREFIX: &str = "\x1bRSPI:";
+
+/// Sent by a client in place of a command, ie. `RSPI_ONESHOT echo hi`, to run that one command and then be disconnected
+/// 
+/// No prompts are sent in this mode, and neither is the message of the day if this is the first thing sent after logging in.
+/// Once the command ends, its output and exit status are sent before the connection closes, whatever kind of command it was
+pub const ONESHOT_PREFIX: &str = "RSPI_ONESHOT ";
+
+/// Longest the first prompt is held back waiting for the client's first message, which says whether it's one-shot
+const FIRST_PROMPT_WAIT: Duration = Duration::from_millis(100);
+
+/// Sent by a client in reply to a `PING` control message
+pub const PONG_MSG: &str = "RSPI_PONG";
+
+/// Sent by a client, on its own, to have every message it sends after this one read as a length-prefixed frame
+/// 
+/// Framed messages are a 4-byte big-endian length followed by that many bytes, so messages sent quickly one after
+/// another can't run together, and long ones can't be split up
+pub const FRAMED_MSG: &str = "RSPI_FRAMED";
+
+/// Sent by a client, on its own, to have every message it sends after this one read up to the next newline
+/// 
+/// Lines can be much longer than messages that are sent as they are, and don't run together when sent quickly.
+/// Input for a running process isn't passed on until its line is finished, so this doesn't suit raw input
+pub const LINES_MSG: &str = "RSPI_LINES";
+
+/// Sent by a client, on its own, to have every message it sends after this one read as a length-prefixed frame
+/// that starts with an 8-byte big-endian sequence number
+/// 
+/// Each sequence number has to be higher than the last, so recorded messages can't be replayed. The connection is
+/// closed if one isn't. With "RSPI_LOGIN_NONCE" set, the login is the first sequenced message on connections encrypted
+/// with the hash, so the numbers carry on from it
+pub const SEQUENCED_MSG: &str = "RSPI_SEQUENCED";
+
+/// Sent by a client, followed by a banner (ie. `RSPI_REQUIRE RSPI/1 features=framing`), to be disconnected
+/// if the server doesn't speak that version of the protocol or is missing any of those features
+pub const REQUIRE_PREFIX: &str = "RSPI_REQUIRE ";
+
+/// Sent by a client when Ctrl-C is pressed, interrupting the running process
+pub const INTERRUPT_MSG: &str = "\x03";
+
+/// Longest time to wait for a process to output something before checking for messages from the client again
+const OUTPUT_WAIT: Duration = Duration::from_millis(20);
+
+/// Most output held back while waiting for more to send along with it, see `ClientSession::coalesce_output`
+const FLUSH_THRESHOLD: usize = 4096;
+
+/// Longest a file transfer waits on the next part of a file before giving up on the client
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest sending output waits on a client that isn't reading it before holding the rest back to try again later, so a
+/// slow client doesn't stop its session from handling input
+const OUTPUT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Number of pings in a row a client can leave unanswered before it is assumed to be gone
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// `rspi` commands that manage processes belonging to the server or other clients, which restricted users can't use
+const ADMIN_COMMANDS: [&str; 5] = ["procs", "adopt", "orphan", "watch", "run-detached"];
+
+/// The control message `Client::send_exit_status` sends for `status`
+fn exit_status_message(status: ExitStatus) -> String{
+    let code = status.code().map_or(String::from("-"), |c| c.to_string());
+    let signal = status.signal().map_or(String::from("-"), |s| s.to_string());
+    format!("{}EXIT {} {}\n", CONTROL_PREFIX, code, signal)
+}
+
+/// Whether an `rspi` command can be used while a process is running, rather than being sent to the process as input
+fn allowed_while_running(msg: &str) -> bool{
+    let mut words = msg.split_whitespace();
+    words.next() == Some("rspi") && matches!(words.next(), Some("orphan" | "info" | "ps-tree" | "clear" | "eof" | "raw" | "flush" | "color" | "which" | "ls" | "mv" | "cp" | "rm"))
+}
+
+/// Whether a message asks to end the connection. Bare `exit` and `logout` are input for a running process,
+/// but `rspi exit` always logs out
+fn is_logout(msg: &str, running: bool) -> bool{
+    let msg = msg.trim();
+    msg == "rspi exit" || (!running && matches!(msg, "exit" | "logout"))
+}
 
 // PCG for random number generation
 fn rng_32(seed: &mut u64) -> u32{
@@ -19,43 +124,53 @@ fn rng_64(seed: &mut u64) -> u64{
     (left << 32) | right
 }
 
-// Unused - If a more scalable system for 'rspi' commands is necessary then this enum should be used to accomplish this
-enum RsPiCmd{
-    Procs,
-    Adopt(usize),
-    Orphan,
-    None
+/// Whether messages over `stream` are encrypted with the hash
+/// 
+/// There's no one to eavesdrop on a Unix socket, and TLS connections are already encrypted, so there's no need to encrypt them
+fn is_hashed(stream: &Transport) -> bool{
+    !stream.is_local() && !stream.is_encrypted()
 }
-impl TryFrom<&str> for RsPiCmd{
-    type Error = io::Error;
-    
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut temp = value.split_whitespace();
 
-        let instructions = "RS-PI process manager commands:\n
-                        procs\tlists processes managed by this app\n
-                        adopt [process id or name]\tmake this client session take control of a running proccess\n
-                        orphan\tgive control of this client's running process back to the server process manager.";
+/// Whether "RSPI_LOGIN_NONCE" is set (to anything but 0), so connections encrypted with the hash are sent a nonce and
+/// have to log in with a sequenced message
+fn nonce_login() -> bool{
+    env::var("RSPI_LOGIN_NONCE").is_ok_and(|v| v != "0")
+}
 
-        if temp.next().ok_or(io::Error::new(ErrorKind::Other, "ERROR: Command does not start with 'rspi'"))?!="rspi"{
-            Err(io::Error::new(ErrorKind::Other, "ERROR: Command does not start with 'rspi'"))
-        }else{
-            if let Some(cmd) = temp.next(){
-                match cmd{
-                    "procs" => Ok(Self::Procs),
-                    "adopt" => 
-                        match temp.next(){
-                            Some(arg) => Ok(Self::Adopt(match arg.parse(){Ok(v) => v, Err(e) => return Err(io::Error::new(ErrorKind::Other, e))})), 
-                            None => Err(io::Error::new(ErrorKind::Other, "Adopt a child process (listed by running 'rspi procs') into this remote client session."))
-                        }
-                    "orphan" => Ok(Self::Orphan),
-                    _ => Err(io::Error::new(ErrorKind::Other, instructions))
-                }
-            }else{
-                Err(io::Error::new(ErrorKind::Other, instructions))
-            }
-        }
+/// Makes a nonce for a new connection, which is mixed into its hash
+fn new_nonce() -> io::Result<u64>{
+    let mut nonce = [0u8; 8];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(u64::from_be_bytes(nonce))
+}
+
+/// Whether TCP connections have to be properly encrypted, set by the "RSPI_REQUIRE_SECURE" enviorment variable
+fn secure_required() -> bool{
+    env::var("RSPI_REQUIRE_SECURE").is_ok_and(|v| v != "0")
+}
+
+/// Checks that connections encrypted with "RSPI_SERVER_HASHKEY" are actually encrypted, warning if they aren't
+/// 
+/// With no key (or a key of 0), the hash only depends on the time, which anyone listening in can work out, so the
+/// password and everything after it are as good as plaintext. If "RSPI_REQUIRE_SECURE" is set, this returns
+/// `io::ErrorKind::InvalidInput` instead of warning
+pub fn check_hashkey() -> io::Result<()>{
+    check_key(env::var("RSPI_SERVER_HASHKEY").ok().as_deref(), secure_required())
+}
+
+/// `check_hashkey`, given the value of "RSPI_SERVER_HASHKEY" and whether secure connections are required
+fn check_key(key: Option<&str>, require_secure: bool) -> io::Result<()>{
+    let unset = key.is_none_or(|key| key.parse::<u64>() == Ok(0));
+    if !unset{
+        return Ok(())
     }
+    if require_secure{
+        return Err(io::Error::new(ErrorKind::InvalidInput,
+            "RSPI_REQUIRE_SECURE is set, but TCP connections would not be encrypted. Set RSPI_SERVER_HASHKEY, or RSPI_TLS_CERT and RSPI_TLS_KEY"))
+    }
+    logger::warn!("RSPI_SERVER_HASHKEY is not set, so TCP connections (including the password) are effectively unencrypted. \
+        Set it, or RSPI_TLS_CERT and RSPI_TLS_KEY, to encrypt them");
+    Ok(())
 }
 
 /// After receiving a connection from a client, this struct is used to store all the necessary data for the server to receive messages,
@@ -63,19 +178,147 @@ impl TryFrom<&str> for RsPiCmd{
 pub struct Client{
     stream: SecureStream,
     session: ClientSession,
-    processes: Arc<Mutex<Vec<ClientSession>>>
+    processes: Arc<Mutex<Vec<ClientSession>>>,
+    recovered: Arc<Mutex<Vec<ProcessRecord>>>,
+    stop: StopFlag,
+    identity: Option<String>,
+    /// What the client is allowed to do, which is everything unless it logged in as a restricted user
+    role: Role,
+    legacy_exit_msg: bool,
+    max_upload_bytes: Option<u64>,
+    heartbeat: Option<Duration>,
+    /// How long output is held back waiting for more before it's sent, changed with `rspi flush`
+    flush_interval: Duration,
+    prompt_format: String,
+    /// Message of the day sent before the first prompt, read when the client logs in
+    motd: Option<String>,
+    hostname: String,
+    username: String,
+    last_status: i32,
+    oneshot: bool,
+    /// Whether a one-shot client's command has finished, after which the connection is closed
+    finished: bool,
+    /// Whether an exit status has been sent, so one-shot clients aren't sent a second one
+    sent_exit_status: bool,
+    raw_input: bool,
+    json_output: bool,
+    /// Copy of the output of the server process this client is watching with `rspi watch`, if it is watching one
+    watching: Option<Receiver<Vec<u8>>>,
+    /// Takes escape sequences out of output before it's sent, once color is turned off with `rspi color off`
+    stripper: Option<AnsiStripper>,
+    /// Output the client hasn't taken yet because its connection was full, which is sent before any more is read from
+    /// the session
+    unsent: Vec<u8>
 }
 impl Client{
     /// Attempts to create a new Client struct to manage a connection to a client
-    pub fn new(stream: TcpStream, processes: Arc<Mutex<Vec<ClientSession>>>) -> Result<Self, io::Error>{
-        let mut stream = SecureStream::new(stream).set_hash(Self::get_hash().unwrap());
+    /// 
+    /// Clients without a TLS client certificate have to get past `auth` before anything else happens. If "RSPI_LOGIN_NONCE"
+    /// is set, a `NONCE` control message follows the banner on connections encrypted with the hash. From then on the
+    /// nonce is mixed into the hash, and the login has to be a sequenced message (see `SEQUENCED_MSG`), so a recorded
+    /// login can't be replayed
+    pub fn new(stream: Transport, processes: Arc<Mutex<Vec<ClientSession>>>, recovered: Arc<Mutex<Vec<ProcessRecord>>>, stop: StopFlag,
+        auth: Arc<dyn Authenticator>) -> Result<Self, io::Error>{
+        Self::with_login(stream, processes, recovered, stop, auth, nonce_login())
+    }
+
+    /// Creates a Client like `new`, sending connections encrypted with the hash a nonce if `use_nonce` is set,
+    /// instead of going by "RSPI_LOGIN_NONCE"
+    fn with_login(stream: Transport, processes: Arc<Mutex<Vec<ClientSession>>>, recovered: Arc<Mutex<Vec<ProcessRecord>>>, stop: StopFlag,
+        auth: Arc<dyn Authenticator>, use_nonce: bool) -> Result<Self, io::Error>{
+        let nonced = is_hashed(&stream) && use_nonce;
+        let mut stream = Self::secure(stream)?.set_rate_limit(rate_limit::limit_from_env());
 
-        // ensure password is correct before creating this client
-        Self::check_password(&mut stream)?;
+        // let the client know what it's talking to before it logs in
+        let _ = stream.write_all(format!("{}BANNER {}\n", CONTROL_PREFIX, Banner::server()).as_bytes());
 
-        let cwd = env::current_dir().unwrap();
+        // the hash only changes every few seconds, so without a nonce, a login recorded from one connection would be
+        // accepted on a new one for the rest of that time. it's opt-in, since clients have to know to log in this way
+        if nonced{
+            let nonce = new_nonce()?;
+            let _ = stream.write_all(format!("{}NONCE {}\n", CONTROL_PREFIX, nonce).as_bytes());
+            stream = stream.mix_nonce(nonce);
+        }
+
+        // a client that proved who it is with a TLS certificate doesn't need the password too
+        let user = match stream.stream.client_identity(){
+            Some(name) => {
+                let ip = stream.peer_ip().unwrap_or(String::from("unknown"));
+                logger::info!("Client {} authenticated with a certificate for {}", ip, name);
+                audit::record(&format!("{}@{}", name, ip), "auth_cert", &name);
+                Some(User{name, home: None, role: Role::Admin})
+            },
+            // ensure the client can log in before creating this client
+            None => Self::check_login(&mut stream, auth.as_ref(), nonced)?
+        };
+        let role = user.as_ref().map_or(Role::Admin, |user| user.role);
+        let home = user.as_ref().and_then(|user| user.home.clone());
+        let identity = user.map(|user| user.name);
+
+        // lets the OS notice clients that vanished without closing the connection, like when they lose power
+        let keepalive = match env::var("RSPI_TCP_KEEPALIVE_SECS").ok().and_then(|v| v.parse::<u64>().ok()){
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(Duration::from_secs(60))
+        };
+        if let Err(e) = stream.set_keepalive(keepalive){
+            logger::warn!("Could not set keepalive for {}: {}", stream.peer_ip().unwrap_or(String::from("unknown")), e);
+        }
+
+        // sessions start in the root directory when clients are confined to one, unless they're given somewhere inside it
+        // users with a home directory start there instead
+        let ip = stream.peer_ip().unwrap_or(String::from("unknown"));
+        let cwd = match &home{
+            Some(home) => start_dir::for_user(home, &ip),
+            None => start_dir::for_client(&ip)
+        };
+
+        // clients which don't understand control messages can still ask for the old "Process exited with status" line
+        let legacy_exit_msg = env::var("RSPI_SERVER_LEGACY_EXIT").is_ok_and(|v| v != "0");
+
+        let max_upload_bytes = env::var("RSPI_MAX_UPLOAD_BYTES").ok().and_then(|v| v.parse().ok());
+
+        // pinging idle clients keeps NAT mappings alive and lets us notice connections that silently died
+        let heartbeat = env::var("RSPI_HEARTBEAT_SECS").ok().and_then(|v| v.parse().ok()).filter(|secs| *secs > 0).map(Duration::from_secs);
+
+        // a few milliseconds is too short to notice, but long enough to send a spinner's output in a handful of writes
+        let flush_interval = Duration::from_millis(env::var("RSPI_FLUSH_INTERVAL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(5));
+
+        let prompt_format = env::var("RSPI_PROMPT").unwrap_or(String::from(prompt::DEFAULT_FORMAT));
+
+        let motd = motd::load();
+
+        // the client is watching this session's output, so it shouldn't lose any of it. if there's no session to
+        // give it, let it know why rather than just hanging up
+        let mut session = ClientSession::new(cwd).inspect_err(|e| {
+            let _ = stream.write_all(format!("Could not start a session: {}\n", e).as_bytes());
+        })?;
+        session.set_is_outputting(true);
+        if let Some(home) = home{
+            session.set_home(home);
+        }
 
-        Ok(Self{stream, session: ClientSession::new(cwd)?, processes})
+        Ok(Self{stream, session, processes, recovered, stop, identity, role, legacy_exit_msg, max_upload_bytes, heartbeat, flush_interval,
+            prompt_format, motd, hostname: prompt::hostname(), username: prompt::username(), last_status: 0, oneshot: false, finished: false, sent_exit_status: false, raw_input: false, json_output: false, watching: None, stripper: None, unsent: Vec::new()})
+    }
+
+    /// Wraps a new connection in the stream used to talk to the client
+    fn secure(stream: Transport) -> io::Result<SecureStream>{
+        if is_hashed(&stream){
+            Ok(SecureStream::new(stream).set_hash(Self::get_hash().map_err(io::Error::other)?))
+        }else{
+            Ok(SecureStream::new_plain(stream))
+        }
+    }
+
+    /// Tells a client why it can't be served, rather than just hanging up on it
+    /// 
+    /// The message is encrypted the same way a client's messages normally are, so this can't be used on connections
+    /// that still need their TLS handshake
+    pub fn refuse(stream: Transport, reason: &str){
+        if let Ok(mut stream) = Self::secure(stream){
+            let _ = stream.write_all(format!("{}\n", reason).as_bytes());
+        }
     }
 
     /// Gets the hash used to encrypt messages by checking for the "RSPI_SERVER_HASHKEY" enviorment variable
@@ -84,84 +327,247 @@ impl Client{
             Ok(n) => n, 
             Err(_) => return Err(String::from("RSPI_SERVER_HASHKEY enviorment variable cannoted be parsed to a u64!")),
         };
-        let mut seed = time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 5;
+        if hashkey == 0 && secure_required(){
+            return Err(String::from("RSPI_SERVER_HASHKEY is not set, and RSPI_REQUIRE_SECURE does not allow unencrypted connections"))
+        }
+        let mut seed = time::SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / 5;
         Ok(hashkey ^ rng_64(&mut seed))
     }
     
-    /// Ensure the first message the client sends to us is the correct password, defined by the "RSPI_SERVER_PASS" enviorment variable
-    fn check_password(stream: &mut SecureStream) -> Result<(), io::Error>{
-        let pass: String = env::var("RSPI_SERVER_PASS").unwrap_or(String::from("Password"));
-
-        let mut read_buffer: [u8; 64] = [0; 64];
-        match stream.read(&mut read_buffer){
-            Ok(msg_len) => {
-                let received_msg = str::from_utf8(&read_buffer[0..msg_len]).unwrap_or_default().trim_end_matches('\0');
-                if pass!=received_msg{
-                    println!("Client {} failed password:\n{}", stream.peer_addr().unwrap().ip(),received_msg);
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    Err(io::Error::new(ErrorKind::PermissionDenied, format!("Client {} inputted incorrect password {}",stream.peer_addr().unwrap().ip(),received_msg)))
-                }else{Ok(())}
+    /// Ensure `auth` accepts the first message the client sends to us, which is normally the password in the
+    /// "RSPI_SERVER_PASS" enviorment variable
+    /// 
+    /// The message is read as a sequenced message if `sequenced` is set, or as it is otherwise.\
+    /// Returns who the client is, if `auth` knows, or `io::ErrorKind::PermissionDenied` if it was rejected
+    fn check_login(stream: &mut SecureStream, auth: &dyn Authenticator, sequenced: bool) -> Result<Option<User>, io::Error>{
+        let submitted = if sequenced{
+            stream.read_sequenced_message()?
+        }else{
+            // room for a username along with the password
+            let mut read_buffer: [u8; 256] = [0; 256];
+            let msg_len = stream.read(&mut read_buffer)?;
+            read_buffer[0..msg_len].to_vec()
+        };
+        let ip = stream.peer_ip().unwrap_or(String::from("unknown"));
+        match auth.authenticate(&submitted, stream.stream.peer_addr()){
+            AuthResult::Accepted(user) => {
+                if let Some(user) = &user{
+                    logger::info!("Client {} logged in as {}", ip, user.name);
+                }
+                Ok(user)
             },
-            Err(e) => {
-                Err(e)
+            AuthResult::Rejected{reason, user} => {
+                // the audit log is kept for good, so it only gets who they tried to log in as, never the reason, in
+                // case it says anything about what they typed
+                let reason = match &user{
+                    Some(user) => format!(" as {}: {}", user, reason),
+                    None => format!(": {}", reason)
+                };
+                logger::warn!("Client {} failed to log in{}", ip, reason);
+                audit::record(&ip, "auth_failed", user.as_deref().unwrap_or_default());
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                Err(io::Error::new(ErrorKind::PermissionDenied, format!("Client {} could not log in{}", ip, reason)))
             }
         }
     }
 
-    /// Runs this client, constantly checking for messages until the client disconnects
+    /// Runs this client, handling its messages and sending it output until the client disconnects
     pub fn run(mut self){
-        let _ = self.stream.set_read_timeout(Some(Duration::new(0, 1000000)));
-        println!("Connection established with {}, {}",self.stream.local_addr().unwrap().ip(),self.stream.peer_addr().unwrap().ip());
-    
-        let mut read_buffer: [u8; 1024] = [0; 1024];
+        let _ = self.stream.set_read_timeout(Some(INPUT_TIMEOUT));
+        // the peer's address can't be looked up anymore once it disconnects, so remember it for logging when it does
+        let peer = self.peer_ip();
+        logger::info!("Connection established with {}, {}",self.stream.local_ip().unwrap_or(String::from("unknown")),peer);
+
+        // messages are read on their own thread, through a clone of the stream. reads and writes lock separate
+        // offsets, so reading on one thread never holds up writing on this one
+        let input = match self.stream.try_clone(){
+            Ok(stream) => InputReader::spawn(stream),
+            Err(e) => {
+                logger::error!("Could not clone stream of {}: {}", self.peer_ip(), e);
+                return
+            }
+        };
     
         let mut running_process = false;
+
+        // when the connection last carried anything, and how many pings have gone unanswered since the client last sent something
+        let mut last_activity = Instant::now();
+        let mut missed_pings = 0;
+        // clients that never answer pings might just not know about them, so only hold it against ones that do
+        let mut answers_pings = false;
     
-        self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes()).unwrap();
+        // the message of the day and first prompt wait until we know whether the client is one-shot, which doesn't get them
+        let connected_at = Instant::now();
+        let mut greeted = false;
     
         loop{
-            // first, check for messages sent by client and run the sent command
-            match self.stream.read(&mut read_buffer){
-                Ok(msg_len) => {
-                    if msg_len==0 {break;}
-                    let received_msg = str::from_utf8(&read_buffer[0..msg_len]).unwrap_or_default().trim_end_matches('\0');
-                    // println!("Recieved response length {}: \n{}", msg_len, received_msg);
+            if self.stop.is_stopped(){
+                let _ = self.stream.write_all(b"\nServer is shutting down, closing connection\n");
+                break;
+            }
+            // so that the session knows whether its process is still running before handling the next message
+            self.session.reap();
+
+            // a message sent right behind a command that has already exited waits until that command has had its
+            // exit status and prompt sent, rather than starting before them
+            let finishing = running_process && !self.session.has_child() && !self.session.is_tailing();
+            // first, check for messages sent by client and run the sent command. when nothing is running,
+            // there's no output to wait for, so wait on the client instead
+            let next = if finishing { Err(RecvTimeoutError::Timeout) } else { input.next(if running_process { None } else { Some(OUTPUT_WAIT) }) };
+            match next{
+                Ok(read_buffer) => {
+                    let msg_len = read_buffer.len();
+                    // only messages that are text can be commands
+                    let mut received_msg = str::from_utf8(&read_buffer).unwrap_or_default();
+                    logger::debug!("Recieved response length {}: {}", msg_len, received_msg);
+                    last_activity = Instant::now();
+                    missed_pings = 0;
+                    if received_msg == PONG_MSG{
+                        answers_pings = true;
+                        input.resume();
+                        continue;
+                    }
+                    let framing = match received_msg{
+                        FRAMED_MSG => Some(Framing::LengthPrefixed),
+                        LINES_MSG => Some(Framing::Lines),
+                        SEQUENCED_MSG => Some(Framing::Sequenced),
+                        _ => None
+                    };
+                    if let Some(framing) = framing{
+                        input.set_framing(framing);
+                        input.resume();
+                        continue;
+                    }
+                    if let Some(required) = received_msg.strip_prefix(REQUIRE_PREFIX){
+                        let missing = match Banner::parse(required){
+                            Some(required) => Banner::server().missing(&required),
+                            None => vec![format!("{} (could not be parsed)", required)]
+                        };
+                        if !missing.is_empty(){
+                            logger::warn!("Client {} needs {}, which this server doesn't support", self.peer_ip(), missing.join(", "));
+                            let _ = self.stream.write_all(format!("This server ({}) does not support {}, closing connection\n", Banner::server(), missing.join(", ")).as_bytes());
+                            break;
+                        }
+                        input.resume();
+                        continue;
+                    }
+                    if !self.oneshot && !self.session.has_child(){
+                        if let Some(cmd) = received_msg.strip_prefix(ONESHOT_PREFIX){
+                            self.oneshot = true;
+                            received_msg = cmd;
+                        }
+                    }
+                    if !greeted{
+                        greeted = true;
+                        if !self.oneshot { self.greet(); }
+                    }
+                    if is_logout(received_msg, self.session.has_child()){
+                        logger::info!("Client {} logged out", self.peer_ip());
+                        // the foreground process goes with the session once the loop ends, orphaned ones are left alone
+                        let _ = self.stream.write_all(b"Goodbye\n");
+                        break;
+                    }
                     if self.session.has_child(){
                         running_process=true;
-                        if received_msg.starts_with("SIG"){
-                            let _ = self.session.signal(received_msg);
-                        }else if received_msg == "rspi orphan"{
+                        if received_msg == INTERRUPT_MSG{
+                            if let Err(e) = self.session.interrupt(){
+                                logger::warn!("Could not interrupt {}: {}", self.session.cmd_name, e);
+                            }
+                        }else if received_msg.starts_with("SIG"){
+                            if let Err(e) = self.session.signal(received_msg){
+                                let _ = self.stream.write_all(format!("Could not signal {}: {}\n", self.session.cmd_name, e).as_bytes());
+                            }
+                        }else if allowed_while_running(received_msg){
                             self.do_rspi_process_cmds(received_msg);
                         }else{
-                            // println!("attempting to write stdin {} to proc {}",received_msg,self.session.cmd_name);
-                            let _ = self.session.write_stdin(received_msg);
+                            // programs can be sent any bytes, so don't rely on the message being text
+                            logger::debug!("attempting to write stdin {} to proc {}",String::from_utf8_lossy(&read_buffer),self.session.cmd_name);
+                            // in raw mode, messages are exactly what the program should get (like single keypresses),
+                            // otherwise each one is a line the user typed
+                            let written = if self.raw_input { self.session.write_stdin(&read_buffer) } else { self.session.write_line(&read_buffer) };
+                            if let Err(e) = written{
+                                let _ = self.stream.write_all(format!("Could not write to {}: {}\n", self.session.cmd_name, e).as_bytes());
+                            }
+                        }
+                    }else if self.session.is_tailing(){
+                        // nothing reads the input of a tail, the client can only interrupt it
+                        if received_msg.starts_with("SIG") || received_msg == INTERRUPT_MSG{
+                            self.session.stop_tail();
+                        }
+                    }else if self.watching.is_some() && !received_msg.starts_with("rspi"){
+                        // watchers can only look, so the only input they can give is to stop watching
+                        if received_msg == INTERRUPT_MSG{
+                            self.watching = None;
+                            let _ = self.stream.write_all(b"Stopped watching\n");
+                            self.write_prompt();
+                        }else{
+                            let _ = self.stream.write_all(b"Input can't be sent to a process you are watching, send 'rspi unwatch' to stop watching\n");
                         }
+                    }else if received_msg == INTERRUPT_MSG{
+                        // like at a shell prompt, there's nothing to interrupt
                     }else if received_msg.starts_with("SIG"){
                         break;
-                    }else if received_msg.starts_with("rspi") && received_msg != "rspi orphan"{
+                    }else if let Some(line) = received_msg.strip_prefix("rspi complete "){
+                        // completions are requested as the client types, so they're kept out of the history and logs
+                        self.send_completions(line);
+                    }else if received_msg.starts_with("rspi"){
+                        logger::info!("Client {} ran {}", self.peer_ip(), received_msg);
+                        self.session.record_history(received_msg);
+                        // commands that start a process get their prompt once it ends
                         if self.do_rspi_process_cmds(received_msg){
                             running_process = true;
+                        }else{
+                            self.write_prompt();
                         }
+                    }else if let Some(cmd) = received_msg.trim_end().strip_suffix('&'){
+                        logger::info!("Client {} ran {}", self.peer_ip(), received_msg);
+                        audit::record(&self.audit_id(), "cmd", received_msg);
+                        self.session.record_history(received_msg);
+                        self.start_background_job(cmd);
+                        self.write_prompt();
                     }else{
+                        logger::info!("Client {} ran {}", self.peer_ip(), received_msg);
+                        audit::record(&self.audit_id(), "cmd", received_msg);
+                        self.session.record_history(received_msg);
                         match self.session.run_command(received_msg){
                             Ok(_) => running_process=true,
-                            Err(e) => {let _ = self.stream.write(format!("{}\n{}$ ", e, self.session.path.display()).as_bytes());},
+                            Err(e) => {
+                                let _ = self.stream.write_all(format!("{}\n", e).as_bytes());
+                                // use the same status a shell would, which one-shot clients also need as an exit code
+                                self.last_status = if e.kind() == ErrorKind::NotFound { 127 } else { 1 };
+                                if self.oneshot{
+                                    let _ = self.stream.write_all(format!("{}EXIT {} -\n", CONTROL_PREFIX, self.last_status).as_bytes());
+                                    self.sent_exit_status = true;
+                                }
+                                self.write_prompt();
+                            },
                         }
                     }
+                    // anything the command needed to read from the socket itself has been read by now
+                    input.resume();
                 },
-                Err(s) => {
-                    match s.kind(){
-                        ErrorKind::WouldBlock | ErrorKind::TimedOut => (),
-                        _ => {
-                            println!("Something went wrong:\n{}\nClosing connection...",s);
-                            break;
-                        }
+                Err(RecvTimeoutError::Timeout) => {
+                    // a client that's waiting on its prompt before sending anything isn't one-shot
+                    if !greeted && connected_at.elapsed() >= FIRST_PROMPT_WAIT{
+                        greeted = true;
+                        self.greet();
                     }
                 },
+                Err(RecvTimeoutError::Disconnected) => break
             }
 
-            // constantly read the output of the session and send it to the client
-            if let Ok(()) = self.session.read_output(&mut self.stream) {}
+            if self.forward_watched_output(){
+                last_activity = Instant::now();
+            }
+
+            // read the output of the session and send it to the client, sleeping until there is some instead of spinning
+            if running_process{
+                self.session.wait_for_output(OUTPUT_WAIT);
+                self.session.coalesce_output(self.flush_interval, FLUSH_THRESHOLD);
+            }
+            if self.send_session_output() {
+                last_activity = Instant::now();
+            }
 
             // send exit status if it has finished.
             else if running_process{
@@ -171,18 +577,249 @@ impl Client{
                 // would require sending a closure to another thread which is headache i dont want to deal with
                 if let Some(status) = self.session.exit_status(){
                     running_process = false;
-                    if !status.success(){let _ = self.stream.write(format!("Process exited with status {}\n",status).as_bytes());}
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
-                }else if !self.session.has_child() {
+                    self.flush_capture();
+                    // a one-shot client is about to be disconnected, so make sure it gets all of the output first
+                    if self.oneshot { self.drain_output(); }
+                    let _ = self.send_exit_status(status);
+                    self.write_prompt();
+                }else if !self.session.has_child() && !self.session.is_tailing() {
                     running_process = false;
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
+                    self.flush_capture();
+                    self.write_prompt();
+                }
+            }
+
+            if self.finished { break; }
+
+            if let Some(interval) = self.heartbeat.filter(|interval| last_activity.elapsed() >= *interval){
+                if answers_pings && missed_pings >= MAX_MISSED_PINGS{
+                    logger::warn!("Client {} stopped answering pings", self.peer_ip());
+                    break;
                 }
+                logger::debug!("Pinging client {} after {:?} idle", self.peer_ip(), interval);
+                if self.stream.write_all(format!("{}PING\n", CONTROL_PREFIX).as_bytes()).is_err() { break; }
+                missed_pings += 1;
+                last_activity = Instant::now();
             }
         }
         self.session.kill();
-        if self.session.close().is_err() { println!("Error closing session"); }
-        println!("Client {} closed connection",self.stream.peer_addr().unwrap().ip());
-        if let Err(e) = self.stream.shutdown(std::net::Shutdown::Both) { println!("Failed to shutdown connection\n{}", e); }
+        if self.session.close().is_err() { logger::error!("Error closing session"); }
+        logger::info!("Client {} closed connection after sending {} bytes and receiving {}",peer,self.stream.bytes_read(),self.stream.bytes_written());
+        if let Err(e) = self.stream.shutdown(std::net::Shutdown::Both) { logger::error!("Failed to shutdown connection: {}", e); }
+        input.stop();
+    }
+
+    /// Sends a control message carrying the exit code of a finished process, along with the signal that killed it if any
+    /// 
+    /// The message looks like `\x1bRSPI:EXIT <code> <signal>\n`, where either field is `-` if it doesn't apply.\
+    /// If `RSPI_SERVER_LEGACY_EXIT` is set, the human-readable status line is also sent for failed processes
+    pub fn send_exit_status(&mut self, status: ExitStatus) -> io::Result<()>{
+        // like a shell, a process killed by a signal gets a status of 128 plus the signal
+        self.last_status = status.code().or(status.signal().map(|s| 128 + s)).unwrap_or(0);
+        self.sent_exit_status = true;
+        self.finish_unsent();
+        self.stream.write_all(exit_status_message(status).as_bytes())?;
+        if self.legacy_exit_msg && !status.success(){
+            self.stream.write_all(format!("Process exited with status {}\n",status).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Sends the message of the day, if there is one, and the first prompt
+    fn greet(&mut self){
+        if let Some(motd) = &self.motd{
+            let _ = self.stream.write_all(motd.as_bytes());
+        }
+        let _ = self.stream.write_all(self.format_prompt().as_bytes());
+    }
+
+    /// Prompts the client for its next command
+    /// 
+    /// This is the only place prompts are sent from, apart from the first one sent by `greet`. `run` sends exactly one
+    /// after each command finishes, and none while a process is running.\
+    /// One-shot clients don't get another command, so this marks the connection to be closed instead. If their command
+    /// didn't have an exit status of its own, like `cd` or an `rspi` command, they're sent a successful one first
+    fn write_prompt(&mut self){
+        self.finish_unsent();
+        if self.oneshot{
+            if !self.finished && !self.sent_exit_status{
+                let _ = self.stream.write_all(format!("{}EXIT 0 -\n", CONTROL_PREFIX).as_bytes());
+            }
+            self.finished = true;
+        }else{
+            let _ = self.stream.write_all(self.format_prompt().as_bytes());
+        }
+    }
+
+    /// Renders the prompt from the "RSPI_PROMPT" enviorment variable, see `prompt::render` for what can go in it
+    fn format_prompt(&self) -> String{
+        prompt::render(&self.prompt_format, &PromptInfo{
+            cwd: &self.session.path.to_string_lossy(),
+            host: &self.hostname,
+            user: &self.username,
+            last_status: self.last_status
+        })
+    }
+
+    /// Sends output left in the session until it has been quiet for a moment, since a process's last output can
+    /// still be making its way through the terminal after it exits
+    fn drain_output(&mut self){
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut quiet_since = Instant::now();
+        while quiet_since.elapsed() < Duration::from_millis(50) && Instant::now() < deadline{
+            if self.send_session_output(){
+                quiet_since = Instant::now();
+            }else{
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    /// Sends whatever the process being watched has output since the last time this was called, returning whether there was anything
+    fn forward_watched_output(&mut self) -> bool{
+        let mut forwarded = false;
+        let mut closed = false;
+        let mut received = Vec::new();
+        if let Some(watching) = &self.watching{
+            loop{
+                match watching.try_recv(){
+                    Ok(output) => {
+                        received.extend_from_slice(&output);
+                        forwarded = true;
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        closed = true;
+                        break
+                    }
+                }
+            }
+        }
+        if forwarded{
+            self.send_output(&received);
+        }
+        if closed{
+            self.watching = None;
+            let _ = self.stream.write_all(b"\nThe process being watched has closed\n");
+            self.write_prompt();
+        }
+        forwarded
+    }
+
+    /// Sends what was captured of a finished command's output, if it was captured rather than streamed
+    /// 
+    /// Like `drain_output`, this first waits for the capture to stop growing, so the end of the output isn't cut off
+    fn flush_capture(&mut self){
+        let Some(mut captured) = self.session.captured_bytes() else { return };
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut quiet_since = Instant::now();
+        while quiet_since.elapsed() < Duration::from_millis(50) && Instant::now() < deadline{
+            thread::sleep(Duration::from_millis(5));
+            let now_captured = self.session.captured_bytes().unwrap_or(captured);
+            if now_captured != captured{
+                captured = now_captured;
+                quiet_since = Instant::now();
+            }
+        }
+        if let Some(output) = self.session.take_capture(){
+            self.send_output(&output);
+        }
+    }
+
+    /// Sends a process's output to the client, without escape sequences if color is off
+    /// 
+    /// Whatever the client doesn't take right away is held back and sent first next time, rather than being dropped
+    fn send_output(&mut self, output: &[u8]){
+        match &mut self.stripper{
+            Some(stripper) => self.unsent.extend_from_slice(&stripper.strip(output)),
+            None => self.unsent.extend_from_slice(output)
+        }
+        self.send_unsent();
+    }
+
+    /// Sends as much of the output held back for the client as it takes without waiting on it for long
+    fn send_unsent(&mut self){
+        if self.unsent.is_empty(){
+            return
+        }
+        match self.stream.with_write_timeout(Some(OUTPUT_WRITE_TIMEOUT), |stream| stream.write_available(&self.unsent)){
+            Ok(sent) => {self.unsent.drain(..sent);},
+            Err(e) => {
+                // the connection is broken, so none of it can be delivered anymore
+                logger::debug!("Could not send output to {}: {}", self.peer_ip(), e);
+                self.unsent.clear();
+            }
+        }
+    }
+
+    /// Waits for the client to take all of the output held back for it, so that whatever is sent next can't overtake it
+    fn finish_unsent(&mut self){
+        if !self.unsent.is_empty(){
+            let _ = self.stream.write_all(&self.unsent);
+            self.unsent.clear();
+        }
+    }
+
+    /// Sends whatever the session has output since the last time this was called, returning whether there was anything,
+    /// including output still held back from before
+    /// 
+    /// While the client hasn't taken everything sent to it, the session's output is left where it is, so a process
+    /// that keeps writing is eventually paused rather than having its output dropped
+    fn send_session_output(&mut self) -> bool{
+        if !self.unsent.is_empty(){
+            self.send_unsent();
+            return true
+        }
+        let mut output = Vec::new();
+        let _ = self.session.read_output(&mut output);
+        if output.is_empty(){
+            return false
+        }
+        self.send_output(&output);
+        true
+    }
+
+    /// Starts a command as a background job, letting the client know its id
+    fn start_background_job(&mut self, cmd: &str){
+        match self.session.run_background(cmd){
+            Ok(id) => {let _ = self.stream.write_all(format!("[{}] started {}\n", id, cmd.trim()).as_bytes());},
+            Err(e) => {let _ = self.stream.write_all(format!("Could not start {}\n{}\n", cmd.trim(), e).as_bytes());}
+        }
+    }
+
+    /// Sends the completions of the last token in `line` as a control message giving the number of candidates,
+    /// followed by one candidate per line
+    /// 
+    /// No prompt is sent afterwards, since the client is still in the middle of typing its command
+    fn send_completions(&mut self, line: &str){
+        let candidates = completion::complete(self.session.root_or_path(), &self.session.path, line);
+        let _ = self.stream.write_all(format!("{}COMPLETE {}\n{}", CONTROL_PREFIX, candidates.len(),
+            candidates.iter().map(|c| format!("{}\n", c)).collect::<String>()).as_bytes());
+    }
+
+    /// IP address of the connected client, or "unknown" if it can't be found
+    fn peer_ip(&self) -> String{
+        self.stream.peer_ip().unwrap_or(String::from("unknown"))
+    }
+
+    /// Who the client is in the audit log: its IP address, prefixed with the name on its certificate if it logged in with one
+    fn audit_id(&self) -> String{
+        match &self.identity{
+            Some(name) => format!("{}@{}", name, self.peer_ip()),
+            None => self.peer_ip()
+        }
+    }
+
+    /// Records the server's orphaned processes to the "RSPI_STATE_FILE", if it is set
+    fn save_process_state(&self, procs: &[ClientSession]){
+        let recovered = {
+            let mut recovered = poison::lock(&self.recovered, "recovered processes");
+            process_state::prune_stale(&mut recovered);
+            recovered.clone()
+        };
+        if let Err(e) = process_state::save_orphans(procs, &recovered){
+            logger::error!("Could not save process state: {}", e);
+        }
     }
 
     /// In addition to running standard terminal commands as child processes, the client should be able to transfer "ownership" 
@@ -190,126 +827,631 @@ impl Client{
     /// 
     /// After the 'rspi' keyword is inputted, this function will get called to run the given command
     fn do_rspi_process_cmds(&mut self, received_msg: &str) -> bool{
+        audit::record(&self.audit_id(), "rspi", received_msg);
         let mut temp = received_msg.split_whitespace();
         temp.next(); // ignore the "rspi"
         if let Some(cmd) = temp.next(){
+            if self.role == Role::Restricted && ADMIN_COMMANDS.contains(&cmd){
+                logger::warn!("Client {} was denied rspi {}, which only admins can use", self.audit_id(), cmd);
+                let _ = self.stream.write_all(format!("Permission denied: rspi {} can only be used by admins\n", cmd).as_bytes());
+                return false
+            }
+            // the server reads and writes these files itself, so it does it as the user commands run as, who might not
+            // be allowed to get at everything the server can
+            let _file_access = match cmd{
+                "getfile" | "sendfile" | "ls" | "mv" | "cp" | "rm" | "tail" | "download" => match self.session.access_files(){
+                    Ok(file_access) => file_access,
+                    Err(e) => {
+                        let _ = self.stream.write_all(format!("Could not rspi {}\n{}\n", cmd, e).as_bytes());
+                        return false
+                    }
+                },
+                _ => None
+            };
             match cmd{
+                "procs" if self.json_output => {
+                    let procs = poison::lock(&self.processes, "processes").iter().enumerate()
+                        .map(|(id, proc)| {
+                            let usage = proc.resource_usage();
+                            format!("{{\"id\":{},\"cmd\":{},\"running\":{},\"pid\":{},\"rss_bytes\":{},\"cpu_secs\":{}}}",
+                                id, json::escape(&proc.cmd_name), proc.has_child(), json::or_null(proc.pid()),
+                                json::or_null(usage.map(|u| u.rss_bytes)), json::or_null(usage.map(|u| u.cpu_time.as_secs_f64())))
+                        })
+                        .collect::<Vec<String>>();
+                    let recovered = poison::lock(&self.recovered, "recovered processes").iter()
+                        .map(|rec| format!("{{\"pid\":{},\"cmd\":{},\"cwd\":{},\"alive\":{}}}",
+                            rec.pid, json::escape(&rec.cmd_name), json::escape(&rec.cwd.to_string_lossy()), rec.alive))
+                        .collect::<Vec<String>>();
+                    let _ = self.stream.write_all(format!("{{\"processes\":[{}],\"recovered\":[{}]}}\n", procs.join(","), recovered.join(",")).as_bytes());
+                    false
+                },
                 "procs" => { // lists processes
-                    if let Ok(procs) = self.processes.lock(){
-                        let _ = self.stream.write((procs.iter()
-                                .enumerate()
-                                .map(|(id, proc)| 
-                                    format!("{}\t{}\t{}",id, proc.cmd_name, if proc.has_child(){"running"}else{"not running"})
+                    let procs = poison::lock(&self.processes, "processes");
+                    let _ = self.stream.write_all((procs.iter()
+                            .enumerate()
+                            .map(|(id, proc)| match proc.resource_usage(){
+                                Some(usage) => format!("{}\t{}\trunning\tpid {}\t{} KiB\t{:.2}s cpu", id, proc.cmd_name,
+                                    usage.pid, usage.rss_bytes / 1024, usage.cpu_time.as_secs_f64()),
+                                None => format!("{}\t{}\t{}",id, proc.cmd_name, if proc.has_child(){"running"}else{"not running"})
+                            })
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                        +"\n").as_bytes());
+                    drop(procs);
+                    // processes orphaned before the server restarted can't be adopted, but are still listed by pid
+                    let recovered = poison::lock(&self.recovered, "recovered processes");
+                    if !recovered.is_empty(){
+                        let _ = self.stream.write_all((String::from("Recovered from a previous run:\n") + &recovered.iter()
+                                .map(|rec|
+                                    format!("pid {}\t{}\t{}\t{}\n", rec.pid, rec.cmd_name, rec.cwd.display(), if rec.alive{"running"}else{"dead"})
                                 )
-                            .collect::<Vec<String>>()
-                            .join("\n")
-                            +"\n").as_bytes());
-                    }else{
-                        let _ = self.stream.write(b"Could not find processes\n");
+                            .collect::<String>()).as_bytes());
                     }
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
                     false
                 },
                 "adopt" => { // client takes ownership of proccess
                     if let Some(arg) = temp.next(){
-                        if let Ok(mut procs) = self.processes.lock(){
-                            if let Ok(id) = arg.parse::<usize>(){
-                                self.session.set_is_outputting(false);
-                                let old_session = std::mem::replace(&mut self.session, procs.remove(id));
-                                let _ = self.stream.write(format!("Successfully took control of process {}: {}\n",id,self.session.cmd_name).as_bytes());
-                                if old_session.close().is_err(){
-                                    let _ = self.stream.write(format!("Error closing old process\n").as_bytes());
-                                }
-                                self.session.set_is_outputting(true);
-                                true
-                            }else if let Some(id) = procs.iter().position(|proc| proc.cmd_name.eq_ignore_ascii_case(arg)){
-                                self.session.set_is_outputting(false);
-                                let old_session = std::mem::replace(&mut self.session, procs.remove(id));
-                                let _ = self.stream.write(format!("Successfully took control of process {}: {}\n",id,self.session.cmd_name).as_bytes());
-                                if old_session.close().is_err(){
-                                    let _ = self.stream.write(format!("Error closing old process\n").as_bytes());
-                                }
-                                self.session.set_is_outputting(true);
-                                let _ = self.stream.write(format!("Successfully took control of process {}: {}\n",id,self.session.cmd_name).as_bytes());
-                                true
-                            }else{
-                                let _ = self.stream.write(format!("ERROR: Could not find process with id or name {}\n",arg).as_bytes());
-                                let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
-                                false
-                            }
-                        }else{
-                            false
+                        let processes = self.processes.clone();
+                        let mut procs = poison::lock(&processes, "processes");
+                        // by id, or else by name
+                        let found = arg.parse::<usize>().ok().filter(|id| *id < procs.len())
+                            .or_else(|| procs.iter().position(|proc| proc.cmd_name.eq_ignore_ascii_case(arg)));
+                        let Some(id) = found else {
+                            let _ = self.stream.write_all(format!("ERROR: Could not find process with id or name {}\n",arg).as_bytes());
+                            return false
+                        };
+                        self.session.set_is_outputting(false);
+                        let old_session = std::mem::replace(&mut self.session, procs.remove(id));
+                        self.session.copy_settings_from(&old_session);
+                        self.save_process_state(&procs);
+                        let _ = self.stream.write_all(format!("Successfully took control of process {}: {}\n",id,self.session.cmd_name).as_bytes());
+                        // a full-screen program is redrawn, rather than picking up halfway through drawing something
+                        if let Some(snapshot) = self.session.take_screen_snapshot(){
+                            self.send_output(&snapshot);
+                        }
+                        if old_session.close().is_err(){
+                            let _ = self.stream.write_all(b"Error closing old process\n");
                         }
+                        self.session.set_is_outputting(true);
+                        true
                     }else{
-                        let _ = self.stream.write(b"Adopt a child process (listed by running 'rspi procs') into this remote client session.\n");
-                        let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
+                        let _ = self.stream.write_all(b"Adopt a child process (listed by running 'rspi procs') into this remote client session.\n");
                         false
                     }
                 },
                 "orphan" => { // client gives up ownership of proccess to the server
+                    if !self.session.has_child(){
+                        let _ = self.stream.write_all(b"No running process to give to the process manager\n");
+                        return false
+                    }
                     let path = self.session.path.clone();
                     let name = self.session.cmd_name.clone();
-                    if let Ok(mut procs) = self.processes.lock(){
-                        match ClientSession::new(path){
-                            Ok(new_session) => {
-                                self.session.set_is_outputting(false);
-                                procs.push(std::mem::replace(&mut self.session, new_session));
-                                let _ = self.stream.write(format!("Sucessfully gave control of {} to proccess manager with id {}\n",name,procs.len()-1).as_bytes());        
-                            },
-                            Err(e) => {
-                                let _ = self.stream.write(format!("Unable to create new session:\n{}",e).as_bytes());        
-                            }
+                    let mut procs = poison::lock(&self.processes, "processes");
+                    match ClientSession::new(path){
+                        Ok(mut new_session) => {
+                            new_session.copy_settings_from(&self.session);
+                            self.session.set_is_outputting(false);
+                            new_session.set_is_outputting(true);
+                            procs.push(std::mem::replace(&mut self.session, new_session));
+                            self.save_process_state(&procs);
+                            let _ = self.stream.write_all(format!("Sucessfully gave control of {} to proccess manager with id {}\n",name,procs.len()-1).as_bytes());        
+                        },
+                        Err(e) => {
+                            let _ = self.stream.write_all(format!("Unable to create new session:\n{}",e).as_bytes());        
+                        }
+                    }
+                    false
+                },
+                "run-detached" => { // starts a command as one of the server's processes, without the client ever being attached to it
+                    let cmd = temp.collect::<Vec<&str>>().join(" ");
+                    if cmd.is_empty(){
+                        let _ = self.stream.write_all(b"Start a command that keeps running after you disconnect: rspi run-detached [command]\n");
+                        return false
+                    }
+                    let started = ClientSession::new(self.session.path.clone()).and_then(|mut session| {
+                        session.copy_settings_from(&self.session);
+                        session.run_command(&cmd)?;
+                        Ok(session)
+                    });
+                    match started{
+                        // builtins like cd don't leave anything behind to detach
+                        Ok(session) if !session.has_child() => {
+                            let _ = session.close();
+                            let _ = self.stream.write_all(format!("{} did not start a process\n", cmd).as_bytes());
+                        },
+                        Ok(session) => {
+                            let mut procs = poison::lock(&self.processes, "processes");
+                            procs.push(session);
+                            self.save_process_state(&procs);
+                            let _ = self.stream.write_all(format!("Started {} as process {}\n", cmd, procs.len()-1).as_bytes());
+                        },
+                        Err(e) => {let _ = self.stream.write_all(format!("Could not start {}\n{}\n", cmd, e).as_bytes());}
+                    }
+                    false
+                },
+                "watch" => { // mirrors the output of one of the server's processes, without taking control of it
+                    let arg = match temp.next(){
+                        Some(arg) => arg,
+                        None => {
+                            let _ = self.stream.write_all(b"Watch the output of a process (listed by running 'rspi procs') without taking control of it: rspi watch [process id or name]\n");
+                            return false
                         }
+                    };
+                    let procs = poison::lock(&self.processes, "processes");
+                    let found = arg.parse::<usize>().ok().filter(|id| *id < procs.len())
+                        .or_else(|| procs.iter().position(|proc| proc.cmd_name.eq_ignore_ascii_case(arg)));
+                    match found{
+                        Some(id) => {
+                            self.watching = Some(procs[id].watch());
+                            let _ = self.stream.write_all(format!("Watching process {}: {}. Send 'rspi unwatch' or Ctrl-C to stop\n", id, procs[id].cmd_name).as_bytes());
+                        },
+                        None => {let _ = self.stream.write_all(format!("ERROR: Could not find process with id or name {}\n", arg).as_bytes());}
                     }
                     false
                 },
+                "unwatch" => { // stops watching, leaving the process running
+                    if self.watching.take().is_some(){
+                        let _ = self.stream.write_all(b"Stopped watching\n");
+                    }else{
+                        let _ = self.stream.write_all(b"Not watching a process\n");
+                    }
+                    false
+                },
+                "ps-tree" => { // lists every process descended from a session's command, not just the command itself
+                    let pid = match temp.next(){
+                        // other sessions belong to the server, so only admins can look inside them
+                        Some(arg) if self.role == Role::Restricted => {
+                            logger::warn!("Client {} was denied rspi ps-tree {}, which only admins can use", self.audit_id(), arg);
+                            let _ = self.stream.write_all(b"Permission denied: only admins can see the processes of the server's sessions\n");
+                            return false
+                        },
+                        Some(arg) => {
+                            let procs = poison::lock(&self.processes, "processes");
+                            let found = arg.parse::<usize>().ok().filter(|id| *id < procs.len())
+                                .or_else(|| procs.iter().position(|proc| proc.cmd_name.eq_ignore_ascii_case(arg)));
+                            match found{
+                                Some(id) => procs[id].pid(),
+                                None => {
+                                    let _ = self.stream.write_all(format!("ERROR: Could not find process with id or name {}\n", arg).as_bytes());
+                                    return false
+                                }
+                            }
+                        },
+                        None => self.session.pid()
+                    };
+                    match pid.and_then(process_tree::process_tree){
+                        Some(tree) if self.json_output => {let _ = self.stream.write_all((tree.to_json() + "\n").as_bytes());},
+                        Some(tree) => {let _ = self.stream.write_all(tree.render().as_bytes());},
+                        None => {let _ = self.stream.write_all(b"That session isn't running a process\n");}
+                    }
+                    false
+                },
+                "history" => { // lists commands previously entered into this session
+                    let _ = self.stream.write_all((self.session.history()
+                            .enumerate()
+                            .map(|(id, cmd)| format!("{}\t{}\n", id + 1, cmd))
+                            .collect::<String>()).as_bytes());
+                    false
+                },
                 "getfile" => {
-                    if let Some(arg) = temp.next(){
-                        let file_loc = self.session.path.join(arg);
+                    let mut arg = temp.next();
+                    let compress = arg == Some("-z");
+                    if compress { arg = temp.next(); }
+                    if let Some(arg) = arg{
+                        let file_loc = match file_transfer::sanitize_within(self.session.root_or_path(), &self.session.path.join(self.session.expand_tilde(arg))){
+                            Ok(loc) => loc,
+                            Err(e) => {
+                                let _ = self.stream.write_all(format!("Could not get {}\n{}\n", arg, e).as_bytes());
+                                return false
+                            }
+                        };
+                        if file_loc.is_dir(){
+                            // let the client know to expect a directory archive rather than a single file
+                            let _ = self.stream.write_all(format!("{}DIR {}\n", CONTROL_PREFIX, arg).as_bytes());
+                            match file_transfer::send_dir(&mut self.stream, &file_loc){
+                                Ok(_) => {let _ = self.stream.write_all(b"Successfully sent directory to client!\n");},
+                                Err(e) => {let _ = self.stream.write_all(format!("Could not send directory {}\n",e).as_bytes());}
+                            };
+                            return false
+                        }
                         let file = File::open(&file_loc);
                         match file{
                             Ok(f) => {
-                                match file_transfer::send(&mut self.stream, f){
-                                    Ok(_) => {let _ = self.stream.write(b"Successfully sent file to client!\n");},
-                                    Err(e) => {let _ = self.stream.write(format!("Could not send file {}\n",e).as_bytes());}
+                                // progress lines would get mixed up with the file if they were sent to the client, which knows the
+                                // total size up front and can track progress of a download by itself, so they're logged instead
+                                let peer = self.peer_ip();
+                                let mut log_progress = |sent: u64, total: Option<u64>| {
+                                    let total = total.map_or(String::from("?"), |t| t.to_string());
+                                    logger::debug!("Sent {} of {} bytes of {} to {}", sent, total, file_loc.display(), peer);
+                                };
+                                match file_transfer::send(&mut self.stream, f, compress, Some(&mut log_progress)){
+                                    Ok(_) => {let _ = self.stream.write_all(b"Successfully sent file to client!\n");},
+                                    Err(e) => {let _ = self.stream.write_all(format!("Could not send file {}\n",e).as_bytes());}
                                 };
                             },
-                            Err(e) => {let _ = self.stream.write(format!("Could not find file at {}\n{}\n",file_loc.display(),e).as_bytes());}
+                            Err(e) => {let _ = self.stream.write_all(format!("Could not find file at {}\n{}\n",file_loc.display(),e).as_bytes());}
                         }
                     }
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
                     false
                 },
                 "sendfile" => {
-                    if let Some(arg) = temp.next(){
+                    let mut flags = Vec::new();
+                    let mut arg = temp.next();
+                    while let Some(flag) = arg.filter(|a| a.starts_with('-')){
+                        flags.push(flag);
+                        arg = temp.next();
+                    }
+                    let recursive = flags.contains(&"-r");
+                    let resume = flags.contains(&"-c");
+                    if let Some(arg) = arg{
                         let client_file_loc = std::path::PathBuf::from(arg);
                         let file_name = client_file_loc.file_name().unwrap_or(std::ffi::OsStr::new("new_file"));
-                        let file_loc = self.session.path.join(file_name);
-                        let file = File::create(&file_loc);
-                        println!("attempting to recieve {}",file_loc.display());
+                        let file_loc = match file_transfer::sanitize_within(self.session.root_or_path(), &self.session.path.join(file_name)){
+                            Ok(loc) => loc,
+                            Err(e) => {
+                                let _ = self.stream.write_all(format!("Could not send {}\n{}\n", arg, e).as_bytes());
+                                return false
+                            }
+                        };
+                        if recursive{
+                            logger::info!("attempting to recieve directory {}",file_loc.display());
+                            match self.stream.with_read_timeout(Some(TRANSFER_TIMEOUT), |stream| file_transfer::recv_dir(stream, &file_loc, self.max_upload_bytes)){
+                                Ok(_) => {let _ = self.stream.write_all(b"Successfully sent directory to server!\n");},
+                                Err(e) => {let _ = self.stream.write_all(format!("Could not send directory\n{}\n",e).as_bytes());}
+                            };
+                            return false
+                        }
+                        // when resuming, keep what we already have and tell the client where to pick up from
+                        let file = if resume{
+                            OpenOptions::new().create(true).append(true).open(&file_loc)
+                        }else{
+                            File::create(&file_loc)
+                        };
+                        logger::info!("attempting to recieve {}",file_loc.display());
                         match file{
                             Ok(f) => {
-                                let _ = self.stream.set_read_timeout(Some(Duration::new(2, 0)));
-
-                                match file_transfer::recv(&mut self.stream, f){
-                                    Ok(_) => {let _ = self.stream.write(b"Successfully sent file to server!\n");},
-                                    Err(e) => {let _ = self.stream.write(format!("Could not send file\n{}\n",e).as_bytes());}
+                                // what was already there from earlier attempts, which is kept whatever happens to this one
+                                let offset = f.metadata().map(|m| m.len()).unwrap_or(0);
+                                if resume{
+                                    let _ = self.stream.write_all(format!("{}OFFSET {}\n", CONTROL_PREFIX, offset).as_bytes());
+                                }
+                                // nothing else is written to the client during an upload, so progress can be reported on a cloned stream
+                                let mut progress_stream = self.stream.try_clone().ok();
+                                let mut report_progress = |transferred: u64, total: Option<u64>| {
+                                    if let Some(out) = progress_stream.as_mut(){
+                                        let total = total.map_or(String::from("-"), |t| t.to_string());
+                                        let _ = out.write_all(format!("{}PROGRESS {} {}\n", CONTROL_PREFIX, transferred, total).as_bytes());
+                                    }
+                                };
+                                let received = self.stream.with_read_timeout(Some(TRANSFER_TIMEOUT),
+                                    |stream| file_transfer::recv(stream, f, self.max_upload_bytes, Some(&mut report_progress)));
+                                match received{
+                                    Ok(_) => {let _ = self.stream.write_all(b"Successfully sent file to server!\n");},
+                                    Err(e) => {
+                                        // nothing from an upload that failed can be trusted, whether it was cut off, too
+                                        // big, timed out or didn't match its checksum, so don't leave it on the disk under
+                                        // its real name. when resuming, what made it here in earlier attempts stays, so
+                                        // it can be picked up from next time without appending to bad data
+                                        if resume{
+                                            let _ = OpenOptions::new().write(true).open(&file_loc).and_then(|f| f.set_len(offset));
+                                        }else{
+                                            let _ = std::fs::remove_file(&file_loc);
+                                        }
+                                        let _ = self.stream.write_all(format!("Could not send file\n{}\n",e).as_bytes());
+                                    }
                                 };
-
-                                let _ = self.stream.set_read_timeout(Some(Duration::new(0, 1000000)));
                             },
-                            Err(e) => {let _ = self.stream.write(format!("Could not create file at {}\n{}\n",file_loc.display(),e).as_bytes());}
+                            Err(e) => {let _ = self.stream.write_all(format!("Could not create file at {}\n{}\n",file_loc.display(),e).as_bytes());}
                         }
                     }
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
+                    false
+                },
+                "restart" => { // runs the last command again, for when a service crashes or was killed
+                    match self.session.last_command.clone(){
+                        Some(cmd) => match self.session.run_command(&cmd){
+                            Ok(_) => {
+                                let _ = self.stream.write_all(format!("Restarting {}\n", cmd).as_bytes());
+                                return true
+                            },
+                            Err(e) => {let _ = self.stream.write_all(format!("Could not restart {}\n{}\n", cmd, e).as_bytes());}
+                        },
+                        None => {let _ = self.stream.write_all(b"No command has been run in this session yet\n");}
+                    }
+                    false
+                },
+                "bg" => { // runs a command in the background
+                    let cmd = temp.collect::<Vec<&str>>().join(" ");
+                    if cmd.is_empty(){
+                        let _ = self.stream.write_all(b"Run a command in the background, discarding its output: rspi bg [command]\n");
+                    }else{
+                        self.start_background_job(&cmd);
+                    }
+                    false
+                },
+                "alias" => { // shortens commands, listing every alias when given nothing to define
+                    let args = temp.collect::<Vec<&str>>().join(" ");
+                    match args.split_once(' '){
+                        Some((name, value)) => self.session.set_alias(name, value.trim_matches(|c| c == '"' || c == '\'')),
+                        None if !args.is_empty() => {
+                            let msg = match self.session.aliases().into_iter().find(|(name, _)| **name == args){
+                                Some((name, value)) => format!("{}\t{}\n", name, value),
+                                None => format!("No alias named {}, define one with: rspi alias {} [command]\n", args, args)
+                            };
+                            let _ = self.stream.write_all(msg.as_bytes());
+                        },
+                        None => {
+                            let _ = self.stream.write_all((self.session.aliases().iter()
+                                    .map(|(name, value)| format!("{}\t{}\n", name, value))
+                                    .collect::<String>()).as_bytes());
+                        }
+                    }
+                    false
+                },
+                "unalias" => {
+                    if let Some(name) = temp.next(){
+                        if !self.session.remove_alias(name){
+                            let _ = self.stream.write_all(format!("No alias named {}\n", name).as_bytes());
+                        }
+                    }else{
+                        let _ = self.stream.write_all(b"Remove an alias: rspi unalias [name]\n");
+                    }
+                    false
+                },
+                "jobs" => { // lists background jobs, forgetting about ones that have finished once they've been listed
+                    let _ = self.stream.write_all((self.session.jobs().iter()
+                            .map(|job| format!("[{}]\t{}\t{}\t{}\n", job.id, job.pid(), job.cmd,
+                                job.status().map_or(String::from("running"), |status| format!("done ({})", status))))
+                            .collect::<String>()).as_bytes());
+                    self.session.clear_finished_jobs();
+                    false
+                },
+                "info" | "whoami" => { // reports facts about this client's session
+                    let now = time::SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                    let created_at = self.session.created_at();
+                    // since the last time the client asked, or since it connected
+                    let throughput = self.stream.throughput();
+                    if self.json_output{
+                        let info = format!("{{\"cwd\":{},\"command\":{},\"running\":{},\"session_started\":{},\"process_started\":{},\"peer\":{},\"tty\":{},\"now\":{},\
+                            \"bytes_in\":{},\"bytes_out\":{},\"in_per_sec\":{:.0},\"out_per_sec\":{:.0}}}\n",
+                            json::escape(&self.session.path.to_string_lossy()), json::escape(&self.session.cmd_name), self.session.has_child(),
+                            created_at, json::or_null(self.session.start_time()), json::escape(&self.peer_ip()), json::escape(self.session.tty()), now,
+                            self.stream.bytes_read(), self.stream.bytes_written(), throughput.read_per_sec, throughput.written_per_sec);
+                        let _ = self.stream.write_all(info.as_bytes());
+                        return false
+                    }
+                    let mut info = format!("cwd\t{}\ncommand\t{}\nrunning\t{}\nsession started\t{} ({}s ago)\npeer\t{}\ntty\t{}\n\
+                        traffic\t{} bytes in, {} bytes out ({:.0} B/s in, {:.0} B/s out since last asked)\n",
+                        self.session.path.display(), self.session.cmd_name, self.session.has_child(),
+                        created_at, now.saturating_sub(created_at), self.peer_ip(), self.session.tty(),
+                        self.stream.bytes_read(), self.stream.bytes_written(), throughput.read_per_sec, throughput.written_per_sec);
+                    if let Some(started) = self.session.start_time(){
+                        info += &format!("process started\t{} ({}s ago)\n", started, now.saturating_sub(started));
+                    }
+                    let _ = self.stream.write_all(info.as_bytes());
+                    false
+                },
+                "clear" => { // throws away output that hasn't been sent yet, like the backlog of an adopted process
+                    self.session.clear_output();
+                    false
+                },
+                "eof" => { // closes the running process's stdin, for programs that read until the end of their input
+                    if !self.session.has_child(){
+                        let _ = self.stream.write_all(b"No running process to send EOF to\n");
+                    }else if !self.session.close_stdin(){
+                        let _ = self.stream.write_all(format!("Stdin of {} has already been closed\n", self.session.cmd_name).as_bytes());
+                    }
+                    false
+                },
+                "format" => { // whether management commands answer in JSON or in text meant for people
+                    match temp.next(){
+                        Some("json") => self.json_output = true,
+                        Some("text") => self.json_output = false,
+                        _ => ()
+                    }
+                    let _ = self.stream.write_all(format!("Output format is {}\n", if self.json_output{"json"}else{"text"}).as_bytes());
+                    false
+                },
+                "capture" => { // whether output is streamed, or only the start and end of it sent once the command finishes
+                    match (temp.next(), temp.next().map(str::parse::<usize>)){
+                        (Some("stream"), _) => self.session.set_capture_limit(None),
+                        (Some("head-tail"), None) => self.session.set_capture_limit(Some(capture::DEFAULT_CAPTURE_BYTES)),
+                        (Some("head-tail"), Some(Ok(kb))) if kb > 0 => self.session.set_capture_limit(Some(kb * 1024)),
+                        (None, _) => (),
+                        _ => {
+                            let _ = self.stream.write_all(b"Usage: rspi capture [stream|head-tail [KB]]\n");
+                            return false
+                        }
+                    }
+                    let _ = match self.session.capture_limit(){
+                        Some(limit) => self.stream.write_all(format!("Output is captured, keeping the first and last {} KB\n", limit / 1024).as_bytes()),
+                        None => self.stream.write_all(b"Output is streamed\n")
+                    };
+                    false
+                },
+                "raw" => { // whether messages are sent to a running process as they are, or as lines
+                    match temp.next(){
+                        Some("on") => self.raw_input = true,
+                        Some("off") => self.raw_input = false,
+                        _ => ()
+                    }
+                    let _ = self.stream.write_all(format!("Raw input is {}\n", if self.raw_input{"on"}else{"off"}).as_bytes());
+                    false
+                },
+                "flush" => { // how long output is held back to be sent along with whatever comes after it
+                    if let Some(ms) = temp.next().and_then(|ms| ms.parse().ok()){
+                        self.flush_interval = Duration::from_millis(ms);
+                    }
+                    let _ = self.stream.write_all(format!("Output is held back for up to {} ms\n", self.flush_interval.as_millis()).as_bytes());
+                    false
+                },
+                "color" => { // whether escape sequences in output, like colors, are passed on to the client
+                    match temp.next(){
+                        Some("on") => self.stripper = None,
+                        Some("off") if self.stripper.is_none() => self.stripper = Some(AnsiStripper::new()),
+                        _ => ()
+                    }
+                    let _ = self.stream.write_all(format!("Color is {}\n", if self.stripper.is_none(){"on"}else{"off"}).as_bytes());
+                    false
+                },
+                "which" => { // where the executable a command would run is
+                    let _ = match temp.next(){
+                        Some(name) if completion::BUILTINS.contains(&name) => self.stream.write_all(format!("{} is built into the server\n", name).as_bytes()),
+                        Some(name) => match completion::resolve_executable(name, env::var_os("PATH").as_deref(), &self.session.path){
+                            Some(path) => self.stream.write_all(format!("{}\n", path.display()).as_bytes()),
+                            None => self.stream.write_all(format!("{} was not found\n", name).as_bytes())
+                        },
+                        None => self.stream.write_all(b"Usage: rspi which <command>\n")
+                    };
+                    false
+                },
+                "ls" => { // lists a directory in a format meant for programs rather than people
+                    let mut arg = temp.next();
+                    let as_json = arg == Some("-j") || self.json_output;
+                    if arg == Some("-j") { arg = temp.next(); }
+                    let dir = arg.unwrap_or(".");
+                    let listed = file_transfer::sanitize_within(self.session.root_or_path(), &self.session.path.join(self.session.expand_tilde(dir)))
+                        .and_then(|path| fileops::list_dir(&path));
+                    let _ = match listed{
+                        Ok(entries) if as_json => self.stream.write_all(format!("[{}]\n",
+                            entries.iter().map(fileops::DirEntry::to_json).collect::<Vec<String>>().join(",")).as_bytes()),
+                        Ok(entries) => self.stream.write_all(entries.iter().map(fileops::DirEntry::to_tsv).collect::<String>().as_bytes()),
+                        Err(e) => self.stream.write_all(format!("Could not list {}\n{}\n", dir, e).as_bytes())
+                    };
+                    false
+                },
+                "mv" | "cp" | "rm" => { // file operations that don't need a process of their own
+                    let mut flags = Vec::new();
+                    let mut args = Vec::new();
+                    for arg in temp{
+                        if arg.starts_with('-') { flags.push(arg) } else { args.push(arg) }
+                    }
+                    let recursive = flags.contains(&"-r");
+                    let root = self.session.root_or_path().to_owned();
+                    let resolve = |arg: &str| self.session.path.join(self.session.expand_tilde(arg));
+                    let result = match (cmd, args.as_slice()){
+                        ("mv", [src, dst]) => fileops::sanitize_entry(&root, &resolve(src))
+                            .and_then(|src| Ok((src, file_transfer::sanitize_within(&root, &resolve(dst))?)))
+                            .and_then(|(src, dst)| fileops::move_path(&src, &dst))
+                            .map(|dst| format!("Moved {} to {}\n", src, dst.display())),
+                        ("cp", [src, dst]) => file_transfer::sanitize_within(&root, &resolve(src))
+                            .and_then(|src| Ok((src, file_transfer::sanitize_within(&root, &resolve(dst))?)))
+                            .and_then(|(src, dst)| fileops::copy_path(&src, &dst, recursive))
+                            .map(|dst| format!("Copied {} to {}\n", src, dst.display())),
+                        ("rm", [path]) => fileops::remove_within(&root, &self.session.path, &resolve(path), recursive)
+                            .map(|_| format!("Removed {}\n", path)),
+                        ("rm", _) => Ok(String::from("Remove a file, or a directory with -r: rspi rm [-r] [path]\n")),
+                        _ => Ok(format!("{} a file, or a directory with -r: rspi {} [-r] [source] [destination]\n",
+                            if cmd == "mv" {"Move"} else {"Copy"}, cmd))
+                    };
+                    let _ = match result{
+                        Ok(msg) => self.stream.write_all(msg.as_bytes()),
+                        Err(e) => self.stream.write_all(format!("Could not {} {}\n{}\n", cmd, args.join(" "), e).as_bytes())
+                    };
+                    false
+                },
+                "sysinfo" => { // reports stats about the server's host
+                    let info = SysInfo::read();
+                    let _ = self.stream.write_all(if self.json_output { info.to_json() + "\n" } else { info.to_string() }.as_bytes());
+                    false
+                },
+                "tail" => { // follows a file, streaming anything appended to it until interrupted
+                    if let Some(arg) = temp.next(){
+                        let lines = temp.next().and_then(|n| n.parse().ok()).unwrap_or(10);
+                        let started = file_transfer::sanitize_within(self.session.root_or_path(), &self.session.path.join(self.session.expand_tilde(arg)))
+                            .and_then(|file_loc| self.session.tail(&file_loc, lines));
+                        match started{
+                            Ok(()) => return true,
+                            Err(e) => {let _ = self.stream.write_all(format!("Could not tail {}\n{}\n", arg, e).as_bytes());}
+                        }
+                    }else{
+                        let _ = self.stream.write_all(b"Follow a file, printing lines as they are added until interrupted: rspi tail [file] [lines]\n");
+                    }
+                    false
+                },
+                #[cfg(feature = "download")]
+                "download" => { // fetches a file from a URL into the session's directory
+                    let mut arg = temp.next();
+                    let parallel = arg == Some("-p");
+                    if parallel { arg = temp.next(); }
+                    if let Some(url) = arg{
+                        let dest = temp.next().unwrap_or(download::file_name_from_url(url));
+                        match file_transfer::sanitize_within(self.session.root_or_path(), &self.session.path.join(self.session.expand_tilde(dest))){
+                            Ok(file_loc) => match download::create_temp(&file_loc){
+                                Ok((temp_loc, f)) => {
+                                    logger::info!("downloading {} to {}", url, file_loc.display());
+                                    let mut progress_stream = self.stream.try_clone().ok();
+                                    let mut report_progress = |transferred: u64, total: Option<u64>| {
+                                        if let Some(out) = progress_stream.as_mut(){
+                                            let total = total.map_or(String::from("-"), |t| t.to_string());
+                                            let _ = out.write_all(format!("{}PROGRESS {} {}\n", CONTROL_PREFIX, transferred, total).as_bytes());
+                                        }
+                                    };
+                                    let downloaded = if parallel{
+                                        download::download_parallel(url, f, download::DEFAULT_PARTS, self.max_upload_bytes, Some(&mut report_progress))
+                                    }else{
+                                        download::download(url, f, self.max_upload_bytes, Some(&mut report_progress))
+                                    }.and_then(|size| std::fs::rename(&temp_loc, &file_loc).map(|_| size));
+                                    match downloaded{
+                                        Ok(size) => {
+                                            // give the client a way to check it got the right file, especially when its parts were written separately
+                                            let checksum = download::checksum(&file_loc).map_or(String::from("unknown"), |crc| format!("{:08x}", crc));
+                                            let _ = self.stream.write_all(format!("Downloaded {} bytes to {} (CRC-32 {})\n", size, file_loc.display(), checksum).as_bytes());
+                                        },
+                                        Err(e) => {
+                                            let _ = std::fs::remove_file(&temp_loc);
+                                            let _ = self.stream.write_all(format!("Could not download {}\n{}\n", url, e).as_bytes());
+                                        }
+                                    }
+                                },
+                                Err(e) => {let _ = self.stream.write_all(format!("Could not create file at {}\n{}\n",file_loc.display(),e).as_bytes());}
+                            },
+                            Err(e) => {let _ = self.stream.write_all(format!("Could not download to {}\n{}\n", dest, e).as_bytes());}
+                        }
+                    }else{
+                        let _ = self.stream.write_all(b"Download a file from a URL into the current directory: rspi download [-p] [url] [destination]\n");
+                    }
+                    false
+                },
+                #[cfg(not(feature = "download"))]
+                "download" => {
+                    let _ = self.stream.write_all(b"Could not download\nthis server was built without the download feature\n");
                     false
                 },
                 _ => { // help instructions
-                    let _ = self.stream.write(b"RS-PI process manager commands:\n
+                    let _ = self.stream.write_all(b"RS-PI process manager commands:\n
                         procs\tlists processes managed by this app\n
                         adopt [process id or name]\tmake this client session take control of a running proccess\n
-                        orphan\tgive control of this client's running process back to the server process manager.\n");
-                    let _ = self.stream.write(format!("{}$ ",self.session.path.display()).as_bytes());
+                        orphan\tgive control of this client's running process back to the server process manager.\n
+                        history\tlists commands previously entered into this session\n
+                        getfile [-z] [path]\tsend a file or directory from the server to the client, gzip compressing files with -z\n
+                        sendfile [-r] [-c] [path]\tsend a file (or directory with -r) from the client to the server, resuming a partial upload with -c\n
+                        bg [command]\trun a command in the background, same as ending it with '&'\n
+                        jobs\tlists this session's background jobs\n
+                        alias [name] [command]\tmakes name run command, or lists aliases if given nothing\n
+                        unalias [name]\tremoves an alias\n
+                        complete [line]\tlists completions of the last word of a command\n
+                        info\tshows the current directory, command, peer, tty and traffic of this session\n
+                        clear\tdiscards output that hasn't been sent yet\n
+                        eof\tcloses the input of the running process, like pressing Ctrl-D\n
+                        format [json|text]\tmakes procs, ps-tree, ls, info and sysinfo answer in JSON or text\n
+                        run-detached [command]\tstarts a command as one of the server's processes, so it keeps running after you disconnect\n
+                        restart\truns the last command started in this session again\n
+                        watch [process id or name]\tshows the output of a process as it runs, without taking control of it\n
+                        unwatch\tstops watching a process, leaving it running\n
+                        ps-tree [process id or name]\tshows every process started by a process's command, or by this session's if not given one\n
+                        exit\tends the foreground process, if there is one, and disconnects. also works as just 'exit' or 'logout'\n
+                        capture [stream|head-tail [KB]]\tstream the output of commands, or only send the first and last KB of it (64 by default) once they finish\n
+                        flush [ms]\thold output back for up to this long so it's sent in fewer, larger writes, or 0 to send it right away\n
+                        color [on|off]\tpass colors and other escape sequences in output on to the client, or take them out\n
+                        raw [on|off]\tsend input to running processes exactly as it is, without adding a newline to each message\n
+                        which <command>\tshows the path of the executable a command would run\n
+                        ls [-j] [path]\tlists the type, size, permissions and name of each entry in a directory, as JSON with -j\n
+                        mv [source] [destination]\tmoves or renames a file or directory\n
+                        cp [-r] [source] [destination]\tcopies a file, or a directory with -r\n
+                        rm [-r] [path]\tremoves a file, or a directory with -r\n
+                        sysinfo\tshows the uptime, load, memory and CPU temperature of the server\n
+                        tail [file] [lines]\tprint the last lines of a file (10 by default), then follow it until interrupted\n
+                        download [-p] [url] [destination]\tdownload a file from a URL into the current directory, in parts on several connections at once with -p\n");
                     false
                 }
             }
@@ -317,4 +1459,780 @@ impl Client{
             false
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{net::{Shutdown, TcpListener, TcpStream}, os::unix::net::UnixStream, process::Command, thread};
+
+    use super::*;
+    use crate::auth::EnvPassword;
+
+    /// A fresh, empty directory for a test to work in, with symlinks resolved
+    fn temp_dir(name: &str) -> std::path::PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-client-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// Lets in clients that send "letmein", as whoever they say they are after it, or "sudo" to be let in as an admin.
+    /// Anything else followed by a name is rejected as that user
+    struct MockAuth;
+    impl Authenticator for MockAuth{
+        fn authenticate(&self, submitted: &[u8], _peer: Option<std::net::SocketAddr>) -> AuthResult{
+            match str::from_utf8(submitted).unwrap_or_default().split_once(' '){
+                Some(("letmein", name)) => AuthResult::Accepted(Some(User{name: name.to_owned(), home: None, role: Role::Restricted})),
+                Some(("sudo", name)) => AuthResult::Accepted(Some(User{name: name.to_owned(), home: None, role: Role::Admin})),
+                Some((_, name)) => AuthResult::Rejected{reason: String::from("wrong word"), user: Some(name.to_owned())},
+                _ => AuthResult::Rejected{reason: String::from("wrong word"), user: None}
+            }
+        }
+    }
+
+    /// Connects to a new Client over a Unix socket, logging in by sending `login` to `auth`
+    fn connect_with(login: &[u8], auth: impl Authenticator + 'static) -> (io::Result<Client>, UnixStream){
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(login).unwrap();
+        (Client::new(Transport::Unix(theirs), Arc::default(), Arc::default(), StopFlag::default(), Arc::new(auth)), ours)
+    }
+
+    /// Connects to a new Client over a Unix socket, logging in with `password`
+    fn connect(password: &[u8]) -> (io::Result<Client>, UnixStream){
+        connect_with(password, EnvPassword)
+    }
+
+    #[test]
+    fn authenticators_decide_who_logs_in(){
+        let (client, _conn) = connect_with(b"letmein carol", MockAuth);
+        let client = client.unwrap();
+        assert_eq!(client.identity.as_deref(), Some("carol"));
+        assert_eq!(client.role, Role::Restricted);
+
+        let (client, _conn) = connect_with(b"open sesame", MockAuth);
+        let err = client.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(err.to_string(), "Client local could not log in as sesame: wrong word");
+
+        let (client, _conn) = connect_with(b"opensesame", MockAuth);
+        assert_eq!(client.err().unwrap().to_string(), "Client local could not log in: wrong word");
+    }
+
+    /// Connects to a new Client over TCP, encrypted with the hash and with `RSPI_LOGIN_NONCE` on, logging in by sending
+    /// whatever `login` gives for the nonce the server sent
+    fn connect_tcp(login: impl FnOnce(u64) -> Vec<u8>) -> io::Result<Client>{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ours = SecureStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap()).set_hash(Client::get_hash().unwrap());
+        let theirs = listener.accept().unwrap().0;
+        let client = thread::spawn(move || Client::with_login(Transport::Tcp(theirs), Arc::default(), Arc::default(), StopFlag::default(), Arc::new(EnvPassword), true));
+        assert!(ours.read_line().unwrap().starts_with(&format!("{}BANNER ", CONTROL_PREFIX)));
+        let nonce = ours.read_line().unwrap().strip_prefix(&format!("{}NONCE ", CONTROL_PREFIX)).unwrap().parse().unwrap();
+        ours.stream.write_all(&login(nonce)).unwrap();
+        // so a login that's cut short doesn't leave the client waiting on the rest of it
+        let _ = ours.stream.shutdown(Shutdown::Write);
+        client.join().unwrap()
+    }
+
+    /// What someone listening in would see of `login` being sent on a connection that was given `nonce`
+    fn sealed_login(login: &[u8], nonce: u64) -> Vec<u8>{
+        let mut sealed = SecureStream::new(io::Cursor::new(Vec::new())).set_hash(Client::get_hash().unwrap()).mix_nonce(nonce);
+        sealed.write_sequenced_message(login).unwrap();
+        sealed.stream.into_inner()
+    }
+
+    /// A client that logged in over a Unix socket with the default password, running on its own thread and driven from
+    /// our end of the socket
+    struct Running{
+        conn: UnixStream,
+        thread: Option<thread::JoinHandle<()>>
+    }
+    impl Running{
+        /// Logs in and waits for the first prompt
+        fn start() -> Self{
+            Self::start_with(|_| ())
+        }
+
+        /// Logs in like `start`, letting `configure` change the client's settings before it starts running
+        fn start_with(configure: impl FnOnce(&mut Client)) -> Self{
+            let mut running = Self::connect(configure);
+            running.read_until("$ ");
+            running
+        }
+
+        /// Logs in without waiting for anything, so the first message can be sent before the first prompt
+        fn connect(configure: impl FnOnce(&mut Client)) -> Self{
+            Self::run_client(connect(b"Password"), configure)
+        }
+
+        /// Logs in by sending `login` to `MockAuth`, letting `configure` change the client's settings, without waiting
+        /// for anything
+        fn log_in_with(login: &str, configure: impl FnOnce(&mut Client)) -> Self{
+            Self::run_client(connect_with(login.as_bytes(), MockAuth), configure)
+        }
+
+        fn run_client((client, conn): (io::Result<Client>, UnixStream), configure: impl FnOnce(&mut Client)) -> Self{
+            let mut client = client.unwrap();
+            configure(&mut client);
+            conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            Self{conn, thread: Some(thread::spawn(move || client.run()))}
+        }
+
+        fn send(&mut self, msg: &str){
+            self.conn.write_all(msg.as_bytes()).unwrap();
+        }
+
+        /// Reads until `end` arrives, returning everything read up to and including it
+        fn read_until(&mut self, end: &str) -> String{
+            let mut received = Vec::new();
+            let mut byte = [0u8];
+            while !received.ends_with(end.as_bytes()){
+                match self.conn.read(&mut byte){
+                    Ok(1) => received.push(byte[0]),
+                    Ok(_) => panic!("connection closed before {:?}, after {:?}", end, String::from_utf8_lossy(&received)),
+                    Err(e) => panic!("{} waiting for {:?}, after {:?}", e, end, String::from_utf8_lossy(&received))
+                }
+            }
+            String::from_utf8_lossy(&received).into_owned()
+        }
+
+        /// Reads until the client closes the connection
+        fn read_to_end(&mut self) -> String{
+            let mut received = Vec::new();
+            self.conn.read_to_end(&mut received).unwrap();
+            String::from_utf8_lossy(&received).into_owned()
+        }
+
+        /// Sends a command and returns everything it output, up to and including the prompt after it
+        fn run(&mut self, cmd: &str) -> String{
+            self.send(cmd);
+            self.read_until("$ ")
+        }
+
+        /// Waits for the client's thread to end, which it does once the connection closes
+        fn join(&mut self){
+            if let Some(thread) = self.thread.take(){
+                thread.join().unwrap();
+            }
+        }
+    }
+    impl Drop for Running{
+        fn drop(&mut self){
+            let _ = self.conn.shutdown(std::net::Shutdown::Both);
+            if let Some(thread) = self.thread.take(){
+                if !thread::panicking() { thread.join().unwrap(); }
+            }
+        }
+    }
+
+    #[test]
+    fn history_lists_commands_in_order(){
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let mut client = Running::start_with(|client| client.processes = processes.clone());
+        for cmd in ["echo one", "rspi procs", "echo two"]{
+            client.run(cmd);
+        }
+        // the history follows the client into the new session it gets after orphaning a process
+        client.send("sleep 30");
+        // messages aren't framed, so give the first one time to arrive on its own
+        thread::sleep(Duration::from_millis(200));
+        client.run("rspi orphan");
+        let history = client.run("rspi history");
+        let listed: Vec<&str> = history.lines().filter(|line| line.contains('\t')).collect();
+        assert_eq!(listed, ["1\techo one", "2\trspi procs", "3\techo two", "4\tsleep 30", "5\trspi history"]);
+        for mut session in processes.lock().unwrap().drain(..){
+            session.kill();
+            let _ = session.close();
+        }
+    }
+
+    #[test]
+    fn sendfile_c_resumes_from_the_partial_file(){
+        let dir = temp_dir("resume");
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("local.bin"), &contents).unwrap();
+        // an earlier upload got this far before it was cut off
+        std::fs::write(dir.join("upload.bin"), &contents[..7000]).unwrap();
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        client.send("rspi sendfile -c upload.bin");
+        client.read_until(&format!("{}OFFSET 7000\n", CONTROL_PREFIX));
+        let mut upload = SecureStream::new(Transport::Unix(client.conn.try_clone().unwrap()));
+        file_transfer::send_from(&mut upload, File::open(dir.join("local.bin")).unwrap(), 7000, false, None).unwrap();
+        client.read_until("Successfully sent file to server!\n");
+        assert!(std::fs::read(dir.join("upload.bin")).unwrap() == contents);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_logins_are_logged_as_warnings(){
+        let ((client, _conn), records) = logger::capture(|| connect(b"open sesame"));
+        assert!(client.is_err());
+        assert!(records.contains(&(logger::Level::Warn, String::from("Client local failed to log in: incorrect password"))), "{:?}", records);
+
+        let ((client, _conn), records) = logger::capture(|| connect(b"Password"));
+        assert!(client.is_ok());
+        assert!(records.iter().all(|(level, _)| *level > logger::Level::Warn), "{:?}", records);
+    }
+
+    #[test]
+    fn missing_hashkeys_are_warned_about_or_refused(){
+        for key in [None, Some("0")]{
+            let (checked, records) = logger::capture(|| check_key(key, false));
+            assert!(checked.is_ok());
+            assert!(records.iter().any(|(level, msg)| *level == logger::Level::Warn && msg.contains("effectively unencrypted")), "{:?}", records);
+            assert_eq!(check_key(key, true).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        let (checked, records) = logger::capture(|| check_key(Some("12345"), true));
+        assert!(checked.is_ok() && records.is_empty(), "{:?}", records);
+    }
+
+    #[test]
+    fn tcp_logins_are_just_the_password_by_default(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ours = SecureStream::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap()).set_hash(Client::get_hash().unwrap());
+        let theirs = listener.accept().unwrap().0;
+        let client = thread::spawn(move || Client::with_login(Transport::Tcp(theirs), Arc::default(), Arc::default(), StopFlag::default(), Arc::new(EnvPassword), false));
+        assert!(ours.read_line().unwrap().starts_with(&format!("{}BANNER ", CONTROL_PREFIX)));
+        // no nonce is sent, and the password goes as it is
+        ours.write_all(b"Password").unwrap();
+        assert!(client.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn logins_cant_be_replayed_on_another_connection(){
+        let mut recorded = Vec::new();
+        let client = connect_tcp(|nonce| {
+            recorded = sealed_login(b"Password", nonce);
+            recorded.clone()
+        });
+        assert!(client.is_ok());
+        assert!(connect_tcp(|_| recorded).is_err());
+        // logging in without a sequence number isn't enough either
+        assert!(connect_tcp(|nonce| {
+            let mut unsequenced = SecureStream::new(io::Cursor::new(Vec::new())).set_hash(Client::get_hash().unwrap()).mix_nonce(nonce);
+            unsequenced.write_message(b"Password").unwrap();
+            unsequenced.stream.into_inner()
+        }).is_err());
+    }
+
+    #[test]
+    fn commands_are_audited(){
+        let log = env::temp_dir().join(format!("rspi-client-audit-{}", std::process::id()));
+        audit::log_to(File::create(&log).unwrap());
+        let mut client = Running::start();
+        assert!(client.run("echo audited-command").contains("audited-command\r\n"));
+        let audited = std::fs::read_to_string(&log).unwrap();
+        let line = audited.lines().find(|line| line.ends_with("\tcmd\techo audited-command")).unwrap();
+        assert_eq!(line.split('\t').nth(1), Some("local"));
+        let _ = std::fs::remove_file(&log);
+    }
+
+    /// Runs `cmd` as a one-shot client, returning everything it was sent after the banner
+    fn oneshot(cmd: &str) -> String{
+        let mut client = Running::connect(|_| ());
+        client.send(&format!("{}{}", ONESHOT_PREFIX, cmd));
+        let received = client.read_to_end();
+        received.split_once('\n').unwrap().1.to_owned()
+    }
+
+    #[test]
+    fn oneshot_commands_send_their_output_and_exit_status(){
+        assert_eq!(oneshot("echo hi"), format!("hi\r\n{}EXIT 0 -\n", CONTROL_PREFIX));
+        assert_eq!(oneshot("false"), format!("{}EXIT 1 -\n", CONTROL_PREFIX));
+        assert_eq!(oneshot("no-such-command-rspi"), format!("No such file or directory (os error 2)\n{}EXIT 127 -\n", CONTROL_PREFIX));
+    }
+
+    #[test]
+    fn oneshot_commands_without_a_process_still_exit(){
+        assert_eq!(oneshot("cd /"), format!("{}EXIT 0 -\n", CONTROL_PREFIX));
+        assert!(oneshot("rspi history").ends_with(&format!("rspi history\n{}EXIT 0 -\n", CONTROL_PREFIX)));
+        assert_eq!(oneshot("cd /no/such/dir"), format!("No such file or directory (os error 2)\n{}EXIT 127 -\n", CONTROL_PREFIX));
+        assert_eq!(oneshot("true &"), format!("[1] started true\n{}EXIT 0 -\n", CONTROL_PREFIX));
+    }
+
+    #[test]
+    fn info_reports_the_directory_and_running_process(){
+        let dir = temp_dir("info");
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        let info = client.run("rspi info");
+        assert!(info.contains(&format!("cwd\t{}\n", dir.display())), "{}", info);
+        assert!(info.contains("running\tfalse\n") && !info.contains("process started\t"), "{}", info);
+
+        client.send("sleep 30");
+        thread::sleep(Duration::from_millis(200));
+        client.send("rspi info");
+        let info = client.read_until("process started\t") + &client.read_until("\n");
+        assert!(info.contains(&format!("cwd\t{}\n", dir.display())), "{}", info);
+        assert!(info.contains("command\tsleep\n") && info.contains("running\ttrue\n"), "{}", info);
+        client.send(INTERRUPT_MSG);
+        client.read_until("$ ");
+    }
+
+    /// CPU time, in clock ticks, used so far by this process's threads named `name`
+    fn cpu_ticks_of_threads(name: &str) -> (usize, u64){
+        let stats: Vec<String> = std::fs::read_dir("/proc/self/task").unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("stat")).ok())
+            .filter(|stat| stat.contains(&format!("({}) ", name)))
+            .collect();
+        // utime and stime are the 12th and 13th fields after the name
+        let ticks = stats.iter().map(|stat| {
+            let fields: Vec<u64> = stat.rsplit_once(") ").unwrap().1.split(' ').skip(11).take(2).map(|f| f.parse().unwrap()).collect();
+            fields.iter().sum::<u64>()
+        }).sum();
+        (stats.len(), ticks)
+    }
+
+    #[test]
+    fn idle_clients_barely_use_the_cpu(){
+        // threads take the name of the thread that starts them, so everything the client starts can be found by it
+        let mut client = thread::Builder::new().name(String::from("rspi-idle")).spawn(Running::start).unwrap().join().unwrap();
+        let (threads, before) = cpu_ticks_of_threads("rspi-idle");
+        assert!(threads >= 2, "{} threads", threads);
+        thread::sleep(Duration::from_secs(2));
+        let (_, after) = cpu_ticks_of_threads("rspi-idle");
+        // clock ticks are usually 10ms, so this is under 5% of a core. polling every millisecond would be well over
+        assert!(after - before < 10, "{} ticks while idle", after - before);
+        assert!(client.run("echo awake").contains("awake\r\n"));
+    }
+
+    #[test]
+    fn every_command_gets_exactly_one_prompt(){
+        let dir = temp_dir("prompts");
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        let prompt = format!("{}$ ", dir.display());
+        let cmds = ["rspi procs", "rspi jobs", "rspi orphan", "rspi adopt", "rspi adopt nothing", "rspi history", "rspi clear",
+            "rspi info", "rspi getfile missing", "sleep 0.2", "sleep 0.1 &", "rspi nonsense", "true"];
+        let mut transcript = String::new();
+        for cmd in cmds{
+            client.send(cmd);
+            transcript += &client.read_until(&prompt);
+        }
+        // a command that sent a second prompt would have it read in place of the next one's, leaving one more to go
+        client.send("echo done");
+        transcript += &client.read_until("done\r\n");
+        transcript += &client.read_until(&prompt);
+        assert_eq!(transcript.matches(&prompt).count(), cmds.len() + 1, "{}", transcript);
+    }
+
+    #[test]
+    fn the_motd_comes_before_the_first_prompt(){
+        let mut client = Running::log_in_with("letmein mona", |client| client.motd = Some(String::from("Maintenance at 5pm\n")));
+        let greeting = client.read_until("$ ");
+        let (_, prompt) = greeting.split_once("Maintenance at 5pm\n").unwrap_or_else(|| panic!("{:?}", greeting));
+        // the prompt follows straight after it, and it isn't sent again
+        assert!(!prompt.contains('\n'), "{:?}", greeting);
+        assert!(!client.run("echo hi").contains("Maintenance"));
+    }
+
+    #[test]
+    fn processes_can_be_adopted_by_name(){
+        let dir = temp_dir("adopt-name");
+        // commands are split on whitespace, so what the process runs is in a script
+        std::fs::write(dir.join("started.sh"), "echo started; exec sleep 30\n").unwrap();
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let mut client = Running::start_with(|client| client.processes = processes.clone());
+        client.run("rspi run-detached sleep 30");
+        client.run(&format!("rspi run-detached sh {}", dir.join("started.sh").display()));
+        // names are matched ignoring case, and the id it had is reported
+        client.send("rspi adopt SH");
+        client.read_until("Successfully took control of process 1: sh\n");
+        client.read_until("started\r\n");
+        client.send(INTERRUPT_MSG);
+        client.read_until("$ ");
+        for mut session in poison::lock(&processes, "processes").drain(..){
+            session.kill();
+            let _ = session.close();
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn processes_can_be_orphaned_and_adopted_after_a_panic(){
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let poisoned = processes.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoned.lock().unwrap();
+            panic!("poisoning the processes");
+        }).join();
+        assert!(processes.is_poisoned());
+
+        let mut client = Running::start_with(|client| client.processes = processes.clone());
+        client.send("sleep 30");
+        // messages aren't framed, so give the first one time to arrive on its own
+        thread::sleep(Duration::from_millis(200));
+        assert!(client.run("rspi orphan").contains("Sucessfully gave control of sleep to proccess manager with id 0\n"));
+        assert!(client.run("rspi procs").contains("0\tsleep\trunning\t"));
+        client.send("rspi adopt 0");
+        client.read_until("Successfully took control of process 0: sleep\n");
+        client.send(INTERRUPT_MSG);
+        client.read_until("$ ");
+    }
+
+    #[test]
+    fn binary_input_reaches_the_process_unchanged(){
+        let dir = temp_dir("binary-stdin");
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        client.send("dd of=received");
+        // messages aren't framed, so give each one time to arrive on its own
+        thread::sleep(Duration::from_millis(200));
+        // not valid UTF-8 in any way: a lone continuation byte, a cut off character, and bytes that never appear
+        let binary = [0x80, b'a', 0xc3, 0x00, 0x01, 0xfe, 0xff, 0x7f, b'z'];
+        client.conn.write_all(&binary).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        client.send("rspi eof");
+        client.read_until("$ ");
+        // without raw input, each message is a line
+        assert_eq!(std::fs::read(dir.join("received")).unwrap(), [&binary[..], b"\n"].concat());
+    }
+
+    /// Logs in with a heartbeat every 100ms, and waits for the first prompt
+    fn with_heartbeat() -> Running{
+        Running::start_with(|client| client.heartbeat = Some(Duration::from_millis(100)))
+    }
+
+    #[test]
+    fn idle_clients_that_answer_pings_stay_connected(){
+        let mut client = with_heartbeat();
+        let ping = format!("{}PING\n", CONTROL_PREFIX);
+        for _ in 0..MAX_MISSED_PINGS + 2{
+            client.read_until(&ping);
+            client.send(PONG_MSG);
+        }
+        client.read_until(&ping);
+        assert!(client.run("echo still here").contains("still here\r\n"));
+    }
+
+    #[test]
+    fn clients_that_stop_answering_pings_are_disconnected(){
+        let mut client = with_heartbeat();
+        let ping = format!("{}PING\n", CONTROL_PREFIX);
+        client.read_until(&ping);
+        client.send(PONG_MSG);
+        let rest = client.read_to_end();
+        assert_eq!(rest.matches(&ping).count(), MAX_MISSED_PINGS as usize, "{:?}", rest);
+    }
+
+    #[test]
+    fn clients_that_never_answer_pings_stay_connected(){
+        let mut client = with_heartbeat();
+        let ping = format!("{}PING\n", CONTROL_PREFIX);
+        for _ in 0..MAX_MISSED_PINGS + 2{
+            client.read_until(&ping);
+        }
+        assert!(client.run("echo still here").contains("still here\r\n"));
+    }
+
+    #[test]
+    fn interrupting_tail_stops_it(){
+        let dir = temp_dir("tail");
+        std::fs::write(dir.join("log"), "first\n").unwrap();
+        let mut client = Running::start();
+        // files can only be followed from inside the session's directory
+        client.run(&format!("cd {}", dir.display()));
+        client.send("rspi tail log");
+        client.read_until("first\n");
+        client.send(INTERRUPT_MSG);
+        client.read_until("$ ");
+        // the connection is still there, and the session is free to run something else
+        assert!(client.run("echo after-tail").contains("after-tail\r\n"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn background_jobs_are_listed(){
+        let mut client = Running::start();
+        assert!(client.run("sleep 30 &").starts_with("[1] started sleep 30\n"));
+        assert!(client.run("sleep 31&").starts_with("[2] started sleep 31\n"));
+        let jobs = client.run("rspi jobs");
+        let jobs: Vec<Vec<&str>> = jobs.lines().take(2).map(|job| job.split('\t').collect()).collect();
+        assert_eq!(jobs.len(), 2);
+        for (job, (id, cmd)) in jobs.iter().zip([("[1]", "sleep 30"), ("[2]", "sleep 31")]){
+            assert_eq!((job[0], job[2], job[3]), (id, cmd, "running"));
+            assert!(job[1].parse::<u32>().is_ok());
+        }
+    }
+
+    #[test]
+    fn aliases_can_be_defined_listed_and_removed(){
+        let mut client = Running::start();
+        client.run("rspi alias greet \"echo hello there\"");
+        client.run("rspi alias hi greet");
+        assert!(client.run("hi").contains("hello there\r\n"));
+        assert!(client.run("rspi alias greet").starts_with("greet\techo hello there\n"));
+        assert!(client.run("rspi alias").starts_with("greet\techo hello there\nhi\tgreet\n"));
+        assert!(!client.run("rspi unalias greet").contains("No alias"));
+        assert!(client.run("rspi unalias greet").starts_with("No alias named greet\n"));
+        assert!(client.run("rspi alias greet").starts_with("No alias named greet"));
+    }
+
+    #[test]
+    fn info_reports_traffic(){
+        let mut client = Running::start();
+        let info = client.run("rspi info");
+        let traffic = info.lines().find_map(|line| line.strip_prefix("traffic\t")).unwrap();
+        let counts: Vec<u64> = traffic.split(' ').filter_map(|word| word.parse().ok()).collect();
+        // the login and `rspi info` came in, and at least the prompt went out
+        assert!(counts[0] >= "rspi info".len() as u64 && counts[1] > 0, "{}", traffic);
+        assert!(traffic.ends_with("since last asked)"));
+    }
+
+    #[test]
+    fn ctrl_c_interrupts_the_process_but_not_the_connection(){
+        let mut client = Running::start();
+        let started = Instant::now();
+        client.send("sleep 30");
+        // messages aren't framed, so give the first one time to arrive on its own
+        thread::sleep(Duration::from_millis(200));
+        client.send(INTERRUPT_MSG);
+        client.read_until("$ ");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(client.run("echo still-connected").contains("still-connected\r\n"));
+    }
+
+    /// Whether process `pid` is running, as opposed to gone or a zombie waiting to be collected
+    fn is_alive(pid: &str) -> bool{
+        std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with('Z')))
+    }
+
+    /// Reads the pid a command wrote to `file`, waiting a few seconds for it to be written
+    fn read_pid(file: &std::path::Path) -> String{
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop{
+            match std::fs::read_to_string(file){
+                Ok(pid) if pid.ends_with('\n') => return pid,
+                _ if Instant::now() > deadline => panic!("{} was never written", file.display()),
+                _ => thread::sleep(Duration::from_millis(10))
+            }
+        }
+    }
+
+    /// What uploading `path` from `offset` on sends over the connection
+    fn upload_of(path: &std::path::Path, offset: u64) -> Vec<u8>{
+        let mut upload = SecureStream::new_plain(io::Cursor::new(Vec::new()));
+        file_transfer::send_from(&mut upload, File::open(path).unwrap(), offset, false, None).unwrap();
+        upload.stream.into_inner()
+    }
+
+    #[test]
+    fn failed_uploads_dont_leave_bad_data_behind(){
+        let dir = temp_dir("failed-upload");
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("local.bin"), &contents).unwrap();
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        // uploads that stop partway through time out, and ones whose last byte is off don't match their checksum
+        let stalled = |upload: Vec<u8>| upload[..upload.len() / 2].to_vec();
+        let corrupted = |mut upload: Vec<u8>| { *upload.last_mut().unwrap() ^= 0xFF; upload };
+        for fail in [stalled, corrupted]{
+            // a new upload is removed
+            client.send("rspi sendfile upload.bin");
+            // nothing comes back before a new upload starts, so give the command time to arrive on its own
+            thread::sleep(Duration::from_millis(200));
+            client.conn.write_all(&fail(upload_of(&dir.join("local.bin"), 0))).unwrap();
+            assert!(client.read_until("$ ").contains("Could not send file\n"));
+            assert!(!dir.join("upload.bin").exists());
+
+            // a resumed one goes back to what an earlier upload left
+            std::fs::write(dir.join("upload.bin"), &contents[..7000]).unwrap();
+            client.send("rspi sendfile -c upload.bin");
+            client.read_until(&format!("{}OFFSET 7000\n", CONTROL_PREFIX));
+            client.conn.write_all(&fail(upload_of(&dir.join("local.bin"), 7000))).unwrap();
+            assert!(client.read_until("$ ").contains("Could not send file\n"));
+            assert_eq!(std::fs::read(dir.join("upload.bin")).unwrap(), &contents[..7000]);
+            let _ = std::fs::remove_file(dir.join("upload.bin"));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn info_names_the_terminal_commands_run_in(){
+        let mut client = Running::start();
+        let info = client.run("rspi info");
+        let tty = info.lines().find_map(|line| line.strip_prefix("tty\t")).unwrap_or_else(|| panic!("{:?}", info)).to_owned();
+        assert!(tty.starts_with("/dev/pts/"), "{}", tty);
+        // errors go straight to the terminal
+        assert!(client.run("readlink /proc/self/fd/2").contains(&format!("{}\r\n", tty)));
+    }
+
+    #[test]
+    fn logging_out_leaves_orphaned_processes_running(){
+        let dir = temp_dir("logout");
+        // commands are split on whitespace, so what each one runs is in a script
+        std::fs::write(dir.join("orphan.sh"), "echo $$ > orphan; exec sleep 30\n").unwrap();
+        std::fs::write(dir.join("attached.sh"), "echo $$ > attached; exec sleep 30\n").unwrap();
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        client.send("sh orphan.sh");
+        let orphan = read_pid(&dir.join("orphan"));
+        client.send("rspi orphan");
+        client.read_until("$ ");
+        client.send("sh attached.sh");
+        let attached = read_pid(&dir.join("attached"));
+        // a plain `exit` would go to the running process
+        client.send("rspi exit");
+        assert!(client.read_to_end().ends_with("Goodbye\n"));
+        client.join();
+        assert!(is_alive(&orphan));
+        assert!(!is_alive(&attached));
+        let _ = Command::new("kill").arg(orphan.trim()).status();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn restart_runs_the_last_command_again(){
+        let dir = temp_dir("restart");
+        let mut client = Running::start();
+        client.run(&format!("cd {}", dir.display()));
+        assert!(client.run("rspi restart").contains("No command has been run in this session yet\n"));
+        std::fs::write(dir.join("ran.sh"), "echo ran >> log\n").unwrap();
+        client.run("sh ran.sh");
+        let restarted = client.run("rspi restart");
+        assert!(restarted.contains("Restarting sh ran.sh\n"), "{}", restarted);
+        assert_eq!(std::fs::read_to_string(dir.join("log")).unwrap(), "ran\nran\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn restarting_an_aliased_command_expands_it_once(){
+        let mut client = Running::start();
+        client.run("rspi alias echo \"echo again\"");
+        assert!(client.run("echo hi").contains("again hi\r\n"));
+        let restarted = client.run("rspi restart");
+        assert!(restarted.contains("Restarting echo hi\n"), "{}", restarted);
+        assert!(restarted.contains("again hi\r\n") && !restarted.contains("again again"), "{}", restarted);
+    }
+
+    #[test]
+    fn detached_processes_are_still_there_after_logging_out(){
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let mut first = Running::start_with(|client| client.processes = processes.clone());
+        assert!(first.run("rspi run-detached sleep 30").contains("Started sleep 30 as process 0\n"));
+        first.send("rspi exit");
+        assert!(first.read_to_end().ends_with("Goodbye\n"));
+        first.join();
+
+        let mut second = Running::start_with(|client| client.processes = processes.clone());
+        let procs = second.run("rspi procs");
+        assert!(procs.contains("0\tsleep\trunning\t"), "{}", procs);
+        for mut session in poison::lock(&processes, "processes").drain(..){
+            session.kill();
+            let _ = session.close();
+        }
+    }
+
+    #[test]
+    fn only_admins_can_adopt_processes(){
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let mut admin = Running::log_in_with("sudo ada", |client| client.processes = processes.clone());
+        admin.read_until("$ ");
+        admin.run("rspi run-detached sleep 30");
+
+        let mut restricted = Running::log_in_with("letmein rex", |client| client.processes = processes.clone());
+        restricted.read_until("$ ");
+        let denied = restricted.run("rspi adopt 0");
+        assert!(denied.contains("Permission denied: rspi adopt can only be used by admins\n"), "{}", denied);
+        assert_eq!(poison::lock(&processes, "processes").len(), 1);
+
+        admin.send("rspi adopt 0");
+        admin.read_until("Successfully took control of process 0: sleep\n");
+        assert!(poison::lock(&processes, "processes").is_empty());
+        admin.send(INTERRUPT_MSG);
+        admin.read_until("$ ");
+    }
+
+    #[test]
+    fn watchers_all_see_the_same_output(){
+        let dir = temp_dir("watchers");
+        // commands are split on whitespace, so what the process runs is in a script
+        std::fs::write(dir.join("ticks.sh"), "for i in 1 2 3 4 5 6 7 8; do echo tick$i; sleep 0.1; done; exec sleep 30\n").unwrap();
+        let processes: Arc<Mutex<Vec<ClientSession>>> = Arc::default();
+        let mut owner = Running::start_with(|client| client.processes = processes.clone());
+        owner.run(&format!("rspi run-detached sh {}", dir.join("ticks.sh").display()));
+        let ticks: String = (1..=8).map(|i| format!("tick{}\r\n", i)).collect();
+
+        let mut watchers: Vec<Running> = (0..2).map(|_| {
+            let mut watcher = Running::start_with(|client| client.processes = processes.clone());
+            assert!(watcher.run("rspi watch 0").contains("Watching process 0: sh."));
+            watcher
+        }).collect();
+        // whatever was output before each one started watching comes first, and nothing is missed or repeated after it
+        for watcher in watchers.iter_mut(){
+            assert_eq!(watcher.read_until("tick8\r\n"), ticks);
+            assert!(watcher.run("rspi unwatch").contains("Stopped watching\n"));
+        }
+        for mut session in poison::lock(&processes, "processes").drain(..){
+            session.kill();
+            let _ = session.close();
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn framed_messages_sent_together_stay_apart(){
+        let mut client = Running::start();
+        client.send(FRAMED_MSG);
+        // the message asking for framing isn't framed itself, so it has to arrive on its own
+        thread::sleep(Duration::from_millis(200));
+        let mut framed = SecureStream::new(Vec::new());
+        // a command that doesn't start a process, so the second can't be taken as input for it
+        framed.write_message(b"rspi history").unwrap();
+        framed.write_message(b"echo two").unwrap();
+        client.conn.write_all(&framed.stream).unwrap();
+        // each gets its own prompt, even though the second was waiting before the first finished
+        let first = client.read_until("$ ");
+        assert!(first.contains("1\trspi history\n") && !first.contains("two"), "{}", first);
+        assert!(client.read_until("$ ").contains("two\r\n"));
+
+        // and one that arrives in pieces is put back together
+        let mut framed = SecureStream::new(Vec::new());
+        framed.write_message(b"echo three").unwrap();
+        let (first, rest) = framed.stream.split_at(6);
+        client.conn.write_all(first).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        client.conn.write_all(rest).unwrap();
+        assert!(client.read_until("$ ").contains("three\r\n"));
+    }
+
+    #[test]
+    fn which_says_where_commands_come_from(){
+        let mut client = Running::start();
+        assert!(client.run("rspi which cd").contains("cd is built into the server\n"));
+        let sh = client.run("rspi which sh");
+        assert!(sh.contains("/sh\n"), "{:?}", sh);
+        assert!(client.run("rspi which no-such-command").contains("no-such-command was not found\n"));
+        assert!(client.run("rspi which").contains("Usage: rspi which <command>\n"));
+    }
+
+    #[test]
+    fn output_waits_for_clients_that_arent_reading(){
+        let mut client = Running::start();
+        // a lot more than the connection can buffer while nobody reads it
+        client.send("seq 1 100000");
+        thread::sleep(Duration::from_millis(500));
+        let output = client.read_until("$ ");
+        let expected: String = (1..=100000).map(|n| format!("{}\r\n", n)).collect();
+        assert!(output.contains(&expected), "{} bytes of output", output.len());
+    }
+
+    #[test]
+    fn long_commands_arent_split_up(){
+        let mut client = Running::start();
+        let word = "x".repeat(3000);
+        assert!(client.run(&format!("echo {}", word)).contains(&format!("{}\r\n", word)));
+    }
+
+    #[test]
+    fn exit_status_carries_the_code(){
+        let status = Command::new("sh").args(["-c", "exit 7"]).status().unwrap();
+        assert_eq!(exit_status_message(status), format!("{}EXIT 7 -\n", CONTROL_PREFIX));
+    }
+
+    #[test]
+    fn exit_status_carries_the_signal(){
+        let status = Command::new("sh").args(["-c", "kill -9 $$"]).status().unwrap();
+        assert_eq!(exit_status_message(status), format!("{}EXIT - 9\n", CONTROL_PREFIX));
+    }
+}
@@ -0,0 +1,99 @@
+use std::{collections::HashSet, env, fs, io::{self, ErrorKind}, path::Path};
+
+use super::logger;
+
+/// Whether the commands listed in a policy are the only ones allowed, or the only ones denied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyMode{
+    Allow,
+    Deny
+}
+
+/// Restricts which programs clients may run, loaded from the file given by the "RSPI_COMMAND_POLICY" enviorment variable
+/// 
+/// The file starts with a line saying `allow` or `deny`, followed by one command name per line. Blank lines and lines
+/// starting with `#` are skipped. In allow mode, a command has to be listed exactly as it is typed (so `/bin/ls` has
+/// to be listed separately from `ls`), while in deny mode, `/bin/rm` is denied if `rm` is listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandPolicy{
+    pub mode: PolicyMode,
+    pub commands: HashSet<String>
+}
+impl CommandPolicy{
+    /// A policy that lets nothing run, used in place of one that couldn't be loaded
+    pub fn deny_all() -> Self{
+        Self{mode: PolicyMode::Allow, commands: HashSet::new()}
+    }
+
+    /// Parses the contents of a policy file, returning `io::ErrorKind::InvalidData` if it doesn't start with its mode
+    pub fn parse(src: &str) -> io::Result<Self>{
+        let mut lines = src.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let mode = match lines.next(){
+            Some("allow") => PolicyMode::Allow,
+            Some("deny") => PolicyMode::Deny,
+            other => return Err(io::Error::new(ErrorKind::InvalidData,
+                format!("Command policy has to start with \"allow\" or \"deny\", not {:?}", other.unwrap_or_default())))
+        };
+        Ok(Self{mode, commands: lines.map(str::to_owned).collect()})
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Loads the policy named by "RSPI_COMMAND_POLICY", or None if it isn't set
+    /// 
+    /// A policy that can't be loaded lets nothing run, rather than letting everything run
+    pub fn from_env() -> Option<Self>{
+        let path = env::var_os("RSPI_COMMAND_POLICY").filter(|p| !p.is_empty())?;
+        Some(Self::load(Path::new(&path)).unwrap_or_else(|e| {
+            logger::error!("Could not load command policy from {}, no commands will be allowed: {}", path.to_string_lossy(), e);
+            Self::deny_all()
+        }))
+    }
+
+    /// Checks whether `cmd_name` may be run
+    pub fn allows(&self, cmd_name: &str) -> bool{
+        match self.mode{
+            PolicyMode::Allow => self.commands.contains(cmd_name),
+            PolicyMode::Deny => {
+                let base_name = Path::new(cmd_name).file_name().map_or(cmd_name.into(), |name| name.to_string_lossy());
+                !self.commands.contains(cmd_name) && !self.commands.contains(base_name.as_ref())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn allow_lists_only_let_listed_commands_run(){
+        let policy = CommandPolicy::parse("# what clients can run\n\nallow\nls\n  uptime  \n# not rm\n").unwrap();
+        assert_eq!(policy.mode, PolicyMode::Allow);
+        assert!(policy.allows("ls") && policy.allows("uptime"));
+        assert!(!policy.allows("rm") && !policy.allows("/bin/ls") && !policy.allows("# not rm") && !policy.allows(""));
+    }
+
+    #[test]
+    fn deny_lists_stop_commands_however_theyre_named(){
+        let policy = CommandPolicy::parse("deny\nrm\n/sbin/reboot\n").unwrap();
+        assert_eq!(policy.mode, PolicyMode::Deny);
+        for denied in ["rm", "/bin/rm", "./rm", "/sbin/reboot"]{
+            assert!(!policy.allows(denied), "{}", denied);
+        }
+        for allowed in ["ls", "rmdir", "reboot"]{
+            assert!(policy.allows(allowed), "{}", allowed);
+        }
+    }
+
+    #[test]
+    fn policies_have_to_start_with_their_mode(){
+        for src in ["", "# just a comment", "ls\nallow", "Allow\nls", "allowed"]{
+            assert_eq!(CommandPolicy::parse(src).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", src);
+        }
+        assert!(CommandPolicy::load(Path::new("/no/such/policy")).is_err());
+        assert!(!CommandPolicy::deny_all().allows("ls"));
+    }
+}
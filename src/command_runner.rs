@@ -1,8 +1,176 @@
-use std::{collections::VecDeque, io::{self, BufReader, ErrorKind, Read, Write}, process::{Child, Command, ExitStatus, Stdio}, sync::{atomic::{self, AtomicBool}, Arc, Mutex}, thread::{self, JoinHandle}};
+use std::{cell::Cell, collections::{HashMap, HashSet, VecDeque}, env, fs::{File, OpenOptions}, io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write}, os::unix::process::CommandExt, process::{Child, Command, ExitStatus, Stdio}, sync::{atomic::{self, AtomicBool}, mpsc::Receiver, Arc, Condvar, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use crate::circular_buffer::CircularBuffer;
+use crate::resource_usage::ResourceUsage;
+use crate::poison;
+use crate::file_transfer;
+use crate::command_policy::CommandPolicy;
+use crate::run_as::{FileAccess, RunAs};
+use crate::capture::HeadTailCapture;
+use crate::watch::Watchers;
+use crate::screen::Screen;
+use crate::glob;
+
+/// Longest time the end of a UTF-8 character is waited on before sending the part of it we have anyways
+const UTF8_HOLD: Duration = Duration::from_millis(50);
 
 use super::pterminal::PseudoTerminal;
 
+unsafe extern "C"{
+    fn kill(pid: i32, sig: i32) -> i32;
+}
+
+const SIGINT: i32 = 2;
+const SIGKILL: i32 = 9;
+
+/// Signals that can be sent to a process with `ClientSession::signal`, by name and number on Linux
+const SIGNALS: [(&str, i32); 31] = [
+    ("HUP", 1), ("INT", 2), ("QUIT", 3), ("ILL", 4), ("TRAP", 5), ("ABRT", 6), ("BUS", 7), ("FPE", 8),
+    ("KILL", 9), ("USR1", 10), ("SEGV", 11), ("USR2", 12), ("PIPE", 13), ("ALRM", 14), ("TERM", 15), ("STKFLT", 16),
+    ("CHLD", 17), ("CONT", 18), ("STOP", 19), ("TSTP", 20), ("TTIN", 21), ("TTOU", 22), ("URG", 23), ("XCPU", 24),
+    ("XFSZ", 25), ("VTALRM", 26), ("PROF", 27), ("WINCH", 28), ("IO", 29), ("PWR", 30), ("SYS", 31)
+];
+
+/// Gets the number of a signal from its name, with or without "SIG" in front (ie. "SIGINT" or "int"), or its number
+pub fn parse_signal(sig: &str) -> Option<i32>{
+    let sig = sig.trim().to_ascii_uppercase();
+    let name = sig.strip_prefix("SIG").unwrap_or(&sig);
+    match name.parse::<i32>(){
+        Ok(num) => SIGNALS.iter().any(|(_, n)| *n == num).then_some(num),
+        Err(_) => SIGNALS.iter().find(|(n, _)| *n == name).map(|(_, num)| *num)
+    }
+}
+
+/// A word of a command line, once its quotes have been taken out and its variables expanded
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Word{
+    text: String,
+    /// Whether any of the word was quoted or escaped, which keeps it from being a redirection or a glob pattern, from
+    /// having a `~` at its start expanded, and from disappearing when it's empty
+    quoted: bool
+}
+
+/// Splits a command line into words like a shell does, expanding `$VAR` and `${VAR}` to what `lookup` gives for them
+/// 
+/// Anything between single quotes is taken as it is. Between double quotes, whitespace and single quotes are kept and
+/// variables are still expanded, and a backslash only escapes `"`, `\` and `$`. Outside of quotes, a backslash escapes
+/// any character.\
+/// Variables `lookup` doesn't know expand to nothing, and unquoted words that were nothing but those disappear. Unlike a
+/// shell, a variable's value is never split into more words, and there's no command substitution, so `$(...)` is left
+/// as it is, along with any other `$` that isn't followed by a variable name.\
+/// Returns `io::ErrorKind::InvalidInput` if a quote is never closed
+fn split_words(line: &str, lookup: impl Fn(&str) -> Option<String>) -> io::Result<Vec<Word>>{
+    let unclosed = |quote: char| io::Error::new(ErrorKind::InvalidInput, format!("Missing a closing {}", quote));
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut in_double = false;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next(){
+        rest = &rest[c.len_utf8()..];
+        if c.is_whitespace() && !in_double{
+            words.extend(current.take().filter(|word| word.quoted || !word.text.is_empty()));
+            continue
+        }
+        let word = current.get_or_insert_with(Word::default);
+        match c{
+            '\'' if !in_double => {
+                let end = rest.find('\'').ok_or_else(|| unclosed('\''))?;
+                word.text.push_str(&rest[..end]);
+                word.quoted = true;
+                rest = &rest[end + 1..];
+            },
+            '"' => {
+                in_double = !in_double;
+                word.quoted = true;
+            },
+            '\\' => match rest.chars().next(){
+                Some(escaped) if !in_double || matches!(escaped, '"' | '\\' | '$') => {
+                    word.text.push(escaped);
+                    word.quoted = true;
+                    rest = &rest[escaped.len_utf8()..];
+                },
+                _ => word.text.push('\\')
+            },
+            '$' => match var_name(rest){
+                Some((name, len)) => {
+                    word.text.push_str(&lookup(name).unwrap_or_default());
+                    rest = &rest[len..];
+                },
+                None => word.text.push('$')
+            },
+            c => word.text.push(c)
+        }
+    }
+    if in_double{
+        return Err(unclosed('"'))
+    }
+    words.extend(current.filter(|word| word.quoted || !word.text.is_empty()));
+    Ok(words)
+}
+
+/// Gets the name of the variable at the start of `after`, which comes just after a `$`, along with how much of `after`
+/// it takes up. Names can be braced, like `${VAR}`. None if there isn't a valid name there
+fn var_name(after: &str) -> Option<(&str, usize)>{
+    let (name, len) = match after.strip_prefix('{'){
+        Some(braced) => {
+            let end = braced.find('}')?;
+            (&braced[..end], end + 2)
+        },
+        None => {
+            let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            (&after[..end], end)
+        }
+    };
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some((name, len))
+}
+
+/// Files a command's input is redirected from, by `< file`, and its output to, by `> file` or `>> file`
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Redirects{
+    stdin: Option<String>,
+    stdout: Option<String>,
+    append: bool
+}
+
+/// Takes `< file`, `> file` and `>> file` (or `<file`, `>file` and `>>file`) redirections out of the words of a command,
+/// returning the rest of the words along with the redirections
+/// 
+/// Quoted words are never redirections, so `'>'` can be passed to a command. Only the last redirection of input or
+/// output counts, like in a shell. Returns `io::ErrorKind::InvalidInput` if one isn't followed by a file
+fn split_redirects(words: Vec<Word>) -> io::Result<(Vec<Word>, Redirects)>{
+    let mut rest = Vec::with_capacity(words.len());
+    let mut redirects = Redirects::default();
+    let mut words = words.into_iter();
+    while let Some(word) = words.next(){
+        let redirect = if word.quoted { None } else { [">>", ">", "<"].into_iter().find_map(|op| Some((op, word.text.strip_prefix(op)?))) };
+        let (op, target) = match redirect{
+            Some(redirect) => redirect,
+            None => {
+                rest.push(word);
+                continue
+            }
+        };
+        let target = match target{
+            "" => words.next().ok_or(io::Error::new(ErrorKind::InvalidInput, format!("Expected a file after {}", op)))?.text,
+            target => target.to_owned()
+        };
+        match op{
+            "<" => redirects.stdin = Some(target),
+            op => {
+                redirects.stdout = Some(target);
+                redirects.append = op == ">>";
+            }
+        }
+    }
+    Ok((rest, redirects))
+}
+
+/// Says which file couldn't be opened for a redirection, keeping the kind of error
+fn redirect_error(file: &str, e: io::Error) -> io::Error{
+    io::Error::new(e.kind(), format!("Could not open {}: {}", file, e))
+}
+
+
 /// Represents a child process initiated by a client.
 /// 
 /// The client has the option to rescind control of the session back to the server, 
@@ -11,15 +179,99 @@ use super::pterminal::PseudoTerminal;
 pub struct ClientSession{
     term: PseudoTerminal,
     pub cmd_name: String,
+    /// The whole line of the last command that was started in the foreground, for `rspi restart`
+    pub last_command: Option<String>,
     process: Option<Child>,
     pub path: std::path::PathBuf,
     // stdin: Option<io::BufWriter<std::process::ChildStdin>>,
     stdin: Option<std::process::ChildStdin>,
+    /// Whether the current child process reads its input from a file, rather than from the client
+    stdin_redirected: bool,
     output: Arc<Mutex<CircularBuffer<4096>>>,
-    is_running: Arc<AtomicBool>,
+    /// Notified whenever something is written to `output`
+    output_ready: Arc<Condvar>,
+    /// Notified whenever room is made in `output`, or a client stops waiting on it
+    output_space: Arc<Condvar>,
+    /// When `try_read_output` started holding back part of a character
+    partial_char_since: Cell<Option<Instant>>,
     outputting: Arc<AtomicBool>,
-    reader_handle: Option<JoinHandle<()>>
+    reader_handle: Option<JoinHandle<()>>,
+    started_at: Option<u64>,
+    history: VecDeque<String>,
+    history_size: usize,
+    root: Option<std::path::PathBuf>,
+    tail_stop: Arc<AtomicBool>,
+    tail_handle: Option<JoinHandle<()>>,
+    jobs: Vec<BackgroundJob>,
+    next_job_id: usize,
+    home: std::path::PathBuf,
+    prev_path: Option<std::path::PathBuf>,
+    created_at: u64,
+    aliases: HashMap<String, String>,
+    unreported_status: Option<ExitStatus>,
+    policy: Option<CommandPolicy>,
+    /// Who commands run as, when it isn't whoever is running the server
+    run_as: Option<RunAs>,
+    /// Number of bytes kept from each end of a command's output when it is captured rather than streamed
+    capture_limit: Option<usize>,
+    /// Output of the current command, when it is being captured. The reader thread writes here instead of to `output`
+    capture: Arc<Mutex<Option<HeadTailCapture>>>,
+    /// Clients mirroring this session's output with `rspi watch`
+    watchers: Watchers,
+    /// What the session's processes have drawn on the terminal, for redrawing it when a client attaches partway through
+    screen: Arc<Mutex<Screen>>
 }
+
+/// A process started in the background of a session, which runs alongside whatever is in the foreground
+/// 
+/// Background jobs don't have a terminal, so they can't read input and their output is discarded
+pub struct BackgroundJob{
+    pub id: usize,
+    pub cmd: String,
+    process: Child,
+    status: Option<ExitStatus>
+}
+impl BackgroundJob{
+    /// OS process id of the job
+    pub fn pid(&self) -> u32{
+        self.process.id()
+    }
+
+    /// Exit status of the job, or None if it is still running
+    pub fn status(&self) -> Option<ExitStatus>{
+        self.status
+    }
+
+    fn poll(&mut self){
+        if self.status.is_none(){
+            self.status = self.process.try_wait().ok().flatten();
+        }
+    }
+}
+/// Gets the directory set by the "RSPI_ROOT_DIR" enviorment variable, which client sessions can't leave
+/// 
+/// If it's set but can't be used, this returns an error rather than None, so that a typo in it can't quietly let
+/// sessions go anywhere
+pub fn root_dir() -> io::Result<Option<std::path::PathBuf>>{
+    env::var_os("RSPI_ROOT_DIR").filter(|p| !p.is_empty()).map(|dir| resolve_root(std::path::Path::new(&dir))).transpose()
+}
+
+/// Resolves a root directory to the canonical path sessions are checked against
+fn resolve_root(dir: &std::path::Path) -> io::Result<std::path::PathBuf>{
+    let unusable = |why: String| io::Error::new(ErrorKind::NotFound, format!("RSPI_ROOT_DIR is set to {}, {}", dir.display(), why));
+    let root = dir.canonicalize().map_err(|e| unusable(format!("which can't be used: {}", e)))?;
+    if !root.is_dir(){
+        return Err(unusable(String::from("which is not a directory")))
+    }
+    Ok(root)
+}
+
+/// Gets the terminal type commands are told they're running in, set by the "RSPI_TERM" enviorment variable or
+/// "xterm-256color" by default
+fn term_name() -> String{
+    env::var("RSPI_TERM").ok().filter(|term| !term.is_empty()).unwrap_or_else(|| String::from("xterm-256color"))
+}
+
 impl ClientSession{
     /// Create a new session for a client to run commands from
     pub fn new(from_path: std::path::PathBuf) -> io::Result<Self>{
@@ -27,15 +279,38 @@ impl ClientSession{
             let mut res = ClientSession{
                 term: PseudoTerminal::new()?, 
                 cmd_name: String::from("None"), 
+                last_command: None,
                 process: None, 
+                home: env::var_os("RSPI_HOME_DIR").filter(|p| !p.is_empty()).map(std::path::PathBuf::from).unwrap_or_else(|| from_path.clone()),
+                prev_path: None,
+                created_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
                 path: from_path, 
                 stdin: None, 
+                stdin_redirected: false,
                 output: Arc::default(),
-                is_running: Arc::new(AtomicBool::new(false)),
+                output_ready: Arc::default(),
+                output_space: Arc::default(),
+                partial_char_since: Cell::new(None),
                 outputting: Arc::new(AtomicBool::new(false)),
-                reader_handle: None
+                reader_handle: None,
+                started_at: None,
+                history: VecDeque::new(),
+                history_size: env::var("RSPI_HISTORY_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(100),
+                root: root_dir()?,
+                tail_stop: Arc::new(AtomicBool::new(false)),
+                tail_handle: None,
+                jobs: Vec::new(),
+                next_job_id: 1,
+                aliases: HashMap::new(),
+                unreported_status: None,
+                policy: CommandPolicy::from_env(),
+                run_as: RunAs::from_env()?,
+                capture_limit: None,
+                capture: Arc::default(),
+                watchers: Watchers::default(),
+                screen: Arc::default()
             };
-            res.reader_handle = Some(res.spawn_buf_reader(res.output.clone(), Box::new(res.term.make_reader()), 64));
+            res.reader_handle = Some(res.spawn_buf_reader(res.output.clone(), Box::new(res.term.make_reader())));
             res
         })
     }
@@ -47,125 +322,224 @@ impl ClientSession{
     /// If the session is already running a process, then this will return Err(String) containing an error message
     pub fn run_command(&mut self, cmd: &str) -> Result<Option<ExitStatus>, std::io::Error>{
         // reap a previously-ran process if it has exited, raise an error if it is still running
-        let mut last_status = None;
-        match self.process{
-            Some(ref mut proc) => {
-                match proc.try_wait(){
-                    Ok(Some(status)) => {
-                        self.process = None;
-                        last_status=Some(status)
-                    },
-                    Ok(None) => return Result::Err(std::io::Error::new(ErrorKind::Other,String::from("A process is already running and must end before a new one can be started."))),
-                    Err(e) => return Result::Err(e)
-                };
-            },
-            None => (),
-        };
+        self.reap();
+        if self.process.is_some(){
+            return Result::Err(std::io::Error::other(String::from("A process is already running and must end before a new one can be started.")))
+        }
+        let last_status = self.unreported_status.take();
         
         // parse the current commnd
-        let mut cmd_splitted = cmd.split_whitespace();
-        let cmd_name = cmd_splitted.next().unwrap_or_default();
+        let line = self.expand_alias(cmd);
+        let (words, redirects) = self.parse_line(&line)?;
+        let cmd_name = words.first().map(|word| word.text.clone()).unwrap_or_default();
 
         // handle empty command and cd separately
         if cmd_name.is_empty(){
-            return Err(io::Error::new(ErrorKind::Other, "Empty command"))
+            return Err(io::Error::other("Empty command"))
         }
         if cmd_name=="cd"{
-            self.change_dir(&cmd_splitted.collect::<Vec<&str>>().join(" "))?;
+            let loc = words.get(1).map(|word| word.text.as_str()).unwrap_or_default();
+            let new_path = self.change_dir(loc)?;
+            // like a shell, say where `cd -` went since it isn't obvious from the command
+            if loc == "-"{
+                let _ = poison::lock(&self.output, "output").write(format!("{}\n", new_path.display()).as_bytes());
+                self.output_ready.notify_all();
+            }
             return Result::Ok(last_status);
         }
 
-        let mut cmd;
-        if cmd_name.as_bytes()[0]!=b'.'{
-            cmd = Command::new(cmd_name);
-            cmd.current_dir(self.path.clone()).args(cmd_splitted);
-        }else{
-            cmd = Command::new(cmd_name);
-            cmd.current_dir(self.path.clone()).args(cmd_splitted);
-        }
-        cmd.stdin(Stdio::piped());
-        
-        self.process = match self.term.run_cmd(cmd){
+        let (mut command, stdin, stdout) = self.build_command(words, &redirects)?;
+        command.stdin(stdin.map_or(Stdio::piped(), Stdio::from));
+        self.process = match self.term.run_cmd(command, stdout){
             Ok(mut proc) => {                
-                self.stdin = Some(proc.stdin.take().expect("process has no stdin"));
+                // there's nothing to write to when input is redirected from a file
+                self.stdin = proc.stdin.take();
+                self.stdin_redirected = self.stdin.is_none();
                 Some(proc)
             },
             Err(e) => {
                 return Result::Err(e);
             }
         };
-        self.cmd_name = cmd_name.to_owned();
-        return Result::Ok(last_status)
+        if let Some(limit) = self.capture_limit{
+            *poison::lock(&self.capture, "capture") = Some(HeadTailCapture::new(limit));
+        }
+        self.cmd_name = cmd_name;
+        // keep what was typed, since the alias is expanded again on restart
+        self.last_command = Some(cmd.trim().to_owned());
+        self.started_at = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+        Result::Ok(last_status)
+    }
+
+    /// Splits a command line into words, with variables expanded and redirections taken out (see `split_words` and
+    /// `split_redirects`)
+    /// 
+    /// Commands see the server's enviorment, apart from the terminal variables set by `build_command`
+    fn parse_line(&self, line: &str) -> io::Result<(Vec<Word>, Redirects)>{
+        let (rows, cols) = poison::lock(&self.screen, "screen").size();
+        let lookup = |name: &str| match name{
+            "TERM" => Some(term_name()),
+            "COLUMNS" => Some(cols.to_string()),
+            "LINES" => Some(rows.to_string()),
+            name => env::var(name).ok()
+        };
+        split_redirects(split_words(line, lookup)?)
+    }
+
+    /// Sets up the process that the words of a command run, in the session's directory and as whoever commands run as,
+    /// along with the files its input and output are redirected to, if they are. Foreground commands and background
+    /// jobs both start here, so they're run the same way
+    /// 
+    /// The process gets a group of its own, which `signal_group` and `kill_jobs` rely on.\
+    /// Returns `io::ErrorKind::PermissionDenied` if the command isn't allowed by the server's command policy, or
+    /// a redirection is to somewhere outside of the session's root
+    fn build_command(&self, words: Vec<Word>, redirects: &Redirects) -> io::Result<(Command, Option<File>, Option<File>)>{
+        let mut words = words.into_iter();
+        let cmd_name = words.next().map(|word| word.text).unwrap_or_default();
+        self.check_policy(&cmd_name)?;
+
+        // the command couldn't open these itself, so the server shouldn't open them for it either
+        let file_access = self.access_files()?;
+        let stdin = match &redirects.stdin{
+            Some(source) => Some(File::open(self.redirect_path(source)?).map_err(|e| redirect_error(source, e))?),
+            None => None
+        };
+        let stdout = match &redirects.stdout{
+            Some(target) => Some(OpenOptions::new().write(true).create(true).append(redirects.append).truncate(!redirects.append)
+                .open(self.redirect_path(target)?).map_err(|e| redirect_error(target, e))?),
+            None => None
+        };
+        drop(file_access);
+
+        let mut cmd = Command::new(&cmd_name);
+        // there's no shell to expand globs, so do it here, without letting them match anything outside of the root.
+        // quoted words are passed on as they are, like in a shell
+        let args: Vec<String> = words.flat_map(|word| match word.quoted{
+            true => vec![word.text],
+            false => glob::expand(&self.expand_tilde(&word.text), &self.path, self.root.as_deref())
+        }).collect();
+        cmd.current_dir(self.path.clone()).args(args);
+        // programs work out what the terminal can do from these, and assume a dumb one without them
+        let (rows, cols) = poison::lock(&self.screen, "screen").size();
+        cmd.env("TERM", term_name()).env("COLUMNS", cols.to_string()).env("LINES", rows.to_string());
+        if let Some(run_as) = &self.run_as { run_as.apply(&mut cmd); }
+        // give the process a group of its own, like a shell's job, so it can be interrupted along with anything it
+        // starts without the signal reaching the server
+        cmd.process_group(0);
+        Ok((cmd, stdin, stdout))
+    }
+
+    /// Resolves a file a command's input or output is redirected to, which has to be inside of the session's root
+    fn redirect_path(&self, file: &str) -> io::Result<std::path::PathBuf>{
+        file_transfer::sanitize_within(self.root_or_path(), &self.path.join(self.expand_tilde(file)))
+    }
+
+    /// Returns `io::ErrorKind::PermissionDenied` if the server's command policy doesn't let `cmd_name` be run
+    fn check_policy(&self, cmd_name: &str) -> io::Result<()>{
+        match &self.policy{
+            Some(policy) if !policy.allows(cmd_name) => Err(io::Error::new(ErrorKind::PermissionDenied,
+                format!("{} is not allowed to be run on this server", cmd_name))),
+            _ => Ok(())
+        }
+    }
+
+    /// Starts a command as a background job, returning its id
+    /// 
+    /// Unlike `run_command`, this can be used while another process is running
+    pub fn run_background(&mut self, cmd: &str) -> io::Result<usize>{
+        let line = self.expand_alias(cmd);
+        let (words, redirects) = self.parse_line(&line)?;
+        if words.first().is_none_or(|word| word.text.is_empty()){
+            return Err(io::Error::other("Empty command"))
+        }
+        let (mut process, stdin, stdout) = self.build_command(words, &redirects)?;
+        // there's no terminal for the job, so it reads nothing and its output goes nowhere unless they're redirected
+        process.stdin(stdin.map_or(Stdio::null(), Stdio::from))
+            .stdout(stdout.map_or(Stdio::null(), Stdio::from))
+            .stderr(Stdio::null());
+        let process = process.spawn()?;
+        let id = self.next_job_id;
+        self.next_job_id += 1;
+        self.jobs.push(BackgroundJob{id, cmd: cmd.trim().to_owned(), process, status: None});
+        Ok(id)
+    }
+
+    /// Background jobs of this session, with the status of each one brought up to date
+    pub fn jobs(&mut self) -> &[BackgroundJob]{
+        for job in self.jobs.iter_mut(){
+            job.poll();
+        }
+        &self.jobs
+    }
+
+    /// Forgets about background jobs which have exited, so they are only reported once
+    pub fn clear_finished_jobs(&mut self){
+        self.jobs.retain(|job| job.status.is_none());
+    }
+
+    /// Kills every background job of this session, along with anything they started
+    fn kill_jobs(&mut self){
+        for mut job in self.jobs.drain(..){
+            // each job leads a process group of its own, see `build_command`
+            if job.status.is_some() || unsafe { kill(-(job.pid() as i32), SIGKILL) } == -1{
+                let _ = job.process.kill();
+            }
+            let _ = job.process.wait();
+        }
     }
 
     /// Separate thread used to read the internal pseudo-terminal running child processe
-    fn spawn_buf_reader(&mut self, out: Arc<Mutex<CircularBuffer<4096>>>, mut src: Box<BufReader<dyn Read + std::marker::Send>>, max_len: usize) -> JoinHandle<()>{
-        let is_running = self.is_running.clone(); 
+    fn spawn_buf_reader(&mut self, out: Arc<Mutex<CircularBuffer<4096>>>, mut src: Box<BufReader<dyn Read + std::marker::Send>>) -> JoinHandle<()>{
         let is_outputting = self.outputting.clone();
+        let ready = self.output_ready.clone();
+        let space = self.output_space.clone();
+        let capture = self.capture.clone();
+        let watchers = self.watchers.clone();
+        let screen = self.screen.clone();
         let handle = thread::spawn(move || {
-            is_running.store(true, atomic::Ordering::Relaxed);
-            let mut byte = [0u8]; let mut buf = Vec::new(); loop {
-            match src.read(&mut byte){
-                Ok(0) => { // EOF
-                    is_running.store(false, atomic::Ordering::Relaxed);
-                    break;
-                },
-                Ok(_) => {
-                    buf.push(byte[0]);
-                    // lock output so that the temporary 'buf' can write to it
-                    if buf.len() > 4096{
-                        match out.lock(){
-                            Ok(mut output) => {
-                                // if someone doesn't read from buffer often enough, data in the
-                                // `output` buffer may get overwritten. this is fine if the client
-                                // is not connected to a socket, because it allows us to clear out
-                                // the piped output of the child process. but if this client session
-                                // is outputting to a socket, then we don't want this, and would
-                                // rather wait so that the client recieves all data. 
-
-                                // this is a really stupid solution but its just for a silly raspberry pi
-                                // home server so hopefully no one else is using it. 
-                                if !is_outputting.load(atomic::Ordering::Relaxed) || 
-                                        output.len() + buf.len() <= output.allocated_size(){
-                                    let _ = output.write(&buf);
-                                    buf.clear();
-                                }
-                            },
-                            Err(e) => {
-                                buf.push(10);
-                                buf.extend_from_slice(e.to_string().as_bytes());
-                                out.clear_poison();
-                            },
-                        }
-                    // otherwise, just try to lock the output and write to it
-                    }else{
-                        match out.try_lock(){
-                            Ok(mut output) => {
-                                if !is_outputting.load(atomic::Ordering::Relaxed) ||
-                                        output.len() + buf.len() <= output.allocated_size() {
-                                    let _ = output.write(&buf);
-                                    buf.clear();
-                                }
-                            },
-                            Err(e) => {
-                                match e{
-                                    std::sync::TryLockError::Poisoned(poison_error) => {
-                                        buf.extend_from_slice(poison_error.to_string().as_bytes());
-                                        out.clear_poison();
-                                    },
-                                    std::sync::TryLockError::WouldBlock => (),
-                                }
-                            },
+            let mut chunk = [0u8; 4096]; loop {
+            match src.read(&mut chunk){
+                Ok(0) => break, // EOF
+                Ok(len) => {
+                    if let Some(capture) = poison::lock(&capture, "capture").as_mut(){
+                        poison::lock(&screen, "screen").feed(&chunk[..len]);
+                        capture.write(&chunk[..len]);
+                        watchers.broadcast(&chunk[..len]);
+                        continue
+                    }
+                    // copy everything that was read into the output at once, rather than locking it for every byte
+                    let mut pending = &chunk[..len];
+                    let mut output = poison::lock(&out, "output");
+                    // while the output is locked, so a snapshot of the screen never includes output that's still waiting to be sent
+                    poison::lock(&screen, "screen").feed(pending);
+                    while !pending.is_empty(){
+                        // while a client is attached, wait for it to read the output rather than overwriting
+                        // anything it hasn't seen yet. not reading from the terminal in the meantime makes the
+                        // process block on its writes, so nothing is lost. without a client, old output is
+                        // overwritten instead so that a process nobody is watching never gets stuck
+                        let outputting = is_outputting.load(atomic::Ordering::Relaxed);
+                        if outputting && output.is_full(){
+                            // wake up every so often in case the client detaches without anyone telling us
+                            output = match space.wait_timeout(output, Duration::from_millis(100)){
+                                Ok((output, _)) => output,
+                                Err(e) => e.into_inner().0
+                            };
+                            continue
                         }
+                        let written = if outputting{
+                            output.write_lossless(pending)
+                        }else{
+                            output.write(pending).unwrap_or(0)
+                        };
+                        // while the output is still locked, so that `watch` can't miss any of it or send it twice
+                        watchers.broadcast(&pending[..written]);
+                        pending = &pending[written..];
+                        ready.notify_all();
                     }
                 },
                 Err(e) => {
-                    match out.lock(){
-                        Ok(mut output) => {
-                            let _ = output.write(e.to_string().as_bytes());
-                        },
-                        Err(_) => out.clear_poison(),
-                    }
+                    let _ = poison::lock(&out, "output").write(e.to_string().as_bytes());
+                    ready.notify_all();
                 }
             }
         }
@@ -173,62 +547,145 @@ impl ClientSession{
         handle
     }
 
+    /// Follows a file, writing its last `lines` lines and then anything appended to it into the session's output
+    /// 
+    /// Like a child process, this occupies the session until it is stopped with `stop_tail` or the session is killed
+    pub fn tail(&mut self, path: &std::path::Path, lines: usize) -> io::Result<()>{
+        if self.has_child() || self.is_tailing(){
+            return Err(io::Error::other("A process is already running and must end before a new one can be started."))
+        }
+        let mut file = File::open(path)?;
+        let mut pos = tail_start(&mut file, lines)?;
+        file.seek(SeekFrom::Start(pos))?;
+
+        self.tail_stop.store(false, atomic::Ordering::Relaxed);
+        let stop = self.tail_stop.clone();
+        let is_outputting = self.outputting.clone();
+        let out = self.output.clone();
+        let ready = self.output_ready.clone();
+        let watchers = self.watchers.clone();
+        self.cmd_name = format!("tail {}", path.display());
+        self.tail_handle = Some(thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            // buf[sent..read] hasn't made it into the output yet
+            let (mut read, mut sent) = (0, 0);
+            while !stop.load(atomic::Ordering::Relaxed){
+                if sent == read{
+                    match file.read(&mut buf){
+                        Ok(0) | Err(_) => {
+                            // start over if the file was truncated, like when a log gets rotated
+                            if file.metadata().is_ok_and(|m| m.len() < pos){
+                                pos = file.seek(SeekFrom::Start(0)).unwrap_or(0);
+                            }
+                            thread::sleep(Duration::from_millis(100));
+                            continue
+                        },
+                        Ok(n) => {
+                            (read, sent) = (n, 0);
+                            pos += n as u64;
+                        }
+                    }
+                }
+                // same as the terminal reader, wait for the client to catch up rather than overwriting output
+                let mut output = poison::lock(&out, "output");
+                let written = if is_outputting.load(atomic::Ordering::Relaxed){
+                    output.write_lossless(&buf[sent..read])
+                }else{
+                    output.write(&buf[sent..read]).unwrap_or(0)
+                };
+                watchers.broadcast(&buf[sent..sent + written]);
+                drop(output);
+                sent += written;
+                if written > 0 { ready.notify_all(); }
+                if sent < read { thread::sleep(Duration::from_millis(10)); }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Check if this session is following a file with `tail`
+    pub fn is_tailing(&self) -> bool{
+        self.tail_handle.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Stops following a file, waiting for the thread reading it to end
+    pub fn stop_tail(&mut self){
+        if let Some(handle) = self.tail_handle.take(){
+            self.tail_stop.store(true, atomic::Ordering::Relaxed);
+            let _ = handle.join();
+        }
+    }
+
     /// Sets whether the client session's internal terminal buffer should
     /// wait instead of overwritting existing data. 
     pub fn set_is_outputting(&self, val: bool){
         self.outputting.store(val, atomic::Ordering::Relaxed);
+        // the reader thread might be waiting on a client that isn't there anymore
+        self.output_space.notify_all();
     }
 
-    /// Aquire the lock for the 'is_running' bool and change its value\
-    /// Returns whether the mutex was successfully aquired or not
-    fn set_running_status(&self, val: bool) -> bool{
-        self.is_running.store(val, atomic::Ordering::Relaxed);
-        true
-    }
-
-    /// Kill the current running child process of the session
+    /// Kill the current running child process of the session, along with anything it started and its background jobs
     pub fn kill(&mut self){
-        match self.process{
-            Some(ref mut proc) => {
-                if proc.kill().is_ok() {
-                    self.set_running_status(false);
+        self.stop_tail();
+        self.kill_jobs();
+        let killed_group = self.signal_group(SIGKILL).is_ok();
+        if let Some(ref mut proc) = self.process {
+            if killed_group || proc.kill().is_ok() {
+                // SIGKILL can't be ignored, so this doesn't block for long, and the process isn't left behind as a zombie
+                if let Ok(status) = proc.wait(){
+                    self.process = None;
+                    self.stdin = None;
+                    self.unreported_status = Some(status);
                 }
-            },
-            None => (),
+            }
         }
     }
 
-    /// Signal to the current running child process
+    /// Signal to the current running child process, and any processes it started
+    /// 
+    /// `sig` is parsed with `parse_signal`, returning `io::ErrorKind::InvalidInput` if it isn't a known signal
     pub fn signal(&self, sig: &str) -> Result<(), io::Error>{
-        let a = match &self.process{
-            Some(proc) => {
-                let mut kill = Command::new("kill")
-                    .args(["-s", sig, &proc.id().to_string()]).spawn()?;
-                kill.wait()?;
-            },
-            None => return Err(io::Error::new(ErrorKind::Other,"No process to signal"))
+        let num = parse_signal(sig).ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Unknown signal {}", sig.trim())))?;
+        self.signal_group(num)
+    }
+
+    /// Sends SIGINT to the process group of the current running child process, like pressing Ctrl-C in a terminal
+    pub fn interrupt(&self) -> io::Result<()>{
+        self.signal_group(SIGINT)
+    }
+
+    /// Sends a signal to the process group of the current running child process, which every process
+    /// it starts is part of unless they leave it themselves
+    fn signal_group(&self, num: i32) -> io::Result<()>{
+        let pid = match &self.process{
+            Some(proc) => proc.id() as i32,
+            None => return Err(io::Error::other("No process to signal"))
         };
-        Ok(a)
+        if unsafe { kill(-pid, num) } == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
     }
 
     /// Consume the error status of the child process if it has ended, otherwise returns None
+    /// 
+    /// A status collected earlier by `reap` is only returned once
     pub fn exit_status(&mut self) -> Option<ExitStatus>{
-        match self.process{
-            Some(ref mut p) => match p.try_wait(){
-                Ok(Some(e)) => {
-                    self.process = None;
-                    Some(e)
-                }, 
-                Ok(None) => None, 
-                Err(_) => None
-            },
-            None => None
-        }
+        self.reap();
+        self.unreported_status.take()
     }
 
-    /// Check if the session is running
-    pub fn is_running(&self) -> bool{
-        self.is_running.load(atomic::Ordering::Relaxed)
+    /// Collects the child process and background jobs of this session if they have exited, so they don't linger as zombies
+    /// 
+    /// The child's exit status is kept until `exit_status` is called, and `has_child` is false from then on.\
+    /// Should be called regularly, even when nobody is waiting on the session
+    pub fn reap(&mut self){
+        for job in self.jobs.iter_mut(){
+            job.poll();
+        }
+        let Some(proc) = self.process.as_mut() else { return };
+        if let Ok(Some(status)) = proc.try_wait(){
+            self.process = None;
+            self.stdin = None;
+            self.unreported_status = Some(status);
+        }
     }
 
     /// Check if there is a currently running child process being managed by this session
@@ -236,50 +693,274 @@ impl ClientSession{
         self.process.is_some()
     }
 
-    /// Used to read from stdout or stderr or child processes
-    fn read_buf(&self, buf: &Arc<Mutex<VecDeque<String>>>) -> Option<String>{
-        match buf.lock(){
-            Ok(mut out) => {
-                if out.is_empty(){None}
-                else{Some(out.drain(..).collect::<String>())}
-            },
-            Err(e) => {
-                Some(e.to_string())
-            }
+    /// OS process id of the current child process, if there is one
+    pub fn pid(&self) -> Option<u32>{
+        self.process.as_ref().map(Child::id)
+    }
+
+    /// Starts mirroring this session's output to a new watcher, beginning with whatever output hasn't been read yet
+    /// 
+    /// Watchers can't send input, and don't take any output away from whoever owns the session
+    pub fn watch(&self) -> Receiver<Vec<u8>>{
+        let output = poison::lock(&self.output, "output");
+        let screen = poison::lock(&self.screen, "screen");
+        self.watchers.add(if screen.is_alternate() { screen.snapshot() } else { output.to_vec() })
+    }
+
+    /// Throws away the output that hasn't been read yet and returns a redraw of the screen in its place, if a full-screen
+    /// program (one drawing on the alternate screen, like `htop` or `vim`) is running
+    /// 
+    /// Part of a full-screen program's output is garbage without the rest of it, so a client attaching partway through
+    /// is better off with a redraw. Other programs' output reads fine on its own, so it is left alone
+    pub fn take_screen_snapshot(&self) -> Option<Vec<u8>>{
+        let mut output = poison::lock(&self.output, "output");
+        let screen = poison::lock(&self.screen, "screen");
+        if !screen.is_alternate(){
+            return None
         }
+        output.clear();
+        self.output_space.notify_all();
+        Some(screen.snapshot())
+    }
+
+    /// Path of the pseudo-terminal this session's processes run in
+    pub fn tty(&self) -> &str{
+        self.term.slave_name()
+    }
+
+    /// Unix timestamp (in seconds) of when this session was created
+    pub fn created_at(&self) -> u64{
+        self.created_at
+    }
+
+    /// Memory and CPU time used by the current child process, or None if there isn't one or it has exited
+    pub fn resource_usage(&self) -> Option<ResourceUsage>{
+        ResourceUsage::of_pid(self.pid()?)
+    }
+
+    /// Unix timestamp (in seconds) of when the current child process was started
+    pub fn start_time(&self) -> Option<u64>{
+        self.process.as_ref().and(self.started_at)
     }
 
     /// Reads the output of the session to a buffer
     /// 
-    /// If the output's mutex is poisoned, returns io::ErrorKind::Other\
-    /// If the output is empty, returns io::ErrorKind::UnexpectedEof
+    /// For callers that don't need to know how much was read, see `try_read_output`
     pub fn read_output<T: Write>(&self, to: &mut T) -> io::Result<()>{
-        match self.output.lock(){
-            Ok(mut out) => {
-                if !out.is_empty(){ let _ = out.write_to(to); Ok(())}
-                else { Err(io::Error::new(ErrorKind::UnexpectedEof, String::from("Output is empty"))) }
-            },
-            Err(e) => {
-                self.output.clear_poison();
-                Err(io::Error::new(ErrorKind::Other, e.to_string()))
+        self.try_read_output(to).map(|_| ())
+    }
+
+    /// Reads the output of the session to a buffer, returning how many bytes were written to it
+    /// 
+    /// A UTF-8 character that has only been partly output is held back until the rest of it arrives, or until
+    /// it has been waited on for a little while in case the rest never comes.\
+    /// Returns 0 if the output is empty (or only has part of a character). If writing to `to` fails, the
+    /// output is left in the session to be read again
+    pub fn try_read_output<T: Write>(&self, to: &mut T) -> io::Result<usize>{
+        let mut out = poison::lock(&self.output, "output");
+        if out.is_empty(){
+            return Ok(0)
+        }
+        let partial = out.incomplete_utf8_len();
+        let held_since = self.partial_char_since.get();
+        let len = if partial > 0 && held_since.is_none_or(|since| since.elapsed() < UTF8_HOLD){
+            let complete = out.len() - partial;
+            // the timer starts over for each new partial character
+            if complete > 0 || held_since.is_none() { self.partial_char_since.set(Some(Instant::now())); }
+            if complete == 0{
+                return Ok(0)
             }
+            out.write_prefix_to(to, complete)?;
+            complete
+        }else{
+            self.partial_char_since.set(None);
+            let len = out.len();
+            out.write_to(to)?;
+            len
+        };
+        self.output_space.notify_all();
+        Ok(len)
+    }
+
+    /// Captures the output of commands started from now on, keeping the first and last `limit` bytes of each,
+    /// or goes back to streaming it if None
+    pub fn set_capture_limit(&mut self, limit: Option<usize>){
+        self.capture_limit = limit;
+    }
+
+    pub fn capture_limit(&self) -> Option<usize>{
+        self.capture_limit
+    }
+
+    /// Number of bytes output by the command being captured so far, or None if its output isn't being captured
+    pub fn captured_bytes(&self) -> Option<u64>{
+        poison::lock(&self.capture, "capture").as_ref().map(HeadTailCapture::total)
+    }
+
+    /// Stops capturing the current command's output, returning what was captured (see `HeadTailCapture::finish`)
+    /// 
+    /// Anything output after this is streamed as usual
+    pub fn take_capture(&self) -> Option<Vec<u8>>{
+        poison::lock(&self.capture, "capture").take().map(HeadTailCapture::finish)
+    }
+
+    /// Blocks until there is output to read, or `timeout` passes. Returns whether there is output
+    pub fn wait_for_output(&self, timeout: Duration) -> bool{
+        let output = poison::lock(&self.output, "output");
+        match self.output_ready.wait_timeout_while(output, timeout, |output| output.is_empty()){
+            Ok((output, _)) => !output.is_empty(),
+            Err(e) => !e.into_inner().0.is_empty()
+        }
+    }
+
+    /// Once there's output, waits up to `interval` for more, so output trickling in a byte at a time is sent in one
+    /// go rather than in lots of tiny writes. Stops waiting as soon as there's `threshold` bytes
+    pub fn coalesce_output(&self, interval: Duration, threshold: usize){
+        let output = poison::lock(&self.output, "output");
+        if output.is_empty(){
+            return
         }
+        let _ = self.output_ready.wait_timeout_while(output, interval, |output| output.len() < threshold);
     }
 
-    /// Write to the stdin of the currently running child process
-    pub fn write_stdin(&mut self, buf: &str) -> Result<usize, io::Error>{
+    /// Discards the output of the session that hasn't been read yet
+    pub fn clear_output(&self){
+        poison::lock(&self.output, "output").clear();
+        self.output_space.notify_all();
+    }
+
+    /// Write exactly the bytes in `buf` to the stdin of the currently running child process
+    /// 
+    /// Returns `io::ErrorKind::BrokenPipe` if stdin has been closed with `close_stdin`
+    pub fn write_stdin(&mut self, buf: &[u8]) -> Result<usize, io::Error>{
         match self.stdin.as_mut(){
             Some(p) => {
-                p.write(format!("{}\n",buf).as_bytes())
+                p.write_all(buf)?;
+                Ok(buf.len())
             },
+            None if self.process.is_some() && self.stdin_redirected => Err(io::Error::new(ErrorKind::BrokenPipe, "Input is redirected from a file")),
+            None if self.process.is_some() => Err(io::Error::new(ErrorKind::BrokenPipe, "Stdin has already been closed")),
             None => Err(io::Error::new(ErrorKind::BrokenPipe, "Stdin does not exist")),
         }
     }
 
+    /// Closes the stdin of the currently running child process, so it reads EOF like after a Ctrl-D in a terminal
+    /// 
+    /// Returns whether there was a stdin to close
+    pub fn close_stdin(&mut self) -> bool{
+        self.stdin.take().is_some()
+    }
+
+    /// Write `buf` followed by a newline to the stdin of the currently running child process, like a line typed into a terminal
+    pub fn write_line(&mut self, buf: &[u8]) -> Result<usize, io::Error>{
+        let mut line = Vec::with_capacity(buf.len() + 1);
+        line.extend_from_slice(buf);
+        line.push(b'\n');
+        self.write_stdin(&line)
+    }
+
+    /// Records a command entered into this session, dropping the oldest one once `RSPI_HISTORY_SIZE` is reached
+    pub fn record_history(&mut self, cmd: &str){
+        let cmd = cmd.trim();
+        if cmd.is_empty() || self.history_size == 0 { return }
+        if self.history.len() >= self.history_size{
+            self.history.pop_front();
+        }
+        self.history.push_back(cmd.to_owned());
+    }
+
+    /// Commands previously entered into this session, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &String>{
+        self.history.iter()
+    }
+
+    /// Changes the directory that `~` and `cd` with no arguments refer to
+    pub fn set_home(&mut self, home: std::path::PathBuf){
+        self.home = home;
+    }
+
+    /// Makes `name` run `value` when it is used as the first word of a command
+    pub fn set_alias(&mut self, name: &str, value: &str){
+        self.aliases.insert(name.to_owned(), value.trim().to_owned());
+    }
+
+    /// Forgets about an alias, returning whether there was one to forget
+    pub fn remove_alias(&mut self, name: &str) -> bool{
+        self.aliases.remove(name).is_some()
+    }
+
+    /// Aliases defined in this session, sorted by name
+    pub fn aliases(&self) -> Vec<(&String, &String)>{
+        let mut aliases: Vec<(&String, &String)> = self.aliases.iter().collect();
+        aliases.sort();
+        aliases
+    }
+
+    /// Replaces this session's aliases, home directory and history with a copy of another's, so they follow a client
+    /// into the sessions it adopts or orphans
+    pub fn copy_settings_from(&mut self, other: &ClientSession){
+        self.aliases = other.aliases.clone();
+        self.home = other.home.clone();
+        self.history = other.history.clone();
+        self.history_size = other.history_size;
+    }
+
+    /// Replaces the first word of a command with the alias it names, over and over until it doesn't name one
+    /// 
+    /// Like in a shell, an alias isn't expanded again inside of its own expansion, so aliases that refer to
+    /// themselves (`ls` to `ls --color`) or each other can't loop forever
+    pub fn expand_alias(&self, cmd: &str) -> String{
+        let mut line = cmd.trim_start().to_owned();
+        let mut expanded = HashSet::new();
+        loop{
+            let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((&line, ""));
+            match self.aliases.get(name){
+                Some(value) if expanded.insert(name.to_owned()) => line = format!("{} {}", value, rest),
+                _ => return line
+            }
+        }
+    }
+
     /// Change the directory this client session is running from
+    /// 
+    /// An empty `loc` goes back to the directory the session started in, and "-" to the previous directory.\
+    /// If "RSPI_ROOT_DIR" is set, returns `io::ErrorKind::PermissionDenied` for directories outside of it
     pub fn change_dir(&mut self, loc: &str) -> Result<std::path::PathBuf, io::Error>{
-        self.path = match self.path.join(loc).canonicalize(){Ok(p) => p, Err(e) => return Err(e)};
-        return Ok(self.path.as_path().to_owned())
+        let target = match loc.trim(){
+            "" => self.home.clone(),
+            "-" => self.prev_path.clone().ok_or(io::Error::other("No previous directory"))?,
+            loc => self.path.join(self.expand_tilde(loc))
+        };
+        let path = target.canonicalize()?;
+        if let Some(root) = &self.root{
+            if !path.starts_with(root){
+                return Err(io::Error::new(ErrorKind::PermissionDenied, format!("{} is outside of {}", path.display(), root.display())))
+            }
+        }
+        self.prev_path = Some(std::mem::replace(&mut self.path, path));
+        Ok(self.path.as_path().to_owned())
+    }
+
+    /// Replaces a leading `~` in a path with the session's home directory, which is set by the "RSPI_HOME_DIR"
+    /// enviorment variable or otherwise is the directory the session started in
+    /// 
+    /// `~` anywhere else, or followed by a user name, is left alone
+    pub fn expand_tilde(&self, input: &str) -> String{
+        match input.strip_prefix('~'){
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", self.home.display(), rest),
+            _ => input.to_owned()
+        }
+    }
+
+    /// The directory clients are confined to, or the session's current directory if there is no root configured
+    pub fn root_or_path(&self) -> &std::path::Path{
+        self.root.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Makes files the server opens on this thread for the session get opened as the user its commands run as, until
+    /// the guard is dropped, or does nothing if commands run as the server. See `RunAs::access_files`
+    pub fn access_files(&self) -> io::Result<Option<FileAccess>>{
+        self.run_as.as_ref().map(RunAs::access_files).transpose()
     }
 
     /// Closes the terminal associated with this client session and joins the thread reading the terminal
@@ -288,7 +969,11 @@ impl ClientSession{
     /// 
     /// This is a horrible solution but according to [stack overflow](https://stackoverflow.com/questions/41331577/joining-a-thread-in-a-method-that-takes-mut-self-like-drop-results-in-cann/42791007#42791007)
     /// joining threads in a destructor is bad
-    pub fn close(self) -> std::thread::Result<()>{
+    pub fn close(mut self) -> std::thread::Result<()>{
+        self.stop_tail();
+        // nothing is going to read the rest of the output, so don't let the reader thread wait on it
+        self.set_is_outputting(false);
+        self.kill_jobs();
         drop(self.term);
 
         match self.reader_handle{
@@ -296,4 +981,615 @@ impl ClientSession{
             None => Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+/// Finds the position of the start of the last `lines` lines of a file, ignoring a trailing newline
+fn tail_start(file: &mut File, lines: usize) -> io::Result<u64>{
+    let mut end = file.seek(SeekFrom::End(0))?;
+    if lines == 0 { return Ok(end) }
+    let mut buf = [0u8; 1024];
+    let mut newlines = 0;
+    let mut skip_trailing = true;
+    while end > 0{
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        for (i, byte) in chunk.iter().enumerate().rev(){
+            if *byte != b'\n' { skip_trailing = false; continue }
+            if skip_trailing { skip_trailing = false; continue }
+            newlines += 1;
+            if newlines == lines{
+                return Ok(start + i as u64 + 1)
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{fs, os::unix::fs::symlink, path::{Path, PathBuf}, time::Instant};
+
+    use super::*;
+
+    /// A fresh, empty directory for a test to work in, with symlinks resolved
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-command-runner-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// A session in `dir` that can't leave `root`
+    fn jailed_session(dir: &Path, root: &Path) -> ClientSession{
+        let mut session = ClientSession::new(dir.to_owned()).unwrap();
+        session.root = Some(root.to_owned());
+        session
+    }
+
+    /// Splits `line` with only `HOME` and `EMPTY` set, giving the text of each word
+    fn words(line: &str) -> Vec<String>{
+        let lookup = |name: &str| match name{
+            "HOME" => Some(String::from("/home/pi")),
+            "EMPTY" => Some(String::new()),
+            _ => None
+        };
+        split_words(line, lookup).unwrap().into_iter().map(|word| word.text).collect()
+    }
+
+    #[test]
+    fn variables_are_expanded(){
+        assert_eq!(words("echo $HOME ${HOME}/src x${HOME}y"), ["echo", "/home/pi", "/home/pi/src", "x/home/piy"]);
+        assert_eq!(words("echo $HOME_DIR ${HOMEx}"), ["echo"]);
+        assert_eq!(words("echo a$UNSET b"), ["echo", "a", "b"]);
+        assert_eq!(words("echo $ $1 $(pwd) ${} ${H-x} cost$"), ["echo", "$", "$1", "$(pwd)", "${}", "${H-x}", "cost$"]);
+    }
+
+    #[test]
+    fn unset_variables_disappear_unless_quoted(){
+        assert_eq!(words("echo $UNSET $EMPTY end"), ["echo", "end"]);
+        assert_eq!(words("echo \"$UNSET\" ''"), ["echo", "", ""]);
+    }
+
+    #[test]
+    fn quotes_keep_words_together(){
+        assert_eq!(words("echo 'a  b' \"c  d\" e\\ f"), ["echo", "a  b", "c  d", "e f"]);
+        assert_eq!(words("echo '$HOME' \"$HOME\" \\$HOME"), ["echo", "$HOME", "/home/pi", "$HOME"]);
+        assert_eq!(words("echo \"it's\" 'say \"hi\"' \"a\\\"b\\\\c\\d\""), ["echo", "it's", "say \"hi\"", "a\"b\\c\\d"]);
+        assert_eq!(words("echo pre'fix'\"ed\""), ["echo", "prefixed"]);
+        for unclosed in ["echo 'a", "echo \"a", "echo \"a'"]{
+            assert_eq!(split_words(unclosed, |_| None).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", unclosed);
+        }
+    }
+
+    #[test]
+    fn quoted_words_are_not_patterns(){
+        let dir = temp_dir("quoted-glob");
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("sh -c 'echo *.txt \"$0\" > out' '*.txt'").unwrap();
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "a.txt *.txt\n");
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn signals_are_parsed_by_name_or_number(){
+        for sig in ["SIGINT", "int", " Int ", "sigint", "2"]{
+            assert_eq!(parse_signal(sig), Some(2), "{}", sig);
+        }
+        assert_eq!(parse_signal("KILL"), Some(9));
+        assert_eq!(parse_signal("SIGSYS"), Some(31));
+    }
+
+    #[test]
+    fn unknown_signals_are_rejected(){
+        for sig in ["", "SIG", "0", "32", "-9", "SIGSIGINT", "INTERRUPT", "INT; rm -rf /", "9 9", "KILL\nINT", "SIGKILL$(id)", "\u{131}nt"]{
+            assert_eq!(parse_signal(sig), None, "{:?}", sig);
+        }
+        let dir = temp_dir("bad-signal");
+        let session = ClientSession::new(dir.clone()).unwrap();
+        assert_eq!(session.signal("INT && reboot").unwrap_err().kind(), ErrorKind::InvalidInput);
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Splits the redirections out of `line`, giving the text of the other words along with them
+    fn redirected(line: &str) -> io::Result<(Vec<String>, Redirects)>{
+        let (rest, redirects) = split_redirects(split_words(line, |_| None)?)?;
+        Ok((rest.into_iter().map(|word| word.text).collect(), redirects))
+    }
+
+    fn redirects(stdin: Option<&str>, stdout: Option<&str>, append: bool) -> Redirects{
+        Redirects{stdin: stdin.map(str::to_owned), stdout: stdout.map(str::to_owned), append}
+    }
+
+    #[test]
+    fn output_is_redirected_or_appended(){
+        assert_eq!(redirected("echo hi > out").unwrap(), (vec![String::from("echo"), String::from("hi")], redirects(None, Some("out"), false)));
+        assert_eq!(redirected("echo hi >>out").unwrap(), (vec![String::from("echo"), String::from("hi")], redirects(None, Some("out"), true)));
+        // only the last one counts
+        assert_eq!(redirected("echo >> first > second").unwrap().1, redirects(None, Some("second"), false));
+        // quoted, they're just words
+        assert_eq!(redirected("echo '>' \">>\" x").unwrap(), (vec![String::from("echo"), String::from(">"), String::from(">>"), String::from("x")], Redirects::default()));
+        for missing in ["echo >", "echo >>", "echo hi >> "]{
+            assert_eq!(redirected(missing).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", missing);
+        }
+    }
+
+    #[test]
+    fn redirected_output_goes_to_the_file(){
+        let dir = temp_dir("redirect-out");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        for cmd in ["echo first > out", "echo second >> out", "echo replaced > new", "echo again >>new"]{
+            session.run_command(cmd).unwrap();
+            while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        }
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "first\nsecond\n");
+        assert_eq!(fs::read_to_string(dir.join("new")).unwrap(), "replaced\nagain\n");
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn input_is_redirected_from_a_file(){
+        assert_eq!(redirected("sort < in").unwrap(), (vec![String::from("sort")], redirects(Some("in"), None, false)));
+        assert_eq!(redirected("sort <in >out").unwrap(), (vec![String::from("sort")], redirects(Some("in"), Some("out"), false)));
+        assert_eq!(redirected("cat <first <second").unwrap().1, redirects(Some("second"), None, false));
+        assert_eq!(redirected("cat \\<in").unwrap(), (vec![String::from("cat"), String::from("<in")], Redirects::default()));
+        assert_eq!(redirected("cat <").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let dir = temp_dir("redirect-in");
+        fs::write(dir.join("in"), "b\nc\na\n").unwrap();
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("sort < in > out").unwrap();
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "a\nb\nc\n");
+        assert_eq!(session.run_command("sort < missing").unwrap_err().kind(), ErrorKind::NotFound);
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commands_are_told_the_terminal_type_and_size(){
+        let dir = temp_dir("terminal-vars");
+        let expected = format!("{} {} {}\n", term_name(), crate::screen::DEFAULT_COLS, crate::screen::DEFAULT_ROWS);
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        // once in the process's enviorment, and once when the server expands them itself
+        session.run_command("sh -c 'echo $TERM $COLUMNS $LINES' > env").unwrap();
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        session.run_command("echo $TERM $COLUMNS $LINES > expanded").unwrap();
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        assert_eq!(fs::read_to_string(dir.join("env")).unwrap(), expected);
+        assert_eq!(fs::read_to_string(dir.join("expanded")).unwrap(), expected);
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Runs `dd` into a file, giving it whatever `write` sends to its stdin, and returns what it received
+    fn received_by_dd(name: &str, write: impl FnOnce(&mut ClientSession)) -> Vec<u8>{
+        let dir = temp_dir(name);
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command(&format!("dd of={}", dir.join("received").display())).unwrap();
+        write(&mut session);
+        session.close_stdin();
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        let received = fs::read(dir.join("received")).unwrap();
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+        received
+    }
+
+    #[test]
+    fn stdin_gets_exactly_what_is_written(){
+        assert_eq!(received_by_dd("stdin-raw", |session| {
+            assert_eq!(session.write_stdin(b"q").unwrap(), 1);
+            assert_eq!(session.write_stdin(b"\x1b[A").unwrap(), 3);
+        }), b"q\x1b[A");
+    }
+
+    #[test]
+    fn lines_get_exactly_one_newline(){
+        assert_eq!(received_by_dd("stdin-lines", |session| {
+            assert_eq!(session.write_line(b"first").unwrap(), 6);
+            assert_eq!(session.write_line(b"").unwrap(), 1);
+            assert_eq!(session.write_line(b"last").unwrap(), 5);
+        }), b"first\n\nlast\n");
+    }
+
+    #[test]
+    fn closing_stdin_lets_cat_finish(){
+        let dir = temp_dir("stdin-eof");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("cat").unwrap();
+        session.write_line(b"echoed").unwrap();
+        assert_eq!(read_output_until(&session, "echoed\r\n"), "echoed\r\n");
+        assert!(session.exit_status().is_none());
+
+        assert!(session.close_stdin());
+        let mut status = None;
+        assert!(eventually(|| { status = session.exit_status(); status.is_some() }));
+        assert!(status.unwrap().success());
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stdin_cant_be_written_once_closed(){
+        let dir = temp_dir("stdin-closed");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("sleep 30").unwrap();
+        assert!(session.close_stdin());
+        assert!(!session.close_stdin());
+        let err = session.write_stdin(b"too late").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(err.to_string(), "Stdin has already been closed");
+        session.kill();
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn finished_processes_dont_linger_as_zombies(){
+        let dir = temp_dir("reap");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("true").unwrap();
+        let pid = session.pid().unwrap().to_string();
+        // until it's collected, a process that has exited is left behind as a zombie
+        assert!(eventually(|| !is_alive(&pid)));
+        assert!(eventually(|| {
+            session.reap();
+            !session.has_child()
+        }));
+        assert!(!fs::exists(format!("/proc/{}", pid)).unwrap());
+        // the status is kept until it's asked for, even though nothing was waiting on it
+        assert!(session.exit_status().unwrap().success());
+        assert_eq!(session.exit_status(), None);
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Reader that counts how many times it was read from
+    struct CountingReads{
+        inner: io::Cursor<Vec<u8>>,
+        reads: Arc<atomic::AtomicUsize>
+    }
+    impl Read for CountingReads{
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+            self.reads.fetch_add(1, atomic::Ordering::Relaxed);
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn bursts_of_output_are_copied_a_chunk_at_a_time(){
+        let dir = temp_dir("burst");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        let burst: Vec<u8> = (0..1024 * 1024u32).map(|i| b'a' + (i % 26) as u8).collect();
+        let reads = Arc::new(atomic::AtomicUsize::new(0));
+        let src = CountingReads{inner: io::Cursor::new(burst.clone()), reads: reads.clone()};
+        // as big as the reader's chunks, so every read it makes goes straight through to ours
+        let out = Arc::new(Mutex::new(CircularBuffer::new()));
+        session.spawn_buf_reader(out.clone(), Box::new(BufReader::with_capacity(4096, src))).join().unwrap();
+
+        // the output is locked once for each read, so a read per byte would lock it a million times
+        let reads = reads.load(atomic::Ordering::Relaxed);
+        assert!(reads <= burst.len() / 4096 + 1, "{} reads", reads);
+        let mut kept = [0u8; 8192];
+        let len = out.lock().unwrap().read(&mut kept).unwrap();
+        assert!(len > 0 && burst.ends_with(&kept[..len]));
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Reads the session's output until it ends with `end`, giving up after a few seconds
+    fn read_output_until(session: &ClientSession, end: &str) -> String{
+        let mut read = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !read.ends_with(end.as_bytes()){
+            assert!(Instant::now() < deadline, "waited for {:?} after {:?}", end, String::from_utf8_lossy(&read));
+            if session.try_read_output(&mut read).unwrap() == 0 { thread::sleep(Duration::from_millis(10)); }
+        }
+        String::from_utf8_lossy(&read).into_owned()
+    }
+
+    #[test]
+    fn slow_clients_hold_up_the_process_instead_of_losing_output(){
+        let dir = temp_dir("backpressure");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.set_is_outputting(true);
+        // far more than the output buffer and the terminal's own buffer can hold
+        session.run_command("seq 1 50000").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(session.exit_status().is_none());
+
+        let expected: String = (1..=50000).map(|n| format!("{}\r\n", n)).collect();
+        assert_eq!(read_output_until(&session, "\n50000\r\n"), expected);
+        while session.exit_status().is_none() { thread::sleep(Duration::from_millis(10)); }
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clearing_output_lets_a_held_up_process_carry_on(){
+        let dir = temp_dir("clear");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.set_is_outputting(true);
+        session.run_command("seq 1 50000").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(session.exit_status().is_none());
+
+        // the reader thread is waiting for room while this clears, so it has to be woken up to carry on
+        assert!(eventually(|| {
+            session.clear_output();
+            session.exit_status().is_some()
+        }));
+        session.run_command("echo after").unwrap();
+        read_output_until(&session, "after\r\n");
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Reads the output of a process printing 30 x's one at a time, the way a client's loop does, returning each read
+    fn trickled_reads(name: &str, interval: Duration) -> Vec<usize>{
+        let dir = temp_dir(name);
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.run_command("sh -c 'for i in $(seq 1 30); do printf x; sleep 0.01; done; echo'").unwrap();
+        let mut reads = Vec::new();
+        let mut read = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !read.ends_with(b"\n"){
+            assert!(Instant::now() < deadline, "read {:?}", String::from_utf8_lossy(&read));
+            session.wait_for_output(Duration::from_millis(50));
+            session.coalesce_output(interval, 4096);
+            match session.try_read_output(&mut read).unwrap(){
+                0 => (),
+                len => reads.push(len)
+            }
+        }
+        assert_eq!(read, [&[b'x'; 30][..], b"\r\n"].concat());
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+        reads
+    }
+
+    #[test]
+    fn coalescing_sends_trickling_output_in_fewer_larger_reads(){
+        let separate = trickled_reads("coalesce-off", Duration::ZERO);
+        let coalesced = trickled_reads("coalesce-on", Duration::from_millis(100));
+        assert!(coalesced.len() * 3 <= separate.len(), "{:?} coalesced, {:?} without", coalesced, separate);
+        assert!(coalesced.iter().max() > separate.iter().max(), "{:?} coalesced, {:?} without", coalesced, separate);
+    }
+
+    #[test]
+    fn characters_split_between_writes_are_read_whole(){
+        let dir = temp_dir("split-char");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        // the euro sign is written in two parts, a little while apart
+        fs::write(dir.join("split.sh"), "printf 'price: \\342\\202'; sleep 0.01; printf '\\254\\n'").unwrap();
+        session.run_command(&format!("sh {}", dir.join("split.sh").display())).unwrap();
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !chunks.concat().ends_with(b"\n"){
+            assert!(Instant::now() < deadline, "read {:?}", chunks);
+            let mut chunk = Vec::new();
+            match session.try_read_output(&mut chunk).unwrap(){
+                0 => thread::sleep(Duration::from_millis(1)),
+                _ => chunks.push(chunk)
+            }
+        }
+        assert!(chunks.iter().all(|chunk| std::str::from_utf8(chunk).is_ok()), "read {:?}", chunks);
+        assert_eq!(chunks.concat(), "price: €\r\n".as_bytes());
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tail_follows_a_file_until_stopped(){
+        let dir = temp_dir("tail");
+        let log = dir.join("log");
+        fs::write(&log, "one\ntwo\nthree\n").unwrap();
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.tail(&log, 2).unwrap();
+        assert!(session.is_tailing());
+        assert_eq!(read_output_until(&session, "three\n"), "two\nthree\n");
+
+        fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"four\n").unwrap();
+        assert_eq!(read_output_until(&session, "four\n"), "four\n");
+
+        // a file that gets shorter was truncated (or rotated), so it's followed from the start again
+        fs::write(&log, "new\n").unwrap();
+        assert_eq!(read_output_until(&session, "new\n"), "new\n");
+
+        session.stop_tail();
+        assert!(!session.is_tailing());
+        fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"unseen\n").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(session.try_read_output(&mut Vec::new()).unwrap(), 0);
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Waits up to a few seconds for `check` to pass, returning whether it did
+    fn eventually(mut check: impl FnMut() -> bool) -> bool{
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !check(){
+            if Instant::now() > deadline { return false }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Whether process `pid` is running, as opposed to gone or a zombie waiting to be collected
+    fn is_alive(pid: &str) -> bool{
+        fs::read_to_string(format!("/proc/{}/stat", pid.trim())).is_ok_and(|stat| stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with('Z')))
+    }
+
+    /// Writer that never takes anything, like a client whose connection is full
+    struct Refusing;
+    impl Write for Refusing{
+        fn write(&mut self, _: &[u8]) -> io::Result<usize>{
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        }
+
+        fn flush(&mut self) -> io::Result<()>{
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reading_output_says_how_much_was_read(){
+        let dir = temp_dir("read-count");
+        let session = ClientSession::new(dir.clone()).unwrap();
+        let mut read = Vec::new();
+        assert_eq!(session.try_read_output(&mut read).unwrap(), 0);
+        assert_eq!(poison::lock(&session.output, "output").write(b"hello").unwrap(), 5);
+        assert!(session.try_read_output(&mut Refusing).is_err());
+        assert_eq!(session.try_read_output(&mut read).unwrap(), 5);
+        assert_eq!(read, b"hello");
+        assert_eq!(session.try_read_output(&mut read).unwrap(), 0);
+        // read_output does the same, without the count
+        poison::lock(&session.output, "output").write_all(b" there").unwrap();
+        session.read_output(&mut read).unwrap();
+        assert_eq!(read, b"hello there");
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn background_jobs_are_parsed_like_foreground_commands(){
+        let dir = temp_dir("bg-parse");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.set_home(dir.join("home"));
+        session.run_background("echo ~/x 'a  b' $TERM > out").unwrap();
+        assert!(eventually(|| fs::read_to_string(dir.join("out")).is_ok_and(|out| out.ends_with('\n'))));
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), format!("{}/x a  b {}\n", dir.join("home").display(), term_name()));
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn killing_background_jobs_kills_what_they_started(){
+        let dir = temp_dir("bg-kill");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        // commands are split on whitespace, so what the job starts is in a script
+        fs::write(dir.join("spawn.sh"), "sleep 30 & echo $! > pid; wait\n").unwrap();
+        session.run_background("sh spawn.sh").unwrap();
+        assert!(eventually(|| fs::read_to_string(dir.join("pid")).is_ok_and(|pid| pid.ends_with('\n'))));
+        let pid = fs::read_to_string(dir.join("pid")).unwrap();
+        assert!(is_alive(&pid));
+        let _ = session.close();
+        assert!(eventually(|| !is_alive(&pid)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn signals_reach_what_the_process_started(){
+        let dir = temp_dir("signal-group");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        fs::write(dir.join("spawn.sh"), "sleep 30 & echo $! > pid; wait\n").unwrap();
+        session.run_command("sh spawn.sh").unwrap();
+        assert!(eventually(|| fs::read_to_string(dir.join("pid")).is_ok_and(|pid| pid.ends_with('\n'))));
+        let pid = fs::read_to_string(dir.join("pid")).unwrap();
+        assert!(is_alive(&pid));
+        session.signal("TERM").unwrap();
+        assert!(eventually(|| !is_alive(&pid)));
+        let _ = session.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cd_stays_inside_the_root(){
+        let root = temp_dir("cd-root");
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        let mut session = jailed_session(&root, &root);
+
+        assert_eq!(session.change_dir("sub/deeper").unwrap(), root.join("sub/deeper"));
+        assert_eq!(session.change_dir("../..").unwrap(), root);
+        for outside in ["..", "/", "sub/../.."]{
+            assert_eq!(session.change_dir(outside).unwrap_err().kind(), ErrorKind::PermissionDenied, "cd {}", outside);
+            assert_eq!(session.path, root);
+        }
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn cd_cant_follow_symlinks_out_of_the_root(){
+        let dir = temp_dir("cd-symlink");
+        fs::create_dir_all(dir.join("root")).unwrap();
+        symlink(&dir, dir.join("root/escape")).unwrap();
+        let mut session = jailed_session(&dir.join("root"), &dir.join("root"));
+
+        assert_eq!(session.change_dir("escape").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cd_dash_goes_back_to_the_previous_directory(){
+        let dir = temp_dir("cd-back");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        assert!(session.change_dir("-").is_err());
+
+        session.change_dir("a/b").unwrap();
+        assert_eq!(session.change_dir("-").unwrap(), dir);
+        assert_eq!(session.change_dir("-").unwrap(), dir.join("a/b"));
+        // a failed cd isn't somewhere to go back to
+        assert!(session.change_dir("missing").is_err());
+        assert_eq!(session.change_dir("-").unwrap(), dir);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tildes_at_the_start_are_the_home_directory(){
+        let dir = temp_dir("tilde");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        let home = dir.join("home");
+        session.set_home(home.clone());
+        assert_eq!(session.expand_tilde("~"), home.display().to_string());
+        assert_eq!(session.expand_tilde("~/src/x"), format!("{}/src/x", home.display()));
+        for unchanged in ["~pi/src", "a/~", "x~", "", "/~/"]{
+            assert_eq!(session.expand_tilde(unchanged), unchanged);
+        }
+        // the home directory doesn't have to exist, but cd can't go there until it does
+        assert_eq!(session.change_dir("~").unwrap_err().kind(), ErrorKind::NotFound);
+        fs::create_dir(&home).unwrap();
+        assert_eq!(session.change_dir("~").unwrap(), home);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn aliases_expand_without_looping(){
+        let dir = temp_dir("alias");
+        let mut session = ClientSession::new(dir.clone()).unwrap();
+        session.set_alias("ll", "ls -l");
+        session.set_alias("la", "ll -a");
+        session.set_alias("ls", "ls --color");
+        session.set_alias("ping", "pong");
+        session.set_alias("pong", "ping");
+        assert_eq!(session.expand_alias("  la /tmp"), "ls --color -l -a /tmp");
+        assert_eq!(session.expand_alias("ping").trim_end(), "ping");
+        // only the first word is an alias
+        assert_eq!(session.expand_alias("echo ll"), "echo ll");
+        assert_eq!(session.expand_alias("lll"), "lll");
+
+        assert!(session.remove_alias("ls"));
+        assert!(!session.remove_alias("ls"));
+        assert_eq!(session.expand_alias("la").trim_end(), "ls -l -a");
+        assert_eq!(session.aliases().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["la", "ll", "ping", "pong"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn roots_that_cant_be_used_are_errors(){
+        let dir = temp_dir("root-missing");
+        fs::write(dir.join("file"), "").unwrap();
+        assert_eq!(resolve_root(&dir).unwrap(), dir);
+        assert!(resolve_root(&dir.join("missing")).is_err());
+        assert!(resolve_root(&dir.join("file")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
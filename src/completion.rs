@@ -0,0 +1,151 @@
+use std::{collections::BTreeSet, env, ffi::OsStr, fs, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
+
+use super::file_transfer;
+
+/// Commands the server handles itself, which won't be found on the PATH
+pub const BUILTINS: [&str; 2] = ["cd", "rspi"];
+
+/// Finds completions for the last token of `line`, the command a client has typed so far
+/// 
+/// The first token is completed from executables on the PATH, and any other token (or one containing a '/')
+/// from the files in `cwd`. Paths that would leave `root` aren't completed. Each candidate replaces the whole token
+pub fn complete(root: &Path, cwd: &Path, line: &str) -> Vec<String>{
+    let token = if line.ends_with(char::is_whitespace) { "" } else { line.split_whitespace().next_back().unwrap_or_default() };
+    let is_first = line.split_whitespace().count() <= 1 && !line.ends_with(char::is_whitespace);
+    if is_first && !token.contains('/'){
+        complete_command(token)
+    }else{
+        complete_path(root, cwd, token)
+    }
+}
+
+/// Names of builtins and executables on the PATH starting with `prefix`
+fn complete_command(prefix: &str) -> Vec<String>{
+    let mut found: BTreeSet<String> = BUILTINS.iter().filter(|b| b.starts_with(prefix)).map(|b| b.to_string()).collect();
+    for dir in env::var_os("PATH").map(|p| env::split_paths(&p).collect::<Vec<_>>()).unwrap_or_default(){
+        let Ok(entries) = fs::read_dir(dir) else { continue };
+        for entry in entries.flatten(){
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || found.contains(&name) { continue }
+            if is_executable(&entry.path()) { found.insert(name); }
+        }
+    }
+    found.into_iter().collect()
+}
+
+/// Whether `path` is a file with any of its execute bits set
+pub fn is_executable(path: &Path) -> bool{
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Finds the executable a command called `name` runs, searching the directories in `path_env` (a PATH-style list)
+/// and then `cwd`
+/// 
+/// Names containing a '/' aren't searched for, they're checked directly, relative to `cwd` unless they're absolute
+pub fn resolve_executable(name: &str, path_env: Option<&OsStr>, cwd: &Path) -> Option<PathBuf>{
+    if name.is_empty(){
+        return None
+    }
+    if name.contains('/'){
+        // collecting the components drops any `.` in the path, like the one in `./script`
+        return Some(cwd.join(name).components().collect::<PathBuf>()).filter(|path| is_executable(path))
+    }
+    path_env.map(|p| env::split_paths(p).collect::<Vec<_>>()).unwrap_or_default().into_iter()
+        .chain([PathBuf::new()])
+        .map(|dir| cwd.join(dir).join(name))
+        .find(|path| is_executable(path))
+}
+
+/// Entries of the directory in `token` whose names start with the rest of it, with a '/' added to directories
+fn complete_path(root: &Path, cwd: &Path, token: &str) -> Vec<String>{
+    let (dir, prefix) = match token.rfind('/'){
+        Some(i) => (&token[..=i], &token[i + 1..]),
+        None => ("", token)
+    };
+    let Ok(search_dir) = file_transfer::sanitize_within(root, &cwd.join(if dir.is_empty() { "." } else { dir })) else { return Vec::new() };
+    let Ok(entries) = fs::read_dir(search_dir) else { return Vec::new() };
+    let mut found: Vec<String> = entries.flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // hidden files are only completed when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) { return None }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, slash))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// A fresh, empty directory for a test to work in, with symlinks resolved
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-completion-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn commands_are_completed_from_builtins_and_the_path(){
+        let dir = temp_dir("commands");
+        assert!(complete(&dir, &dir, "c").contains(&String::from("cd")));
+        assert!(complete(&dir, &dir, "rsp").contains(&String::from("rspi")));
+        assert!(complete(&dir, &dir, "s").contains(&String::from("sh")));
+        assert!(complete(&dir, &dir, "s").iter().all(|cmd| cmd.starts_with('s')));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_are_completed_from_the_directory(){
+        let dir = temp_dir("paths");
+        fs::create_dir_all(dir.join("alps/peak")).unwrap();
+        for file in ["alpha.txt", "beta", ".hidden", "alps/snow"]{
+            fs::write(dir.join(file), "").unwrap();
+        }
+        assert_eq!(complete(&dir, &dir, "cat al"), ["alpha.txt", "alps/"]);
+        assert_eq!(complete(&dir, &dir, "cat "), ["alpha.txt", "alps/", "beta"]);
+        assert_eq!(complete(&dir, &dir, "cat ."), [".hidden"]);
+        assert_eq!(complete(&dir, &dir, "cat alps/"), ["alps/peak/", "alps/snow"]);
+        // a first word with a '/' in it is a path to a program
+        assert_eq!(complete(&dir, &dir, "./al"), ["./alpha.txt", "./alps/"]);
+        assert_eq!(complete(&dir, &dir.join("alps"), "cat ../b"), ["../beta"]);
+        assert!(complete(&dir, &dir, "cat nothing").is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn executables_are_found_on_the_path_then_in_the_directory(){
+        let dir = temp_dir("resolve");
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        for (file, mode) in [("bin/tool", 0o755), ("bin/plain", 0o644), ("script", 0o700), ("tool", 0o755)]{
+            fs::write(dir.join(file), "").unwrap();
+            fs::set_permissions(dir.join(file), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let path = OsStr::new("/nonexistent:bin");
+        // the path comes before the directory, and relative entries are relative to it
+        assert_eq!(resolve_executable("tool", Some(path), &dir), Some(dir.join("bin/tool")));
+        assert_eq!(resolve_executable("script", Some(path), &dir), Some(dir.join("script")));
+        assert_eq!(resolve_executable("./tool", Some(path), &dir), Some(dir.join("tool")));
+        assert_eq!(resolve_executable("bin/tool", None, &dir), Some(dir.join("bin/tool")));
+        // files that can't be run aren't executables
+        assert_eq!(resolve_executable("plain", Some(path), &dir), None);
+        assert_eq!(resolve_executable("bin/plain", Some(path), &dir), None);
+        assert_eq!(resolve_executable("missing", Some(path), &dir), None);
+        assert_eq!(resolve_executable("", Some(path), &dir), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_outside_the_root_arent_completed(){
+        let dir = temp_dir("outside");
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        assert!(complete(&dir.join("root"), &dir.join("root"), "cat ../").is_empty());
+        assert!(complete(&dir.join("root"), &dir.join("root"), &format!("cat {}/", dir.display())).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
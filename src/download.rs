@@ -0,0 +1,472 @@
+use std::{env, fs::{File, OpenOptions}, io::{self, BufReader, BufWriter, ErrorKind, Read, Write}, os::unix::fs::FileExt, path::{Path, PathBuf}, process, sync::atomic::{AtomicU64, Ordering}, thread, time::Duration};
+
+use super::file_transfer::{Crc32, Progress};
+
+/// Number of bytes between calls to a download's progress callback
+const PROGRESS_INTERVAL: u64 = 64 * 1024;
+
+/// Number of parts `download_parallel` splits a file into when asked to by `rspi download -p`
+pub const DEFAULT_PARTS: usize = 4;
+
+/// Smallest part worth a request of its own. Files less than twice this are downloaded in one go
+const MIN_PART_SIZE: u64 = 1024 * 1024;
+
+/// Times a part that fails is retried, picking up where it left off, before the whole download gives up
+const PART_RETRIES: u32 = 3;
+
+/// How often progress is reported while the parts of a download are on their own threads
+const PROGRESS_POLL: Duration = Duration::from_millis(100);
+
+/// Longest a download waits to connect and hear back from the server when RSPI_DOWNLOAD_TIMEOUT_SECS isn't set
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The agent downloads are made with, so a server that stops answering can't leave a session waiting on it forever
+/// 
+/// RSPI_DOWNLOAD_TIMEOUT_SECS limits how long connecting and waiting for the response take, and RSPI_DOWNLOAD_MAX_SECS
+/// how long a whole request can take, body and all
+fn agent() -> ureq::Agent{
+    let secs = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok()).filter(|secs| *secs > 0).map(Duration::from_secs);
+    agent_with(secs("RSPI_DOWNLOAD_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT), secs("RSPI_DOWNLOAD_MAX_SECS"))
+}
+
+/// An agent that gives up on a request if connecting or waiting for the response takes longer than `timeout`, or the
+/// whole request longer than `max`
+fn agent_with(timeout: Duration, max: Option<Duration>) -> ureq::Agent{
+    ureq::Agent::config_builder()
+        .timeout_resolve(Some(timeout))
+        .timeout_connect(Some(timeout))
+        .timeout_send_request(Some(timeout))
+        .timeout_recv_response(Some(timeout))
+        .timeout_global(max)
+        .build()
+        .into()
+}
+
+/// Downloads `url` with an HTTP(S) GET, writing the body to `file`
+/// 
+/// Redirects are followed, and any other status besides 200 is returned as an error. Returns `io::ErrorKind::Other`
+/// if the body is bigger than `max_bytes`, leaving it up to the caller to remove the partial file.\
+/// On success, returns the number of bytes downloaded
+pub fn download(url: &str, file: File, max_bytes: Option<u64>, mut progress: Option<Progress>) -> Result<u64, io::Error>{
+    let response = agent().get(url).call().map_err(|e| match e{
+        ureq::Error::StatusCode(code) => io::Error::other(format!("Server responded with HTTP status {}", code)),
+        e => e.into_io()
+    })?;
+    if response.status() != 200{
+        return Err(io::Error::other(format!("Server responded with HTTP status {}", response.status())))
+    }
+    let total = response.body().content_length();
+    if let (Some(total), Some(max)) = (total, max_bytes){
+        if total > max { return Err(limit_error(max)) }
+    }
+
+    let mut body = response.into_body().into_reader();
+    let mut buf_writer = BufWriter::new(file);
+    let mut buf = [0u8; 8192];
+    let mut downloaded = 0u64;
+    let mut last_report = 0u64;
+    loop{
+        let read_bytes = match body.read(&mut buf){
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        downloaded += read_bytes as u64;
+        // the server may not have sent a length, or may have lied about it
+        if let Some(max) = max_bytes.filter(|max| downloaded > *max){
+            return Err(limit_error(max))
+        }
+        buf_writer.write_all(&buf[..read_bytes])?;
+        if downloaded - last_report >= PROGRESS_INTERVAL{
+            last_report = downloaded;
+            if let Some(cb) = progress.as_mut() { cb(downloaded, total) }
+        }
+    }
+    buf_writer.flush()?;
+    if let Some(cb) = progress.as_mut() { cb(downloaded, total) }
+    Ok(downloaded)
+}
+
+/// What a server said about a file that it can send in parts
+struct RangeSupport{
+    len: u64,
+    /// ETag or Last-Modified date of the file, so parts of it can only come from the same version of it
+    validator: Option<String>
+}
+
+/// Asks the server about `url` with a HEAD request, returning None if it can't send the file in parts or won't say
+/// how big it is
+fn range_support(url: &str) -> Option<RangeSupport>{
+    let response = agent().head(url).call().ok()?;
+    if response.status() != 200{
+        return None
+    }
+    let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+    if !header("accept-ranges").is_some_and(|ranges| ranges.eq_ignore_ascii_case("bytes")){
+        return None
+    }
+    let len = header("content-length")?.parse().ok()?;
+    // weak ETags can't be used to ask for a range
+    let validator = header("etag").filter(|tag| !tag.starts_with("W/")).or_else(|| header("last-modified")).map(str::to_owned);
+    Some(RangeSupport{len, validator})
+}
+
+/// Downloads `url` like `download`, but as `parts` ranges of the file on threads of their own, each written straight
+/// to where it goes in `file`
+/// 
+/// A part that fails, like when a flaky connection drops, is retried from where it left off. Falls back to `download`
+/// if the server doesn't say it accepts ranges, or the file is too small to be worth splitting up. Returns
+/// `io::ErrorKind::InvalidData` if the file changes on the server partway through.\
+/// On success, returns the number of bytes downloaded
+pub fn download_parallel(url: &str, file: File, parts: usize, max_bytes: Option<u64>, mut progress: Option<Progress>) -> Result<u64, io::Error>{
+    let Some(support) = range_support(url).filter(|support| parts > 1 && support.len >= MIN_PART_SIZE * 2) else {
+        return download(url, file, max_bytes, progress)
+    };
+    let total = support.len;
+    if let Some(max) = max_bytes.filter(|max| total > *max){
+        return Err(limit_error(max))
+    }
+    file.set_len(total)?;
+    let parts = (parts as u64).min(total / MIN_PART_SIZE);
+    let part_size = total.div_ceil(parts);
+    let downloaded = AtomicU64::new(0);
+
+    let results = thread::scope(|scope| {
+        let handles: Vec<_> = (0..parts).map(|i| {
+            let (start, end) = (i * part_size, ((i + 1) * part_size).min(total) - 1);
+            let (file, downloaded, validator) = (&file, &downloaded, support.validator.as_deref());
+            scope.spawn(move || download_part(url, file, start, end, validator, downloaded))
+        }).collect();
+        // the progress callback can't be shared between threads, so this one reports it while the others download
+        let mut last_report = 0u64;
+        while !handles.iter().all(|handle| handle.is_finished()){
+            thread::sleep(PROGRESS_POLL);
+            let so_far = downloaded.load(Ordering::Relaxed);
+            if so_far - last_report >= PROGRESS_INTERVAL{
+                last_report = so_far;
+                if let Some(cb) = progress.as_mut() { cb(so_far, Some(total)) }
+            }
+        }
+        handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_| Err(io::Error::other("A download thread panicked")))).collect::<Vec<_>>()
+    });
+    results.into_iter().collect::<Result<Vec<()>, io::Error>>()?;
+
+    let so_far = downloaded.load(Ordering::Relaxed);
+    if so_far != total{
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Only {} of {} bytes were downloaded", so_far, total)))
+    }
+    if let Some(cb) = progress.as_mut() { cb(so_far, Some(total)) }
+    Ok(so_far)
+}
+
+/// Downloads bytes `start` to `end` (inclusive) of `url` into the same place in `file`, retrying from wherever it got
+/// to if the connection fails
+fn download_part(url: &str, file: &File, start: u64, end: u64, validator: Option<&str>, downloaded: &AtomicU64) -> io::Result<()>{
+    let mut offset = start;
+    let mut failures = 0;
+    loop{
+        let err = match download_range(url, file, &mut offset, end, validator, downloaded){
+            Ok(()) if offset > end => return Ok(()),
+            Ok(()) => io::Error::new(ErrorKind::UnexpectedEof, format!("Server stopped sending bytes {}-{} early", start, end)),
+            // retrying won't bring back a version of the file that's gone
+            Err(e) if e.kind() == ErrorKind::InvalidData => return Err(e),
+            Err(e) => e
+        };
+        failures += 1;
+        if failures > PART_RETRIES{
+            return Err(err)
+        }
+    }
+}
+
+/// Requests bytes `offset` to `end` of `url`, writing them to `file` and moving `offset` along as they arrive
+fn download_range(url: &str, file: &File, offset: &mut u64, end: u64, validator: Option<&str>, downloaded: &AtomicU64) -> io::Result<()>{
+    let mut request = agent().get(url).header("Range", &format!("bytes={}-{}", offset, end));
+    if let Some(validator) = validator{
+        request = request.header("If-Range", validator);
+    }
+    let response = request.call().map_err(|e| match e{
+        ureq::Error::StatusCode(code) => io::Error::other(format!("Server responded with HTTP status {}", code)),
+        e => e.into_io()
+    })?;
+    // servers send the whole file instead of a part of it when it has changed since the download started
+    if response.status() != 206{
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Server responded to a range request with HTTP status {}, the file may have changed", response.status())))
+    }
+    let mut body = response.into_body().into_reader();
+    let mut buf = [0u8; 8192];
+    while *offset <= end{
+        let read_bytes = match body.read(&mut buf){
+            Ok(0) => break,
+            Ok(n) => n.min((end + 1 - *offset) as usize),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        file.write_all_at(&buf[..read_bytes], *offset)?;
+        *offset += read_bytes as u64;
+        downloaded.fetch_add(read_bytes as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Creates an empty file next to `dest` to download into, returning its path along with it
+/// 
+/// Downloading straight into `dest` would destroy whatever was there if the download failed, so the download goes
+/// here first and is renamed over `dest` once it's complete. Being in the same directory keeps the rename atomic
+pub fn create_temp(dest: &Path) -> io::Result<(PathBuf, File)>{
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let dir = dest.parent().unwrap_or(Path::new("."));
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    loop{
+        let path = dir.join(format!(".{}.rspi-download-{}-{}", name, process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        match OpenOptions::new().write(true).create_new(true).open(&path){
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// CRC-32 of the file at `path`, for checking a download against what the client expected
+pub fn checksum(path: &Path) -> io::Result<u32>{
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut crc = Crc32::new();
+    let mut buf = [0u8; 8192];
+    loop{
+        match reader.read(&mut buf){
+            Ok(0) => return Ok(crc.finish()),
+            Ok(n) => crc.update(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+fn limit_error(max: u64) -> io::Error{
+    io::Error::other(format!("download exceeds limit of {} bytes", max))
+}
+
+/// Picks a file name for a download from the last segment of its URL's path
+pub fn file_name_from_url(url: &str) -> &str{
+    let without_query = url.split(['?', '#']).next().unwrap_or_default();
+    let path = without_query.split_once("://").map_or(without_query, |(_, rest)| rest);
+    match path.split_once('/'){
+        Some((_, path)) => path.rsplit('/').next().filter(|name| !name.is_empty() && *name != "." && *name != "..").unwrap_or("download"),
+        None => "download"
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{fs, net::{TcpListener, TcpStream}, sync::{atomic::AtomicBool, Arc, Mutex}, time::Instant};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-download-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Serves one request with `response`, returning the URL to request
+    fn serve_once(response: &'static str) -> String{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") && conn.read(&mut byte).unwrap_or(0) == 1{
+                request.push(byte[0]);
+            }
+            let _ = conn.write_all(response.as_bytes());
+        });
+        url
+    }
+
+    #[test]
+    fn bodies_are_written_to_the_file(){
+        let dir = temp_dir("body");
+        let url = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world");
+        let size = download(&url, File::create(dir.join("out")).unwrap(), None, None).unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(fs::read(dir.join("out")).unwrap(), b"hello world");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn servers_that_never_answer_time_out(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+        let started = Instant::now();
+        let err = agent_with(Duration::from_millis(500), None).get(&url).call().unwrap_err();
+        assert!(matches!(err, ureq::Error::Timeout(_)), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn other_statuses_are_errors(){
+        let dir = temp_dir("status");
+        let url = serve_once("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let e = download(&url, File::create(dir.join("out")).unwrap(), None, None).unwrap_err();
+        assert!(e.to_string().contains("404"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_errors(){
+        let dir = temp_dir("limit");
+        let url = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world");
+        assert!(download(&url, File::create(dir.join("out")).unwrap(), Some(5), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// How a test server sends the parts of a file it's asked for
+    #[derive(Clone, Copy, PartialEq)]
+    enum Ranges{
+        Sent,
+        /// The connection drops halfway through the first part asked for
+        FirstDropped,
+        /// The file changed, so the whole new file is sent instead of the part
+        Changed,
+        /// Ranges aren't supported, so they're never offered and the whole file is always sent
+        Unsupported
+    }
+
+    /// Serves `data` to as many requests as it gets, answering HEAD requests and range requests, and returning the URL to
+    /// request along with the Range header of every GET it gets
+    fn serve_ranges(data: Vec<u8>, ranges: Ranges) -> (String, Arc<Mutex<Vec<String>>>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/big.bin", listener.local_addr().unwrap());
+        let requested: Arc<Mutex<Vec<String>>> = Arc::default();
+        let (data, dropped) = (Arc::new(data), Arc::new(AtomicBool::new(false)));
+        let requested_ref = requested.clone();
+        thread::spawn(move || for conn in listener.incoming().flatten(){
+            let (data, requested, dropped) = (data.clone(), requested_ref.clone(), dropped.clone());
+            thread::spawn(move || answer(conn, &data, ranges, &requested, &dropped));
+        });
+        (url, requested)
+    }
+
+    fn answer(mut conn: TcpStream, data: &[u8], ranges: Ranges, requested: &Mutex<Vec<String>>, dropped: &AtomicBool){
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") && conn.read(&mut byte).unwrap_or(0) == 1{
+            request.push(byte[0]);
+        }
+        let request = String::from_utf8_lossy(&request).into_owned();
+        let headers = match ranges{
+            Ranges::Unsupported => "ETag: \"v1\"\r\nConnection: close\r\n",
+            _ => "Accept-Ranges: bytes\r\nETag: \"v1\"\r\nConnection: close\r\n"
+        };
+        if request.starts_with("HEAD"){
+            let _ = conn.write_all(format!("HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n", headers, data.len()).as_bytes());
+            return
+        }
+        let range = request.lines().find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_owned)).unwrap_or_default();
+        requested.lock().unwrap().push(range.clone());
+        if ranges == Ranges::Changed || ranges == Ranges::Unsupported{
+            let _ = conn.write_all(format!("HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n", headers, data.len()).as_bytes());
+            let _ = conn.write_all(data);
+            return
+        }
+        let (start, end) = range.split_once('-').map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap())).unwrap();
+        let _ = conn.write_all(format!("HTTP/1.1 206 Partial Content\r\n{}Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+            headers, start, end, data.len(), end + 1 - start).as_bytes());
+        let part = &data[start..=end];
+        if ranges == Ranges::FirstDropped && !dropped.swap(true, Ordering::SeqCst){
+            let _ = conn.write_all(&part[..part.len() / 2]);
+            return
+        }
+        let _ = conn.write_all(part);
+    }
+
+    /// Bytes that are different all the way through, so a part written to the wrong place would be noticed
+    fn big_file() -> Vec<u8>{
+        (0..3 * MIN_PART_SIZE as usize + 17).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// CRC-32 of `data` worked out a bit at a time, to check `checksum` against
+    fn crc32(data: &[u8]) -> u32{
+        !data.iter().fold(!0u32, |crc, byte| (0..8).fold(crc ^ *byte as u32, |crc, _| (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg())))
+    }
+
+    #[test]
+    fn files_are_downloaded_in_parallel_ranges(){
+        let dir = temp_dir("parallel");
+        let data = big_file();
+        let (url, requested) = serve_ranges(data.clone(), Ranges::Sent);
+        let size = download_parallel(&url, File::create(dir.join("out")).unwrap(), DEFAULT_PARTS, None, None).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert!(fs::read(dir.join("out")).unwrap() == data);
+        // parts are at least MIN_PART_SIZE, so there's only room for 3
+        let mut requested = requested.lock().unwrap().clone();
+        requested.sort_by_key(|range| range.split('-').next().unwrap().parse::<u64>().unwrap());
+        assert_eq!(requested, ["0-1048581", "1048582-2097163", "2097164-3145744"]);
+        assert_eq!(checksum(&dir.join("out")).unwrap(), crc32(&data));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn servers_without_ranges_send_the_whole_file_at_once(){
+        let dir = temp_dir("parallel-unsupported");
+        let data = big_file();
+        let (url, requested) = serve_ranges(data.clone(), Ranges::Unsupported);
+        let size = download_parallel(&url, File::create(dir.join("out")).unwrap(), DEFAULT_PARTS, None, None).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert!(fs::read(dir.join("out")).unwrap() == data);
+        assert_eq!(checksum(&dir.join("out")).unwrap(), crc32(&data));
+        // a single request, which doesn't ask for a range
+        assert_eq!(*requested.lock().unwrap(), [""]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_parts_pick_up_where_they_left_off(){
+        let dir = temp_dir("parallel-dropped");
+        let data = big_file();
+        let (url, requested) = serve_ranges(data.clone(), Ranges::FirstDropped);
+        download_parallel(&url, File::create(dir.join("out")).unwrap(), 2, None, None).unwrap();
+        assert!(fs::read(dir.join("out")).unwrap() == data);
+        // the part that was cut off is asked for again from halfway through, not from the start
+        let requested = requested.lock().unwrap().clone();
+        assert_eq!(requested.len(), 3, "{:?}", requested);
+        assert!(requested.iter().any(|range| range == "786436-1572872" || range == "2359309-3145744"), "{:?}", requested);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_that_change_partway_through_are_invalid_data(){
+        let dir = temp_dir("parallel-changed");
+        let (url, _) = serve_ranges(big_file(), Ranges::Changed);
+        let e = download_parallel(&url, File::create(dir.join("out")).unwrap(), 2, None, None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checksums_are_crc32(){
+        let dir = temp_dir("checksum");
+        fs::write(dir.join("digits"), "123456789").unwrap();
+        assert_eq!(checksum(&dir.join("digits")).unwrap(), 0xCBF43926);
+        fs::write(dir.join("empty"), "").unwrap();
+        assert_eq!(checksum(&dir.join("empty")).unwrap(), 0);
+        assert_eq!(checksum(&dir.join("missing")).unwrap_err().kind(), ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_files_are_next_to_the_destination(){
+        let dir = temp_dir("temp");
+        fs::write(dir.join("dest"), "keep me").unwrap();
+        let (first, _) = create_temp(&dir.join("dest")).unwrap();
+        let (second, _) = create_temp(&dir.join("dest")).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(dir.as_path()));
+        assert_eq!(fs::read_to_string(dir.join("dest")).unwrap(), "keep me");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,41 +1,780 @@
-use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}};
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write}, os::unix::fs::PermissionsExt, path::{Component, Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+#[cfg(feature = "compression")]
+use flate2::{read::GzEncoder, write::GzDecoder, Compression};
 
 use super::secure_stream::SecureStream;
 
-/// Sends the given file through a SecureStream
-pub fn send(stream: &mut SecureStream, file: File) -> Result<(), io::Error>{
-    let mut buf_reader = BufReader::new(file);
+/// Running CRC-32 (IEEE) checksum, used to detect files that got corrupted during a transfer
+pub struct Crc32(u32);
+impl Crc32{
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256{
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8{
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    pub const fn new() -> Self{
+        Self(0xFFFFFFFF)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]){
+        for byte in bytes{
+            self.0 = Self::TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32{
+        !self.0
+    }
+}
+
+/// Callback used to report how many bytes of a transfer have been done so far, along with the total size if it is known
+pub type Progress<'a> = &'a mut dyn FnMut(u64, Option<u64>);
+
+/// Number of chunks between calls to a transfer's progress callback
+const PROGRESS_INTERVAL: u64 = 64;
+
+// first byte of a single file transfer, saying whether the chunks that follow are gzip compressed
+const FLAG_RAW: u8 = 0;
+const FLAG_GZIP: u8 = 1;
+
+/// Writer that refuses to let the total number of bytes written go over a limit
+struct Limited<W: Write>{
+    inner: W,
+    written: u64,
+    max_bytes: Option<u64>
+}
+impl<W: Write> Write for Limited<W>{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        if let Some(max) = self.max_bytes{
+            if self.written + buf.len() as u64 > max { return Err(limit_error(max)) }
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.inner.flush()
+    }
+}
+
+fn limit_error(max: u64) -> io::Error{
+    io::Error::other(format!("upload exceeds limit of {} bytes", max))
+}
+
+fn compression_unsupported() -> io::Error{
+    io::Error::other("this server was built without the compression feature")
+}
+
+/// Where `recv` writes the bytes it receives, which may need to be decompressed first
+enum Sink{
+    Raw(Limited<BufWriter<File>>),
+    #[cfg(feature = "compression")]
+    Gzip(GzDecoder<Limited<BufWriter<File>>>)
+}
+impl Sink{
+    /// Flushes everything to the file, checking that a compressed stream ended properly
+    fn finish(self) -> io::Result<()>{
+        match self{
+            Sink::Raw(mut w) => w.flush(),
+            #[cfg(feature = "compression")]
+            Sink::Gzip(d) => d.finish()?.flush()
+        }
+    }
+}
+impl Write for Sink{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        match self{
+            Sink::Raw(w) => w.write(buf),
+            #[cfg(feature = "compression")]
+            Sink::Gzip(d) => d.write(buf)
+        }
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        match self{
+            Sink::Raw(w) => w.flush(),
+            #[cfg(feature = "compression")]
+            Sink::Gzip(d) => d.flush()
+        }
+    }
+}
+
+/// Sends the given file through a SecureStream, gzip compressing it first if `compress` is set
+/// 
+/// The transfer starts with a byte saying whether it is compressed, followed by the total size of the file
+/// (or `u64::MAX` if it is unknown or compressed) so the receiver can show progress, and then the Unix mode and
+/// modification time of the file (a mode of 0 means they couldn't be read).\
+/// After the zero-length chunk that ends the file, a CRC-32 of the chunks is sent so the receiver can check it
+pub fn send<S: Read + Write>(stream: &mut SecureStream<S>, file: File, compress: bool, progress: Option<Progress>) -> Result<(), io::Error>{
+    send_from(stream, file, 0, compress, progress)
+}
+
+/// Sends the given file through a SecureStream, starting at `offset` bytes into the file
+/// 
+/// This is used to resume a transfer that was interrupted, where the receiver already has the first `offset` bytes.\
+/// Returns `io::ErrorKind::InvalidInput` if the receiver claims to have more bytes than the file contains
+pub fn send_from<S: Read + Write>(stream: &mut SecureStream<S>, mut file: File, offset: u64, compress: bool, mut progress: Option<Progress>) -> Result<(), io::Error>{
+    let metadata = file.metadata().ok();
+    let len = metadata.as_ref().filter(|m| m.is_file()).map(|m| m.len());
+    if let Some(len) = len{
+        if offset > len { return Err(io::Error::new(ErrorKind::InvalidInput, format!("Receiver already has {} bytes, but the file is only {} bytes", offset, len))) }
+    }
+    if compress && !cfg!(feature = "compression"){
+        return Err(compression_unsupported())
+    }
+    if offset > 0 { file.seek(SeekFrom::Start(offset))?; }
+    // the compressed size isn't known until we're done, so there's no total to give
+    let total = if compress { None } else { len.map(|len| len - offset) };
+    stream.write_all(&[if compress { FLAG_GZIP } else { FLAG_RAW }])?;
+    stream.write_all(&total.unwrap_or(u64::MAX).to_le_bytes())?;
+    write_file_metadata(stream, metadata.as_ref())?;
+
+    let mut buf_reader: Box<dyn Read> = match compress{
+        #[cfg(feature = "compression")]
+        true => Box::new(GzEncoder::new(BufReader::new(file), Compression::default())),
+        _ => Box::new(BufReader::new(file))
+    };
     let mut buf = [0u8; 1024];
-    let mut read_bytes = buf_reader.read(&mut buf)?;
-    while read_bytes!=0{
-        stream.write(&(read_bytes as u64).to_le_bytes())?;
-        stream.write(&buf[..read_bytes])?;
-        read_bytes = buf_reader.read(&mut buf)?;
+    let mut crc = Crc32::new();
+    let mut transferred = 0u64;
+    let mut chunks = 0u64;
+    // an empty file is just the zero-length chunk that ends it, and so is the read after the last full chunk of a
+    // file whose size is a multiple of the chunk size
+    loop{
+        let read_bytes = buf_reader.read(&mut buf)?;
+        if read_bytes == 0{
+            break
+        }
+        crc.update(&buf[..read_bytes]);
+        stream.write_all(&(read_bytes as u64).to_le_bytes())?;
+        stream.write_all(&buf[..read_bytes])?;
+
+        transferred += read_bytes as u64;
+        chunks += 1;
+        if chunks.is_multiple_of(PROGRESS_INTERVAL){
+            if let Some(cb) = progress.as_mut() { cb(transferred, total) }
+        }
     }
-    stream.write(&0u64.to_le_bytes())?; // signify that file has finished being sent
+    stream.write_all(&0u64.to_le_bytes())?; // signify that file has finished being sent
+    stream.write_all(&crc.finish().to_le_bytes())?;
+    if let Some(cb) = progress.as_mut() { cb(transferred, total) }
     Ok(())
 }
 
 /// Receives and writes a file which is being sent through the given SecureStream
-pub fn recv(stream: &mut SecureStream, file: File) -> Result<(), io::Error>{
-    let mut buf_writer = BufWriter::new(file);
+/// 
+/// To resume an interrupted transfer, open `file` in append mode and have the sender start from its current length.
+/// The file gets the sender's permissions and modification time, apart from the setuid, setgid and sticky bits.\
+/// Returns `io::ErrorKind::InvalidData` if the checksum sent after the file doesn't match what was received,
+/// `io::ErrorKind::UnexpectedEof` if the sender disconnects partway through, and `io::ErrorKind::Other` if the file
+/// would grow past `max_bytes`. The caller is responsible for removing the partial file
+/// 
+/// If the file can't be written (including when it goes over `max_bytes`), the rest of the transfer is still read and
+/// thrown away before returning, so none of it is mistaken for the commands that come after it
+pub fn recv<S: Read + Write>(stream: &mut SecureStream<S>, file: File, max_bytes: Option<u64>, mut progress: Option<Progress>) -> Result<(), io::Error>{
     let mut buf = [0u8; 1024];
     let mut size_buf = [0u8; 8];
+    let mut crc = Crc32::new();
+    let mut transferred = 0u64;
+    let mut chunks = 0u64;
+
+    let mut flag = [0u8];
+    stream.read_exact(&mut flag)?;
+    let metadata_handle = file.try_clone()?;
+    // anything already in the file (from a resumed transfer) counts towards the limit
+    let existing = metadata_handle.metadata().map(|m| m.len()).unwrap_or(0);
+    let limited = Limited{inner: BufWriter::new(file), written: existing, max_bytes};
+    // a compressed file that can't be decompressed is still read, so the stream is left at the end of it
+    let (mut buf_writer, unsupported) = match flag[0]{
+        FLAG_RAW => (Sink::Raw(limited), None),
+        #[cfg(feature = "compression")]
+        FLAG_GZIP => (Sink::Gzip(GzDecoder::new(limited)), None),
+        #[cfg(not(feature = "compression"))]
+        FLAG_GZIP => (Sink::Raw(limited), Some(compression_unsupported())),
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "Unknown file transfer format"))
+    };
 
-    // before every <=1024 bytes, we expect 8 bytes representing the number of bytes being sent
+    // the sender tells us the total size first, which is u64::MAX if they don't know it
     stream.read_exact(&mut size_buf)?;
-    let mut size = u64::from_le_bytes(size_buf) as usize; // (u64::from_le_bytes(size_buf) as usize + 7) / 8 * 8;
+    let total = Some(u64::from_le_bytes(size_buf)).filter(|t| *t != u64::MAX);
+    let file_metadata = read_file_metadata(stream)?;
+    // once writing fails, the rest of the file is read without being written anywhere
+    let mut failed = unsupported.or(match (total, max_bytes){
+        (Some(total), Some(max)) if existing + total > max => Some(limit_error(max)),
+        _ => None
+    });
+
+    // before every <=1024 bytes, we expect 8 bytes representing the number of bytes being sent. a chunk of 0 bytes
+    // ends the file, so an empty file is nothing but that
+    loop{
+        stream.read_exact(&mut size_buf)?;
+        let mut size = u64::from_le_bytes(size_buf) as usize;
+        if size == 0{
+            break
+        }
+        // read_exact rather than read, so a connection that closes partway through a chunk is an error instead of
+        // a loop reading nothing forever
+        while size > 0{
+            let read_bytes = size.min(buf.len());
+            stream.read_exact(&mut buf[..read_bytes]).map_err(|e| match e.kind(){
+                ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, "the connection closed partway through the file"),
+                _ => e
+            })?;
+            crc.update(&buf[..read_bytes]);
+            if failed.is_none(){
+                failed = buf_writer.write_all(&buf[..read_bytes]).err();
+            }
+            transferred += read_bytes as u64;
+            size -= read_bytes;
+        }
+        chunks += 1;
+        if chunks.is_multiple_of(PROGRESS_INTERVAL){
+            if let Some(cb) = progress.as_mut() { cb(transferred, total) }
+        }
+    }
+    let finished = match failed{
+        Some(e) => Err(e),
+        None => buf_writer.finish()
+    };
 
-    while size!=0{
-        let read_bytes = stream.read(&mut buf[..size.min(1024)])?;
-        buf_writer.write_all(&buf[..read_bytes])?;
+    let mut crc_buf = [0u8; 4];
+    stream.read_exact(&mut crc_buf)?;
+    finished?;
+    if u32::from_le_bytes(crc_buf) != crc.finish(){
+        return Err(io::Error::new(ErrorKind::InvalidData, "checksum mismatch, the file was corrupted during the transfer"))
+    }
+    if let Some((mode, mtime)) = file_metadata{
+        // like directory uploads, never keep setuid, setgid or sticky bits from the sender
+        metadata_handle.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+        metadata_handle.set_modified(mtime)?;
+    }
+    if let Some(cb) = progress.as_mut() { cb(transferred, total) }
+    Ok(())
+}
+
+/// Writes the Unix mode and modification time of a file, or a mode of 0 if they aren't available
+fn write_file_metadata<S: Read + Write>(stream: &mut SecureStream<S>, metadata: Option<&fs::Metadata>) -> Result<(), io::Error>{
+    let mode = metadata.map_or(0, |m| m.permissions().mode());
+    let mtime = metadata.and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    stream.write_all(&mode.to_le_bytes())?;
+    stream.write_all(&mtime.as_secs().to_le_bytes())?;
+    stream.write_all(&mtime.subsec_nanos().to_le_bytes())
+}
 
-        size-=read_bytes;
-        if size==0{
-            stream.read_exact(&mut size_buf)?;
-            size = u64::from_le_bytes(size_buf) as usize;
+/// Reads the metadata written by `write_file_metadata`, returning None if the sender couldn't read it
+fn read_file_metadata<S: Read + Write>(stream: &mut SecureStream<S>) -> Result<Option<(u32, SystemTime)>, io::Error>{
+    let mut u32_buf = [0u8; 4];
+    let mut u64_buf = [0u8; 8];
+    stream.read_exact(&mut u32_buf)?;
+    let mode = u32::from_le_bytes(u32_buf);
+    stream.read_exact(&mut u64_buf)?;
+    let secs = u64::from_le_bytes(u64_buf);
+    stream.read_exact(&mut u32_buf)?;
+    let nanos = u32::from_le_bytes(u32_buf).min(999_999_999);
+    if mode == 0 { return Ok(None) }
+    Ok(UNIX_EPOCH.checked_add(Duration::new(secs, nanos)).map(|mtime| (mode, mtime)))
+}
+
+// record kinds used by send_dir and recv_dir
+const RECORD_END: u8 = 0;
+const RECORD_DIR: u8 = 1;
+const RECORD_FILE: u8 = 2;
+
+/// Sends the directory at `dir` and everything inside it through a SecureStream
+/// 
+/// The subtree is sent as a sequence of records, each one starting with a byte for the kind of record,
+/// followed by the length-prefixed path relative to `dir`, the Unix mode, the size, and then the contents
+/// of the file. A single `RECORD_END` byte marks the end of the directory. 
+pub fn send_dir<S: Read + Write>(stream: &mut SecureStream<S>, dir: &Path) -> Result<(), io::Error>{
+    send_dir_entries(stream, dir, Path::new(""))?;
+    stream.write_all(&[RECORD_END])
+}
+
+fn send_dir_entries<S: Read + Write>(stream: &mut SecureStream<S>, root: &Path, rel: &Path) -> Result<(), io::Error>{
+    for entry in fs::read_dir(root.join(rel))?{
+        let entry = entry?;
+        let rel_path = rel.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let path_bytes = rel_path.as_os_str().as_encoded_bytes();
+        if metadata.is_dir(){
+            write_record_header(stream, RECORD_DIR, path_bytes, metadata.permissions().mode(), 0)?;
+            send_dir_entries(stream, root, &rel_path)?;
+        }else if metadata.is_file(){
+            write_record_header(stream, RECORD_FILE, path_bytes, metadata.permissions().mode(), metadata.len())?;
+            let mut file = File::open(entry.path())?.take(metadata.len());
+            let mut buf = [0u8; 1024];
+            let mut remaining = metadata.len();
+            while remaining > 0{
+                let read_bytes = file.read(&mut buf)?;
+                if read_bytes == 0 { break }
+                stream.write_all(&buf[..read_bytes])?;
+                remaining -= read_bytes as u64;
+            }
+            // the file shrunk while we were reading it, so pad it out to the size we already promised
+            while remaining > 0{
+                let pad = remaining.min(1024) as usize;
+                stream.write_all(&[0u8; 1024][..pad])?;
+                remaining -= pad as u64;
+            }
         }
+        // anything else (symlinks, sockets, ...) is skipped
     }
-    buf_writer.flush()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+fn write_record_header<S: Read + Write>(stream: &mut SecureStream<S>, kind: u8, path: &[u8], mode: u32, size: u64) -> Result<(), io::Error>{
+    stream.write_all(&[kind])?;
+    stream.write_all(&(path.len() as u64).to_le_bytes())?;
+    stream.write_all(path)?;
+    stream.write_all(&mode.to_le_bytes())?;
+    stream.write_all(&size.to_le_bytes())
+}
+
+/// Receives a directory sent by `send_dir`, recreating its subtree under `dest`
+/// 
+/// Files keep their permissions, apart from the setuid, setgid and sticky bits.\
+/// Returns `io::ErrorKind::Other` if the directory's total size would go over `max_bytes`. If anything goes wrong, the
+/// files and directories that were created are removed again, and as long as the records themselves could be read, the
+/// rest of the transfer is read and thrown away first so none of it is mistaken for the commands that come after it
+pub fn recv_dir<S: Read + Write>(stream: &mut SecureStream<S>, dest: &Path, max_bytes: Option<u64>) -> Result<(), io::Error>{
+    let mut created = Vec::new();
+    let res = recv_dir_records(stream, dest, max_bytes, &mut created);
+    if res.is_err(){
+        // newest first, so each directory has already been emptied by the time it's removed
+        for path in created.iter().rev(){
+            let _ = if path.is_dir() { fs::remove_dir(path) } else { fs::remove_file(path) };
+        }
+    }
+    res
+}
+
+/// Does the work of `recv_dir`, adding every file and directory it creates to `created`
+fn recv_dir_records<S: Read + Write>(stream: &mut SecureStream<S>, dest: &Path, max_bytes: Option<u64>, created: &mut Vec<PathBuf>) -> Result<(), io::Error>{
+    create_dirs(dest, created)?;
+    let mut received = 0u64;
+    let mut kind = [0u8];
+    let mut u64_buf = [0u8; 8];
+    let mut u32_buf = [0u8; 4];
+    let mut buf = [0u8; 1024];
+    // once something goes wrong, the rest of the records are read without writing anything
+    let mut failed: Option<io::Error> = None;
+    loop{
+        stream.read_exact(&mut kind)?;
+        if kind[0] == RECORD_END { return failed.map_or(Ok(()), Err) }
+
+        stream.read_exact(&mut u64_buf)?;
+        let path_len = u64::from_le_bytes(u64_buf) as usize;
+        if path_len > 4096 { return Err(io::Error::new(ErrorKind::InvalidData, "Path in directory transfer is too long")) }
+        let mut path_bytes = vec![0u8; path_len];
+        stream.read_exact(&mut path_bytes)?;
+        stream.read_exact(&mut u32_buf)?;
+        let mode = u32::from_le_bytes(u32_buf);
+        stream.read_exact(&mut u64_buf)?;
+        let mut size = u64::from_le_bytes(u64_buf);
+
+        let path = match relative_path(&path_bytes){
+            Ok(rel_path) => dest.join(rel_path),
+            Err(e) => {
+                failed.get_or_insert(e);
+                PathBuf::new()
+            }
+        };
+        match kind[0]{
+            RECORD_DIR => {
+                if failed.is_none(){
+                    failed = create_dirs(&path, created).err();
+                }
+            },
+            RECORD_FILE => {
+                received = received.saturating_add(size);
+                if let Some(max) = max_bytes.filter(|max| received > *max){
+                    failed.get_or_insert(limit_error(max));
+                }
+                let mut file = None;
+                if failed.is_none(){
+                    // files that were already there are overwritten, but they aren't ours to remove if the transfer fails
+                    let existed = fs::symlink_metadata(&path).is_ok();
+                    match File::create(&path){
+                        Ok(f) => {
+                            if !existed { created.push(path.clone()); }
+                            file = Some(BufWriter::new(f));
+                        },
+                        Err(e) => failed = Some(e)
+                    }
+                }
+                while size > 0{
+                    let read_bytes = stream.read(&mut buf[..size.min(1024) as usize])?;
+                    if read_bytes == 0 { return Err(io::Error::from(ErrorKind::UnexpectedEof)) }
+                    if let Some(Err(e)) = file.as_mut().map(|f| f.write_all(&buf[..read_bytes])){
+                        failed = Some(e);
+                        file = None;
+                    }
+                    size -= read_bytes as u64;
+                }
+                if let Some(mut file) = file{
+                    // setuid, setgid and sticky bits are left off, since a server running as root would otherwise be
+                    // writing root-owned setuid programs for whoever sent them
+                    failed = file.flush().and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))).err();
+                }
+            },
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "Unknown record in directory transfer"))
+        }
+    }
+}
+
+/// Creates `dir` along with any of its parents that are missing, adding the ones it made to `created`
+fn create_dirs(dir: &Path, created: &mut Vec<PathBuf>) -> Result<(), io::Error>{
+    let mut missing: Vec<&Path> = dir.ancestors().take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists()).collect();
+    fs::create_dir_all(dir)?;
+    missing.reverse();
+    created.extend(missing.into_iter().map(Path::to_owned));
+    Ok(())
+}
+
+/// Parses a path received from the other end of a directory transfer, making sure it
+/// can't point outside of the directory being received into
+fn relative_path(bytes: &[u8]) -> Result<PathBuf, io::Error>{
+    let path = PathBuf::from(String::from_utf8_lossy(bytes).into_owned());
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))){
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid path in directory transfer: {}", path.display())))
+    }
+    Ok(path)
+}
+
+/// Resolves `requested` relative to `base`, returning `io::ErrorKind::PermissionDenied` if the result isn't inside `base`
+/// 
+/// Symlinks are followed before checking, so a link pointing outside of `base` is rejected too. The last component of
+/// `requested` doesn't have to exist yet, so this can also be used for the destination of an upload
+pub fn sanitize_within(base: &Path, requested: &Path) -> Result<PathBuf, io::Error>{
+    let base = base.canonicalize()?;
+    let joined = base.join(requested);
+    let resolved = match joined.canonicalize(){
+        Ok(path) => path,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let file_name = match joined.components().next_back(){
+                Some(Component::Normal(name)) => name.to_owned(),
+                _ => return Err(e)
+            };
+            joined.parent().ok_or(e)?.canonicalize()?.join(file_name)
+        },
+        Err(e) => return Err(e)
+    };
+    if resolved.starts_with(&base){
+        Ok(resolved)
+    }else{
+        Err(io::Error::new(ErrorKind::PermissionDenied, format!("{} is outside of {}", requested.display(), base.display())))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+
+    use super::*;
+
+    const HASH: u64 = 0x1234_5678_9abc_def0;
+
+    /// A fresh, empty directory for a test to work in
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("rspi-file-transfer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs the sending half of a transfer, returning a stream the receiving half can read it back from
+    fn transfer(send: impl FnOnce(&mut SecureStream<Cursor<Vec<u8>>>) -> io::Result<()>) -> SecureStream<Cursor<Vec<u8>>>{
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        send(&mut sender).unwrap();
+        SecureStream::new(Cursor::new(sender.stream.into_inner())).set_hash(HASH)
+    }
+
+    fn mode_of(path: &Path) -> u32{
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// Sends the file at `src` and receives it into `dest`
+    fn transfer_file(src: &Path, dest: &Path, compress: bool) -> io::Result<()>{
+        let mut received = transfer(|stream| send(stream, File::open(src)?, compress, None));
+        recv(&mut received, File::create(dest)?, None, None)
+    }
+
+    #[test]
+    fn files_round_trip(){
+        let dir = temp_dir("file-round-trip");
+        // a multiple of the chunk size, so the last read before the end of the file is empty
+        let contents: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("src"), &contents).unwrap();
+        fs::set_permissions(dir.join("src"), fs::Permissions::from_mode(0o600)).unwrap();
+        for compress in [false, cfg!(feature = "compression")]{
+            transfer_file(&dir.join("src"), &dir.join("dest"), compress).unwrap();
+            assert_eq!(fs::read(dir.join("dest")).unwrap(), contents);
+            assert_eq!(mode_of(&dir.join("dest")), 0o600);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn transfers_cut_off_partway_through_are_unexpected_eof(){
+        let dir = temp_dir("file-truncated");
+        fs::write(dir.join("src"), vec![9u8; 3000]).unwrap();
+        let mut sender = SecureStream::new_plain(Cursor::new(Vec::new()));
+        send(&mut sender, File::open(dir.join("src")).unwrap(), false, None).unwrap();
+        let sent = sender.stream.into_inner();
+        // in the header, a chunk's length, a chunk, the chunk that ends the file, and the checksum
+        for cut in [5, 25 + 4, 25 + 8 + 500, sent.len() - 8, sent.len() - 1]{
+            let started = std::time::Instant::now();
+            let mut received = SecureStream::new_plain(Cursor::new(sent[..cut].to_vec()));
+            let err = recv(&mut received, File::create(dir.join("dest")).unwrap(), None, None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "cut at {}", cut);
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn empty_files_round_trip(){
+        let dir = temp_dir("file-empty");
+        fs::write(dir.join("src"), "").unwrap();
+        transfer_file(&dir.join("src"), &dir.join("dest"), false).unwrap();
+        assert_eq!(fs::read(dir.join("dest")).unwrap(), b"");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn files_lose_setuid_setgid_and_sticky_bits(){
+        let dir = temp_dir("file-setuid");
+        fs::write(dir.join("src"), "#!/bin/sh").unwrap();
+        fs::set_permissions(dir.join("src"), fs::Permissions::from_mode(0o6755)).unwrap();
+        transfer_file(&dir.join("src"), &dir.join("dest"), false).unwrap();
+        assert_eq!(mode_of(&dir.join("dest")), 0o755);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn files_corrupted_on_the_wire_are_invalid_data(){
+        let dir = temp_dir("file-corrupted");
+        fs::write(dir.join("src"), vec![5u8; 3000]).unwrap();
+        let mut sent = Vec::new();
+        transfer(|stream| send(stream, File::open(dir.join("src"))?, false, None)).stream.read_to_end(&mut sent).unwrap();
+        // flip a byte in the middle of the second chunk, after the flag, size, metadata and two chunk lengths
+        sent[25 + 8 + 1024 + 8 + 512] ^= 0xFF;
+        let mut received = transfer(|stream| stream.stream.write_all(&sent));
+        let err = recv(&mut received, File::create(dir.join("dest")).unwrap(), None, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // the checksum is still read, so nothing is left behind to be mistaken for the next message
+        assert!(fully_read(&received));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Whether everything sent was read, so nothing would be left over to be taken for commands
+    fn fully_read(stream: &SecureStream<Cursor<Vec<u8>>>) -> bool{
+        stream.stream.position() == stream.stream.get_ref().len() as u64
+    }
+
+    #[test]
+    fn progress_adds_up_to_the_file(){
+        let dir = temp_dir("progress");
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("src"), &contents).unwrap();
+        let len = contents.len() as u64;
+
+        let mut sent = Vec::new();
+        let mut received = transfer(|stream| send(stream, File::open(dir.join("src"))?, false, Some(&mut |done, total| sent.push((done, total)))));
+        let mut recved = Vec::new();
+        recv(&mut received, File::create(dir.join("dest")).unwrap(), None, Some(&mut |done, total| recved.push((done, total)))).unwrap();
+
+        for calls in [&sent, &recved]{
+            assert!(calls.len() > 1);
+            assert!(calls.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+            assert!(calls.iter().all(|(_, total)| *total == Some(len)));
+            assert_eq!(calls.last(), Some(&(len, Some(len))));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_files_are_smaller_on_the_wire(){
+        let dir = temp_dir("file-gzip");
+        let contents = "the same line, over and over\n".repeat(1000);
+        fs::write(dir.join("src"), &contents).unwrap();
+        // read back through a SecureStream, so what's checked is what was sent rather than how it was shuffled
+        let mut sent = Vec::new();
+        transfer(|stream| send(stream, File::open(dir.join("src"))?, true, None)).read_to_end(&mut sent).unwrap();
+        assert_eq!(sent[0], FLAG_GZIP);
+        // the size isn't known up front
+        assert_eq!(sent[1..9], u64::MAX.to_le_bytes());
+        assert!(sent.len() < contents.len() / 10, "{} bytes sent", sent.len());
+
+        let mut totals = Vec::new();
+        let mut received = transfer(|stream| stream.write_all(&sent));
+        recv(&mut received, File::create(dir.join("dest")).unwrap(), None, Some(&mut |_, total| totals.push(total))).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("dest")).unwrap(), contents);
+        assert!(totals.iter().all(Option::is_none));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compression_is_unsupported_without_the_feature(){
+        let dir = temp_dir("file-no-gzip");
+        fs::write(dir.join("src"), "hello").unwrap();
+        let mut sent = Vec::new();
+        transfer(|stream| {
+            let err = send(stream, File::open(dir.join("src"))?, true, None).unwrap_err();
+            assert!(err.to_string().contains("without the compression feature"), "{}", err);
+            send(stream, File::open(dir.join("src"))?, false, None)
+        }).read_to_end(&mut sent).unwrap();
+
+        // a client that compresses anyways has its file read and thrown away
+        sent[0] = FLAG_GZIP;
+        let mut received = transfer(|stream| stream.write_all(&sent));
+        let err = recv(&mut received, File::create(dir.join("dest")).unwrap(), None, None).unwrap_err();
+        assert!(err.to_string().contains("without the compression feature"), "{}", err);
+        assert!(fully_read(&received));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn transfers_resume_from_an_offset(){
+        let dir = temp_dir("file-resume");
+        let contents: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("src"), &contents).unwrap();
+        for compress in [false, cfg!(feature = "compression")]{
+            fs::write(dir.join("dest"), &contents[..3000]).unwrap();
+            let mut received = transfer(|stream| send_from(stream, File::open(dir.join("src"))?, 3000, compress, None));
+            recv(&mut received, fs::OpenOptions::new().append(true).open(dir.join("dest")).unwrap(), None, None).unwrap();
+            assert!(fs::read(dir.join("dest")).unwrap() == contents);
+        }
+        // the receiver can't already have more than there is
+        transfer(|stream| {
+            let err = send_from(stream, File::open(dir.join("src")).unwrap(), 10001, false, None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            Ok(())
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_files_are_rejected_and_read_to_the_end(){
+        let dir = temp_dir("file-oversized");
+        fs::write(dir.join("src"), vec![7u8; 5000]).unwrap();
+        // compressed files don't say how big they are up front, so they're only caught while being written
+        for compress in [false, cfg!(feature = "compression")]{
+            let mut received = transfer(|stream| send(stream, File::open(dir.join("src"))?, compress, None));
+            let err = recv(&mut received, File::create(dir.join("dest")).unwrap(), Some(4096), None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Other);
+            assert!(fully_read(&received));
+            assert!(fs::metadata(dir.join("dest")).unwrap().len() <= 4096);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_directories_are_rejected_removed_and_read_to_the_end(){
+        let dir = temp_dir("dir-oversized");
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        fs::write(dir.join("src/sub/small"), vec![1u8; 100]).unwrap();
+        // whichever of these is sent first overwrites a file that was already in the destination, and the other one
+        // goes over the limit, so an existing file is always written to before the transfer fails
+        fs::write(dir.join("src/one"), vec![2u8; 3000]).unwrap();
+        fs::write(dir.join("src/two"), vec![3u8; 3000]).unwrap();
+        fs::create_dir_all(dir.join("existing")).unwrap();
+        fs::write(dir.join("existing/one"), "mine").unwrap();
+        fs::write(dir.join("existing/two"), "mine").unwrap();
+
+        for dest in ["dest", "existing"]{
+            let mut received = transfer(|stream| send_dir(stream, &dir.join("src")));
+            let err = recv_dir(&mut received, &dir.join(dest), Some(4096)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Other);
+            assert!(fully_read(&received));
+        }
+        assert!(!dir.join("dest").exists());
+        assert!(!dir.join("existing/sub").exists());
+        assert!(dir.join("existing/one").exists() && dir.join("existing/two").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directories_round_trip(){
+        let dir = temp_dir("dir-round-trip");
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::write(dir.join("src/a.txt"), "hello").unwrap();
+        fs::write(dir.join("src/nested/b.bin"), [0u8, 1, 2, 255]).unwrap();
+        fs::write(dir.join("src/nested/empty"), "").unwrap();
+        fs::write(dir.join("src/empty"), "").unwrap();
+        fs::create_dir(dir.join("src/nothing")).unwrap();
+        fs::set_permissions(dir.join("src/a.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut received = transfer(|stream| send_dir(stream, &dir.join("src")));
+        recv_dir(&mut received, &dir.join("dest"), None).unwrap();
+        assert_eq!(fs::read(dir.join("dest/a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dir.join("dest/nested/b.bin")).unwrap(), [0u8, 1, 2, 255]);
+        assert_eq!(fs::read(dir.join("dest/nested/empty")).unwrap(), b"");
+        // zero-byte files and empty directories are records with nothing after them
+        assert_eq!(fs::read(dir.join("dest/empty")).unwrap(), b"");
+        assert_eq!(fs::read_dir(dir.join("dest/nothing")).unwrap().count(), 0);
+        assert_eq!(mode_of(&dir.join("dest/a.txt")), 0o640);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directories_lose_setuid_setgid_and_sticky_bits(){
+        let dir = temp_dir("dir-setuid");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/prog"), "#!/bin/sh").unwrap();
+        fs::set_permissions(dir.join("src/prog"), fs::Permissions::from_mode(0o7755)).unwrap();
+
+        let mut received = transfer(|stream| send_dir(stream, &dir.join("src")));
+        recv_dir(&mut received, &dir.join("dest"), None).unwrap();
+        assert_eq!(mode_of(&dir.join("dest/prog")), 0o755);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_cant_leave_the_base(){
+        let dir = temp_dir("sanitize");
+        let base = dir.join("base");
+        fs::create_dir_all(base.join("sub")).unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        std::os::unix::fs::symlink(&dir, base.join("out")).unwrap();
+        std::os::unix::fs::symlink(base.join("sub"), base.join("in")).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        assert_eq!(sanitize_within(&base, Path::new("sub/../sub")).unwrap(), base.join("sub"));
+        // uploads name a file that isn't there yet
+        assert_eq!(sanitize_within(&base, Path::new("sub/new.txt")).unwrap(), base.join("sub/new.txt"));
+        assert_eq!(sanitize_within(&base, Path::new("in/new.txt")).unwrap(), base.join("sub/new.txt"));
+        assert_eq!(sanitize_within(&base, &base.join("sub")).unwrap(), base.join("sub"));
+        for escape in ["../secret", "sub/../../secret", "../new.txt", "out/secret", "out/new.txt", "/etc/passwd", "/"]{
+            assert_eq!(sanitize_within(&base, Path::new(escape)).unwrap_err().kind(), ErrorKind::PermissionDenied, "{}", escape);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directory_paths_cant_leave_the_destination(){
+        let dir = temp_dir("dir-escape");
+        let mut received = transfer(|stream| {
+            write_record_header(stream, RECORD_FILE, b"../escaped", 0o644, 0)?;
+            stream.write_all(&[RECORD_END])
+        });
+        assert_eq!(recv_dir(&mut received, &dir.join("dest"), None).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.join("escaped").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{fmt, fs, io::{self, ErrorKind}, os::unix::fs::{symlink, PermissionsExt}, path::{Component, Path, PathBuf}};
+
+use super::{file_transfer, json};
+
+/// Like `file_transfer::sanitize_within`, but a symlink at the end of `requested` is left alone rather than followed
+/// 
+/// Used for paths that are moved or removed, so it's the link that gets acted on instead of what it points to
+pub fn sanitize_entry(base: &Path, requested: &Path) -> io::Result<PathBuf>{
+    let name = match requested.components().next_back(){
+        Some(Component::Normal(name)) => name.to_owned(),
+        _ => return file_transfer::sanitize_within(base, requested)
+    };
+    let parent = requested.parent().unwrap_or(Path::new(""));
+    Ok(file_transfer::sanitize_within(base, parent)?.join(name))
+}
+
+/// Where `src` ends up when moved or copied to `dst`, which is inside of `dst` if it's a directory
+fn destination(src: &Path, dst: &Path) -> PathBuf{
+    match src.file_name(){
+        Some(name) if dst.is_dir() => dst.join(name),
+        _ => dst.to_owned()
+    }
+}
+
+/// Moves `src` to `dst`, or into `dst` if it is a directory, returning where it ended up
+/// 
+/// Falls back on copying and then removing `src` when it's on a different filesystem than `dst`
+pub fn move_path(src: &Path, dst: &Path) -> io::Result<PathBuf>{
+    let dst = destination(src, dst);
+    if dst.starts_with(src) && dst != src{
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Can't move {} inside of itself", src.display())))
+    }
+    match fs::rename(src, &dst){
+        Ok(()) => Ok(dst),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_entry(src, &dst)?;
+            remove_path(src, true)?;
+            Ok(dst)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// Copies `src` to `dst`, or into `dst` if it is a directory, returning where the copy ended up
+/// 
+/// Directories are only copied if `recursive` is set, otherwise this returns `io::ErrorKind::IsADirectory`
+pub fn copy_path(src: &Path, dst: &Path, recursive: bool) -> io::Result<PathBuf>{
+    if src.is_dir() && !recursive{
+        return Err(io::Error::new(ErrorKind::IsADirectory, format!("{} is a directory, use -r to copy it", src.display())))
+    }
+    let dst = destination(src, dst);
+    if dst.starts_with(src){
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Can't copy {} onto or inside of itself", src.display())))
+    }
+    copy_entry(src, &dst)?;
+    Ok(dst)
+}
+
+/// Copies a file, symlink or directory (along with everything in it) to exactly `dst`
+/// 
+/// Symlinks are copied as links, so copying a directory never pulls in anything from outside of it
+fn copy_entry(src: &Path, dst: &Path) -> io::Result<()>{
+    let file_type = fs::symlink_metadata(src)?.file_type();
+    if file_type.is_symlink(){
+        symlink(fs::read_link(src)?, dst)
+    }else if file_type.is_dir(){
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)?{
+            let entry = entry?;
+            copy_entry(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        Ok(())
+    }else{
+        fs::copy(src, dst).map(|_| ())
+    }
+}
+
+/// Removes a file or symlink, or a directory along with everything in it if `recursive` is set
+/// 
+/// Returns `io::ErrorKind::IsADirectory` for directories when `recursive` isn't set
+pub fn remove_path(path: &Path, recursive: bool) -> io::Result<()>{
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if !file_type.is_dir(){
+        fs::remove_file(path)
+    }else if recursive{
+        fs::remove_dir_all(path)
+    }else{
+        Err(io::Error::new(ErrorKind::IsADirectory, format!("{} is a directory, use -r to remove it", path.display())))
+    }
+}
+
+/// Removes `requested` like `remove_path`, after checking that it's inside of `root` with `sanitize_entry`, returning
+/// the path that was removed
+/// 
+/// Returns `io::ErrorKind::PermissionDenied` rather than removing `root` itself, `cwd` or any directory `cwd` is in,
+/// along with anything ending in `.` or `..`, so a session can't pull its own directory out from under itself
+pub fn remove_within(root: &Path, cwd: &Path, requested: &Path, recursive: bool) -> io::Result<PathBuf>{
+    // `Path` drops a trailing `.`, so look at what was actually asked for
+    let raw = requested.as_os_str().as_encoded_bytes();
+    let last = raw.rsplit(|b| *b == b'/').find(|part| !part.is_empty());
+    if matches!(last, Some(b"." | b"..")){
+        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("Refusing to remove {}, which ends in . or ..", requested.display())))
+    }
+    let path = sanitize_entry(root, requested)?;
+    if path == root || cwd.starts_with(&path){
+        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("Refusing to remove {}, which the current directory is in", path.display())))
+    }
+    remove_path(&path, recursive)?;
+    Ok(path)
+}
+
+/// What kind of thing a directory entry is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind{
+    Dir,
+    File,
+    Symlink,
+    Other
+}
+impl fmt::Display for EntryKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.write_str(match self{
+            EntryKind::Dir => "dir",
+            EntryKind::File => "file",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Other => "other"
+        })
+    }
+}
+
+/// An entry of a directory listed by `list_dir`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry{
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32
+}
+impl DirEntry{
+    /// One tab-separated line of the kind, size, octal permissions and name of this entry
+    /// 
+    /// The name goes last so that it can have tabs in it without throwing the other fields off
+    pub fn to_tsv(&self) -> String{
+        format!("{}\t{}\t{:o}\t{}\n", self.kind, self.size, self.mode, self.name)
+    }
+
+    pub fn to_json(&self) -> String{
+        format!("{{\"name\":{},\"type\":\"{}\",\"size\":{},\"mode\":\"{:o}\"}}", json::escape(&self.name), self.kind, self.size, self.mode)
+    }
+}
+
+/// Lists the entries of a directory, directories first and then by name
+/// 
+/// Symlinks are reported as they are, rather than as what they point to
+pub fn list_dir(path: &Path) -> io::Result<Vec<DirEntry>>{
+    let mut entries = fs::read_dir(path)?.map(|entry| {
+        let entry = entry?;
+        let meta = fs::symlink_metadata(entry.path())?;
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() { EntryKind::Dir }
+            else if file_type.is_symlink() { EntryKind::Symlink }
+            else if file_type.is_file() { EntryKind::File }
+            else { EntryKind::Other };
+        Ok(DirEntry{name: entry.file_name().to_string_lossy().into_owned(), kind, size: meta.len(), mode: meta.permissions().mode() & 0o7777})
+    }).collect::<io::Result<Vec<DirEntry>>>()?;
+    entries.sort_by(|a, b| (a.kind != EntryKind::Dir, &a.name).cmp(&(b.kind != EntryKind::Dir, &b.name)));
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests{
+    use std::env;
+
+    use super::*;
+
+    /// A fresh, empty directory for a test to work in, with symlinks resolved
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-fileops-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn files_move_into_directories(){
+        let dir = temp_dir("move");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        assert_eq!(move_path(&dir.join("a.txt"), &dir.join("sub")).unwrap(), dir.join("sub/a.txt"));
+        assert_eq!(move_path(&dir.join("sub/a.txt"), &dir.join("b.txt")).unwrap(), dir.join("b.txt"));
+        assert_eq!(fs::read(dir.join("b.txt")).unwrap(), b"hello");
+        assert!(!dir.join("a.txt").exists() && !dir.join("sub/a.txt").exists());
+        assert_eq!(move_path(&dir.join("sub"), &dir.join("sub/deeper")).unwrap_err().kind(), ErrorKind::InvalidInput);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directories_only_copy_recursively(){
+        let dir = temp_dir("copy");
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::write(dir.join("src/nested/file"), "data").unwrap();
+        symlink("nested/file", dir.join("src/link")).unwrap();
+
+        assert_eq!(copy_path(&dir.join("src"), &dir.join("dst"), false).unwrap_err().kind(), ErrorKind::IsADirectory);
+        assert_eq!(copy_path(&dir.join("src"), &dir.join("dst"), true).unwrap(), dir.join("dst"));
+        assert_eq!(fs::read(dir.join("dst/nested/file")).unwrap(), b"data");
+        assert_eq!(fs::read_link(dir.join("dst/link")).unwrap(), Path::new("nested/file"));
+        assert_eq!(copy_path(&dir.join("src"), &dir.join("src/nested"), true).unwrap_err().kind(), ErrorKind::InvalidInput);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directories_list_directories_first_then_by_name(){
+        let dir = temp_dir("list");
+        fs::create_dir_all(dir.join("zoo")).unwrap();
+        fs::write(dir.join("b.txt"), "12345").unwrap();
+        fs::write(dir.join("a\tb"), "").unwrap();
+        fs::set_permissions(dir.join("b.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("missing", dir.join("link")).unwrap();
+
+        let entries = list_dir(&dir).unwrap();
+        let listed: Vec<(&str, EntryKind)> = entries.iter().map(|entry| (entry.name.as_str(), entry.kind)).collect();
+        assert_eq!(listed, [("zoo", EntryKind::Dir), ("a\tb", EntryKind::File), ("b.txt", EntryKind::File), ("link", EntryKind::Symlink)]);
+        assert_eq!((entries[2].size, entries[2].mode), (5, 0o640));
+        assert_eq!(list_dir(&dir.join("missing")).unwrap_err().kind(), ErrorKind::NotFound);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn entries_are_written_as_tsv_or_json(){
+        let entry = DirEntry{name: String::from("say \"hi\"\tnow"), kind: EntryKind::File, size: 42, mode: 0o755};
+        assert_eq!(entry.to_tsv(), "file\t42\t755\tsay \"hi\"\tnow\n");
+        let json = json::parse(&entry.to_json()).unwrap();
+        assert_eq!(json.get("name").and_then(json::Value::as_str), Some("say \"hi\"\tnow"));
+        assert_eq!(json.get("type").and_then(json::Value::as_str), Some("file"));
+        assert_eq!(json.get("size").and_then(json::Value::as_u64), Some(42));
+        assert_eq!(json.get("mode").and_then(json::Value::as_str), Some("755"));
+    }
+
+    #[test]
+    fn removing_is_kept_inside_the_root(){
+        let dir = temp_dir("remove");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("cwd/sub")).unwrap();
+        fs::write(dir.join("outside"), "").unwrap();
+        fs::write(root.join("cwd/file"), "").unwrap();
+        let cwd = root.join("cwd");
+
+        for refused in ["../../outside", "/", ".", "..", "sub/..", "sub/.", "./", &root.display().to_string(), &cwd.display().to_string()]{
+            let err = remove_within(&root, &cwd, &cwd.join(refused), true).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied, "rm -r {}", refused);
+        }
+        assert!(dir.join("outside").exists() && cwd.join("sub").exists());
+
+        assert_eq!(remove_within(&root, &cwd, &cwd.join("sub"), false).unwrap_err().kind(), ErrorKind::IsADirectory);
+        assert_eq!(remove_within(&root, &cwd, &cwd.join("sub"), true).unwrap(), cwd.join("sub"));
+        assert_eq!(remove_within(&root, &cwd, &cwd.join("file"), false).unwrap(), cwd.join("file"));
+        assert!(fs::read_dir(&cwd).unwrap().next().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{fs, path::Path};
+
+use super::file_transfer;
+
+/// Whether `arg` has any of the characters that make it a pattern
+fn is_pattern(arg: &str) -> bool{
+    arg.contains(['*', '?', '['])
+}
+
+/// Whether `name` matches all of `pattern`, where `*` matches any run of characters, `?` any one character and `[...]`
+/// any one of the characters listed (or any character not listed if it starts with `!` or `^`)
+pub fn matches(pattern: &str, name: &str) -> bool{
+    matches_chars(&pattern.chars().collect::<Vec<char>>(), &name.chars().collect::<Vec<char>>())
+}
+
+/// Matches without recursing, by going back to the last `*` seen whenever the rest of the pattern doesn't match and
+/// letting it take one more character. Earlier stars never need to be revisited, so this can't blow up on patterns
+/// with lots of them
+fn matches_chars(pattern: &[char], name: &[char]) -> bool{
+    let (mut p, mut n) = (0, 0);
+    // where the last star is in the pattern, and where the name carries on after what it has matched so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len(){
+        // how much of the pattern matched the next character of the name
+        let matched = match pattern.get(p){
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+                continue
+            },
+            Some('?') => Some(1),
+            Some('[') => match match_class(&pattern[p + 1..], Some(name[n])){
+                Some((true, len)) => Some(len + 1),
+                Some((false, _)) => None,
+                // a `[` that's never closed is just a `[`
+                None => (name[n] == '[').then_some(1)
+            },
+            Some(c) => (name[n] == *c).then_some(1),
+            None => None
+        };
+        match (matched, star){
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            },
+            (None, Some((star_p, star_n))) => {
+                star = Some((star_p, star_n + 1));
+                p = star_p + 1;
+                n = star_n + 1;
+            },
+            (None, None) => return false
+        }
+    }
+    // stars can match nothing, but anything else left over needs more of the name
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Checks `c` against the character class at the start of `class` (just after its `[`), returning whether it matched
+/// and how many characters of `class` there were up to and including the `]`. None if the class is never closed
+fn match_class(class: &[char], c: Option<char>) -> Option<(bool, usize)>{
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut i = if negated { 1 } else { 0 };
+    let mut found = false;
+    let mut first = true;
+    loop{
+        match class.get(i){
+            None => return None,
+            // a `]` right at the start is part of the class rather than the end of it
+            Some(']') if !first => return Some((found != negated, i + 1)),
+            Some(&low) => {
+                if let (Some('-'), Some(&high)) = (class.get(i + 1), class.get(i + 2)){
+                    if high != ']'{
+                        found |= c.is_some_and(|c| low <= c && c <= high);
+                        i += 3;
+                        first = false;
+                        continue
+                    }
+                }
+                found |= c == Some(low);
+                i += 1;
+            }
+        }
+        first = false;
+    }
+}
+
+/// Expands `arg` to the paths it matches, relative to `cwd` unless it's absolute, like a shell would
+/// 
+/// Each part of the path can be a pattern, so `src/*/mod.rs` works. Hidden files are only matched by patterns starting
+/// with a `.`, and when `root` is given, paths outside of it aren't matched. Arguments that aren't patterns, or that
+/// don't match anything, are left as they are
+pub fn expand(arg: &str, cwd: &Path, root: Option<&Path>) -> Vec<String>{
+    if !is_pattern(arg){
+        return vec![arg.to_owned()]
+    }
+    let (mut found, parts) = match arg.strip_prefix('/'){
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], arg)
+    };
+    let parts: Vec<&str> = parts.split('/').collect();
+    for (i, part) in parts.iter().enumerate(){
+        let last = i == parts.len() - 1;
+        let mut next = Vec::new();
+        for prefix in found{
+            if !is_pattern(part){
+                next.push(format!("{}{}{}", prefix, part, if last { "" } else { "/" }));
+                continue
+            }
+            let Ok(entries) = fs::read_dir(cwd.join(if prefix.is_empty() { "." } else { &prefix })) else { continue };
+            let mut names: Vec<String> = entries.flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| (!name.starts_with('.') || part.starts_with('.')) && matches(part, name))
+                .collect();
+            names.sort();
+            for name in names{
+                let path = format!("{}{}", prefix, name);
+                // patterns in the middle of a path can only match directories
+                if !last && !cwd.join(&path).is_dir(){
+                    continue
+                }
+                next.push(if last { path } else { path + "/" });
+            }
+        }
+        found = next;
+    }
+    // the parts that aren't patterns were never checked, so `*/mod.rs` could have found directories without one
+    found.retain(|path| cwd.join(path).symlink_metadata().is_ok());
+    if let Some(root) = root{
+        found.retain(|path| file_transfer::sanitize_within(root, &cwd.join(path)).is_ok());
+    }
+    if found.is_empty(){
+        vec![arg.to_owned()]
+    }else{
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{env, path::PathBuf};
+
+    use super::*;
+
+    /// A fresh, empty directory for a test to work in, with symlinks resolved
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-glob-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn patterns_match_like_a_shell(){
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(!matches("a*b*c", "aXXbYY"));
+        assert!(matches("?at", "cat") && !matches("?at", "at"));
+        assert!(matches("[ch]at", "hat") && !matches("[ch]at", "bat"));
+        assert!(matches("[!ch]at", "bat") && !matches("[^ch]at", "cat"));
+        assert!(matches("file[0-9]", "file7") && !matches("file[0-9]", "filex"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[abc", "[abc") && !matches("[abc", "a"));
+    }
+
+    #[test]
+    fn lots_of_stars_dont_take_forever(){
+        let name = "a".repeat(100);
+        let started = std::time::Instant::now();
+        assert!(!matches(&format!("{}b", "a*".repeat(50)), &name));
+        assert!(matches(&"a*".repeat(50), &name));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn patterns_expand_to_every_file_they_match(){
+        let dir = temp_dir("several");
+        for file in ["b.txt", "a.txt", "c.log", ".hidden.txt"]{
+            fs::write(dir.join(file), "").unwrap();
+        }
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/d.txt"), "").unwrap();
+        assert_eq!(expand("*.txt", &dir, None), ["a.txt", "b.txt"]);
+        assert_eq!(expand(".*.txt", &dir, None), [".hidden.txt"]);
+        assert_eq!(expand("*/*.txt", &dir, None), ["sub/d.txt"]);
+        assert_eq!(expand(&format!("{}/?.log", dir.display()), &dir, None), [format!("{}/c.log", dir.display())]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn patterns_that_match_nothing_are_left_as_they_are(){
+        let dir = temp_dir("no-match");
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::create_dir_all(dir.join("root")).unwrap();
+        assert_eq!(expand("*.rs", &dir, None), ["*.rs"]);
+        assert_eq!(expand("plain.txt", &dir, None), ["plain.txt"]);
+        // matches outside of the root don't count
+        assert_eq!(expand("../*.txt", &dir.join("root"), None), ["../a.txt"]);
+        assert_eq!(expand("../*.txt", &dir.join("root"), Some(&dir.join("root"))), ["../*.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
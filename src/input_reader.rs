@@ -0,0 +1,165 @@
+use std::{io::{self, ErrorKind, Read}, sync::{atomic::{AtomicBool, AtomicU8, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError}, Arc}, thread::{self, JoinHandle}, time::Duration};
+
+use super::{logger, secure_stream::{SecureStream, MAX_MESSAGE_LEN}};
+
+/// Read timeout of a client's socket while its input is being read on another thread, which is how often
+/// that thread checks whether it should stop
+pub const INPUT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How a client's messages are told apart from each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing{
+    /// Each read from the stream is one message (see `read_unframed`), with any `\0`s at the end of it trimmed off.
+    /// Messages sent quickly one after another can run together
+    Reads = 0,
+    /// Each line is one message, read with `SecureStream::read_line`
+    Lines = 1,
+    /// Each message is length-prefixed, read with `SecureStream::read_message`
+    LengthPrefixed = 2,
+    /// Each message is length-prefixed and starts with a sequence number, read with `SecureStream::read_sequenced_message`
+    Sequenced = 3
+}
+impl Framing{
+    fn from_u8(val: u8) -> Self{
+        match val{
+            1 => Self::Lines,
+            2 => Self::LengthPrefixed,
+            3 => Self::Sequenced,
+            _ => Self::Reads
+        }
+    }
+}
+
+/// Reads a message that was sent without any framing, which is everything that arrives in one read
+/// 
+/// A read that fills all of `chunk` probably didn't get the whole message, so reading carries on until one doesn't,
+/// or until nothing more arrives before the read times out. Some clients send every message as a whole buffer,
+/// padded out with nulls, so a full read ending in a null is taken to be the whole message, and the nulls are trimmed off
+fn read_unframed<S: Read>(stream: &mut SecureStream<S>, chunk: &mut [u8]) -> io::Result<Vec<u8>>{
+    let mut msg = Vec::new();
+    loop{
+        match stream.read(chunk){
+            Ok(0) if msg.is_empty() => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(0) => break,
+            Ok(len) => {
+                msg.extend_from_slice(&chunk[..len]);
+                if len < chunk.len() || chunk[len - 1] == 0 || msg.len() >= MAX_MESSAGE_LEN { break }
+            },
+            Err(e) if !msg.is_empty() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+    let trimmed_len = msg.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    msg.truncate(trimmed_len);
+    Ok(msg)
+}
+
+/// Reads messages from a client on a separate thread, so the client's thread can wait on output instead of
+/// polling the socket
+/// 
+/// After handing over a message, the thread stops reading until `resume` is called. That way, commands that read
+/// from the socket themselves, like file uploads, can do so without the reader thread taking their data
+pub struct InputReader{
+    messages: Receiver<Vec<u8>>,
+    resume: Sender<()>,
+    stop: Arc<AtomicBool>,
+    framing: Arc<AtomicU8>,
+    handle: JoinHandle<()>
+}
+impl InputReader{
+    /// Starts reading messages from `stream`, which should have a read timeout of `INPUT_TIMEOUT`
+    /// 
+    /// Until `set_framing` is called, each read from the stream is taken to be one message
+    pub fn spawn<S: Read + Send + 'static>(mut stream: SecureStream<S>) -> Self{
+        let (message_tx, messages) = mpsc::channel();
+        let (resume, resume_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let should_stop = stop.clone();
+        let framing = Arc::new(AtomicU8::new(Framing::Reads as u8));
+        let current_framing = framing.clone();
+        let handle = thread::spawn(move || {
+            let mut read_buffer: [u8; 1024] = [0; 1024];
+            while !should_stop.load(Ordering::Relaxed){
+                let msg = match Framing::from_u8(current_framing.load(Ordering::Relaxed)){
+                    Framing::LengthPrefixed => stream.read_message(),
+                    Framing::Sequenced => stream.read_sequenced_message(),
+                    Framing::Lines => stream.read_line().map(String::into_bytes),
+                    Framing::Reads => read_unframed(&mut stream, &mut read_buffer)
+                };
+                match msg{
+                    Ok(msg) => {
+                        if message_tx.send(msg).is_err() || resume_rx.recv().is_err() { break }
+                    },
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => (),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => {
+                        logger::error!("Something went wrong: {}. Closing connection...", e);
+                        break
+                    }
+                }
+            }
+        });
+        Self{messages, resume, stop, framing, handle}
+    }
+
+    /// Changes how every message after the current one is told apart from the next
+    /// 
+    /// Should be called before `resume`
+    pub fn set_framing(&self, framing: Framing){
+        self.framing.store(framing as u8, Ordering::Relaxed);
+    }
+
+    /// Gets the next message from the client, waiting up to `timeout` for one if it is given
+    /// 
+    /// Returns `Err(RecvTimeoutError::Disconnected)` once the client has disconnected
+    pub fn next(&self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvTimeoutError>{
+        match timeout{
+            Some(timeout) => self.messages.recv_timeout(timeout),
+            None => self.messages.try_recv().map_err(|e| match e{
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected
+            })
+        }
+    }
+
+    /// Lets the reader thread go back to reading, once the last message has been handled
+    pub fn resume(&self){
+        let _ = self.resume.send(());
+    }
+
+    /// Stops the reader thread and waits for it to finish
+    pub fn stop(self){
+        self.stop.store(true, Ordering::Relaxed);
+        // a thread waiting to be resumed gives up once it can't be
+        drop(self.resume);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn long_unframed_messages_arrive_whole(){
+        let long: Vec<u8> = (0..3000).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut stream = SecureStream::new_plain(Cursor::new(long.clone()));
+        let mut chunk = [0u8; 1024];
+        assert_eq!(read_unframed(&mut stream, &mut chunk).unwrap(), long);
+        assert_eq!(read_unframed(&mut stream, &mut chunk).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn null_padded_messages_are_trimmed(){
+        let mut padded = b"ls -la".to_vec();
+        padded.resize(1024, 0);
+        padded.extend_from_slice(b"pwd");
+        let mut stream = SecureStream::new_plain(Cursor::new(padded));
+        let mut chunk = [0u8; 1024];
+        assert_eq!(read_unframed(&mut stream, &mut chunk).unwrap(), b"ls -la");
+        assert_eq!(read_unframed(&mut stream, &mut chunk).unwrap(), b"pwd");
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::BTreeMap, fmt, io::{self, ErrorKind}};
+
+/// Minimal JSON value, just enough to read back the files this server writes itself
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value{
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>)
+}
+impl Value{
+    pub fn get(&self, key: &str) -> Option<&Value>{
+        match self{
+            Value::Object(map) => map.get(key),
+            _ => None
+        }
+    }
+    pub fn as_str(&self) -> Option<&str>{
+        match self{
+            Value::String(s) => Some(s),
+            _ => None
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64>{
+        match self{
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool>{
+        match self{
+            Value::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+    pub fn as_array(&self) -> Option<&Vec<Value>>{
+        match self{
+            Value::Array(a) => Some(a),
+            _ => None
+        }
+    }
+}
+
+/// Quotes and escapes a string so it can be embedded in a JSON document
+pub fn escape(s: &str) -> String{
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars(){
+        match c{
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Writes a number (or anything else already in JSON form) as is, or `null` if there isn't one
+pub fn or_null<T: fmt::Display>(value: Option<T>) -> String{
+    value.map_or(String::from("null"), |v| v.to_string())
+}
+
+/// Parses a JSON document, returning `io::ErrorKind::InvalidData` if it is malformed
+pub fn parse(src: &str) -> io::Result<Value>{
+    let mut parser = Parser{src, pos: 0};
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.src.len() { return Err(parser.error("trailing characters")) }
+    Ok(value)
+}
+
+struct Parser<'a>{
+    src: &'a str,
+    pos: usize
+}
+impl Parser<'_>{
+    fn error(&self, msg: &str) -> io::Error{
+        io::Error::new(ErrorKind::InvalidData, format!("Invalid JSON at byte {}: {}", self.pos, msg))
+    }
+
+    fn skip_whitespace(&mut self){
+        while self.pos < self.src.len() && self.src.as_bytes()[self.pos].is_ascii_whitespace(){
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, lit: &str) -> io::Result<()>{
+        if self.src[self.pos..].starts_with(lit){
+            self.pos += lit.len();
+            Ok(())
+        }else{
+            Err(self.error(&format!("expected '{}'", lit)))
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value>{
+        self.skip_whitespace();
+        match self.src.as_bytes().get(self.pos){
+            Some(b'n') => self.expect("null").map(|_| Value::Null),
+            Some(b't') => self.expect("true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.src.as_bytes().get(self.pos) == Some(&b']') { self.pos += 1; return Ok(Value::Array(items)) }
+                loop{
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.src.as_bytes().get(self.pos){
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(Value::Array(items)) },
+                        _ => return Err(self.error("expected ',' or ']'"))
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut map = BTreeMap::new();
+                self.skip_whitespace();
+                if self.src.as_bytes().get(self.pos) == Some(&b'}') { self.pos += 1; return Ok(Value::Object(map)) }
+                loop{
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    map.insert(key, self.value()?);
+                    self.skip_whitespace();
+                    match self.src.as_bytes().get(self.pos){
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(Value::Object(map)) },
+                        _ => return Err(self.error("expected ',' or '}'"))
+                    }
+                }
+            },
+            Some(c) if *c == b'-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.pos < self.src.len() && matches!(self.src.as_bytes()[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'){
+                    self.pos += 1;
+                }
+                self.src[start..self.pos].parse().ok()
+                    .map(Value::Number)
+                    .ok_or_else(|| self.error("invalid number"))
+            },
+            _ => Err(self.error("unexpected character"))
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String>{
+        self.expect("\"")?;
+        let mut res = String::new();
+        loop{
+            let rest = &self.src[self.pos..];
+            let mut chars = rest.chars();
+            match chars.next(){
+                Some('"') => { self.pos += 1; return Ok(res) },
+                Some('\\') => {
+                    let escaped = chars.next().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1 + escaped.len_utf8();
+                    match escaped{
+                        'n' => res.push('\n'),
+                        'r' => res.push('\r'),
+                        't' => res.push('\t'),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        'u' => {
+                            let hex = rest.get(2..6).ok_or_else(|| self.error("invalid unicode escape"))?;
+                            let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("invalid unicode escape"))?;
+                            res.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                            self.pos += 4;
+                        },
+                        c => res.push(c)
+                    }
+                },
+                Some(c) => { res.push(c); self.pos += c.len_utf8() },
+                None => return Err(self.error("unterminated string"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn strings_are_quoted_and_escaped(){
+        assert_eq!(escape("plain"), "\"plain\"");
+        assert_eq!(escape("a\"b\\c\nd\re\tf"), "\"a\\\"b\\\\c\\nd\\re\\tf\"");
+        assert_eq!(escape("\u{1}\u{1f}é"), "\"\\u0001\\u001fé\"");
+    }
+
+    #[test]
+    fn escaped_strings_parse_back(){
+        for s in ["", "plain", "quote \" backslash \\ slash /", "lines\r\nand\ttabs", "\u{0}\u{1b}[0m", "ünïcödé ✓"]{
+            assert_eq!(parse(&escape(s)).unwrap(), Value::String(s.to_owned()), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn documents_parse(){
+        let value = parse(" {\"name\": \"pi\", \"pid\": 42, \"neg\": -1.5e1, \"ok\": true, \"gone\": null, \"list\": [1, [], {}], \"esc\": \"\\u00e9\\b\\f\\/\"} ").unwrap();
+        assert_eq!(value.get("name").and_then(Value::as_str), Some("pi"));
+        assert_eq!(value.get("pid").and_then(Value::as_u64), Some(42));
+        assert_eq!(value.get("neg"), Some(&Value::Number(-15.0)));
+        assert_eq!(value.get("neg").and_then(Value::as_u64), None);
+        assert_eq!(value.get("ok").and_then(Value::as_bool), Some(true));
+        assert_eq!(value.get("gone"), Some(&Value::Null));
+        assert_eq!(value.get("list").and_then(Value::as_array).map(Vec::len), Some(3));
+        assert_eq!(value.get("esc").and_then(Value::as_str), Some("é\u{8}\u{c}/"));
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(parse("[]").unwrap().get("name"), None);
+    }
+
+    #[test]
+    fn malformed_documents_are_invalid_data(){
+        for src in ["", "{", "{\"a\" 1}", "{\"a\": 1,}", "[1 2]", "\"unterminated", "\"bad \\u12\"", "tru", "nul", "1.2.3", "{} {}", "'single'", "{a: 1}"]{
+            assert_eq!(parse(src).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", src);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Server side of the RSPI Process Manager, which runs and manages shell commands for remote clients
+//! 
+//! The `rs-pi-server` binary runs a `server::Server` configured from enviorment variables, but one can also be embedded
+
+pub mod secure_stream;
+pub mod auth;
+mod rate_limit;
+mod command_runner;
+mod command_policy;
+mod run_as;
+mod start_dir;
+mod file_transfer;
+mod fileops;
+mod circular_buffer;
+mod capture;
+mod watch;
+mod screen;
+mod ansi;
+mod pterminal;
+mod json;
+mod process_state;
+pub mod shutdown;
+pub mod logger;
+mod poison;
+mod audit;
+mod transport;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(not(feature = "tls"))]
+#[path = "no_tls.rs"]
+mod tls;
+mod input_reader;
+#[cfg(feature = "download")]
+mod download;
+mod resource_usage;
+mod process_tree;
+mod sysinfo;
+mod completion;
+mod glob;
+mod prompt;
+mod banner;
+mod motd;
+mod client;
+pub mod server;
@@ -0,0 +1,102 @@
+use std::{env, fmt, io::Write, sync::OnceLock, time::{SystemTime, UNIX_EPOCH}};
+#[cfg(test)]
+use std::cell::RefCell;
+
+/// How important a log message is. Messages less important than "RSPI_LOG_LEVEL" are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level{
+    Error,
+    Warn,
+    Info,
+    Debug
+}
+impl Level{
+    fn parse(s: &str) -> Option<Self>{
+        match s.trim().to_ascii_lowercase().as_str(){
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None
+        }
+    }
+}
+impl fmt::Display for Level{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.write_str(match self{
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG"
+        })
+    }
+}
+
+/// Gets the most verbose level that gets logged, from the "RSPI_LOG_LEVEL" enviorment variable (defaults to info)
+pub fn max_level() -> Level{
+    static MAX_LEVEL: OnceLock<Level> = OnceLock::new();
+    *MAX_LEVEL.get_or_init(|| env::var("RSPI_LOG_LEVEL").ok().and_then(|l| Level::parse(&l)).unwrap_or(Level::Info))
+}
+
+/// Writes a message to stderr, prefixed with a unix timestamp and its level
+pub fn log_event(level: Level, msg: fmt::Arguments){
+    #[cfg(test)]
+    if CAPTURED.with_borrow_mut(|captured| captured.as_mut().map(|records| records.push((level, msg.to_string()))).is_some()){
+        return
+    }
+    if level > max_level() { return }
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    // lock so lines from different client threads don't get interleaved
+    let _ = writeln!(std::io::stderr().lock(), "[{}] {:<5} {}", secs, level, msg);
+}
+
+#[cfg(test)]
+thread_local!{
+    static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, returning what it logged on this thread instead of writing it to stderr, so tests can check it
+#[cfg(test)]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<(Level, String)>){
+    let outer = CAPTURED.replace(Some(Vec::new()));
+    let result = f();
+    let records = CAPTURED.replace(outer).unwrap_or_default();
+    (result, records)
+}
+
+macro_rules! error{
+    ($($arg:tt)*) => { $crate::logger::log_event($crate::logger::Level::Error, format_args!($($arg)*)) }
+}
+macro_rules! log_warn{
+    ($($arg:tt)*) => { $crate::logger::log_event($crate::logger::Level::Warn, format_args!($($arg)*)) }
+}
+macro_rules! info{
+    ($($arg:tt)*) => { $crate::logger::log_event($crate::logger::Level::Info, format_args!($($arg)*)) }
+}
+macro_rules! debug{
+    ($($arg:tt)*) => { $crate::logger::log_event($crate::logger::Level::Debug, format_args!($($arg)*)) }
+}
+// `warn` clashes with the built-in lint attribute, so it has to be renamed on the way out
+pub(crate) use {error, log_warn as warn, info, debug};
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn levels_parse_case_insensitively(){
+        assert_eq!(Level::parse(" WARNING "), Some(Level::Warn));
+        assert_eq!(Level::parse("Debug"), Some(Level::Debug));
+        assert_eq!(Level::parse("loud"), None);
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+    }
+
+    #[test]
+    fn records_are_captured_with_their_level(){
+        let ((), records) = capture(|| {
+            warn!("disk {} is full", 1);
+            debug!("checked disks");
+        });
+        assert_eq!(records, [(Level::Warn, String::from("disk 1 is full")), (Level::Debug, String::from("checked disks"))]);
+    }
+}
@@ -1,34 +1,27 @@
-pub(self) mod secure_stream;
-pub(self) mod command_runner;
-pub(self) mod file_transfer;
-pub(self) mod circular_buffer;
-pub(self) mod pterminal;
-mod client;
+use std::{io, process::ExitCode};
+use rs_pi_server::{auth, logger::{self, Level}, server::Server, shutdown};
 
-use std::{env, net::TcpListener, sync::{Arc, Mutex}, thread};
-use command_runner::ClientSession;
-use client::Client;
-
-// Binds a listener to the address provided by either the "RSPI_SERVER_ADDR" enviorment variable or the first command line argument
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut addr = env::var("RSPI_SERVER_ADDR").unwrap_or(String::from("127.0.0.1:8080"));
-    if args.len()>1{
-        addr = args[1].clone();
-    }
-
-    let listener = TcpListener::bind(&addr).unwrap();
-    println!("Server started on {}",addr);
-
-    let child_processes = Arc::new(Mutex::new(Vec::<ClientSession>::new()));
-
-    for stream in listener.incoming() {
-        match stream{
-            Ok(stream) => {
-                let child_processes_ref = child_processes.clone();
-                thread::spawn(move || {if let Ok(mut client) = Client::new(stream, child_processes_ref){client.run()}});
+fn main() -> ExitCode {
+    // makes a password hash for a line of the RSPI_USERS_FILE, from a password given on stdin
+    if std::env::args().nth(1).as_deref() == Some("--hash-password"){
+        let mut password = String::new();
+        return match io::stdin().read_line(&mut password).and_then(|_| auth::hash_password(password.trim_end_matches(['\r', '\n']))){
+            Ok(hash) => {
+                println!("{}", hash);
+                ExitCode::SUCCESS
             },
-            Err(_) => {println!("Could not connect to client")},
+            Err(e) => {
+                logger::log_event(Level::Error, format_args!("{}", e));
+                ExitCode::FAILURE
+            }
+        }
+    }
+    shutdown::install_handlers();
+    match Server::from_env().and_then(|server| server.run()){
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            logger::log_event(Level::Error, format_args!("{}", e));
+            ExitCode::FAILURE
         }
     }
-}
\ No newline at end of file
+}
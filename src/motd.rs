@@ -0,0 +1,61 @@
+use std::{env, fs, path::Path};
+
+use super::logger;
+
+/// Loads the message of the day that clients are sent once they've logged in, before their first prompt
+/// 
+/// It's read from the file named by the "RSPI_MOTD_FILE" enviorment variable, or taken from "RSPI_MOTD" itself if
+/// that isn't set, and read again for every login so it can be changed without restarting the server.\
+/// Returns None if neither is set, or if the message is empty
+pub fn load() -> Option<String>{
+    let file = env::var_os("RSPI_MOTD_FILE").filter(|path| !path.is_empty());
+    from(file.as_deref().map(Path::new), env::var("RSPI_MOTD").ok())
+}
+
+/// Reads the message of the day from `file`, or uses `message` if there's no file, as described in `load`
+fn from(file: Option<&Path>, message: Option<String>) -> Option<String>{
+    let motd = match file{
+        Some(path) => match fs::read_to_string(path){
+            Ok(motd) => motd,
+            Err(e) => {
+                logger::warn!("Could not read RSPI_MOTD_FILE {}: {}", path.display(), e);
+                return None
+            }
+        },
+        None => message?
+    };
+    if motd.trim().is_empty(){
+        return None
+    }
+    // so the prompt after it starts on a line of its own
+    Some(if motd.ends_with('\n') { motd } else { motd + "\n" })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn messages_end_with_a_newline(){
+        assert_eq!(from(None, Some(String::from("Welcome to the Pi"))).as_deref(), Some("Welcome to the Pi\n"));
+        assert_eq!(from(None, Some(String::from("Two\nlines\n"))).as_deref(), Some("Two\nlines\n"));
+    }
+
+    #[test]
+    fn empty_or_missing_messages_arent_sent(){
+        assert_eq!(from(None, None), None);
+        assert_eq!(from(None, Some(String::from(" \n\t"))), None);
+        assert_eq!(from(Some(Path::new("/nonexistent/motd")), Some(String::from("unused"))), None);
+    }
+
+    #[test]
+    fn files_are_read_again_every_time(){
+        let path = env::temp_dir().join(format!("rspi-motd-{}", std::process::id()));
+        fs::write(&path, "Maintenance at 5pm").unwrap();
+        // the file wins over the message
+        assert_eq!(from(Some(&path), Some(String::from("unused"))).as_deref(), Some("Maintenance at 5pm\n"));
+        fs::write(&path, "Maintenance is over\n").unwrap();
+        assert_eq!(from(Some(&path), None).as_deref(), Some("Maintenance is over\n"));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,54 @@
+//! Stands in for `tls` when the server is built without the tls feature, so nothing else has to know whether it was
+
+use std::{env, io::{self, ErrorKind, Read, Write}, net::TcpStream, sync::Arc};
+
+/// TLS settings, which can't be made without the tls feature
+pub enum ServerConfig{}
+
+/// Returns None, unless any of the TLS enviorment variables are set, which is `io::ErrorKind::InvalidInput` since
+/// connections would be left without the encryption they were meant to have
+pub fn config_from_env() -> io::Result<Option<Arc<ServerConfig>>>{
+    match ["RSPI_TLS_CERT", "RSPI_TLS_KEY", "RSPI_TLS_CLIENT_CA"].into_iter().find(|name| env::var_os(name).is_some_and(|p| !p.is_empty())){
+        Some(name) => Err(io::Error::new(ErrorKind::InvalidInput, format!("{} is set, but this server was built without the tls feature", name))),
+        None => Ok(None)
+    }
+}
+
+/// A TCP connection encrypted with TLS, which can't be made without the tls feature
+pub enum TlsStream{}
+impl TlsStream{
+    pub fn accept(config: Arc<ServerConfig>, _sock: TcpStream) -> io::Result<Self>{
+        match *config{}
+    }
+
+    pub fn client_identity(&self) -> Option<String>{
+        match *self{}
+    }
+
+    pub fn sock(&self) -> &TcpStream{
+        match *self{}
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self>{
+        match *self{}
+    }
+
+    pub fn close(&self) -> io::Result<()>{
+        match *self{}
+    }
+}
+
+impl Read for &TlsStream{
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize>{
+        match **self{}
+    }
+}
+
+impl Write for &TlsStream{
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize>{
+        match **self{}
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        match **self{}
+    }
+}
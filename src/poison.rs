@@ -0,0 +1,16 @@
+use std::sync::{Mutex, MutexGuard};
+
+use super::logger;
+
+/// Locks a mutex, recovering it if another thread panicked while holding it
+/// 
+/// The mutexes this is used for only guard small bits of state (like a stream's offsets or a session's output)
+/// which are still usable after a panic, so losing the whole connection over one isn't worth it.\
+/// `what` names the mutex in the warning that gets logged
+pub fn lock<'a, T>(mutex: &'a Mutex<T>, what: &str) -> MutexGuard<'a, T>{
+    mutex.lock().unwrap_or_else(|e| {
+        logger::warn!("Recovered the {} lock after a thread panicked while holding it", what);
+        mutex.clear_poison();
+        e.into_inner()
+    })
+}
@@ -0,0 +1,175 @@
+use std::{env, fs, io, path::{Path, PathBuf}};
+
+use super::command_runner::ClientSession;
+use super::json;
+use super::resource_usage;
+
+unsafe extern "C"{
+    fn kill(pid: i32, sig: i32) -> i32;
+}
+
+/// Furthest apart, in seconds, the start time recorded for a process and the one in `/proc` can be while still being
+/// the same process. The recorded time is taken just after the process is spawned, and both are rounded to the second
+const START_TIME_TOLERANCE: u64 = 2;
+
+/// Metadata about a process orphaned to the server, which gets saved to the file given by the
+/// "RSPI_STATE_FILE" enviorment variable so it can be tracked again after the server restarts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessRecord{
+    pub pid: u32,
+    pub cmd_name: String,
+    pub cwd: PathBuf,
+    pub start_time: u64,
+    pub alive: bool
+}
+impl ProcessRecord{
+    /// Creates a record for the child process of a session, or None if it isn't running anything
+    pub fn from_session(session: &ClientSession) -> Option<Self>{
+        Some(Self{
+            pid: session.pid()?,
+            cmd_name: session.cmd_name.clone(),
+            cwd: session.path.clone(),
+            start_time: session.start_time()?,
+            alive: true
+        })
+    }
+
+    fn to_json(&self) -> String{
+        format!("{{\"pid\":{},\"cmd_name\":{},\"cwd\":{},\"start_time\":{},\"alive\":{}}}",
+            self.pid, json::escape(&self.cmd_name), json::escape(&self.cwd.to_string_lossy()), self.start_time, self.alive)
+    }
+
+    fn from_json(value: &json::Value) -> Option<Self>{
+        Some(Self{
+            pid: value.get("pid")?.as_u64()? as u32,
+            cmd_name: value.get("cmd_name")?.as_str()?.to_owned(),
+            cwd: PathBuf::from(value.get("cwd")?.as_str()?),
+            start_time: value.get("start_time")?.as_u64()?,
+            alive: value.get("alive").and_then(json::Value::as_bool).unwrap_or(true)
+        })
+    }
+
+    /// Checks whether a process with this record's pid still exists
+    pub fn pid_exists(&self) -> bool{
+        let Ok(pid) = i32::try_from(self.pid) else { return false };
+        if pid <= 0 { return false }
+        // signal 0 only checks if the process exists. EPERM means it exists but belongs to someone else
+        unsafe { kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(1) }
+    }
+
+    /// Checks whether this record's process is still running, and not some other process that was given the same pid
+    /// after a reboot or once pids wrapped around
+    /// 
+    /// If when the process started can't be read from `/proc`, it's assumed to be a different one, so that nothing
+    /// unrelated is ever mistaken for it
+    pub fn is_running(&self) -> bool{
+        self.pid_exists() && resource_usage::start_time_of_pid(self.pid)
+            .is_some_and(|started| started.abs_diff(self.start_time) <= START_TIME_TOLERANCE)
+    }
+}
+
+/// Gets the path of the state file from the "RSPI_STATE_FILE" enviorment variable, if it is set
+pub fn state_file() -> Option<PathBuf>{
+    env::var_os("RSPI_STATE_FILE").filter(|p| !p.is_empty()).map(PathBuf::from)
+}
+
+/// Serializes the records to a JSON array
+pub fn to_json(records: &[ProcessRecord]) -> String{
+    format!("[{}]\n", records.iter().map(ProcessRecord::to_json).collect::<Vec<String>>().join(",\n"))
+}
+
+/// Deserializes records from a JSON array, skipping any entries that are missing fields
+pub fn from_json(src: &str) -> io::Result<Vec<ProcessRecord>>{
+    match json::parse(src)?.as_array(){
+        Some(items) => Ok(items.iter().filter_map(ProcessRecord::from_json).collect()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Expected a JSON array of processes"))
+    }
+}
+
+/// Marks every record whose process is no longer running as dead
+pub fn prune_stale(records: &mut [ProcessRecord]){
+    for record in records.iter_mut(){
+        if record.alive && !record.is_running(){
+            record.alive = false;
+        }
+    }
+}
+
+/// Loads the records saved at `path`, marking processes that have since exited as dead
+/// 
+/// A missing file is treated as having no records
+pub fn load(path: &Path) -> io::Result<Vec<ProcessRecord>>{
+    let mut records = match fs::read_to_string(path){
+        Ok(src) => from_json(&src)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e)
+    };
+    prune_stale(&mut records);
+    Ok(records)
+}
+
+/// Writes the records to `path`, going through a temporary file so a crash can't leave it half-written
+pub fn save(path: &Path, records: &[ProcessRecord]) -> io::Result<()>{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, to_json(records))?;
+    fs::rename(&tmp, path)
+}
+
+/// Saves the server's orphaned sessions along with any records recovered from a previous run
+/// that are still alive. Does nothing if "RSPI_STATE_FILE" isn't set
+pub fn save_orphans(sessions: &[ClientSession], recovered: &[ProcessRecord]) -> io::Result<()>{
+    let Some(path) = state_file() else { return Ok(()) };
+    let records = sessions.iter()
+        .filter_map(ProcessRecord::from_session)
+        .chain(recovered.iter().filter(|r| r.alive).cloned())
+        .collect::<Vec<ProcessRecord>>();
+    save(&path, &records)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn record(pid: u32, start_time: u64) -> ProcessRecord{
+        ProcessRecord{pid, cmd_name: String::from("sleep"), cwd: PathBuf::from("/tmp/a \"quoted\" dir"), start_time, alive: true}
+    }
+
+    #[test]
+    fn records_round_trip_through_json(){
+        let records = vec![record(1234, 1_700_000_000), ProcessRecord{alive: false, ..record(99, 5)}];
+        assert_eq!(from_json(&to_json(&records)).unwrap(), records);
+    }
+
+    #[test]
+    fn entries_missing_fields_are_skipped(){
+        let records = from_json("[{\"pid\":1,\"cmd_name\":\"a\",\"cwd\":\"/\",\"start_time\":2}, {\"pid\":3}]").unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].alive);
+    }
+
+    #[test]
+    fn prune_keeps_processes_still_running(){
+        let pid = std::process::id();
+        let mut records = vec![record(pid, resource_usage::start_time_of_pid(pid).unwrap())];
+        prune_stale(&mut records);
+        assert!(records[0].alive);
+    }
+
+    #[test]
+    fn prune_marks_exited_processes_dead(){
+        // above the largest pid Linux hands out, so it can't be running
+        let mut records = vec![record(0x7fff_fff0, 0)];
+        prune_stale(&mut records);
+        assert!(!records[0].alive);
+    }
+
+    #[test]
+    fn prune_marks_reused_pids_dead(){
+        // this process has the pid, but didn't start when the record says it did
+        let pid = std::process::id();
+        let mut records = vec![record(pid, resource_usage::start_time_of_pid(pid).unwrap() - 3600)];
+        prune_stale(&mut records);
+        assert!(!records[0].alive);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, fs};
+
+use super::json;
+
+/// A process as listed in `/proc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo{
+    pub pid: u32,
+    pub ppid: u32,
+    /// State letter from `/proc/<pid>/stat`, like `R` for running, `S` for sleeping or `Z` for a zombie
+    pub state: char,
+    /// The process's arguments joined by spaces, or its name in brackets if it has none (like kernel threads and zombies)
+    pub cmdline: String
+}
+impl ProcessInfo{
+    /// Reads a process from `/proc/<pid>/stat` and `/proc/<pid>/cmdline`, or None if it has exited
+    pub fn of_pid(pid: u32) -> Option<Self>{
+        let (name, state, ppid) = parse_stat(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)?;
+        let args = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+        let cmdline = args.split(|b| *b == 0).filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        Some(Self{pid, ppid, state, cmdline: if cmdline.is_empty() { format!("[{}]", name) } else { cmdline }})
+    }
+}
+
+/// A process along with every process descended from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tree{
+    pub process: ProcessInfo,
+    /// Processes started by this one, in order of pid
+    pub children: Vec<Tree>
+}
+impl Tree{
+    /// Lists the tree one process per line, with each child indented under its parent
+    pub fn render(&self) -> String{
+        let mut res = String::new();
+        self.render_into(&mut res, 0);
+        res
+    }
+
+    fn render_into(&self, res: &mut String, depth: usize){
+        res.push_str(&format!("{}{}\t{}\t{}\n", "  ".repeat(depth), self.process.pid, self.process.state, self.process.cmdline));
+        for child in &self.children{
+            child.render_into(res, depth + 1);
+        }
+    }
+
+    /// Formats the tree as a JSON object, with the children of each process in a `children` array
+    pub fn to_json(&self) -> String{
+        format!("{{\"pid\":{},\"state\":{},\"cmd\":{},\"children\":[{}]}}",
+            self.process.pid, json::escape(&self.process.state.to_string()), json::escape(&self.process.cmdline),
+            self.children.iter().map(Tree::to_json).collect::<Vec<String>>().join(","))
+    }
+}
+
+/// Finds the process `root_pid` and all of its descendants by reading the parent of every process in `/proc`
+/// 
+/// Processes can exit while `/proc` is being read, so any that disappear partway through are left out, along with
+/// their children. Returns None if `root_pid` itself isn't running
+pub fn process_tree(root_pid: u32) -> Option<Tree>{
+    let root = ProcessInfo::of_pid(root_pid)?;
+    let mut children: HashMap<u32, Vec<ProcessInfo>> = HashMap::new();
+    for entry in fs::read_dir("/proc").ok()?.flatten(){
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue };
+        if let Some(info) = ProcessInfo::of_pid(pid){
+            children.entry(info.ppid).or_default().push(info);
+        }
+    }
+    Some(build(root, &mut children))
+}
+
+fn build(process: ProcessInfo, children: &mut HashMap<u32, Vec<ProcessInfo>>) -> Tree{
+    // taking the children out means a pid that was reused while reading can't make the tree loop back on itself
+    let mut kids = children.remove(&process.pid).unwrap_or_default();
+    kids.sort_by_key(|kid| kid.pid);
+    Tree{process, children: kids.into_iter().map(|kid| build(kid, children)).collect()}
+}
+
+/// Gets the name, state and parent pid of a process from the contents of `/proc/<pid>/stat`
+fn parse_stat(stat: &str) -> Option<(String, char, u32)>{
+    // the command name can contain spaces and parentheses, so it's everything between the first '(' and the last ')'
+    let name = stat.get(stat.find('(')? + 1..stat.rfind(')')?)?;
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    Some((name.to_owned(), state, ppid))
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{process::Command, thread, time::{Duration, Instant}};
+    use super::*;
+
+    fn info(pid: u32, ppid: u32, cmdline: &str) -> ProcessInfo{
+        ProcessInfo{pid, ppid, state: 'S', cmdline: cmdline.to_owned()}
+    }
+
+    #[test]
+    fn stat_names_can_have_spaces_and_parentheses(){
+        assert_eq!(parse_stat("1234 (bash) S 1000 1234 1234 34816 1234 4194560"), Some((String::from("bash"), 'S', 1000)));
+        assert_eq!(parse_stat("77 (my (odd) name) Z 1 77 77 0 -1"), Some((String::from("my (odd) name"), 'Z', 1)));
+        assert_eq!(parse_stat("77 (cut off"), None);
+        assert_eq!(parse_stat("77 (sh) R"), None);
+    }
+
+    #[test]
+    fn trees_are_built_in_pid_order_and_rendered(){
+        let mut children = HashMap::new();
+        children.insert(10, vec![info(30, 10, "sleep 5"), info(20, 10, "sh -c make")]);
+        children.insert(20, vec![info(21, 20, "make")]);
+        children.insert(99, vec![info(100, 99, "unrelated")]);
+        let tree = build(info(10, 1, "bash"), &mut children);
+        assert_eq!(tree.render(), "10\tS\tbash\n  20\tS\tsh -c make\n    21\tS\tmake\n  30\tS\tsleep 5\n");
+        assert_eq!(json::parse(&tree.to_json()).unwrap(), json::parse(concat!(
+            r#"{"pid":10,"state":"S","cmd":"bash","children":[{"pid":20,"state":"S","cmd":"sh -c make","children":"#,
+            r#"[{"pid":21,"state":"S","cmd":"make","children":[]}]},{"pid":30,"state":"S","cmd":"sleep 5","children":[]}]}"#)).unwrap());
+    }
+
+    #[test]
+    fn reused_pids_cant_make_the_tree_loop(){
+        // 10 looks like a child of its own child, as it would if a pid were reused while reading /proc
+        let mut children = HashMap::new();
+        children.insert(10, vec![info(20, 10, "child")]);
+        children.insert(20, vec![info(10, 20, "reused")]);
+        let tree = build(info(10, 1, "parent"), &mut children);
+        assert_eq!(tree.render(), "10\tS\tparent\n  20\tS\tchild\n    10\tS\treused\n");
+    }
+
+    #[test]
+    fn running_processes_are_found(){
+        let mut child = Command::new("sh").args(["-c", "sleep 30 & wait"]).spawn().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let tree = loop{
+            let tree = process_tree(child.id()).unwrap();
+            if !tree.children.is_empty() || Instant::now() > deadline { break tree }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let _ = Command::new("pkill").args(["-P", &child.id().to_string()]).status();
+        let _ = child.kill();
+        let _ = child.wait();
+        assert_eq!(tree.process.cmdline, "sh -c sleep 30 & wait");
+        assert_eq!(tree.children.len(), 1);
+        assert_eq!(tree.children[0].process.cmdline, "sleep 30");
+        assert_eq!(tree.children[0].process.ppid, child.id());
+        assert!(process_tree(child.id()).is_none());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{env, ffi::CStr, fs};
+
+unsafe extern "C"{
+    fn gethostname(name: *mut i8, len: usize) -> i32;
+    fn getuid() -> u32;
+}
+
+/// The prompt that is used when "RSPI_PROMPT" isn't set, which shows the current directory
+pub const DEFAULT_FORMAT: &str = "%d$ ";
+
+/// Values substituted into a prompt's format string
+pub struct PromptInfo<'a>{
+    pub cwd: &'a str,
+    pub host: &'a str,
+    pub user: &'a str,
+    pub last_status: i32
+}
+
+/// Renders a prompt, replacing `%d` with the current directory, `%h` with the hostname, `%u` with the user
+/// and `%s` with the exit status of the last process. `%%` is a literal '%', and anything else is left alone
+pub fn render(format: &str, info: &PromptInfo) -> String{
+    let mut prompt = String::with_capacity(format.len() + info.cwd.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next(){
+        if c != '%'{
+            prompt.push(c);
+            continue
+        }
+        match chars.next(){
+            Some('d') => prompt.push_str(info.cwd),
+            Some('h') => prompt.push_str(info.host),
+            Some('u') => prompt.push_str(info.user),
+            Some('s') => prompt.push_str(&info.last_status.to_string()),
+            Some('%') => prompt.push('%'),
+            Some(other) => { prompt.push('%'); prompt.push(other); },
+            None => prompt.push('%')
+        }
+    }
+    prompt
+}
+
+/// Name of the machine the server is running on
+pub fn hostname() -> String{
+    let mut buf = [0i8; 256];
+    unsafe{
+        if gethostname(buf.as_mut_ptr(), buf.len() - 1) != 0 { return String::from("unknown") }
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+/// Name of the user the server is running as, from the enviorment or otherwise `/etc/passwd`. Falls back to their uid
+pub fn username() -> String{
+    if let Ok(user) = env::var("USER").or_else(|_| env::var("LOGNAME")){
+        return user
+    }
+    let uid = unsafe { getuid() }.to_string();
+    fs::read_to_string("/etc/passwd").ok()
+        .and_then(|passwd| passwd.lines()
+            .map(|line| line.split(':').collect::<Vec<&str>>())
+            .find(|fields| fields.get(2) == Some(&uid.as_str()))
+            .map(|fields| fields[0].to_owned()))
+        .unwrap_or(uid)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const INFO: PromptInfo = PromptInfo{cwd: "/home/pi", host: "raspberrypi", user: "pi", last_status: 127};
+
+    #[test]
+    fn placeholders_are_replaced(){
+        assert_eq!(render(DEFAULT_FORMAT, &INFO), "/home/pi$ ");
+        assert_eq!(render("%u@%h:%d [%s]%% ", &INFO), "pi@raspberrypi:/home/pi [127]% ");
+        assert_eq!(render("%d%d", &INFO), "/home/pi/home/pi");
+    }
+
+    #[test]
+    fn anything_else_is_left_alone(){
+        assert_eq!(render("", &INFO), "");
+        assert_eq!(render("plain > ", &INFO), "plain > ");
+        assert_eq!(render("%x %D 100%", &INFO), "%x %D 100%");
+        assert_eq!(render("%%d é%", &INFO), "%d é%");
+    }
+}
@@ -5,44 +5,63 @@ unsafe extern "C"{
     fn posix_openpt(oflag: i32) -> i32;
     fn grantpt(fd: i32) -> i32;
     fn unlockpt(fd: i32) -> i32;
-    fn ptsname(fd: i32) -> *mut i8;
+    fn ptsname_r(fd: i32, buf: *mut ffi::c_char, buflen: usize) -> i32;
+}
+
+/// Adds what we were trying to do to an error from opening the pseudo-terminal, since running out of file descriptors
+/// is by far the most likely cause and the OS's message for it doesn't mention terminals
+fn alloc_error(e: io::Error) -> io::Error{
+    // EMFILE and ENFILE, when this process or the whole system is out of file descriptors
+    let hint = if matches!(e.raw_os_error(), Some(23 | 24)) { ", possibly out of file descriptors" } else { "" };
+    io::Error::new(e.kind(), format!("Could not allocate pseudo-terminal{}: {}", hint, e))
 }
 
 pub struct PseudoTerminal{
     master: Arc<File>,
-    slave: Option<File>
+    slave: Option<File>,
+    slave_name: String
 }
 impl PseudoTerminal{
     /// Creates a new pseudo-terminal which can be used to run processes
     pub fn new() -> Result<PseudoTerminal, io::Error>{
-        let slavename: &str;
+        let mut name_buf = [0 as ffi::c_char; 128];
         let master = unsafe {
             let master_fd = posix_openpt(2);
-            if master_fd==-1 { return Err(io::Error::last_os_error()) }
+            if master_fd==-1 { return Err(alloc_error(io::Error::last_os_error())) }
 
             // unlock pty while checking for errors
             if grantpt(master_fd) == -1 { close(master_fd); return Err(io::Error::last_os_error()) }
             if unlockpt(master_fd) == -1 { close(master_fd); return Err(io::Error::last_os_error()) }
 
-            // get the name of the slave end of the pty
-            slavename = ffi::CStr::from_ptr(ptsname(master_fd))
-                            .to_str()
-                            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            // get the name of the slave end of the pty. ptsname_r copies it into our buffer, rather than
+            // a static one that another session's terminal could overwrite
+            let err = ptsname_r(master_fd, name_buf.as_mut_ptr(), name_buf.len());
+            if err != 0 { close(master_fd); return Err(io::Error::from_raw_os_error(err)) }
             // return master
             File::from_raw_fd(master_fd)
         };
-        let slave = File::create(slavename)?;
-        Ok(Self{master: Arc::new(master), slave: Some(slave)})
+        let slave_name = unsafe { ffi::CStr::from_ptr(name_buf.as_ptr()) }
+            .to_str()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            .to_owned();
+        let slave = File::create(&slave_name).map_err(alloc_error)?;
+        Ok(Self{master: Arc::new(master), slave: Some(slave), slave_name})
+    }
+
+    /// Path of the slave end of this pseudo-terminal (ie. `/dev/pts/3`), which is what processes run in it see as their tty
+    pub fn slave_name(&self) -> &str{
+        &self.slave_name
     }
 
     /// Runs the command in this pseudo-terminal by redirecting its output
     /// 
     /// This will only redirect `stdout` and `stderr` to this pseudo-terminal.\
     /// It's recommended to write to `stdin` of the returned child directly if
-    /// necessary
-    pub fn run_cmd(&self, mut cmd: Command) -> io::Result<Child>{
+    /// necessary.\
+    /// If `stdout` is given, the command's output goes there instead, and only its errors go to the terminal
+    pub fn run_cmd(&self, mut cmd: Command, stdout: Option<File>) -> io::Result<Child>{
         match &self.slave{
-            Some(slave) => cmd.stdout(slave.try_clone()?).stderr(slave.try_clone()?).spawn(),
+            Some(slave) => cmd.stdout(match stdout{ Some(file) => file, None => slave.try_clone()? }).stderr(slave.try_clone()?).spawn(),
             None => Err(io::Error::from(ErrorKind::BrokenPipe))
         }
     }
@@ -89,4 +108,42 @@ impl Read for TermReader{
             None => Ok(0)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use std::{path::Path, process::Stdio};
+    use super::*;
+
+    #[test]
+    fn each_terminal_has_a_slave_of_its_own(){
+        let first = PseudoTerminal::new().unwrap();
+        let second = PseudoTerminal::new().unwrap();
+        assert!(first.slave_name().starts_with("/dev/pts/"), "{}", first.slave_name());
+        assert!(Path::new(first.slave_name()).exists());
+        assert_ne!(first.slave_name(), second.slave_name());
+    }
+
+    #[test]
+    fn processes_see_the_slave_as_their_terminal(){
+        let mut term = PseudoTerminal::new().unwrap();
+        let mut cmd = Command::new("readlink");
+        cmd.arg("/proc/self/fd/2").stdin(Stdio::null());
+        let mut child = term.run_cmd(cmd, None).unwrap();
+        child.wait().unwrap();
+        let mut read = vec![0u8; 128];
+        let len = term.read(&mut read).unwrap();
+        assert_eq!(String::from_utf8_lossy(&read[..len]).trim_end(), term.slave_name());
+    }
+
+    #[test]
+    fn allocation_errors_hint_at_running_out_of_file_descriptors(){
+        for errno in [23, 24]{
+            let e = alloc_error(io::Error::from_raw_os_error(errno));
+            assert!(e.to_string().starts_with("Could not allocate pseudo-terminal, possibly out of file descriptors: "), "{}", e);
+        }
+        // anything else is just said as it is, keeping its kind
+        let e = alloc_error(io::Error::from_raw_os_error(13));
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(e.to_string().starts_with("Could not allocate pseudo-terminal: "), "{}", e);
+    }
+}
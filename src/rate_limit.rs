@@ -0,0 +1,81 @@
+use std::{env, sync::Mutex, thread, time::{Duration, Instant}};
+
+use super::poison;
+
+/// Gets the number of bytes per second each connection is limited to, set by the "RSPI_RATE_LIMIT_BPS" enviorment variable
+pub fn limit_from_env() -> Option<u64>{
+    env::var("RSPI_RATE_LIMIT_BPS").ok().and_then(|v| v.parse().ok()).filter(|bps| *bps > 0)
+}
+
+/// Token bucket holding up to a second's worth of bytes, refilled at `bytes_per_sec`
+/// 
+/// Bytes are taken out after they've been sent or received, which can leave the bucket in debt. Whoever
+/// put it there sleeps until the debt is paid off, so transfers average out to the limit without busy-waiting
+pub struct RateLimiter{
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>
+}
+
+struct Bucket{
+    tokens: f64,
+    refilled_at: Instant
+}
+
+impl RateLimiter{
+    pub fn new(bytes_per_sec: u64) -> Self{
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self{bytes_per_sec, bucket: Mutex::new(Bucket{tokens: bytes_per_sec as f64, refilled_at: Instant::now()})}
+    }
+
+    /// Most bytes that should be read or written at once, so that no single transfer goes far over the limit
+    pub fn burst(&self) -> usize{
+        usize::try_from(self.bytes_per_sec).unwrap_or(usize::MAX)
+    }
+
+    /// Takes `bytes` out of the bucket, sleeping until it is no longer in debt
+    pub fn consume(&self, bytes: usize){
+        let debt = {
+            let mut bucket = poison::lock(&self.bucket, "rate limiter");
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.bytes_per_sec as f64;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec as f64) - bytes as f64;
+            bucket.refilled_at = now;
+            -bucket.tokens
+        };
+        if debt > 0.0{
+            thread::sleep(Duration::from_secs_f64(debt / self.bytes_per_sec as f64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::{Cursor, Write};
+
+    use crate::secure_stream::SecureStream;
+
+    use super::*;
+
+    #[test]
+    fn going_over_the_limit_sleeps(){
+        let limiter = RateLimiter::new(100_000);
+        let started = Instant::now();
+        // the first second's worth is already in the bucket, so only the rest has to wait
+        for _ in 0..15{
+            limiter.consume(10_000);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn limited_streams_take_at_least_as_long_as_the_limit_allows(){
+        let data = vec![7u8; 150_000];
+        let mut stream = SecureStream::new_plain(Cursor::new(Vec::new())).set_rate_limit(Some(100_000));
+        let started = Instant::now();
+        stream.write_all(&data).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450), "{:?}", started.elapsed());
+        assert_eq!(stream.stream.into_inner(), data);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fs, time::Duration};
+
+unsafe extern "C"{
+    fn sysconf(name: i32) -> i64;
+}
+
+const SC_CLK_TCK: i32 = 2;
+const SC_PAGESIZE: i32 = 30;
+
+/// Memory and CPU time used by a process, as reported by `/proc`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResourceUsage{
+    pub pid: u32,
+    /// Resident set size, in bytes
+    pub rss_bytes: u64,
+    /// Time spent running in both user and kernel mode
+    pub cpu_time: Duration
+}
+impl ResourceUsage{
+    /// Reads the resource usage of a process from `/proc/<pid>/stat` and `/proc/<pid>/statm`
+    /// 
+    /// Returns None if the process has exited, or if `/proc` isn't available
+    pub fn of_pid(pid: u32) -> Option<Self>{
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+        let (ticks_per_sec, page_size) = unsafe { (sysconf(SC_CLK_TCK), sysconf(SC_PAGESIZE)) };
+        let ticks = parse_cpu_ticks(&stat)?;
+        let pages = parse_resident_pages(&statm)?;
+        Some(Self{
+            pid,
+            rss_bytes: pages * u64::try_from(page_size).ok()?,
+            cpu_time: Duration::from_secs_f64(ticks as f64 / u64::try_from(ticks_per_sec).ok().filter(|t| *t > 0)? as f64)
+        })
+    }
+}
+
+/// Gets when a process started, as a Unix timestamp in seconds, from `/proc/<pid>/stat` and the boot time in `/proc/stat`
+/// 
+/// Returns None if the process has exited, or if `/proc` isn't available
+pub fn start_time_of_pid(pid: u32) -> Option<u64>{
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let boot_time = parse_boot_time(&fs::read_to_string("/proc/stat").ok()?)?;
+    let ticks_per_sec = u64::try_from(unsafe { sysconf(SC_CLK_TCK) }).ok().filter(|t| *t > 0)?;
+    Some(boot_time + parse_start_ticks(&stat)? / ticks_per_sec)
+}
+
+/// Gets how long after boot a process started, in clock ticks, from the contents of `/proc/<pid>/stat`
+fn parse_start_ticks(stat: &str) -> Option<u64>{
+    // starttime is the 22nd field, counting from the state (3rd field) just after the name
+    stat.get(stat.rfind(')')? + 1..)?.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Gets the Unix timestamp the system booted at from the `btime` line of `/proc/stat`
+fn parse_boot_time(stat: &str) -> Option<u64>{
+    stat.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse().ok()
+}
+
+/// Gets the user plus system time of a process, in clock ticks, from the contents of `/proc/<pid>/stat`
+fn parse_cpu_ticks(stat: &str) -> Option<u64>{
+    // the command name can contain spaces and parentheses, so start counting fields after the last ')'
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    // utime and stime are the 14th and 15th fields, and the state (3rd field) is the first one after the name
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Gets the number of resident pages of a process from the contents of `/proc/<pid>/statm`
+fn parse_resident_pages(statm: &str) -> Option<u64>{
+    statm.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use super::*;
+
+    const STAT: &str = "1234 (my (odd) prog) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 1 0 98765 12345678 300 18446744073709551615\n";
+
+    #[test]
+    fn proc_files_are_parsed(){
+        assert_eq!(parse_cpu_ticks(STAT), Some(300));
+        assert_eq!(parse_start_ticks(STAT), Some(98765));
+        assert_eq!(parse_resident_pages("2590 300 245 1 0 187 0\n"), Some(300));
+        assert_eq!(parse_boot_time("cpu  10 0 10 1000\nintr 12345\nbtime 1700000000\nprocesses 500\n"), Some(1700000000));
+    }
+
+    #[test]
+    fn malformed_files_are_none(){
+        assert_eq!(parse_cpu_ticks("1234 (cut off"), None);
+        assert_eq!(parse_cpu_ticks("1234 (sh) S 1 1234"), None);
+        assert_eq!(parse_start_ticks("1234 (sh) S 1 1234"), None);
+        assert_eq!(parse_resident_pages("2590"), None);
+        assert_eq!(parse_boot_time("cpu  10 0 10 1000\n"), None);
+    }
+
+    #[test]
+    fn this_process_uses_something(){
+        let usage = ResourceUsage::of_pid(std::process::id()).unwrap();
+        assert!(usage.rss_bytes > 0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let started = start_time_of_pid(std::process::id()).unwrap();
+        // the boot time is rounded, so this could be a second either way
+        assert!(started <= now + 1 && started + 600 > now, "started at {}, now {}", started, now);
+        assert_eq!(ResourceUsage::of_pid(u32::MAX), None);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{env, fs, io::{self, ErrorKind}, os::unix::process::CommandExt, process::Command};
+
+unsafe extern "C"{
+    fn geteuid() -> u32;
+    fn setfsuid(uid: u32) -> i32;
+    fn setfsgid(gid: u32) -> i32;
+    fn setgroups(size: usize, list: *const u32) -> i32;
+}
+
+/// A user that commands are run as instead of the one running the server, set by the "RSPI_RUN_AS_USER" enviorment
+/// variable
+/// 
+/// Lets the server run as root, to bind to low ports or get at hardware, without every command running as root too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs{
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String
+}
+impl RunAs{
+    /// Finds `user`, by name or uid, in the contents of a passwd file
+    pub fn parse(passwd: &str, user: &str) -> Option<Self>{
+        passwd.lines()
+            .map(|line| line.split(':').collect::<Vec<&str>>())
+            .filter(|fields| fields.len() >= 6)
+            .find(|fields| fields[0] == user || fields[2] == user)
+            .and_then(|fields| Some(Self{name: fields[0].to_owned(), uid: fields[2].parse().ok()?, gid: fields[3].parse().ok()?, home: fields[5].to_owned()}))
+    }
+
+    /// Looks `user` up in /etc/passwd, returning `io::ErrorKind::NotFound` if there's no such user
+    pub fn lookup(user: &str) -> io::Result<Self>{
+        Self::parse(&fs::read_to_string("/etc/passwd")?, user)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("RSPI_RUN_AS_USER is set to {:?}, which is not a user", user)))
+    }
+
+    /// Loads the user named by "RSPI_RUN_AS_USER", or None if it isn't set
+    /// 
+    /// Only root can start processes as someone else, so this returns `io::ErrorKind::PermissionDenied` if the server
+    /// isn't running as root, unless the user is the one it's already running as
+    pub fn from_env() -> io::Result<Option<Self>>{
+        let Some(user) = env::var("RSPI_RUN_AS_USER").ok().filter(|user| !user.trim().is_empty()) else { return Ok(None) };
+        let run_as = Self::lookup(user.trim())?;
+        let euid = unsafe { geteuid() };
+        if euid != 0 && euid != run_as.uid{
+            return Err(io::Error::new(ErrorKind::PermissionDenied,
+                format!("RSPI_RUN_AS_USER is set to {}, but the server can only run commands as another user when it runs as root", run_as.name)))
+        }
+        Ok(Some(run_as))
+    }
+
+    /// Makes files opened by the server on this thread get opened as this user, until the returned guard is dropped
+    /// 
+    /// The server opens some files itself on behalf of clients, like redirects and the files `rspi` commands move
+    /// around, and those shouldn't get any further than the commands clients run. This only changes the ids used to
+    /// check file access (see `setfsuid(2)`), and only for this thread and the threads it starts while the guard lives,
+    /// so the server can still do everything else as itself. Files created meanwhile belong to this user.\
+    /// Returns `io::ErrorKind::PermissionDenied` if the server isn't allowed to take on this user's ids
+    pub fn access_files(&self) -> io::Result<FileAccess>{
+        // setfsgid has to come first, since the server can't change its group once it's no longer root
+        let gid = unsafe { setfsgid(self.gid) } as u32;
+        let uid = unsafe { setfsuid(self.uid) } as u32;
+        let guard = FileAccess{uid, gid};
+        // neither call reports failure directly, but setting the same id again returns what it was left as
+        if unsafe { setfsgid(self.gid) } as u32 != self.gid || unsafe { setfsuid(self.uid) } as u32 != self.uid{
+            return Err(io::Error::new(ErrorKind::PermissionDenied, format!("Could not access files as {}", self.name)))
+        }
+        Ok(guard)
+    }
+
+    /// Drops the supplementary groups the server is running with, which `access_files` can't change
+    /// 
+    /// Unlike the ids changed by `access_files`, these are shared by every thread, so this is done once before any
+    /// clients connect. Only root can do this, and only root needs to
+    pub fn drop_server_groups() -> io::Result<()>{
+        if unsafe { geteuid() } != 0 || unsafe { setgroups(0, std::ptr::null()) } == 0{
+            Ok(())
+        }else{
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Makes `cmd` run as this user, with the enviorment variables saying who and where home is changed to match
+    /// 
+    /// The supplementary groups of the server are dropped along with its uid and gid, so they don't carry over either
+    pub fn apply(&self, cmd: &mut Command){
+        cmd.uid(self.uid).gid(self.gid).env("USER", &self.name).env("LOGNAME", &self.name).env("HOME", &self.home);
+    }
+}
+
+/// Puts back the ids a thread used for file access before `RunAs::access_files`, once it's dropped
+#[derive(Debug)]
+pub struct FileAccess{
+    uid: u32,
+    gid: u32
+}
+impl Drop for FileAccess{
+    fn drop(&mut self){
+        // the reverse order of `access_files`, so the server is root again before it changes its group back
+        unsafe{
+            setfsuid(self.uid);
+            setfsgid(self.gid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{os::unix::fs::{MetadataExt, PermissionsExt}, path::PathBuf, process};
+
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\n\
+        broken\n\
+        pi:x:1000:1000:,,,:/home/pi:/bin/bash\n";
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-run-as-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Someone other than root to run as, when the tests are running as root
+    fn unprivileged() -> Option<RunAs>{
+        if unsafe { geteuid() } != 0{
+            return None
+        }
+        RunAs::lookup("nobody").ok()
+    }
+
+    #[test]
+    fn users_are_found_by_name_or_uid(){
+        let pi = RunAs{name: String::from("pi"), uid: 1000, gid: 1000, home: String::from("/home/pi")};
+        assert_eq!(RunAs::parse(PASSWD, "pi"), Some(pi.clone()));
+        assert_eq!(RunAs::parse(PASSWD, "1000"), Some(pi));
+        assert_eq!(RunAs::parse(PASSWD, "0").map(|user| user.name), Some(String::from("root")));
+        assert_eq!(RunAs::parse(PASSWD, "broken"), None);
+        assert_eq!(RunAs::parse(PASSWD, "nobody"), None);
+    }
+
+    #[test]
+    fn commands_run_as_the_user(){
+        let Some(user) = unprivileged() else { return };
+        let mut cmd = Command::new("id");
+        cmd.arg("-u");
+        user.apply(&mut cmd);
+        let out = cmd.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), user.uid.to_string());
+    }
+
+    #[test]
+    fn files_are_accessed_as_the_user(){
+        let Some(user) = unprivileged() else { return };
+        let dir = temp_dir("access");
+        fs::create_dir(dir.join("private")).unwrap();
+        fs::set_permissions(dir.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        {
+            let _file_access = user.access_files().unwrap();
+            assert_eq!(fs::write(dir.join("private/file"), "hi").unwrap_err().kind(), ErrorKind::PermissionDenied);
+            fs::write(dir.join("theirs"), "hi").unwrap();
+        }
+        assert_eq!(fs::metadata(dir.join("theirs")).unwrap().uid(), user.uid);
+        // and the server is back to itself once the guard is gone
+        fs::write(dir.join("private/file"), "hi").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
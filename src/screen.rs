@@ -0,0 +1,398 @@
+use std::fmt::Write;
+
+/// Size of the screen programs are assumed to draw on
+pub const DEFAULT_ROWS: usize = 24;
+pub const DEFAULT_COLS: usize = 80;
+
+/// Longest parameter list of a control sequence that's kept, so a program spewing garbage can't make it grow forever
+const MAX_PARAMS_LEN: usize = 64;
+
+/// Where the parser is in an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State{
+    Ground,
+    /// Just after an `ESC`
+    Escape,
+    /// In a control sequence (`ESC [`), collecting its parameters
+    Csi,
+    /// In an operating system command (`ESC ]`, like setting the window title), which is skipped up to the BEL or ST ending it
+    Osc,
+    /// An `ESC` in an operating system command, which is usually the start of the ST ending it
+    OscEscape,
+    /// Just after `ESC (` or similar, which picks a character set with the next byte
+    Charset
+}
+
+/// Model of what a program has drawn on its terminal, kept by following the cursor movement and erase sequences in its output
+/// 
+/// Only text is kept, not colors or other attributes, and only common sequences are understood. That's enough to
+/// redraw something recognizable for a client that attaches to a full-screen program partway through its output
+pub struct Screen{
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    saved_cursor: (usize, usize),
+    /// First and last rows (inclusive) that scroll when the cursor moves down past the bottom, set with `ESC [ top ; bottom r`
+    scroll_top: usize,
+    scroll_bottom: usize,
+    /// Whether the program switched to the alternate screen, which full-screen programs like `htop` and `vim` draw on
+    alternate: bool,
+    state: State,
+    params: String,
+    /// Start of a UTF-8 character whose other bytes haven't been seen yet
+    utf8: Vec<u8>,
+    /// Set after a character is written in the last column. The cursor only wraps once the next one is written
+    wrap_pending: bool
+}
+impl Screen{
+    pub fn new(rows: usize, cols: usize) -> Self{
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        Self{rows, cols, cells: vec![vec![' '; cols]; rows], row: 0, col: 0, saved_cursor: (0, 0), scroll_top: 0, scroll_bottom: rows - 1,
+            alternate: false, state: State::Ground, params: String::new(), utf8: Vec::new(), wrap_pending: false}
+    }
+
+    pub fn is_alternate(&self) -> bool{
+        self.alternate
+    }
+
+    /// Number of rows and columns
+    pub fn size(&self) -> (usize, usize){
+        (self.rows, self.cols)
+    }
+
+    /// Row and column of the cursor, counting from 0
+    pub fn cursor(&self) -> (usize, usize){
+        (self.row, self.col)
+    }
+
+    /// Text of each row, without trailing spaces
+    pub fn lines(&self) -> Vec<String>{
+        self.cells.iter().map(|row| row.iter().collect::<String>().trim_end().to_owned()).collect()
+    }
+
+    /// Updates the screen with output from the program. Escape sequences can be split across calls
+    pub fn feed(&mut self, output: &[u8]){
+        for &byte in output{
+            match self.state{
+                State::Ground => self.ground(byte),
+                State::Escape => self.escape(byte),
+                State::Csi => match byte{
+                    0x40..=0x7e => {
+                        self.state = State::Ground;
+                        self.csi(byte);
+                    },
+                    0x1b => self.state = State::Escape,
+                    _ => if self.params.len() < MAX_PARAMS_LEN { self.params.push(byte as char) }
+                },
+                State::Osc => match byte{
+                    0x07 => self.state = State::Ground,
+                    0x1b => self.state = State::OscEscape,
+                    _ => ()
+                },
+                State::OscEscape => self.state = if byte == b'\\' { State::Ground } else { State::Osc },
+                State::Charset => self.state = State::Ground
+            }
+        }
+    }
+
+    /// Output that draws this screen from scratch on a client's terminal, leaving the cursor where the program left it
+    pub fn snapshot(&self) -> Vec<u8>{
+        let mut res = String::new();
+        if self.alternate{
+            res.push_str("\x1b[?1049h");
+        }
+        // setting the scroll region moves the cursor, so it's done before anything is drawn
+        if (self.scroll_top, self.scroll_bottom) != (0, self.rows - 1){
+            let _ = write!(res, "\x1b[{};{}r", self.scroll_top + 1, self.scroll_bottom + 1);
+        }
+        res.push_str("\x1b[H\x1b[2J");
+        for (i, line) in self.lines().iter().enumerate().filter(|(_, line)| !line.is_empty()){
+            let _ = write!(res, "\x1b[{};1H{}", i + 1, line);
+        }
+        let (row, col) = self.cursor();
+        let _ = write!(res, "\x1b[{};{}H", row + 1, col + 1);
+        res.into_bytes()
+    }
+
+    fn ground(&mut self, byte: u8){
+        match byte{
+            0x1b => {
+                self.utf8.clear();
+                self.state = State::Escape;
+            },
+            b'\r' => {
+                self.col = 0;
+                self.wrap_pending = false;
+            },
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            0x08 => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            },
+            b'\t' => self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1),
+            // other control characters (like BEL) don't draw anything
+            0x00..=0x1f | 0x7f => (),
+            0x20..=0x7e => self.put(byte as char),
+            _ => {
+                self.utf8.push(byte);
+                match std::str::from_utf8(&self.utf8){
+                    Ok(s) => {
+                        let c = s.chars().next().unwrap_or(char::REPLACEMENT_CHARACTER);
+                        self.utf8.clear();
+                        self.put(c);
+                    },
+                    // still waiting on the rest of the character
+                    Err(e) if e.error_len().is_none() && self.utf8.len() < 4 => (),
+                    Err(_) => {
+                        self.utf8.clear();
+                        self.put(char::REPLACEMENT_CHARACTER);
+                    }
+                }
+            }
+        }
+    }
+
+    fn escape(&mut self, byte: u8){
+        self.state = State::Ground;
+        match byte{
+            b'[' => {
+                self.params.clear();
+                self.state = State::Csi;
+            },
+            b']' => self.state = State::Osc,
+            b'(' | b')' | b'*' | b'+' => self.state = State::Charset,
+            b'7' => self.saved_cursor = (self.row, self.col),
+            b'8' => (self.row, self.col) = self.saved_cursor,
+            b'D' => self.line_feed(),
+            b'E' => {
+                self.col = 0;
+                self.line_feed();
+            },
+            // reverse line feed, which scrolls the other way at the top
+            b'M' => if self.row == self.scroll_top { self.scroll_down(1) } else { self.row = self.row.saturating_sub(1) },
+            b'c' => *self = Self::new(self.rows, self.cols),
+            _ => ()
+        }
+    }
+
+    fn csi(&mut self, final_byte: u8){
+        let private = self.params.starts_with('?');
+        let nums: Vec<usize> = self.params.trim_start_matches('?').split(';').map(|n| n.parse().unwrap_or(0)).collect();
+        // missing parameters and 0s both mean the default
+        let arg = |i: usize, default: usize| nums.get(i).copied().filter(|n| *n != 0).unwrap_or(default);
+        let mode = nums.first().copied().unwrap_or(0);
+        self.wrap_pending = false;
+        if private{
+            if matches!(final_byte, b'h' | b'l') && nums.iter().any(|n| matches!(n, 47 | 1047 | 1049)){
+                // programs expect a blank screen when switching to or from the alternate one. what was on the
+                // main screen isn't kept, since it's only redrawn for programs on the alternate screen anyway
+                self.alternate = final_byte == b'h';
+                self.erase_rows(0, self.rows);
+            }
+            return
+        }
+        match final_byte{
+            b'A' => self.row = self.row.saturating_sub(arg(0, 1)),
+            b'B' | b'e' => self.row = self.row.saturating_add(arg(0, 1)).min(self.rows - 1),
+            b'C' | b'a' => self.col = self.col.saturating_add(arg(0, 1)).min(self.cols - 1),
+            b'D' => self.col = self.col.saturating_sub(arg(0, 1)),
+            b'E' => (self.row, self.col) = (self.row.saturating_add(arg(0, 1)).min(self.rows - 1), 0),
+            b'F' => (self.row, self.col) = (self.row.saturating_sub(arg(0, 1)), 0),
+            b'G' | b'`' => self.col = (arg(0, 1) - 1).min(self.cols - 1),
+            b'd' => self.row = (arg(0, 1) - 1).min(self.rows - 1),
+            b'H' | b'f' => (self.row, self.col) = ((arg(0, 1) - 1).min(self.rows - 1), (arg(1, 1) - 1).min(self.cols - 1)),
+            b'J' => match mode{
+                0 => {
+                    self.erase_cols(self.row, self.col, self.cols);
+                    self.erase_rows(self.row + 1, self.rows);
+                },
+                1 => {
+                    self.erase_rows(0, self.row);
+                    self.erase_cols(self.row, 0, self.col + 1);
+                },
+                _ => self.erase_rows(0, self.rows)
+            },
+            b'K' => match mode{
+                0 => self.erase_cols(self.row, self.col, self.cols),
+                1 => self.erase_cols(self.row, 0, self.col + 1),
+                _ => self.erase_cols(self.row, 0, self.cols)
+            },
+            b'L' | b'M' if (self.scroll_top..=self.scroll_bottom).contains(&self.row) => {
+                // inserting and deleting lines only shifts the rows between the cursor and the bottom of the scroll region
+                for _ in 0..arg(0, 1).min(self.scroll_bottom - self.row + 1){
+                    if final_byte == b'L'{
+                        self.cells.remove(self.scroll_bottom);
+                        self.cells.insert(self.row, vec![' '; self.cols]);
+                    }else{
+                        self.cells.remove(self.row);
+                        self.cells.insert(self.scroll_bottom, vec![' '; self.cols]);
+                    }
+                }
+            },
+            b'P' => {
+                let line = &mut self.cells[self.row];
+                let len = arg(0, 1).min(self.cols - self.col);
+                line.drain(self.col..self.col + len);
+                line.resize(self.cols, ' ');
+            },
+            b'@' => {
+                let line = &mut self.cells[self.row];
+                let len = arg(0, 1).min(self.cols - self.col);
+                line.splice(self.col..self.col, std::iter::repeat_n(' ', len));
+                line.truncate(self.cols);
+            },
+            b'X' => self.erase_cols(self.row, self.col, self.col.saturating_add(arg(0, 1))),
+            b'S' => self.scroll_up(arg(0, 1)),
+            b'T' => self.scroll_down(arg(0, 1)),
+            b'r' => {
+                let (top, bottom) = (arg(0, 1) - 1, (arg(1, self.rows) - 1).min(self.rows - 1));
+                (self.scroll_top, self.scroll_bottom) = if top < bottom { (top, bottom) } else { (0, self.rows - 1) };
+                (self.row, self.col) = (0, 0);
+            },
+            b's' => self.saved_cursor = (self.row, self.col),
+            b'u' => (self.row, self.col) = self.saved_cursor,
+            // colors and everything else don't change what's on the screen
+            _ => ()
+        }
+    }
+
+    /// Writes a character at the cursor and moves the cursor along
+    fn put(&mut self, c: char){
+        if self.wrap_pending{
+            self.col = 0;
+            self.line_feed();
+        }
+        self.cells[self.row][self.col] = c;
+        if self.col + 1 == self.cols{
+            self.wrap_pending = true;
+        }else{
+            self.col += 1;
+        }
+    }
+
+    /// Moves the cursor down a row, scrolling if it's at the bottom of the scroll region
+    fn line_feed(&mut self){
+        self.wrap_pending = false;
+        if self.row == self.scroll_bottom{
+            self.scroll_up(1);
+        }else if self.row + 1 < self.rows{
+            self.row += 1;
+        }
+    }
+
+    /// Moves the rows of the scroll region up by `count`, adding blank ones at the bottom
+    fn scroll_up(&mut self, count: usize){
+        for _ in 0..count.min(self.scroll_bottom - self.scroll_top + 1){
+            self.cells.remove(self.scroll_top);
+            self.cells.insert(self.scroll_bottom, vec![' '; self.cols]);
+        }
+    }
+
+    /// Moves the rows of the scroll region down by `count`, adding blank ones at the top
+    fn scroll_down(&mut self, count: usize){
+        for _ in 0..count.min(self.scroll_bottom - self.scroll_top + 1){
+            self.cells.remove(self.scroll_bottom);
+            self.cells.insert(self.scroll_top, vec![' '; self.cols]);
+        }
+    }
+
+    /// Blanks rows `from` up to (but not including) `to`
+    fn erase_rows(&mut self, from: usize, to: usize){
+        for row in from..to.min(self.rows){
+            self.cells[row].fill(' ');
+        }
+    }
+
+    /// Blanks columns `from` up to (but not including) `to` of `row`
+    fn erase_cols(&mut self, row: usize, from: usize, to: usize){
+        let to = to.min(self.cols);
+        if from < to{
+            self.cells[row][from..to].fill(' ');
+        }
+    }
+}
+
+impl Default for Screen{
+    fn default() -> Self{
+        Self::new(DEFAULT_ROWS, DEFAULT_COLS)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn drawn(rows: usize, cols: usize, output: &str) -> Screen{
+        let mut screen = Screen::new(rows, cols);
+        screen.feed(output.as_bytes());
+        screen
+    }
+
+    #[test]
+    fn text_is_drawn_at_the_cursor(){
+        let screen = drawn(3, 10, "hello\r\nworld\x1b[1;3HX");
+        assert_eq!(screen.lines(), ["heXlo", "world", ""]);
+        assert_eq!(screen.cursor(), (0, 3));
+    }
+
+    #[test]
+    fn long_lines_wrap_and_the_bottom_scrolls(){
+        let screen = drawn(2, 4, "abcdefgh\r\nij");
+        assert_eq!(screen.lines(), ["efgh", "ij"]);
+        assert_eq!(screen.cursor(), (1, 2));
+    }
+
+    #[test]
+    fn erasing_blanks_part_of_the_screen(){
+        let screen = drawn(3, 5, "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[K");
+        assert_eq!(screen.lines(), ["aaaaa", "bb", "ccccc"]);
+        let screen = drawn(3, 5, "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[J");
+        assert_eq!(screen.lines(), ["aaaaa", "bb", ""]);
+        let screen = drawn(3, 5, "aaaaa\r\nbbbbb\x1b[2J");
+        assert_eq!(screen.lines(), ["", "", ""]);
+    }
+
+    #[test]
+    fn only_the_scroll_region_scrolls(){
+        let screen = drawn(4, 5, "top\r\n1\r\n2\r\nend\x1b[2;3r\x1b[3;1H\n");
+        assert_eq!(screen.lines(), ["top", "2", "", "end"]);
+    }
+
+    #[test]
+    fn sequences_can_be_split_across_output(){
+        let mut screen = Screen::new(2, 10);
+        for chunk in [&b"ab\x1b["[..], b"2;", b"4Hc\xc3", b"\xa9"]{
+            screen.feed(chunk);
+        }
+        assert_eq!(screen.lines(), ["ab", "   c\u{e9}"]);
+        assert_eq!(screen.cursor(), (1, 5));
+    }
+
+    #[test]
+    fn titles_and_colors_dont_draw_anything(){
+        let screen = drawn(1, 10, "\x1b]0;title\x07\x1b[1;31mred\x1b[0m");
+        assert_eq!(screen.lines(), ["red"]);
+    }
+
+    #[test]
+    fn the_alternate_screen_starts_blank(){
+        let screen = drawn(2, 10, "shell\x1b[?1049h\x1b[Hvim");
+        assert!(screen.is_alternate());
+        assert_eq!(screen.lines(), ["vim", ""]);
+        let screen = drawn(2, 10, "shell\x1b[?1049h\x1b[Hvim\x1b[?1049l");
+        assert!(!screen.is_alternate());
+        assert_eq!(screen.lines(), ["", ""]);
+    }
+
+    #[test]
+    fn snapshots_redraw_the_same_screen(){
+        let original = drawn(5, 20, "\x1b[?1049h\x1b[2;4rheader\x1b[3;2Hbody\x1b[5;1Hstatus\x1b[4;7H");
+        let redrawn = drawn(5, 20, &String::from_utf8(original.snapshot()).unwrap());
+        assert_eq!(redrawn.lines(), original.lines());
+        assert_eq!(redrawn.cursor(), original.cursor());
+        assert!(redrawn.is_alternate());
+        assert_eq!((redrawn.scroll_top, redrawn.scroll_bottom), (1, 3));
+    }
+}
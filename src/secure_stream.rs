@@ -1,108 +1,682 @@
-use std::{io::{self, BufWriter, Read, Write}, net::TcpStream, sync::{Arc, Mutex}};
+//! The stream the server talks to clients through, which clients written in Rust can use to frame their messages the same way
 
-/// Wrapper around TcpStream that automatically hashes data sent and received through the socket
-pub struct SecureStream{
-    pub stream: TcpStream,
+use std::{io::{self, ErrorKind, Read, Write}, net::Shutdown, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use super::{poison, rate_limit::RateLimiter, transport::Transport};
+
+/// Socket operations a SecureStream passes through to the transport underneath it
+/// 
+/// Transports that aren't real sockets (like in-memory pipes) can rely on the defaults, which do nothing
+pub trait Socket: Sized{
+    fn peer_ip(&self) -> io::Result<String>{
+        Ok(String::from("unknown"))
+    }
+    fn local_ip(&self) -> io::Result<String>{
+        Ok(String::from("unknown"))
+    }
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()>{
+        Ok(())
+    }
+    fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()>{
+        Ok(())
+    }
+    fn read_timeout(&self) -> io::Result<Option<Duration>>{
+        Ok(None)
+    }
+    fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()>{
+        Ok(())
+    }
+    fn write_timeout(&self) -> io::Result<Option<Duration>>{
+        Ok(None)
+    }
+    fn set_keepalive(&self, _dur: Option<Duration>) -> io::Result<()>{
+        Ok(())
+    }
+    fn try_clone(&self) -> io::Result<Self>{
+        Err(io::Error::from(ErrorKind::Unsupported))
+    }
+}
+
+/// How fast data went through a SecureStream between two samples, in bytes per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput{
+    pub read_per_sec: f64,
+    pub written_per_sec: f64
+}
+
+/// Byte counts of a SecureStream at some point in time
+#[derive(Debug, Clone, Copy)]
+struct Sample{
+    at: Instant,
+    bytes_read: u64,
+    bytes_written: u64
+}
+
+/// Largest message `read_message` (or line `read_line`) accepts, so a corrupted length can't make us allocate gigabytes
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Wrapper around a client's socket that automatically hashes data sent and received through the socket
+/// 
+/// The transport defaults to a client's TCP or Unix socket, but anything that can be read and written works
+pub struct SecureStream<S = Transport>{
+    pub stream: S,
     hash: u64,
+    // skips the keystream entirely, rather than XORing with a hash of 0 (see `new_plain`)
+    plain: bool,
     read_offset: Arc<Mutex<u32>>,
-    write_offset: Arc<Mutex<u32>>
+    write_offset: Arc<Mutex<u32>>,
+    // sequence numbers of the last sequenced message read and written, so replayed messages can be caught
+    read_seq: Arc<Mutex<u64>>,
+    write_seq: Arc<Mutex<u64>>,
+    write_buf: Vec<u8>,
+    // shared between clones, so they add up to everything sent through the socket
+    bytes_read: Arc<AtomicU64>,
+    bytes_written: Arc<AtomicU64>,
+    last_sample: Arc<Mutex<Sample>>,
+    // also shared between clones, so reading and writing both count towards the same limit
+    rate_limit: Option<Arc<RateLimiter>>,
+    // the part of a framed message or line that has been read so far, kept when a read times out partway through
+    message_buf: Vec<u8>
 }
-impl SecureStream{
-    pub fn new(stream: TcpStream) -> Self{
-        Self{stream, hash: 0, read_offset: Arc::new(0.into()), write_offset: Arc::new(0.into())}
+impl<S> SecureStream<S>{
+    pub fn new(stream: S) -> Self{
+        Self{stream, hash: 0, plain: false, read_offset: Arc::new(0.into()), write_offset: Arc::new(0.into()),
+            read_seq: Arc::new(0.into()), write_seq: Arc::new(0.into()), write_buf: Vec::new(),
+            bytes_read: Arc::default(), bytes_written: Arc::default(),
+            last_sample: Arc::new(Mutex::new(Sample{at: Instant::now(), bytes_read: 0, bytes_written: 0})), rate_limit: None, message_buf: Vec::new()}
+    }
+
+    /// Creates a SecureStream that passes everything through to `stream` as it is, without encrypting it
+    /// 
+    /// Meant for transports that are already private (like Unix sockets) or already encrypted (like TLS), and for
+    /// benchmarking or testing against tools like `nc`. Framing, byte counts and rate limits still work as usual
+    pub fn new_plain(stream: S) -> Self{
+        Self{plain: true, ..Self::new(stream)}
+    }
+
+    /// Total number of (decrypted) bytes read through this stream and its clones
+    pub fn bytes_read(&self) -> u64{
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Total number of (unencrypted) bytes written through this stream and its clones
+    pub fn bytes_written(&self) -> u64{
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// How fast bytes have been read and written since the last time this was called, or since the stream was created
+    pub fn throughput(&self) -> Throughput{
+        let now = Sample{at: Instant::now(), bytes_read: self.bytes_read(), bytes_written: self.bytes_written()};
+        let last = std::mem::replace(&mut *poison::lock(&self.last_sample, "throughput sample"), now);
+        let secs = now.at.duration_since(last.at).as_secs_f64();
+        if secs <= 0.0 { return Throughput{read_per_sec: 0.0, written_per_sec: 0.0} }
+        Throughput{
+            read_per_sec: (now.bytes_read - last.bytes_read) as f64 / secs,
+            written_per_sec: (now.bytes_written - last.bytes_written) as f64 / secs
+        }
     }
 
     /// Sets a hash value for this SecureStream, returning itself 
+    /// 
+    /// A hash of 0 leaves everything as it is, so it's no better than `new_plain`
     pub fn set_hash(mut self, hash: u64) -> Self{
         self.hash=hash;
         self
     }
 
-    pub fn peer_addr(&self) -> Result<std::net::SocketAddr, io::Error>{
-        self.stream.peer_addr()
+    /// Mixes a nonce into the hash, returning itself
+    /// 
+    /// The server sends every encrypted connection its own nonce, so nothing recorded from one connection decrypts
+    /// the same way on another, even when they share a hash
+    pub fn mix_nonce(mut self, nonce: u64) -> Self{
+        self.hash ^= nonce;
+        self
+    }
+
+    /// Limits this SecureStream (and its clones) to reading and writing `bytes_per_sec` bytes per second between them,\
+    /// or removes the limit if None, returning itself
+    pub fn set_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self{
+        self.rate_limit = bytes_per_sec.map(|bps| Arc::new(RateLimiter::new(bps)));
+        self
+    }
+
+    /// Most bytes that should be transferred by a single read or write of `len` bytes, which is all of them if there's no limit
+    fn chunk_len(&self, len: usize) -> usize{
+        self.rate_limit.as_ref().map_or(len, |limit| len.min(limit.burst()))
+    }
+
+    /// Counts `bytes` towards the rate limit, sleeping if we've gone over it
+    fn throttle(&self, bytes: usize){
+        if let Some(limit) = &self.rate_limit{
+            limit.consume(bytes);
+        }
     }
-    pub fn local_addr(&self) -> Result<std::net::SocketAddr, io::Error>{
-        self.stream.local_addr()
+}
+impl<S: Socket> SecureStream<S>{
+    pub fn peer_ip(&self) -> Result<String, io::Error>{
+        self.stream.peer_ip()
+    }
+    pub fn local_ip(&self) -> Result<String, io::Error>{
+        self.stream.local_ip()
     }
-    pub fn shutdown(&self, how: std::net::Shutdown) -> Result<(), io::Error>{
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), io::Error>{
         self.stream.shutdown(how)
     }
-    pub fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> io::Result<()>{
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>{
         self.stream.set_read_timeout(dur)
     }
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>>{
+        self.stream.read_timeout()
+    }
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()>{
+        self.stream.set_write_timeout(dur)
+    }
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>>{
+        self.stream.write_timeout()
+    }
+    /// Runs `f` with the read timeout set to `dur`, then puts back whatever it was before, whether or not `f` succeeded
+    /// 
+    /// If `f` succeeds but the old timeout can't be put back, that error is returned instead
+    pub fn with_read_timeout<T>(&mut self, dur: Option<Duration>, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<T>{
+        let previous = self.read_timeout()?;
+        self.set_read_timeout(dur)?;
+        let res = f(self);
+        let restored = self.set_read_timeout(previous);
+        res.and_then(|res| restored.map(|_| res))
+    }
+    /// Runs `f` with the write timeout set to `dur`, then puts back whatever it was before, like `with_read_timeout`
+    pub fn with_write_timeout<T>(&mut self, dur: Option<Duration>, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<T>{
+        let previous = self.write_timeout()?;
+        self.set_write_timeout(dur)?;
+        let res = f(self);
+        let restored = self.set_write_timeout(previous);
+        res.and_then(|res| restored.map(|_| res))
+    }
+    /// Enables OS-level keepalive probes after the connection has been idle for `dur`, or disables them if None
+    pub fn set_keepalive(&self, dur: Option<Duration>) -> io::Result<()>{
+        self.stream.set_keepalive(dur)
+    }
     pub fn try_clone(&self) -> Result<Self, io::Error>{
-        Ok(Self{stream: self.stream.try_clone()?, hash: self.hash, read_offset: self.read_offset.clone(), write_offset: self.write_offset.clone()})
+        Ok(Self{stream: self.stream.try_clone()?, hash: self.hash, plain: self.plain, read_offset: self.read_offset.clone(), write_offset: self.write_offset.clone(),
+            read_seq: self.read_seq.clone(), write_seq: self.write_seq.clone(), write_buf: Vec::new(),
+            bytes_read: self.bytes_read.clone(), bytes_written: self.bytes_written.clone(), last_sample: self.last_sample.clone(),
+            rate_limit: self.rate_limit.clone(), message_buf: Vec::new()})
     }
 }
 
-impl Read for SecureStream{
-    /// Wrapper around the TcpStream's read() function which unshuffles bytes based on the hash before reading. 
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>{
-        match self.read_offset.lock(){
-            Ok(mut offset) => {
-                let read_bytes = self.stream.read(buf)?;
-                let mut bytes = [0u8; 8];
-                let hash = self.hash.rotate_left(*offset * 8);
-                for chunk in buf[..read_bytes].chunks_mut(8){
-                    bytes[..chunk.len()].copy_from_slice(chunk);
-                    let unshuffled = u64::from_be_bytes(bytes) ^ hash;
-                    chunk.copy_from_slice(&unshuffled.to_be_bytes()[..chunk.len()]);
-                }
-                if read_bytes%8!=0{
-                    *offset = (read_bytes as u32 + *offset) % 8;
-                }
-                Ok(read_bytes)
+/// XORs `buf` with the keystream, where `offset` is the position in the stream of `buf[0]` (mod 8)
+/// 
+/// Every byte in the stream is XORed with byte `position % 8` of the hash, so both ends stay in sync no
+/// matter how the stream is split up into reads and writes, as long as the offsets are advanced by the
+/// number of bytes that were actually transferred
+fn apply_keystream(hash: u64, offset: u32, buf: &mut [u8]){
+    let key = hash.rotate_left(offset * 8).to_be_bytes();
+    for (i, byte) in buf.iter_mut().enumerate(){
+        *byte ^= key[i % 8];
+    }
+}
+
+impl<S: Read> SecureStream<S>{
+    /// Reads one whole message written by `write_message`, no matter how it was split up or joined together on the way
+    /// 
+    /// Nothing past the end of the message is read, so whatever comes after it can still be read directly.\
+    /// If this fails partway through a message (for example, from a read timeout), what was read is kept for the next call.\
+    /// Returns `io::ErrorKind::InvalidData` for messages longer than `MAX_MESSAGE_LEN`
+    pub fn read_message(&mut self) -> io::Result<Vec<u8>>{
+        loop{
+            let wanted = match self.message_buf.get(..4){
+                Some(len) => {
+                    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                    if len > MAX_MESSAGE_LEN{
+                        self.message_buf.clear();
+                        return Err(io::Error::new(ErrorKind::InvalidData, format!("Message of {} bytes is too long", len)))
+                    }
+                    4 + len
+                },
+                None => 4
+            };
+            let mut buf = std::mem::take(&mut self.message_buf);
+            if buf.len() == wanted{
+                return Ok(buf.split_off(4))
+            }
+            let have = buf.len();
+            buf.resize(wanted, 0);
+            let read = self.read(&mut buf[have..]);
+            buf.truncate(have + *read.as_ref().unwrap_or(&0));
+            self.message_buf = buf;
+            match read{
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(_) => (),
+                Err(e) => return Err(e)
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string()))
         }
     }
 
-    /// Wrapper around TcpStream's read_exact() function which decrypts bytes based on given hash before reading. 
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), io::Error>{
-        match self.read_offset.lock(){
-            Ok(mut offset) => {
-                self.stream.read_exact(buf)?;
-                let mut bytes = [0u8; 8];
-                let hash = self.hash.rotate_left(*offset * 8);
-                for chunk in buf.chunks_mut(8){
-                    bytes[..chunk.len()].copy_from_slice(chunk);
-                    let unshuffled = u64::from_be_bytes(bytes) ^ hash;
-                    chunk.copy_from_slice(&unshuffled.to_be_bytes()[..chunk.len()]);
-                }
-                if buf.len()%8!=0{
-                    *offset = (buf.len() as u32 + *offset) % 8;
-                }
-                Ok(())
+    /// Reads one whole message written by `write_sequenced_message`, checking that it came after the last one
+    /// 
+    /// The keystream only depends on the hash and the position in the stream, so an eavesdropper could otherwise
+    /// record a message and send it again. Each message carries a sequence number, which has to be higher than the last one read.\
+    /// Returns `io::ErrorKind::InvalidData` for messages that were replayed or reordered, or are too short to have a sequence number
+    pub fn read_sequenced_message(&mut self) -> io::Result<Vec<u8>>{
+        let mut msg = self.read_message()?;
+        if msg.len() < 8{
+            return Err(io::Error::new(ErrorKind::InvalidData, "Sequenced message is missing its sequence number"))
+        }
+        let rest = msg.split_off(8);
+        let seq = u64::from_be_bytes([msg[0], msg[1], msg[2], msg[3], msg[4], msg[5], msg[6], msg[7]]);
+        let mut last_seq = poison::lock(&self.read_seq, "read sequence number");
+        if seq <= *last_seq{
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Message {} was replayed or out of order (last was {})", seq, *last_seq)))
+        }
+        *last_seq = seq;
+        Ok(rest)
+    }
+
+    /// Reads up to the next newline, returning the line without it (or a `\r` before it)
+    /// 
+    /// The stream is read a byte at a time, so nothing past the end of the line is read and whatever comes after it
+    /// can still be read directly. Like `read_message`, what was read is kept for the next call if this fails partway through.\
+    /// Invalid UTF-8 is replaced rather than rejected. Returns `io::ErrorKind::InvalidData` for lines longer than `MAX_MESSAGE_LEN`
+    pub fn read_line(&mut self) -> io::Result<String>{
+        let mut byte = [0u8; 1];
+        while self.message_buf.last() != Some(&b'\n'){
+            if self.message_buf.len() > MAX_MESSAGE_LEN{
+                self.message_buf.clear();
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("Line is longer than {} bytes", MAX_MESSAGE_LEN)))
+            }
+            match self.read(&mut byte)?{
+                0 => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                _ => self.message_buf.push(byte[0])
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string()))
         }
+        let mut line = std::mem::take(&mut self.message_buf);
+        line.pop();
+        if line.last() == Some(&b'\r') { line.pop(); }
+        Ok(String::from_utf8_lossy(&line).into_owned())
     }
 }
 
-impl Write for SecureStream{
-    /// Wrapper around the TcpStream's write() function which encrypts bytes based on the hash before writing. 
+impl<S: Write> SecureStream<S>{
+    /// Writes `msg` prefixed by its length, so the other end can read it back as one message
+    pub fn write_message(&mut self, msg: &[u8]) -> io::Result<()>{
+        let mut framed = Vec::with_capacity(4 + msg.len());
+        framed.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        framed.extend_from_slice(msg);
+        self.write_all(&framed)
+    }
+
+    /// Writes `msg` like `write_message`, with the next sequence number in front of it, so the other end can read
+    /// it back with `read_sequenced_message`
+    pub fn write_sequenced_message(&mut self, msg: &[u8]) -> io::Result<()>{
+        let seq = {
+            let mut seq = poison::lock(&self.write_seq, "write sequence number");
+            *seq += 1;
+            *seq
+        };
+        let mut sequenced = Vec::with_capacity(8 + msg.len());
+        sequenced.extend_from_slice(&seq.to_be_bytes());
+        sequenced.extend_from_slice(msg);
+        self.write_message(&sequenced)
+    }
+
+    /// Writes as much of `buf` as the transport takes before a write would block or time out, returning how much that was
+    /// 
+    /// Unlike `write_all`, running out of room isn't an error, so the caller knows exactly which bytes are left to send.\
+    /// Other errors are only returned if nothing was sent, since they'll come up again on the next write anyway
+    pub fn write_available(&mut self, buf: &[u8]) -> io::Result<usize>{
+        let mut sent = 0;
+        while sent < buf.len(){
+            match self.write(&buf[sent..]){
+                Ok(0) => break,
+                Ok(written) => sent += written,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) || sent > 0 => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(sent)
+    }
+}
+
+impl<S: Read> Read for SecureStream<S>{
+    /// Wrapper around the transport's read() function which unshuffles bytes based on the hash before reading. 
+    /// 
+    /// `read_exact` isn't overridden, so it is built out of calls to this. That way, if it fails partway through
+    /// (for example, from a read timeout), the bytes it did read still advance the offset
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>{
+        let len = self.chunk_len(buf.len());
+        let read_bytes = if self.plain{
+            self.stream.read(&mut buf[..len])?
+        }else{
+            let mut offset = poison::lock(&self.read_offset, "read offset");
+            let read_bytes = self.stream.read(&mut buf[..len])?;
+            apply_keystream(self.hash, *offset, &mut buf[..read_bytes]);
+            *offset = (*offset + (read_bytes % 8) as u32) % 8;
+            read_bytes
+        };
+        self.bytes_read.fetch_add(read_bytes as u64, Ordering::Relaxed);
+        self.throttle(read_bytes);
+        Ok(read_bytes)
+    }
+}
+
+impl<S: Write> Write for SecureStream<S>{
+    /// Wrapper around the transport's write() function which encrypts bytes based on the hash before writing. 
+    /// 
+    /// Like the transport, this may only write part of `buf`, returning the number of bytes that were actually sent
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error>{
-        match self.write_offset.lock(){
-            Ok(mut offset) => {
-                let mut num_bytes_written = 0;
-                let mut writer = BufWriter::new(&self.stream);
-                let hash = self.hash.rotate_left(*offset * 8);
-                for chunk in buf.chunks(8){
-                    let mut bytes = [0u8; 8];
-                    bytes[..chunk.len()].copy_from_slice(chunk);
-                    let shuffled = u64::from_be_bytes(bytes) ^ hash;
-                    writer.write_all(&shuffled.to_be_bytes()[..chunk.len()])?;
-                    num_bytes_written+=chunk.len();
+        let len = self.chunk_len(buf.len());
+        let written = if self.plain{
+            self.stream.write(&buf[..len])?
+        }else{
+            let mut offset = poison::lock(&self.write_offset, "write offset");
+            self.write_buf.clear();
+            self.write_buf.extend_from_slice(&buf[..len]);
+            apply_keystream(self.hash, *offset, &mut self.write_buf);
+            let written = self.stream.write(&self.write_buf)?;
+            *offset = (*offset + (written % 8) as u32) % 8;
+            written
+        };
+        self.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        self.throttle(written);
+        Ok(written)
+    }
+
+    /// Encrypts all of `buf` once, then keeps writing until the transport has taken all of it
+    /// 
+    /// If this fails partway through, the offset still accounts for the bytes that were sent
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), io::Error>{
+        // with nothing to encrypt, there's no need to copy `buf` first
+        if self.plain{
+            while !buf.is_empty(){
+                match self.write(buf){
+                    Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                    Ok(written) => buf = &buf[written..],
+                    Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e)
                 }
-                writer.flush()?;
-                *offset = (*offset + num_bytes_written as u32) % 8;
-                Ok(num_bytes_written)
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string()))
+            return Ok(())
+        }
+        let mut offset = poison::lock(&self.write_offset, "write offset");
+        self.write_buf.clear();
+        self.write_buf.extend_from_slice(buf);
+        apply_keystream(self.hash, *offset, &mut self.write_buf);
+        let mut sent = 0;
+        while sent < self.write_buf.len(){
+            let len = self.chunk_len(self.write_buf.len() - sent);
+            match self.stream.write(&self.write_buf[sent..sent + len]){
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(written) => {
+                    sent += written;
+                    *offset = (*offset + (written % 8) as u32) % 8;
+                    self.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+                    self.throttle(written);
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
         }
+        Ok(())
     }
     
     fn flush(&mut self) -> io::Result<()> {
         self.stream.flush()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+
+    use super::*;
+
+    const HASH: u64 = 0x5eed_cafe_f00d_beef;
+
+    /// Encrypts `writes` one after another, returning a stream that reads them back
+    fn encrypted(writes: &[&[u8]]) -> SecureStream<Cursor<Vec<u8>>>{
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        for data in writes{
+            sender.write_all(data).unwrap();
+        }
+        SecureStream::new(Cursor::new(sender.stream.into_inner())).set_hash(HASH)
+    }
+
+    /// Writer that takes at most `max` bytes per write, and is interrupted every other write
+    struct Trickle{
+        written: Vec<u8>,
+        max: usize,
+        calls: usize
+    }
+    impl Write for Trickle{
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) { return Err(io::Error::from(ErrorKind::Interrupted)) }
+            let len = buf.len().min(self.max);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn flush(&mut self) -> io::Result<()>{
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes_arent_lost_or_sent_twice(){
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        for plain in [false, true]{
+            let trickle = Trickle{written: Vec::new(), max: 3, calls: 0};
+            let mut sender = if plain { SecureStream::new_plain(trickle) } else { SecureStream::new(trickle).set_hash(HASH) };
+            sender.write_all(&data[..500]).unwrap();
+            // write only promises some of it, so the caller sends whatever is left next time
+            let mut rest = &data[500..];
+            while !rest.is_empty(){
+                match sender.write(rest){
+                    Ok(written) => {
+                        assert!(written <= 3);
+                        rest = &rest[written..];
+                    },
+                    Err(e) => assert_eq!(e.kind(), ErrorKind::Interrupted)
+                }
+            }
+            assert_eq!(sender.bytes_written(), 1000);
+
+            let sent = sender.stream.written;
+            let mut receiver = if plain { SecureStream::new_plain(Cursor::new(sent)) } else { SecureStream::new(Cursor::new(sent)).set_hash(HASH) };
+            let mut received = Vec::new();
+            receiver.read_to_end(&mut received).unwrap();
+            assert!(received == data);
+        }
+    }
+
+    #[test]
+    fn plaintext_round_trips(){
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        sender.write_all(b"hello, ").unwrap();
+        sender.write_all(b"world").unwrap();
+        let sent = sender.stream.into_inner();
+        assert_ne!(&sent[..], b"hello, world");
+        let mut receiver = SecureStream::new(Cursor::new(sent)).set_hash(HASH);
+        let mut received = String::new();
+        receiver.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello, world");
+    }
+
+    #[test]
+    fn plain_streams_pass_bytes_through_unchanged(){
+        let bytes: Vec<u8> = (0..=255).collect();
+        let mut sender = SecureStream::new_plain(Cursor::new(Vec::new())).set_hash(HASH);
+        sender.write_all(&bytes).unwrap();
+        sender.write_all(b"line\n").unwrap();
+        let sent = sender.stream.into_inner();
+        assert_eq!(&sent[..256], &bytes[..]);
+        assert_eq!(&sent[256..], b"line\n");
+
+        let mut receiver = SecureStream::new_plain(Cursor::new(sent));
+        let mut received = vec![0u8; 256];
+        receiver.read_exact(&mut received).unwrap();
+        assert_eq!(received, bytes);
+        assert_eq!(receiver.read_line().unwrap(), "line");
+        assert_eq!(receiver.bytes_read(), 261);
+    }
+
+    #[test]
+    fn reading_a_byte_at_a_time_matches_a_bulk_read(){
+        let writes: [&[u8]; 4] = [b"abc", b"defghijklmn", b"o", b"pqrstuvwxyz0123456789"];
+        let mut bulk = Vec::new();
+        encrypted(&writes).read_to_end(&mut bulk).unwrap();
+        assert_eq!(bulk, writes.concat());
+
+        let mut receiver = encrypted(&writes);
+        let mut single = Vec::new();
+        let mut byte = [0u8; 1];
+        while receiver.read(&mut byte).unwrap() == 1{
+            single.push(byte[0]);
+        }
+        assert_eq!(single, bulk);
+    }
+
+    /// Poisons `mutex` by panicking on another thread while holding it
+    fn poison<T: Send + 'static>(mutex: Arc<Mutex<T>>){
+        let held = mutex.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = held.lock().unwrap();
+            panic!("poisoning the lock on purpose");
+        }).join();
+        assert!(mutex.is_poisoned());
+    }
+
+    #[test]
+    fn messages_and_lines_can_be_mixed(){
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        sender.write_message(b"first").unwrap();
+        sender.write_all(b"a line\r\n").unwrap();
+        sender.write_message(b"").unwrap();
+        let mut receiver = SecureStream::new(Cursor::new(sender.stream.into_inner())).set_hash(HASH);
+        assert_eq!(receiver.read_message().unwrap(), b"first");
+        assert_eq!(receiver.read_line().unwrap(), "a line");
+        assert_eq!(receiver.read_message().unwrap(), b"");
+        assert_eq!(receiver.read_message().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn lines_end_at_newlines_with_or_without_a_carriage_return(){
+        let mut receiver = encrypted(&[b"unix\nwindows\r\n\r\nlone\rcr\n\xffbad utf8\nunfinished"]);
+        assert_eq!(receiver.read_line().unwrap(), "unix");
+        assert_eq!(receiver.read_line().unwrap(), "windows");
+        assert_eq!(receiver.read_line().unwrap(), "");
+        assert_eq!(receiver.read_line().unwrap(), "lone\rcr");
+        assert_eq!(receiver.read_line().unwrap(), "\u{fffd}bad utf8");
+        // a line the stream ends partway through isn't a line
+        assert_eq!(receiver.read_line().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn over_long_lines_are_rejected(){
+        let long = vec![b'x'; MAX_MESSAGE_LEN + 2];
+        let mut receiver = encrypted(&[&long, b"\nnext\n"]);
+        assert_eq!(receiver.read_line().unwrap_err().kind(), ErrorKind::InvalidData);
+        // what was read of it is thrown away, and reading carries on from there
+        let rest = receiver.read_line().unwrap();
+        assert!(rest.len() < long.len() && rest.bytes().all(|b| b == b'x'));
+        assert_eq!(receiver.read_line().unwrap(), "next");
+    }
+
+    #[test]
+    fn poisoned_offsets_are_recovered(){
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        sender.write_all(b"before, ").unwrap();
+        poison(sender.write_offset.clone());
+        let ((), records) = crate::logger::capture(|| sender.write_all(b"after").unwrap());
+        assert!(records.iter().any(|(level, msg)| *level == crate::logger::Level::Warn && msg.contains("write offset")), "{:?}", records);
+        assert!(!sender.write_offset.is_poisoned());
+
+        let mut receiver = SecureStream::new(Cursor::new(sender.stream.into_inner())).set_hash(HASH);
+        let mut received = [0u8; 3];
+        receiver.read_exact(&mut received).unwrap();
+        poison(receiver.read_offset.clone());
+        let mut rest = String::new();
+        receiver.read_to_string(&mut rest).unwrap();
+        assert_eq!(format!("{}{}", String::from_utf8_lossy(&received), rest), "before, after");
+    }
+
+    #[test]
+    fn clones_can_read_and_write_at_the_same_time(){
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = SecureStream::new(Transport::from(a)).set_hash(HASH);
+        let client = SecureStream::new(Transport::from(b)).set_hash(HASH);
+        // far more than the socket can buffer, so neither side finishes writing until the other is reading
+        let input: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let output: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 241) as u8).collect();
+
+        // each side reads on a clone while it writes on the original, like a client's input and output threads
+        let read_all = |mut stream: SecureStream, len: usize| std::thread::spawn(move || {
+            let mut received = vec![0u8; len];
+            stream.read_exact(&mut received).map(|_| received)
+        });
+        let input_read = read_all(server.try_clone().unwrap(), input.len());
+        let output_read = read_all(client.try_clone().unwrap(), output.len());
+        let write_all = |mut stream: SecureStream, data: Vec<u8>| std::thread::spawn(move || stream.write_all(&data));
+        let output_written = write_all(server, output.clone());
+        let input_written = write_all(client, input.clone());
+
+        input_written.join().unwrap().unwrap();
+        output_written.join().unwrap().unwrap();
+        assert!(input_read.join().unwrap().unwrap() == input);
+        assert!(output_read.join().unwrap().unwrap() == output);
+    }
+
+    #[test]
+    fn sequenced_messages_have_to_be_in_order(){
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH);
+        sender.write_sequenced_message(b"one").unwrap();
+        sender.write_message(&[&1u64.to_be_bytes()[..], b"one again"].concat()).unwrap();
+        sender.write_message(b"short").unwrap();
+        let mut receiver = SecureStream::new(Cursor::new(sender.stream.into_inner())).set_hash(HASH);
+        assert_eq!(receiver.read_sequenced_message().unwrap(), b"one");
+        assert_eq!(receiver.read_sequenced_message().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(receiver.read_sequenced_message().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nonces_keep_streams_apart(){
+        let mut sender = SecureStream::new(Cursor::new(Vec::new())).set_hash(HASH).mix_nonce(1);
+        sender.write_all(b"only for nonce 1").unwrap();
+        let sent = sender.stream.into_inner();
+        let mut received = Vec::new();
+        SecureStream::new(Cursor::new(sent.clone())).set_hash(HASH).mix_nonce(2).read_to_end(&mut received).unwrap();
+        assert_ne!(received, b"only for nonce 1");
+        received.clear();
+        SecureStream::new(Cursor::new(sent)).set_hash(HASH).mix_nonce(1).read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"only for nonce 1");
+    }
+
+    #[test]
+    fn timeouts_are_put_back_after_with_read_timeout(){
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut stream = SecureStream::new_plain(crate::transport::Transport::from(ours));
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.set_write_timeout(Some(Duration::from_secs(3))).unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(stream.write_timeout().unwrap(), Some(Duration::from_secs(3)));
+
+        // nothing has been sent, so the read times out, and the old timeout comes back anyways
+        let res = stream.with_read_timeout(Some(Duration::from_millis(50)), |stream| {
+            // the OS rounds timeouts to its clock ticks, so this is only roughly 50ms
+            assert!(stream.read_timeout().unwrap().is_some_and(|dur| dur < Duration::from_secs(1)));
+            stream.read(&mut [0u8; 4])
+        });
+        assert!(matches!(res.unwrap_err().kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+
+        (&theirs).write_all(b"data").unwrap();
+        let mut buf = [0u8; 4];
+        stream.with_read_timeout(None, |stream| stream.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+
+        stream.with_write_timeout(Some(Duration::from_millis(50)), |stream| stream.write_all(b"reply")).unwrap();
+        assert_eq!(stream.write_timeout().unwrap(), Some(Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,635 @@
+use std::{env, fs, io::{self, ErrorKind}, net::{SocketAddr, TcpListener, ToSocketAddrs}, os::unix::{fs::{FileTypeExt, PermissionsExt}, net::UnixListener}, path::{Path, PathBuf}, sync::{mpsc, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use super::command_runner::{self, ClientSession};
+use super::process_state::{self, ProcessRecord};
+use super::client::{self, Client};
+use super::transport::Transport;
+use super::shutdown::StopFlag;
+use super::logger;
+use super::poison;
+use super::tls::{self, ServerConfig};
+use super::run_as::RunAs;
+use super::auth::{self, Authenticator};
+
+/// How often processes orphaned to the server are checked on, to collect the ones that have exited
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One of the processes a server is managing for its clients, as listed by `Server::managed_processes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcInfo{
+    /// Id clients use to adopt the process with `rspi adopt`
+    pub id: usize,
+    pub cmd: String,
+    pub pid: Option<u32>,
+    pub running: bool,
+    pub cwd: PathBuf
+}
+
+enum Listener{
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+/// Listens for clients and keeps track of the processes they've orphaned to the server
+/// 
+/// The binary runs one of these, but it can also be embedded and run in-process with `run_nonblocking`, after
+/// which it can be asked about its state and stopped with `shutdown`
+pub struct Server{
+    listeners: Mutex<Vec<Listener>>,
+    local_addrs: Vec<SocketAddr>,
+    child_processes: Arc<Mutex<Vec<ClientSession>>>,
+    recovered: Arc<Mutex<Vec<ProcessRecord>>>,
+    client_threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    tls: Option<Arc<ServerConfig>>,
+    auth: Arc<dyn Authenticator>,
+    stop: StopFlag
+}
+impl Server{
+    /// Binds to each of `addrs`, along with the Unix socket given by "RSPI_SOCKET_PATH" if it is set, and picks back
+    /// up any processes recorded in the "RSPI_STATE_FILE". TCP connections use TLS if "RSPI_TLS_CERT" and "RSPI_TLS_KEY" are set
+    /// 
+    /// Addresses that can't be bound to are logged and skipped. Returns `io::ErrorKind::AddrNotAvailable` if none of them could be
+    pub fn bind(addrs: &[SocketAddr]) -> io::Result<Self>{
+        Self::bind_with_socket(addrs, env::var_os("RSPI_SOCKET_PATH").filter(|p| !p.is_empty()).as_deref().map(Path::new))
+    }
+
+    /// Binds like `bind`, but to the Unix socket at `socket_path` instead of the one in "RSPI_SOCKET_PATH"
+    fn bind_with_socket(addrs: &[SocketAddr], socket_path: Option<&Path>) -> io::Result<Self>{
+        let tls = tls::config_from_env()?;
+        // TCP connections without TLS rely on the hashkey for encryption
+        if tls.is_none() && !addrs.is_empty(){
+            client::check_hashkey()?;
+        }
+        // better to find out that commands can't be run as RSPI_RUN_AS_USER now than when the first client connects
+        if let Some(run_as) = RunAs::from_env()?{
+            logger::info!("Running commands as {} (uid {}, gid {})", run_as.name, run_as.uid, run_as.gid);
+            // otherwise files the server opens as that user could still be opened through one of the server's groups
+            RunAs::drop_server_groups()?;
+        }
+        // a jail that can't be set up has to stop the server, rather than leave sessions free to go anywhere
+        if let Some(root) = command_runner::root_dir()?{
+            logger::info!("Keeping sessions inside {}", root.display());
+        }
+        let auth = auth::from_env()?;
+        // keep going with whichever addresses we could bind to
+        let mut listeners: Vec<Listener> = addrs.iter()
+            .filter_map(|addr| match TcpListener::bind(addr){
+                Ok(listener) => {
+                    logger::info!("Server started on {}", listener.local_addr().unwrap_or(*addr));
+                    Some(Listener::Tcp(listener))
+                },
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    logger::error!("Could not bind to {}: {}, is another server already using that port?", addr, e);
+                    None
+                },
+                Err(e) => {
+                    logger::error!("Could not bind to {}: {}", addr, e);
+                    None
+                }
+            })
+            .collect();
+        let local_addrs = listeners.iter().filter_map(|listener| match listener{
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(_) => None
+        }).collect();
+        // local tools can also connect through a Unix socket, skipping the encryption
+        if let Some(path) = socket_path{
+            // anyone who can connect to the socket skips the encryption, so only the user the server runs as may
+            let bound = remove_stale_socket(path).and_then(|_| UnixListener::bind(path)).and_then(|listener| {
+                // the socket is ours by now, so don't leave it behind if it can't be locked down
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map(|_| listener)
+                    .inspect_err(|_| { let _ = fs::remove_file(path); })
+            });
+            match bound{
+                Ok(listener) => {
+                    logger::info!("Server started on {}", path.display());
+                    listeners.push(Listener::Unix(listener));
+                },
+                Err(e) => logger::error!("Could not bind to {}: {}", path.display(), e)
+            }
+        }
+        if listeners.is_empty(){
+            return Err(io::Error::new(ErrorKind::AddrNotAvailable,
+                format!("Could not bind to any of {}", addrs.iter().map(SocketAddr::to_string).collect::<Vec<String>>().join(","))))
+        }
+
+        // pick back up any processes that were orphaned before the server last restarted
+        let mut recovered = Vec::new();
+        if let Some(path) = process_state::state_file(){
+            match process_state::load(&path){
+                Ok(records) => recovered = records,
+                Err(e) => logger::error!("Could not load process state from {}: {}", path.display(), e)
+            }
+            if let Err(e) = process_state::save_orphans(&[], &recovered){
+                logger::error!("Could not save process state to {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(Self{listeners: Mutex::new(listeners), local_addrs, child_processes: Arc::default(), recovered: Arc::new(Mutex::new(recovered)),
+            client_threads: Arc::default(), tls, auth, stop: StopFlag::default()})
+    }
+
+    /// Decides who can log in with `auth` instead of the password in "RSPI_SERVER_PASS" or users in "RSPI_USERS_FILE"
+    pub fn set_authenticator(mut self, auth: Arc<dyn Authenticator>) -> Self{
+        self.auth = auth;
+        self
+    }
+
+    /// Binds to the addresses given by `resolve_bind_addr`, see `bind`
+    pub fn from_env() -> io::Result<Self>{
+        Self::bind(&resolve_bind_addr()?)
+    }
+
+    /// Addresses the server is listening on, which tells you the port it got when bound to port 0
+    pub fn local_addrs(&self) -> &[SocketAddr]{
+        &self.local_addrs
+    }
+
+    /// Number of clients that are currently connected, including ones that haven't logged in yet
+    pub fn active_connections(&self) -> usize{
+        poison::lock(&self.client_threads, "client threads").iter().filter(|handle| !handle.is_finished()).count()
+    }
+
+    /// Processes that clients have given to the server to manage, which keep running while nobody is connected to them
+    pub fn managed_processes(&self) -> Vec<ProcInfo>{
+        poison::lock(&self.child_processes, "processes").iter().enumerate()
+            .map(|(id, session)| ProcInfo{id, cmd: session.cmd_name.clone(), pid: session.pid(), running: session.has_child(), cwd: session.path.clone()})
+            .collect()
+    }
+
+    /// Asks the server to stop, which disconnects its clients and kills the processes it is managing
+    /// 
+    /// If "RSPI_STATE_FILE" is set, the processes are left running instead, and recorded in the state file so the next
+    /// server can list them. Their output has nowhere to go once the server is gone though.\
+    /// `run` returns once it has finished doing so
+    pub fn shutdown(&self){
+        self.stop.stop();
+    }
+
+    /// Runs `run` on a thread of its own, so the server can be asked about its state and stopped while it's running
+    pub fn run_nonblocking(self: Arc<Self>) -> JoinHandle<io::Result<()>>{
+        thread::spawn(move || self.run())
+    }
+
+    /// Serves clients until the server is shut down, either by `shutdown` or because the process is shutting down
+    /// 
+    /// A server can only be run once, running it again returns an error
+    pub fn run(&self) -> io::Result<()>{
+        let listeners = std::mem::take(&mut *poison::lock(&self.listeners, "listeners"));
+        if listeners.is_empty(){
+            return Err(io::Error::other("Server has already been run"))
+        }
+
+        // nobody is waiting on orphaned processes, so collect them as they exit instead of leaving zombies around
+        let reaper = {
+            let child_processes = self.child_processes.clone();
+            let stop = self.stop.clone();
+            thread::spawn(move || {
+                while !stop.is_stopped(){
+                    for session in poison::lock(&child_processes, "processes").iter_mut(){
+                        session.reap();
+                    }
+                    thread::sleep(REAP_INTERVAL);
+                }
+            })
+        };
+
+        let acceptors: Vec<JoinHandle<()>> = listeners.into_iter().map(|listener| {
+            let child_processes = self.child_processes.clone();
+            let recovered = self.recovered.clone();
+            let client_threads = self.client_threads.clone();
+            let tls = self.tls.clone();
+            let auth = self.auth.clone();
+            let stop = self.stop.clone();
+            thread::spawn(move || {
+                // accept connections without blocking so we can notice when we're asked to shut down
+                let nonblocking = match &listener{
+                    Listener::Tcp(listener) => listener.set_nonblocking(true),
+                    Listener::Unix(listener) => listener.set_nonblocking(true)
+                };
+                if let Err(e) = nonblocking{
+                    logger::error!("Could not set listener to non-blocking: {}", e);
+                    return
+                }
+                match listener{
+                    Listener::Tcp(listener) => accept_clients(|| listener.accept().map(|(stream, _)| Transport::from(stream)),
+                        child_processes, recovered, client_threads, tls, auth, stop),
+                    Listener::Unix(listener) => {
+                        accept_clients(|| listener.accept().map(|(stream, _)| Transport::from(stream)), child_processes, recovered, client_threads, None, auth, stop);
+                        if let Ok(addr) = listener.local_addr(){
+                            if let Some(path) = addr.as_pathname() { let _ = fs::remove_file(path); }
+                        }
+                    }
+                }
+            })
+        }).collect();
+        for acceptor in acceptors{
+            let _ = acceptor.join();
+        }
+        let _ = reaper.join();
+
+        logger::info!("Shutting down...");
+
+        // connected clients notice the shutdown on their own, but don't wait forever on ones stuck logging in
+        let client_threads = std::mem::take(&mut *poison::lock(&self.client_threads, "client threads"));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client_threads.iter().any(|handle| !handle.is_finished()) && Instant::now() < deadline{
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let sessions = std::mem::take(&mut *poison::lock(&self.child_processes, "processes"));
+        match process_state::state_file(){
+            // with a state file, orphaned processes outlive the server, and the next one picks them up from the file
+            Some(path) => {
+                let mut recovered = poison::lock(&self.recovered, "recovered processes").clone();
+                process_state::prune_stale(&mut recovered);
+                match process_state::save_orphans(&sessions, &recovered){
+                    Ok(_) => logger::info!("Leaving {} orphaned processes running, recorded in {}",
+                        sessions.iter().filter(|session| session.has_child()).count(), path.display()),
+                    Err(e) => logger::error!("Could not save orphaned processes to {}: {}", path.display(), e)
+                }
+                for session in sessions{
+                    if session.close().is_err() { logger::error!("Error closing session"); }
+                }
+            },
+            // otherwise they'd be left running with nothing managing them or any way to find them again
+            None => for mut session in sessions{
+                session.kill();
+                if session.close().is_err() { logger::error!("Error closing session"); }
+            }
+        }
+        logger::info!("Server stopped");
+        Ok(())
+    }
+}
+
+/// Works out which addresses the server should listen on, using the first of these that is set:
+/// - comma separated addresses in the first command line argument, ie. "0.0.0.0:8080,[::]:8080" to serve both IPv4 and IPv6
+/// - comma separated addresses in the "RSPI_SERVER_ADDR" enviorment variable
+/// - the "RSPI_HOST" and "RSPI_PORT" enviorment variables, defaulting to 127.0.0.1 and 8080 if only one of them is set
+/// - 127.0.0.1:8080
+/// 
+/// Hostnames are resolved to the first address they point to.\
+/// Returns `io::ErrorKind::InvalidInput` if an address or port can't be parsed
+pub fn resolve_bind_addr() -> io::Result<Vec<SocketAddr>>{
+    bind_addr_from(env::args().nth(1), env::var("RSPI_SERVER_ADDR").ok(), env::var("RSPI_HOST").ok(), env::var("RSPI_PORT").ok())
+}
+
+/// `resolve_bind_addr`, given the command line argument and each of the enviorment variables it looks at
+fn bind_addr_from(arg: Option<String>, server_addr: Option<String>, host: Option<String>, port: Option<String>) -> io::Result<Vec<SocketAddr>>{
+    let invalid = |what: &str, e: &dyn std::fmt::Display| io::Error::new(ErrorKind::InvalidInput, format!("Invalid {}: {}", what, e));
+    let addrs = arg.or(server_addr).filter(|addrs| !addrs.trim().is_empty());
+    let Some(addrs) = addrs else {
+        let host = host.filter(|h| !h.is_empty());
+        let port = port.filter(|p| !p.is_empty());
+        let port = match port{
+            Some(port) => port.trim().parse::<u16>().map_err(|e| invalid(&format!("port {:?}", port), &e))?,
+            None => 8080
+        };
+        let host = host.unwrap_or(String::from("127.0.0.1"));
+        return (host.trim(), port).to_socket_addrs()
+            .map_err(|e| invalid(&format!("host {:?}", host), &e))?
+            .next()
+            .map(|addr| vec![addr])
+            .ok_or_else(|| invalid(&format!("host {:?}", host), &"it doesn't resolve to any address"))
+    };
+    parse_addrs(&addrs)
+}
+
+/// Resolves comma separated addresses, like "0.0.0.0:8080,[::]:8080", skipping any that are blank
+/// 
+/// Returns `io::ErrorKind::InvalidInput` if one can't be parsed or doesn't resolve to anything
+fn parse_addrs(addrs: &str) -> io::Result<Vec<SocketAddr>>{
+    let invalid = |addr: &str, e: &dyn std::fmt::Display| io::Error::new(ErrorKind::InvalidInput, format!("Invalid address {:?}: {}", addr, e));
+    addrs.split(',').map(str::trim).filter(|addr| !addr.is_empty())
+        .map(|addr| addr.to_socket_addrs()
+            .map_err(|e| invalid(addr, &e))?
+            .next()
+            .ok_or_else(|| invalid(addr, &"it doesn't resolve to any address")))
+        .collect()
+}
+
+/// Removes the socket file a previous run left behind at `path`, which would stop us from binding to it
+/// 
+/// Anything else there is left alone, and returns `io::ErrorKind::AlreadyExists`
+fn remove_stale_socket(path: &Path) -> io::Result<()>{
+    match fs::symlink_metadata(path){
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} already exists, and isn't a socket", path.display()))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Sets up the thread a client is served on
+fn client_thread() -> thread::Builder{
+    let builder = thread::Builder::new();
+    // lets tests make starting it fail, the way it does when a Pi runs out of threads
+    #[cfg(test)]
+    let builder = match tests::CLIENT_STACK_SIZE.get(){
+        Some(size) => builder.stack_size(size),
+        None => builder
+    };
+    builder
+}
+
+/// Accepts connections from a non-blocking listener until the server stops, running each client on its own thread
+/// 
+/// If `tls` is given, each client's thread starts with the TLS handshake, so a slow one doesn't hold up the others
+fn accept_clients(mut accept: impl FnMut() -> io::Result<Transport>, child_processes: Arc<Mutex<Vec<ClientSession>>>, recovered: Arc<Mutex<Vec<ProcessRecord>>>,
+    client_threads: Arc<Mutex<Vec<JoinHandle<()>>>>, tls: Option<Arc<ServerConfig>>, auth: Arc<dyn Authenticator>, stop: StopFlag){
+    while !stop.is_stopped(){
+        match accept(){
+            Ok(stream) => {
+                let _ = stream.set_nonblocking(false);
+                let child_processes_ref = child_processes.clone();
+                let recovered_ref = recovered.clone();
+                let stop_ref = stop.clone();
+                let tls_ref = tls.clone();
+                let auth_ref = auth.clone();
+                // the thread is only given the connection once it's running, so if it can't be started we still have
+                // the connection to explain why it's being closed
+                let (stream_tx, stream_rx) = mpsc::channel::<Transport>();
+                let spawned = client_thread().spawn(move || {
+                    let Ok(stream) = stream_rx.recv() else { return };
+                    let stream = match tls_ref{
+                        Some(config) => stream.into_tls(config),
+                        None => Ok(stream)
+                    };
+                    match stream.and_then(|stream| Client::new(stream, child_processes_ref, recovered_ref, stop_ref, auth_ref)){
+                        Ok(client) => client.run(),
+                        // wrong passwords are already logged when they're checked
+                        Err(e) if e.kind() == ErrorKind::PermissionDenied => (),
+                        Err(e) => logger::warn!("Could not set up client: {}", e)
+                    }
+                });
+                match spawned{
+                    Ok(handle) => {
+                        let _ = stream_tx.send(stream);
+                        let mut threads = poison::lock(&client_threads, "client threads");
+                        threads.retain(|handle| !handle.is_finished());
+                        threads.push(handle);
+                    },
+                    // a Pi can run out of threads, and the client deserves to know it was turned away on purpose
+                    Err(e) => {
+                        logger::warn!("Could not start a thread for a client, turning it away: {}", e);
+                        if tls.is_none(){
+                            Client::refuse(stream, "Server is at capacity, try again later");
+                        }
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+            Err(e) => {
+                logger::warn!("Could not connect to client: {}", e);
+                // running out of file descriptors leaves the connection waiting to be accepted, so don't spin on it
+                thread::sleep(Duration::from_millis(50));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{io::{Read, Write}, os::unix::net::UnixStream};
+
+    use super::*;
+    use crate::auth::EnvPassword;
+
+    /// A server listening on a port of its own on localhost
+    fn local_server() -> Arc<Server>{
+        Arc::new(Server::bind(&[SocketAddr::from(([127, 0, 0, 1], 0))]).unwrap())
+    }
+
+    #[test]
+    fn running_in_the_background_stops_after_shutdown(){
+        let server = local_server();
+        assert_ne!(server.local_addrs()[0].port(), 0);
+        let running = server.clone().run_nonblocking();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!running.is_finished());
+
+        let asked = Instant::now();
+        server.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(asked.elapsed() < Duration::from_secs(2));
+        // its listeners were used up the first time
+        assert!(server.run().is_err());
+    }
+
+    #[test]
+    fn ports_already_in_use_are_errors_not_panics(){
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let (server, records) = logger::capture(|| Server::bind_with_socket(&[addr], None));
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::AddrNotAvailable);
+        assert!(records.iter().any(|(level, msg)| *level == logger::Level::Error && msg.ends_with("is another server already using that port?")), "{:?}", records);
+
+        // any address that is free is still listened on
+        let server = Server::bind_with_socket(&[addr, SocketAddr::from(([127, 0, 0, 1], 0))], None).unwrap();
+        assert_eq!(server.local_addrs().len(), 1);
+        assert_ne!(server.local_addrs()[0], addr);
+    }
+
+    #[test]
+    fn addresses_are_comma_separated(){
+        assert_eq!(parse_addrs("0.0.0.0:8080, [::]:8080,").unwrap(),
+            [SocketAddr::from(([0, 0, 0, 0], 8080)), SocketAddr::from(([0u16; 8], 8080))]);
+        assert_eq!(parse_addrs("[::1]:9000").unwrap(), [SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 9000))]);
+        assert_eq!(parse_addrs("localhost:9000").unwrap()[0].port(), 9000);
+        assert!(parse_addrs(" , ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unparseable_addresses_are_invalid_input(){
+        for addrs in ["127.0.0.1", "127.0.0.1:8080,127.0.0.1", "127.0.0.1:99999", "[::1]:port"]{
+            assert_eq!(parse_addrs(addrs).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", addrs);
+        }
+    }
+
+    #[test]
+    fn bind_addresses_come_from_the_first_place_theyre_set(){
+        let some = |s: &str| Some(String::from(s));
+        let addr = |port| vec![SocketAddr::from(([127, 0, 0, 1], port))];
+        assert_eq!(bind_addr_from(some("127.0.0.1:1"), some("127.0.0.1:2"), some("0.0.0.0"), some("3")).unwrap(), addr(1));
+        assert_eq!(bind_addr_from(None, some("127.0.0.1:2"), some("0.0.0.0"), some("3")).unwrap(), addr(2));
+        // blank values count as unset
+        assert_eq!(bind_addr_from(some(" "), some(""), None, some("3")).unwrap(), addr(3));
+        assert_eq!(bind_addr_from(None, None, some("0.0.0.0"), None).unwrap(), [SocketAddr::from(([0, 0, 0, 0], 8080))]);
+        assert_eq!(bind_addr_from(None, None, some("::1"), some(" 4 ")).unwrap(), [SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 4))]);
+        assert_eq!(bind_addr_from(None, None, None, None).unwrap(), addr(8080));
+    }
+
+    #[test]
+    fn malformed_bind_addresses_are_invalid_input(){
+        let some = |s: &str| Some(String::from(s));
+        let cases = [(some("127.0.0.1"), None, None, None), (None, some("localhost:http"), None, None),
+            (None, None, None, some("65536")), (None, None, some("127.0.0.1"), some("port"))];
+        for (arg, server_addr, host, port) in cases{
+            let err = bind_addr_from(arg.clone(), server_addr.clone(), host.clone(), port.clone()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?} {:?} {:?} {:?}", arg, server_addr, host, port);
+            assert!(err.to_string().starts_with("Invalid "), "{}", err);
+        }
+    }
+
+    /// Reads from `conn` until `end` arrives, returning everything read up to and including it
+    fn read_until(mut conn: impl Read, end: &str) -> String{
+        let mut received = Vec::new();
+        let mut byte = [0u8];
+        while !received.ends_with(end.as_bytes()){
+            assert_eq!(conn.read(&mut byte).unwrap(), 1, "connection closed before {:?}, after {:?}", end, String::from_utf8_lossy(&received));
+            received.push(byte[0]);
+        }
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    #[test]
+    fn clients_can_connect_through_a_unix_socket(){
+        let path = env::temp_dir().join(format!("rspi-server-socket-{}", std::process::id()));
+        let server = Arc::new(Server::bind_with_socket(&[], Some(&path)).unwrap());
+        assert!(server.local_addrs().is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let running = server.clone().run_nonblocking();
+
+        // nothing is encrypted, so the password is all it takes to log in
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        conn.write_all(b"Password").unwrap();
+        read_until(&conn, "$ ");
+        conn.write_all(b"echo through the socket").unwrap();
+        assert!(read_until(&conn, "$ ").contains("through the socket\r\n"));
+
+        server.shutdown();
+        running.join().unwrap().unwrap();
+        // the socket file is cleaned up, so the next server can bind to it
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn only_sockets_are_removed_from_the_socket_path(){
+        let path = env::temp_dir().join(format!("rspi-server-not-a-socket-{}", std::process::id()));
+        fs::write(&path, "keep me").unwrap();
+        let server = Server::bind_with_socket(&[], Some(&path));
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::AddrNotAvailable);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+
+        // nor what a symlink there points to
+        let link = path.with_extension("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(Server::bind_with_socket(&[], Some(&link)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_file(&path);
+
+        // a socket left behind by a previous server is replaced
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let server = Arc::new(Server::bind_with_socket(&[], Some(&path)).unwrap());
+        let running = server.clone().run_nonblocking();
+        server.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn commands_can_be_run_over_tls(){
+        use rustls::{crypto::ring, pki_types::{pem::PemObject, CertificateDer}, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        let mut server = Server::bind_with_socket(&[SocketAddr::from(([127, 0, 0, 1], 0))], None).unwrap();
+        // set here rather than through RSPI_TLS_CERT, which every other server in the tests would pick up too
+        server.tls = Some(tls::load_config(&testdata.join("server.pem"), &testdata.join("server.key"), None).unwrap());
+        let server = Arc::new(server);
+        let running = server.clone().run_nonblocking();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(testdata.join("server.pem")).unwrap()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let sock = std::net::TcpStream::connect(server.local_addrs()[0]).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut conn = StreamOwned::new(ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap(), sock);
+
+        conn.write_all(b"Password").unwrap();
+        read_until(&mut conn, "$ ");
+        conn.write_all(b"echo over tls").unwrap();
+        assert!(read_until(&mut conn, "$ ").contains("over tls\r\n"));
+
+        server.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Waits up to a few seconds for `check` to be true
+    fn eventually(check: impl Fn() -> bool) -> bool{
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !check(){
+            if Instant::now() > deadline { return false }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn connections_and_orphaned_processes_are_counted(){
+        let path = env::temp_dir().join(format!("rspi-server-counted-{}", std::process::id()));
+        let dir = env::temp_dir().canonicalize().unwrap();
+        let server = Arc::new(Server::bind_with_socket(&[], Some(&path)).unwrap());
+        let running = server.clone().run_nonblocking();
+        assert_eq!(server.active_connections(), 0);
+        assert!(server.managed_processes().is_empty());
+
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        conn.write_all(b"Password").unwrap();
+        read_until(&conn, "$ ");
+        assert!(eventually(|| server.active_connections() == 1));
+        conn.write_all(format!("cd {}", dir.display()).as_bytes()).unwrap();
+        read_until(&conn, "$ ");
+        conn.write_all(b"sleep 30").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        conn.write_all(b"rspi orphan").unwrap();
+        read_until(&conn, "$ ");
+
+        let procs = server.managed_processes();
+        assert_eq!(procs.len(), 1);
+        assert_eq!((procs[0].id, procs[0].cmd.as_str(), procs[0].running, procs[0].cwd.as_path()), (0, "sleep", true, dir.as_path()));
+        assert!(procs[0].pid.is_some());
+
+        // orphaned processes outlive the client that started them
+        drop(conn);
+        assert!(eventually(|| server.active_connections() == 0));
+        assert!(server.managed_processes()[0].running);
+        server.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    thread_local!{
+        /// Stack size of client threads started by `accept_clients` on this thread, see `client_thread`
+        pub static CLIENT_STACK_SIZE: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+    }
+
+    #[test]
+    fn clients_are_told_when_their_thread_cant_start(){
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut pending = Some(Transport::Unix(theirs));
+        let stop = StopFlag::default();
+        let client_threads: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+        let accept = || match pending.take(){
+            Some(stream) => Ok(stream),
+            None => {
+                stop.stop();
+                Err(io::Error::from(ErrorKind::WouldBlock))
+            }
+        };
+        // no thread can have a stack this big, so starting one fails
+        CLIENT_STACK_SIZE.set(Some(1 << 46));
+        accept_clients(accept, Arc::default(), Arc::default(), client_threads.clone(), None, Arc::new(EnvPassword), stop.clone());
+
+        let mut refusal = String::new();
+        (&ours).read_to_string(&mut refusal).unwrap();
+        assert_eq!(refusal, "Server is at capacity, try again later\n");
+        assert!(poison::lock(&client_threads, "client threads").is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+unsafe extern "C"{
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+/// Set once the server has been asked to stop, after which clients should finish up and disconnect
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: i32){
+    // only async-signal-safe work is allowed in here, so just raise the flag
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT and SIGTERM start a graceful shutdown instead of killing the server immediately
+pub fn install_handlers(){
+    unsafe{
+        signal(SIGINT, handle_signal);
+        signal(SIGTERM, handle_signal);
+    }
+}
+
+/// Check if the server is shutting down
+pub fn is_shutting_down() -> bool{
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Asks one server to stop, without stopping any others running in the same process
+/// 
+/// Clones share the same flag. It also counts as raised once the whole process is shutting down
+#[derive(Debug, Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+impl StopFlag{
+    pub fn stop(&self){
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the server this flag belongs to should stop
+    pub fn is_stopped(&self) -> bool{
+        self.0.load(Ordering::SeqCst) || is_shutting_down()
+    }
+}
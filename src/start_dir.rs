@@ -0,0 +1,194 @@
+use std::{env, ffi::OsString, fs, io::{self, ErrorKind}, path::{Path, PathBuf}};
+
+use super::{command_runner, logger};
+
+/// Maps client IP addresses to the directories their sessions start in
+/// 
+/// Loaded from the file given by the "RSPI_START_DIR_MAP" enviorment variable, which has one `<ip> <directory>` pair
+/// per line. Blank lines and lines starting with `#` are skipped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartDirMap{
+    pub dirs: Vec<(String, PathBuf)>
+}
+impl StartDirMap{
+    /// Parses the contents of a mapping file, returning `io::ErrorKind::InvalidData` for lines missing a directory
+    pub fn parse(src: &str) -> io::Result<Self>{
+        let mut dirs = Vec::new();
+        for line in src.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')){
+            match line.split_once(char::is_whitespace){
+                Some((ip, dir)) => dirs.push((ip.to_owned(), PathBuf::from(dir.trim()))),
+                None => return Err(io::Error::new(ErrorKind::InvalidData, format!("Expected an IP address and a directory, not {:?}", line)))
+            }
+        }
+        Ok(Self{dirs})
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Loads the map named by "RSPI_START_DIR_MAP", or None if it isn't set or can't be loaded
+    pub fn from_env() -> Option<Self>{
+        let path = env::var_os("RSPI_START_DIR_MAP").filter(|p| !p.is_empty())?;
+        Self::load(Path::new(&path)).inspect_err(|e| {
+            logger::error!("Could not load start directories from {}: {}", path.to_string_lossy(), e);
+        }).ok()
+    }
+
+    /// The directory clients from `ip` start in, if they have one
+    pub fn get(&self, ip: &str) -> Option<&Path>{
+        self.dirs.iter().find(|(mapped_ip, _)| mapped_ip == ip).map(|(_, dir)| dir.as_path())
+    }
+}
+
+/// Checks that `dir` is somewhere a session can start, logging why if it isn't
+/// 
+/// When clients are confined to `root`, the directory also has to be inside of it
+fn usable_dir(dir: &Path, root: Option<&Path>, source: &str) -> Option<PathBuf>{
+    let dir = match dir.canonicalize(){
+        Ok(dir) if dir.is_dir() => dir,
+        Ok(_) => {
+            logger::warn!("{} {} is not a directory, ignoring it", source, dir.display());
+            return None
+        },
+        Err(e) => {
+            logger::warn!("{} {} can't be used, ignoring it: {}", source, dir.display(), e);
+            return None
+        }
+    };
+    match root{
+        Some(root) if !dir.starts_with(root) => {
+            logger::warn!("{} {} is outside of RSPI_ROOT_DIR, ignoring it", source, dir.display());
+            None
+        },
+        _ => Some(dir)
+    }
+}
+
+/// Somewhere to start when the server's working directory can't be found, like when a client deleted it
+/// 
+/// That's "RSPI_HOME_DIR" or the `HOME` enviorment variable, whichever is set to a directory first, or `/` if neither is
+fn fallback_dir() -> PathBuf{
+    first_dir(["RSPI_HOME_DIR", "HOME"].iter().map(env::var_os))
+}
+
+/// The first of `candidates` that is set to a directory, or `/` if none are
+fn first_dir(candidates: impl IntoIterator<Item = Option<OsString>>) -> PathBuf{
+    candidates.into_iter()
+        .filter_map(|dir| dir.filter(|p| !p.is_empty()).map(PathBuf::from))
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
+
+/// The server's working directory `cwd`, or `fallback_dir` if it couldn't be found
+fn or_fallback(cwd: io::Result<PathBuf>) -> PathBuf{
+    cwd.unwrap_or_else(|e| {
+        let dir = fallback_dir();
+        logger::warn!("Could not get the server's working directory, starting in {} instead: {}", dir.display(), e);
+        dir
+    })
+}
+
+/// The root directory sessions are kept inside, if there is one. One that can't be used is ignored here, since
+/// `ClientSession::new` refuses to create sessions at all until it's fixed
+fn root_dir() -> Option<PathBuf>{
+    command_runner::root_dir().ok().flatten()
+}
+
+/// Finds the directory a session for a user whose home directory is `home` starts in, which is their home unless it
+/// can't be used, in which case it's wherever `for_client` says
+pub fn for_user(home: &Path, ip: &str) -> PathBuf{
+    let root = root_dir();
+    usable_dir(home, root.as_deref(), "Home directory").unwrap_or_else(|| for_client(ip))
+}
+
+/// Finds the directory a session for the client at `ip` starts in
+/// 
+/// That's the directory mapped to `ip` in "RSPI_START_DIR_MAP", or failing that, "RSPI_START_DIR". Configured directories
+/// that don't exist are skipped with a warning, and if there aren't any left, sessions start in "RSPI_ROOT_DIR" or the
+/// server's working directory. If that's gone too, they start in `fallback_dir` instead
+pub fn for_client(ip: &str) -> PathBuf{
+    let root = root_dir();
+    let mapped = StartDirMap::from_env().and_then(|map| map.get(ip).map(Path::to_path_buf));
+    if let Some(dir) = mapped.and_then(|dir| usable_dir(&dir, root.as_deref(), &format!("Start directory for {}", ip))){
+        return dir
+    }
+    let configured = env::var_os("RSPI_START_DIR").filter(|p| !p.is_empty()).map(PathBuf::from);
+    if let Some(dir) = configured.and_then(|dir| usable_dir(&dir, root.as_deref(), "RSPI_START_DIR")){
+        return dir
+    }
+    if let Some(root) = root{
+        return root
+    }
+    or_fallback(env::current_dir())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = env::temp_dir().join(format!("rspi-start_dir-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn maps_skip_blank_lines_and_comments(){
+        let map = StartDirMap::parse("# lab machines\n\n192.168.1.10 /home/pi/lab\n  10.0.0.2\t/srv/my projects  \n").unwrap();
+        assert_eq!(map.get("192.168.1.10"), Some(Path::new("/home/pi/lab")));
+        assert_eq!(map.get("10.0.0.2"), Some(Path::new("/srv/my projects")));
+        assert_eq!(map.get("10.0.0.3"), None);
+    }
+
+    #[test]
+    fn the_first_mapping_for_an_ip_wins(){
+        let map = StartDirMap::parse("10.0.0.2 /first\n10.0.0.2 /second\n").unwrap();
+        assert_eq!(map.get("10.0.0.2"), Some(Path::new("/first")));
+    }
+
+    #[test]
+    fn lines_without_a_directory_are_invalid(){
+        let err = StartDirMap::parse("10.0.0.2 /srv\n10.0.0.3\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn only_directories_inside_the_root_are_usable(){
+        let dir = temp_dir("usable");
+        fs::create_dir_all(dir.join("root/inside")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(dir.join("root/file"), "").unwrap();
+        let root = dir.join("root");
+
+        assert_eq!(usable_dir(&dir.join("root/inside"), Some(&root), "test"), Some(dir.join("root/inside")));
+        assert_eq!(usable_dir(&dir.join("outside"), None, "test"), Some(dir.join("outside")));
+        assert_eq!(usable_dir(&dir.join("outside"), Some(&root), "test"), None);
+        // `..` can't be used to get back out
+        assert_eq!(usable_dir(&dir.join("root/inside/../../outside"), Some(&root), "test"), None);
+        assert_eq!(usable_dir(&dir.join("root/file"), Some(&root), "test"), None);
+        assert_eq!(usable_dir(&dir.join("root/missing"), Some(&root), "test"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_first_candidate_thats_a_directory_is_the_fallback(){
+        let dir = temp_dir("fallback");
+        fs::write(dir.join("file"), "").unwrap();
+        let candidates = [None, Some(OsString::new()), Some(dir.join("missing").into()), Some(dir.join("file").into()), Some(dir.clone().into())];
+        assert_eq!(first_dir(candidates), dir);
+        assert_eq!(first_dir([None, Some(dir.join("missing").into())]), Path::new("/"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sessions_start_somewhere_when_the_working_directory_is_gone(){
+        let dir = temp_dir("cwd");
+        assert_eq!(or_fallback(Ok(dir.clone())), dir);
+        let fallback = or_fallback(Err(io::Error::from(ErrorKind::NotFound)));
+        assert_eq!(fallback, fallback_dir());
+        assert!(fallback.is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fmt, fs, time::Duration};
+
+use super::json;
+
+/// Load averages over the last 1, 5 and 15 minutes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadAverage{
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64
+}
+
+/// Memory of the whole system, in bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemInfo{
+    pub total: u64,
+    pub free: u64,
+    /// Memory that can be used without swapping, which counts caches that can be dropped. Missing on older kernels
+    pub available: Option<u64>
+}
+
+/// Stats about the host the server is running on
+/// 
+/// Each field is None if it couldn't be read on this platform
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SysInfo{
+    pub uptime: Option<Duration>,
+    pub load: Option<LoadAverage>,
+    pub memory: Option<MemInfo>,
+    /// CPU temperature in degrees Celsius
+    pub cpu_temp: Option<f64>
+}
+impl SysInfo{
+    /// Reads stats from `/proc` and `/sys`
+    pub fn read() -> Self{
+        Self{
+            uptime: fs::read_to_string("/proc/uptime").ok().and_then(|s| parse_uptime(&s)),
+            load: fs::read_to_string("/proc/loadavg").ok().and_then(|s| parse_loadavg(&s)),
+            memory: fs::read_to_string("/proc/meminfo").ok().and_then(|s| parse_meminfo(&s)),
+            cpu_temp: fs::read_to_string("/sys/class/thermal/thermal_zone0/temp").ok().and_then(|s| parse_temp(&s))
+        }
+    }
+
+    /// The same stats as the Display output, as a JSON object. Memory is in bytes and uptime in seconds
+    pub fn to_json(self) -> String{
+        format!("{{\"uptime_secs\":{},\"load\":{},\"memory\":{},\"cpu_temp\":{}}}",
+            json::or_null(self.uptime.map(|uptime| uptime.as_secs())),
+            json::or_null(self.load.map(|load| format!("[{},{},{}]", load.one, load.five, load.fifteen))),
+            json::or_null(self.memory.map(|mem| format!("{{\"total\":{},\"free\":{},\"available\":{}}}", mem.total, mem.free, json::or_null(mem.available)))),
+            json::or_null(self.cpu_temp))
+    }
+}
+
+impl fmt::Display for SysInfo{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        if let Some(uptime) = self.uptime{
+            let secs = uptime.as_secs();
+            writeln!(f, "uptime\t{}d {}h {}m", secs / 86400, secs % 86400 / 3600, secs % 3600 / 60)?;
+        }
+        if let Some(load) = self.load{
+            writeln!(f, "load\t{:.2} {:.2} {:.2}", load.one, load.five, load.fifteen)?;
+        }
+        if let Some(mem) = self.memory{
+            writeln!(f, "memory\t{} MiB total, {} MiB free", mem.total / (1024 * 1024), mem.free / (1024 * 1024))?;
+            if let Some(available) = mem.available{
+                writeln!(f, "\t{} MiB available", available / (1024 * 1024))?;
+            }
+        }
+        if let Some(temp) = self.cpu_temp{
+            writeln!(f, "cpu temp\t{:.1}°C", temp)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the contents of `/proc/uptime`, ie. "350735.47 234388.90"
+pub fn parse_uptime(src: &str) -> Option<Duration>{
+    let secs: f64 = src.split_whitespace().next()?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Parses the contents of `/proc/loadavg`, ie. "0.20 0.18 0.12 1/80 11206"
+pub fn parse_loadavg(src: &str) -> Option<LoadAverage>{
+    let mut fields = src.split_whitespace();
+    Some(LoadAverage{
+        one: fields.next()?.parse().ok()?,
+        five: fields.next()?.parse().ok()?,
+        fifteen: fields.next()?.parse().ok()?
+    })
+}
+
+/// Parses the contents of `/proc/meminfo`, which lists sizes in kB like "MemTotal:  948304 kB"
+pub fn parse_meminfo(src: &str) -> Option<MemInfo>{
+    let field = |name: &str| src.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+    Some(MemInfo{total: field("MemTotal")?, free: field("MemFree")?, available: field("MemAvailable")})
+}
+
+/// Parses a thermal zone's temperature, which is given in thousandths of a degree Celsius
+pub fn parse_temp(src: &str) -> Option<f64>{
+    src.trim().parse::<i64>().ok().map(|millis| millis as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const MEMINFO: &str = "MemTotal:         948304 kB\nMemFree:          102400 kB\nMemAvailable:     512000 kB\nBuffers:           20480 kB\n";
+
+    #[test]
+    fn proc_files_are_parsed(){
+        assert_eq!(parse_uptime("350735.47 234388.90\n"), Some(Duration::from_secs_f64(350735.47)));
+        assert_eq!(parse_loadavg("0.20 0.18 0.12 1/80 11206\n"), Some(LoadAverage{one: 0.2, five: 0.18, fifteen: 0.12}));
+        assert_eq!(parse_meminfo(MEMINFO), Some(MemInfo{total: 948304 * 1024, free: 102400 * 1024, available: Some(512000 * 1024)}));
+        assert_eq!(parse_temp("48312\n"), Some(48.312));
+        assert_eq!(parse_temp("-5000"), Some(-5.0));
+    }
+
+    #[test]
+    fn older_kernels_dont_have_available_memory(){
+        let mem = parse_meminfo("MemTotal: 2048 kB\nMemFree: 1024 kB\n").unwrap();
+        assert_eq!(mem.available, None);
+        // a field whose name only starts with another's doesn't count as it
+        assert_eq!(parse_meminfo("MemTotalish: 1 kB\nMemFree: 1024 kB\n"), None);
+    }
+
+    #[test]
+    fn malformed_files_are_none(){
+        assert_eq!(parse_uptime(""), None);
+        assert_eq!(parse_uptime("-1.0 2.0"), None);
+        assert_eq!(parse_loadavg("0.20 0.18"), None);
+        assert_eq!(parse_loadavg("0.20 high 0.12"), None);
+        assert_eq!(parse_meminfo("MemTotal: lots kB\nMemFree: 1024 kB\n"), None);
+        assert_eq!(parse_temp("hot"), None);
+    }
+
+    #[test]
+    fn stats_are_displayed_and_written_as_json(){
+        let info = SysInfo{
+            uptime: Some(Duration::from_secs(2 * 86400 + 3 * 3600 + 4 * 60 + 5)),
+            load: Some(LoadAverage{one: 0.5, five: 0.25, fifteen: 0.125}),
+            memory: parse_meminfo(MEMINFO),
+            cpu_temp: Some(48.312)
+        };
+        assert_eq!(info.to_string(), "uptime\t2d 3h 4m\nload\t0.50 0.25 0.12\nmemory\t926 MiB total, 100 MiB free\n\t500 MiB available\ncpu temp\t48.3°C\n");
+        assert_eq!(json::parse(&info.to_json()).unwrap(), json::parse(concat!(r#"{"uptime_secs":183845,"load":[0.5,0.25,0.125],"#,
+            r#""memory":{"total":971063296,"free":104857600,"available":524288000},"cpu_temp":48.312}"#)).unwrap());
+        assert_eq!(SysInfo::default().to_string(), "");
+        assert_eq!(SysInfo::default().to_json(), r#"{"uptime_secs":null,"load":null,"memory":null,"cpu_temp":null}"#);
+    }
+}
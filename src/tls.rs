@@ -0,0 +1,270 @@
+use std::{env, io::{self, ErrorKind, Read, Write}, net::TcpStream, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+pub use rustls::ServerConfig;
+use rustls::{crypto::ring, server::WebPkiClientVerifier, RootCertStore, ServerConnection};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use super::poison;
+
+/// Longest time a client gets to finish the TLS handshake, so one that never does can't hold a thread forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads the certificate chain and private key given by the "RSPI_TLS_CERT" and "RSPI_TLS_KEY" enviorment variables,
+/// both PEM files, or None if they aren't set
+/// 
+/// If "RSPI_TLS_CLIENT_CA" is also set, clients have to present a certificate signed by one of the CAs in that PEM file.\
+/// Returns `io::ErrorKind::InvalidInput` if only some of them are set, or if they can't be used
+pub fn config_from_env() -> io::Result<Option<Arc<ServerConfig>>>{
+    let cert = env::var_os("RSPI_TLS_CERT").filter(|p| !p.is_empty());
+    let key = env::var_os("RSPI_TLS_KEY").filter(|p| !p.is_empty());
+    let client_ca = env::var_os("RSPI_TLS_CLIENT_CA").filter(|p| !p.is_empty());
+    let (cert, key) = match (cert, key){
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if client_ca.is_none() => return Ok(None),
+        (None, None) => return Err(io::Error::new(ErrorKind::InvalidInput, "RSPI_TLS_CLIENT_CA needs RSPI_TLS_CERT and RSPI_TLS_KEY to be set too")),
+        _ => return Err(io::Error::new(ErrorKind::InvalidInput, "RSPI_TLS_CERT and RSPI_TLS_KEY have to be set together"))
+    };
+    load_config(Path::new(&cert), Path::new(&key), client_ca.as_deref().map(Path::new)).map(Some)
+}
+
+/// Builds the TLS settings from the certificate chain and private key at `cert` and `key`, and optionally the CAs
+/// client certificates have to be signed by at `client_ca`, all PEM files
+pub fn load_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Arc<ServerConfig>>{
+    let invalid = |e: &dyn std::fmt::Display| io::Error::new(ErrorKind::InvalidInput, format!("Could not load TLS certificate: {}", e));
+    let certs = CertificateDer::pem_file_iter(cert).map_err(|e| invalid(&e))?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| invalid(&e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(&e))?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions().map_err(|e| invalid(&e))?;
+    let builder = match client_ca{
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(client_ca).map_err(|e| invalid(&e))?{
+                roots.add(ca.map_err(|e| invalid(&e))?).map_err(|e| invalid(&e))?;
+            }
+            // clients without a certificate are turned away during the handshake
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().map_err(|e| invalid(&e))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+    Ok(Arc::new(builder.with_single_cert(certs, key).map_err(|e| invalid(&e))?))
+}
+
+/// Splits the DER value at the start of `der` into its tag, its contents and whatever comes after it
+fn der_next(der: &[u8]) -> Option<(u8, &[u8], &[u8])>{
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    // lengths over 127 are given by the big-endian number in the next (len & 0x7f) bytes
+    let (len, rest) = if len & 0x80 == 0 { (len as usize, rest) } else {
+        let len_bytes = (len & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 || rest.len() < len_bytes { return None }
+        (rest[..len_bytes].iter().fold(0, |len, byte| len << 8 | *byte as usize), &rest[len_bytes..])
+    };
+    if rest.len() < len { return None }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// Finds the common name (CN) of the subject of a DER-encoded X.509 certificate
+fn common_name(cert: &[u8]) -> Option<String>{
+    let (_, cert, _) = der_next(cert)?;
+    let (_, tbs, _) = der_next(cert)?;
+    // the version comes first, but only if it isn't the default. the subject comes after the serial number,
+    // signature algorithm, issuer and validity
+    let (tag, _, mut rest) = der_next(tbs)?;
+    let skip = if tag == 0xa0 { 4 } else { 3 };
+    for _ in 0..skip{
+        rest = der_next(rest)?.2;
+    }
+    let (_, mut subject, _) = der_next(rest)?;
+    while !subject.is_empty(){
+        let (_, name, next) = der_next(subject)?;
+        subject = next;
+        let (_, attribute, _) = der_next(name)?;
+        let (_, oid, value) = der_next(attribute)?;
+        // 2.5.4.3 is the OID of the common name
+        if oid == [0x55, 0x04, 0x03]{
+            return Some(String::from_utf8_lossy(der_next(value)?.1).into_owned())
+        }
+    }
+    None
+}
+
+/// A TCP connection encrypted with TLS
+/// 
+/// Clones share the same TLS session, so one can be read from while another is written to. The socket is read
+/// without holding onto the session, so a read waiting on the client doesn't hold up writes
+pub struct TlsStream{
+    conn: Arc<Mutex<ServerConnection>>,
+    sock: TcpStream
+}
+impl TlsStream{
+    /// Performs the server's side of the TLS handshake over `sock`
+    pub fn accept(config: Arc<ServerConfig>, mut sock: TcpStream) -> io::Result<Self>{
+        let mut conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking(){
+            conn.complete_io(&mut sock)?;
+        }
+        while conn.wants_write(){
+            conn.write_tls(&mut sock)?;
+        }
+        sock.set_read_timeout(None)?;
+        Ok(Self{conn: Arc::new(Mutex::new(conn)), sock})
+    }
+
+    /// Common name of the certificate the client authenticated with, or None if it didn't present one
+    /// 
+    /// Certificates are only asked for when the server was configured with "RSPI_TLS_CLIENT_CA", in which case
+    /// they've already been verified by the time the handshake is done
+    pub fn client_identity(&self) -> Option<String>{
+        let conn = poison::lock(&self.conn, "TLS session");
+        let cert = conn.peer_certificates()?.first()?;
+        Some(common_name(cert).unwrap_or(String::from("unknown")))
+    }
+
+    pub fn sock(&self) -> &TcpStream{
+        &self.sock
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self>{
+        Ok(Self{conn: self.conn.clone(), sock: self.sock.try_clone()?})
+    }
+
+    /// Sends anything the TLS session has queued up, like encrypted data or alerts
+    fn flush_tls(&self, conn: &mut ServerConnection) -> io::Result<()>{
+        while conn.wants_write(){
+            conn.write_tls(&mut &self.sock)?;
+        }
+        Ok(())
+    }
+
+    /// Lets the client know the connection is closing, so it can tell a clean close apart from a dropped connection
+    pub fn close(&self) -> io::Result<()>{
+        let mut conn = poison::lock(&self.conn, "TLS session");
+        conn.send_close_notify();
+        self.flush_tls(&mut conn)
+    }
+}
+
+impl Read for &TlsStream{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        let mut raw = [0u8; 4096];
+        loop{
+            match poison::lock(&self.conn, "TLS session").reader().read(buf){
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                res => return res
+            }
+            // nothing has been decrypted yet, so wait on the client for more
+            let len = (&self.sock).read(&mut raw)?;
+            if len == 0 { return Ok(0) }
+            let mut conn = poison::lock(&self.conn, "TLS session");
+            let mut received = &raw[..len];
+            while !received.is_empty(){
+                if conn.read_tls(&mut received)? == 0 { break }
+                let processed = conn.process_new_packets().map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+                // if the client did something wrong, try to tell it what before giving up
+                self.flush_tls(&mut conn)?;
+                processed?;
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        let mut conn = poison::lock(&self.conn, "TLS session");
+        let written = conn.writer().write(buf)?;
+        self.flush_tls(&mut conn)?;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.flush_tls(&mut poison::lock(&self.conn, "TLS session"))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{net::TcpListener, thread};
+    use rustls::{ClientConfig, ClientConnection, StreamOwned};
+    use super::*;
+
+    /// Path of one of the certificates or keys in src/testdata
+    fn testdata(name: &str) -> std::path::PathBuf{
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata").join(name)
+    }
+
+    /// Client settings that trust the server's self-signed certificate, presenting the certificate `client` if given
+    fn client_config(client: Option<&str>) -> Arc<ClientConfig>{
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(testdata("server.pem")).unwrap()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots);
+        Arc::new(match client{
+            Some(name) => {
+                let cert = CertificateDer::from_pem_file(testdata(&format!("{}.pem", name))).unwrap();
+                let key = PrivateKeyDer::from_pem_file(testdata(&format!("{}.key", name))).unwrap();
+                builder.with_client_auth_cert(vec![cert], key).unwrap()
+            },
+            None => builder.with_no_client_auth()
+        })
+    }
+
+    /// Connects a client using `client` to a server using `server`, returning the client's side of the connection
+    /// and what the server made of it
+    fn connect(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> (StreamOwned<ClientConnection, TcpStream>, io::Result<TlsStream>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = thread::spawn(move || TlsStream::accept(server, listener.accept()?.0));
+        let sock = TcpStream::connect(addr).unwrap();
+        let mut stream = StreamOwned::new(ClientConnection::new(client, "localhost".try_into().unwrap()).unwrap(), sock);
+        // the server may turn the client away as soon as the handshake is done, so this can fail
+        let _ = stream.conn.complete_io(&mut stream.sock);
+        (stream, accepting.join().unwrap())
+    }
+
+    #[test]
+    fn clients_connect_to_a_self_signed_certificate(){
+        let server = load_config(&testdata("server.pem"), &testdata("server.key"), None).unwrap();
+        let (mut client, accepted) = connect(server, client_config(None));
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.client_identity(), None);
+
+        client.write_all(b"hello\n").unwrap();
+        let mut buf = [0u8; 6];
+        (&accepted).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+
+        (&accepted).write_all(b"hi back").unwrap();
+        accepted.close().unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "hi back");
+    }
+
+    #[test]
+    fn client_certificates_signed_by_the_ca_are_accepted(){
+        let server = load_config(&testdata("server.pem"), &testdata("server.key"), Some(&testdata("client_ca.pem"))).unwrap();
+        let (_client, accepted) = connect(server, client_config(Some("alice")));
+        assert_eq!(accepted.unwrap().client_identity().as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn other_client_certificates_are_turned_away(){
+        let server = load_config(&testdata("server.pem"), &testdata("server.key"), Some(&testdata("client_ca.pem"))).unwrap();
+        let (_client, accepted) = connect(server.clone(), client_config(Some("mallory")));
+        assert!(accepted.is_err());
+        let (_client, accepted) = connect(server, client_config(None));
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn unusable_or_missing_files_are_invalid_input(){
+        let err = load_config(&testdata("server.pem"), &testdata("server.pem"), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = load_config(&testdata("server.pem"), &testdata("nothing.key"), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
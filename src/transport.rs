@@ -0,0 +1,246 @@
+use std::{ffi::c_void, io::{self, Read, Write}, net::{Shutdown, SocketAddr, TcpStream}, os::{fd::AsRawFd, unix::net::UnixStream}, sync::Arc, time::Duration};
+
+use super::secure_stream::Socket;
+use super::tls::{ServerConfig, TlsStream};
+
+unsafe extern "C"{
+    fn setsockopt(fd: i32, level: i32, name: i32, value: *const c_void, len: u32) -> i32;
+}
+
+const SOL_SOCKET: i32 = 1;
+const SO_KEEPALIVE: i32 = 9;
+const IPPROTO_TCP: i32 = 6;
+const TCP_KEEPIDLE: i32 = 4;
+const TCP_KEEPINTVL: i32 = 5;
+
+/// Sets an integer socket option
+fn set_int_option(fd: i32, level: i32, name: i32, value: i32) -> io::Result<()>{
+    let res = unsafe { setsockopt(fd, level, name, &value as *const i32 as *const c_void, size_of::<i32>() as u32) };
+    if res == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// The socket a client is connected through
+/// 
+/// Clients on the same machine can connect through a Unix domain socket instead of TCP, and TCP connections
+/// can be encrypted with TLS when the server has a certificate
+pub enum Transport{
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream)
+}
+impl Transport{
+    /// Address of the connected client, or None for Unix sockets
+    pub fn peer_addr(&self) -> Option<SocketAddr>{
+        match self{
+            Transport::Tcp(s) => s.peer_addr().ok(),
+            Transport::Unix(_) => None,
+            Transport::Tls(s) => s.sock().peer_addr().ok()
+        }
+    }
+    /// Whether data sent through this transport never leaves the machine, so it doesn't need to be encrypted
+    pub fn is_local(&self) -> bool{
+        matches!(self, Transport::Unix(_))
+    }
+    /// Whether this transport is already encrypted, so it doesn't need to be encrypted again
+    pub fn is_encrypted(&self) -> bool{
+        matches!(self, Transport::Tls(_))
+    }
+    /// Who the client proved it is with a TLS client certificate, if it did
+    pub fn client_identity(&self) -> Option<String>{
+        match self{
+            Transport::Tls(s) => s.client_identity(),
+            _ => None
+        }
+    }
+    /// Wraps a TCP connection in TLS, performing the handshake. Other transports are returned as they are
+    pub fn into_tls(self, config: Arc<ServerConfig>) -> io::Result<Self>{
+        match self{
+            Transport::Tcp(s) => TlsStream::accept(config, s).map(Transport::Tls),
+            other => Ok(other)
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        match self{
+            Transport::Tcp(s) => s.set_nonblocking(nonblocking),
+            Transport::Unix(s) => s.set_nonblocking(nonblocking),
+            Transport::Tls(s) => s.sock().set_nonblocking(nonblocking)
+        }
+    }
+}
+
+impl Socket for Transport{
+    /// IP address of the connected client, or "local" for Unix sockets
+    fn peer_ip(&self) -> io::Result<String>{
+        match self{
+            Transport::Tcp(s) => s.peer_addr().map(|addr| addr.ip().to_string()),
+            Transport::Unix(_) => Ok(String::from("local")),
+            Transport::Tls(s) => s.sock().peer_addr().map(|addr| addr.ip().to_string())
+        }
+    }
+    /// IP address this end of the connection is bound to, or the socket's path for Unix sockets
+    fn local_ip(&self) -> io::Result<String>{
+        match self{
+            Transport::Tcp(s) => s.local_addr().map(|addr| addr.ip().to_string()),
+            Transport::Unix(s) => Ok(s.local_addr()?.as_pathname().map_or(String::from("local"), |p| p.display().to_string())),
+            Transport::Tls(s) => s.sock().local_addr().map(|addr| addr.ip().to_string())
+        }
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>{
+        match self{
+            Transport::Tcp(s) => s.shutdown(how),
+            Transport::Unix(s) => s.shutdown(how),
+            Transport::Tls(s) => {
+                if how != Shutdown::Read { let _ = s.close(); }
+                s.sock().shutdown(how)
+            }
+        }
+    }
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>{
+        match self{
+            Transport::Tcp(s) => s.set_read_timeout(dur),
+            Transport::Unix(s) => s.set_read_timeout(dur),
+            Transport::Tls(s) => s.sock().set_read_timeout(dur)
+        }
+    }
+    fn read_timeout(&self) -> io::Result<Option<Duration>>{
+        match self{
+            Transport::Tcp(s) => s.read_timeout(),
+            Transport::Unix(s) => s.read_timeout(),
+            Transport::Tls(s) => s.sock().read_timeout()
+        }
+    }
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()>{
+        match self{
+            Transport::Tcp(s) => s.set_write_timeout(dur),
+            Transport::Unix(s) => s.set_write_timeout(dur),
+            Transport::Tls(s) => s.sock().set_write_timeout(dur)
+        }
+    }
+    fn write_timeout(&self) -> io::Result<Option<Duration>>{
+        match self{
+            Transport::Tcp(s) => s.write_timeout(),
+            Transport::Unix(s) => s.write_timeout(),
+            Transport::Tls(s) => s.sock().write_timeout()
+        }
+    }
+    /// Turns on TCP keepalive, sending the first probe after the connection has been idle for `dur` and
+    /// then one every `dur` until the peer answers or the OS gives up. Does nothing for Unix sockets
+    fn set_keepalive(&self, dur: Option<Duration>) -> io::Result<()>{
+        let s = match self{
+            Transport::Tcp(s) => s,
+            Transport::Tls(s) => s.sock(),
+            Transport::Unix(_) => return Ok(())
+        };
+        let fd = s.as_raw_fd();
+        match dur{
+            Some(dur) => {
+                let secs = i32::try_from(dur.as_secs().max(1)).unwrap_or(i32::MAX);
+                set_int_option(fd, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+                set_int_option(fd, IPPROTO_TCP, TCP_KEEPIDLE, secs)?;
+                set_int_option(fd, IPPROTO_TCP, TCP_KEEPINTVL, secs)
+            },
+            None => set_int_option(fd, SOL_SOCKET, SO_KEEPALIVE, 0)
+        }
+    }
+    fn try_clone(&self) -> io::Result<Self>{
+        match self{
+            Transport::Tcp(s) => s.try_clone().map(Transport::Tcp),
+            Transport::Unix(s) => s.try_clone().map(Transport::Unix),
+            Transport::Tls(s) => s.try_clone().map(Transport::Tls)
+        }
+    }
+}
+
+impl Read for Transport{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        match self{
+            Transport::Tcp(s) => s.read(buf),
+            Transport::Unix(s) => s.read(buf),
+            Transport::Tls(s) => (&*s).read(buf)
+        }
+    }
+}
+
+impl Write for &Transport{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        match self{
+            Transport::Tcp(s) => (&*s).write(buf),
+            Transport::Unix(s) => (&*s).write(buf),
+            Transport::Tls(s) => (&*s).write(buf)
+        }
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        match self{
+            Transport::Tcp(s) => (&*s).flush(),
+            Transport::Unix(s) => (&*s).flush(),
+            Transport::Tls(s) => (&*s).flush()
+        }
+    }
+}
+
+impl Write for Transport{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        (&*self).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        (&*self).flush()
+    }
+}
+
+impl From<TcpStream> for Transport{
+    fn from(stream: TcpStream) -> Self{
+        Transport::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Transport{
+    fn from(stream: UnixStream) -> Self{
+        Transport::Unix(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::net::TcpListener;
+    use super::*;
+
+    unsafe extern "C"{
+        fn getsockopt(fd: i32, level: i32, name: i32, value: *mut c_void, len: *mut u32) -> i32;
+    }
+
+    /// Reads back an integer socket option
+    fn int_option(s: &TcpStream, level: i32, name: i32) -> i32{
+        let mut value = 0i32;
+        let mut len = size_of::<i32>() as u32;
+        let res = unsafe { getsockopt(s.as_raw_fd(), level, name, &mut value as *mut i32 as *mut c_void, &mut len) };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn keepalive_is_set_on_tcp_sockets(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let transport = Transport::from(listener.accept().unwrap().0);
+        let Transport::Tcp(sock) = &transport else { unreachable!() };
+
+        transport.set_keepalive(Some(Duration::from_secs(45))).unwrap();
+        assert_eq!(int_option(sock, SOL_SOCKET, SO_KEEPALIVE), 1);
+        assert_eq!(int_option(sock, IPPROTO_TCP, TCP_KEEPIDLE), 45);
+        assert_eq!(int_option(sock, IPPROTO_TCP, TCP_KEEPINTVL), 45);
+        // the OS counts in whole seconds, and won't take 0
+        transport.set_keepalive(Some(Duration::from_millis(300))).unwrap();
+        assert_eq!(int_option(sock, IPPROTO_TCP, TCP_KEEPIDLE), 1);
+        transport.set_keepalive(None).unwrap();
+        assert_eq!(int_option(sock, SOL_SOCKET, SO_KEEPALIVE), 0);
+        drop(client);
+    }
+
+    #[test]
+    fn keepalive_does_nothing_for_unix_sockets(){
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let transport = Transport::from(ours);
+        assert!(transport.set_keepalive(Some(Duration::from_secs(45))).is_ok());
+        assert!(transport.set_keepalive(None).is_ok());
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::{mpsc::{self, Receiver, SyncSender, TrySendError}, Arc, Mutex};
+
+use super::poison;
+
+/// Most chunks of output a watcher can fall behind by before it starts missing some
+const WATCH_BACKLOG: usize = 64;
+
+/// Clients watching a session's output without owning it, each getting their own copy of everything it outputs
+/// 
+/// Watchers only ever get copies, so a slow one never holds up the process or whoever owns the session. One that
+/// falls more than `WATCH_BACKLOG` chunks behind misses output until it catches up
+#[derive(Clone, Default)]
+pub struct Watchers(Arc<Mutex<Vec<SyncSender<Vec<u8>>>>>);
+impl Watchers{
+    /// Adds a watcher, which gets `backlog` before anything else
+    /// 
+    /// Once the session is gone, the returned receiver is disconnected
+    pub fn add(&self, backlog: Vec<u8>) -> Receiver<Vec<u8>>{
+        let (tx, rx) = mpsc::sync_channel(WATCH_BACKLOG);
+        if !backlog.is_empty(){
+            let _ = tx.try_send(backlog);
+        }
+        poison::lock(&self.0, "watchers").push(tx);
+        rx
+    }
+
+    /// Sends a copy of `output` to every watcher, forgetting about the ones that have stopped watching
+    pub fn broadcast(&self, output: &[u8]){
+        poison::lock(&self.0, "watchers").retain(|tx| !matches!(tx.try_send(output.to_vec()), Err(TrySendError::Disconnected(_))));
+    }
+}
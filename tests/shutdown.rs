@@ -0,0 +1,46 @@
+//! SIGTERM stops every server in the process, so this runs in a test binary of its own
+
+use std::{env, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpListener}, os::unix::net::UnixStream, process::Command,
+    sync::Arc, time::{Duration, Instant}};
+
+use rs_pi_server::{server::Server, shutdown};
+
+/// Reads from `conn` until `end` arrives, returning everything read up to and including it
+fn read_until(mut conn: &UnixStream, end: &str) -> String{
+    let mut received = Vec::new();
+    let mut byte = [0u8];
+    while !received.ends_with(end.as_bytes()){
+        assert_eq!(conn.read(&mut byte).unwrap(), 1, "connection closed before {:?}, after {:?}", end, String::from_utf8_lossy(&received));
+        received.push(byte[0]);
+    }
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+#[test]
+fn sigterm_disconnects_clients_and_frees_the_port(){
+    let path = env::temp_dir().join(format!("rspi-shutdown-socket-{}", std::process::id()));
+    env::set_var("RSPI_SOCKET_PATH", &path);
+    let server = Arc::new(Server::bind(&[SocketAddr::from(([127, 0, 0, 1], 0))]).unwrap());
+    let addr = server.local_addrs()[0];
+    shutdown::install_handlers();
+    let running = server.clone().run_nonblocking();
+
+    // the Unix socket skips the encryption, so a client can log in with just the password
+    let mut conn = UnixStream::connect(&path).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    conn.write_all(b"Password").unwrap();
+    read_until(&conn, "$ ");
+    assert!(TcpListener::bind(addr).is_err_and(|e| e.kind() == ErrorKind::AddrInUse));
+
+    let signalled = Instant::now();
+    assert!(Command::new("kill").args(["-TERM", &std::process::id().to_string()]).status().unwrap().success());
+    running.join().unwrap().unwrap();
+    assert!(signalled.elapsed() < Duration::from_secs(5));
+    assert!(shutdown::is_shutting_down());
+
+    // the client is hung up on, rather than left waiting on a server that's gone
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest).unwrap();
+    TcpListener::bind(addr).unwrap();
+    assert!(!path.exists());
+}